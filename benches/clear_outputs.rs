@@ -0,0 +1,58 @@
+//! Timings for `clear_outputs` on large documents, including adversarial ones.
+//!
+//! Run with `cargo bench --bench clear_outputs`. Each case reports the best of
+//! several runs, so it is fit for comparing changes on the same machine. These
+//! are plain timing loops rather than Criterion benchmarks, since Criterion is
+//! not available to this build.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use runmd::config::Config;
+use runmd::core::clear_outputs;
+
+const RUNS: usize = 5;
+
+/// `blocks` executed blocks, each followed by an output section of `lines` lines.
+fn with_outputs(blocks: usize, lines: usize) -> String {
+    let mut doc = String::from("# Benchmark\n\n");
+    for i in 0..blocks {
+        doc.push_str(&format!("Block {}\n\n```python\nprint({})\n```\n**Output**\n```\n", i, i));
+        for line in 0..lines {
+            doc.push_str(&format!("output line {}\n", line));
+        }
+        doc.push_str("```\n\n");
+    }
+    doc
+}
+
+/// Output headers whose fences are never closed.
+fn unclosed_sections(count: usize) -> String {
+    "**Output**\n```\n".repeat(count)
+}
+
+/// Headers with no fence after them, between ordinary prose.
+fn bare_headers(count: usize) -> String {
+    "Some text\n**Output**\n".repeat(count)
+}
+
+fn bench(name: &str, doc: &str, config: &Config) {
+    let best = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(clear_outputs(black_box(doc), config).unwrap());
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or(Duration::ZERO);
+    println!("{:<32} {:>8} KiB {:>10.2?}", name, doc.len() / 1024, best);
+}
+
+fn main() {
+    let config = Config::default();
+    bench("1000 outputs of 5 lines", &with_outputs(1000, 5), &config);
+    bench("10000 outputs of 1 line", &with_outputs(10_000, 1), &config);
+    bench("100 outputs of 1000 lines", &with_outputs(100, 1000), &config);
+    bench("20000 unclosed sections", &unclosed_sections(20_000), &config);
+    bench("20000 headers without fences", &bare_headers(20_000), &config);
+}
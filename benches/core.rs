@@ -0,0 +1,22 @@
+//! Timings of parsing, clearing and rendering a synthetic 10,000-block document.
+//!
+//! Run with `cargo bench --bench core`. The same stages are measured by
+//! `runmd --bench-internal`, which can also compare them with a saved baseline.
+//! Like `clear_outputs`, this is a timing loop rather than a Criterion benchmark.
+
+use runmd::bench;
+
+fn main() {
+    for blocks in [100, 1000, 10_000] {
+        let timings = bench::run(blocks, 5).unwrap();
+        for (stage, ms) in &timings.stages_ms {
+            println!(
+                "{:<8} {:>6} blocks {:>6} KiB {:>10.2}ms",
+                stage,
+                timings.blocks,
+                timings.bytes / 1024,
+                ms
+            );
+        }
+    }
+}
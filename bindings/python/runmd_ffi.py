@@ -0,0 +1,106 @@
+"""Python bindings for runmd's processing core.
+
+Loads the shared library built by ``cargo build --release`` (``librunmd.so``,
+``librunmd.dylib`` or ``runmd.dll``) from ``RUNMD_LIB``, next to this module, or
+``target/release`` of the checkout it lives in. Install with
+``pip install bindings/python``; an installed copy needs ``RUNMD_LIB`` unless the
+library is copied next to it.
+
+    import runmd_ffi
+
+    def execute(language, code):
+        return f"ran {language}", 0
+
+    text = runmd_ffi.process(open("notes.md").read(), execute)
+"""
+
+import ctypes
+import os
+import sys
+from pathlib import Path
+from typing import Callable, Optional, Tuple
+
+_EXECUTE_FN = ctypes.CFUNCTYPE(
+    ctypes.c_int, ctypes.c_void_p, ctypes.c_char_p, ctypes.c_char_p, ctypes.c_void_p
+)
+
+
+def _library_path() -> str:
+    if "RUNMD_LIB" in os.environ:
+        return os.environ["RUNMD_LIB"]
+    if sys.platform == "darwin":
+        name = "librunmd.dylib"
+    elif sys.platform == "win32":
+        name = "runmd.dll"
+    else:
+        name = "librunmd.so"
+    here = Path(__file__).resolve().parent
+    bundled = here / name
+    if bundled.exists():
+        return str(bundled)
+    return str(here.parents[1] / "target" / "release" / name)
+
+
+_lib = ctypes.CDLL(_library_path())
+_lib.runmd_clear_outputs.argtypes = [ctypes.c_char_p, ctypes.c_char_p]
+_lib.runmd_clear_outputs.restype = ctypes.c_void_p
+_lib.runmd_process.argtypes = [ctypes.c_char_p, ctypes.c_char_p, _EXECUTE_FN, ctypes.c_void_p]
+_lib.runmd_process.restype = ctypes.c_void_p
+_lib.runmd_result_set_output.argtypes = [ctypes.c_void_p, ctypes.c_char_p]
+_lib.runmd_result_set_output.restype = None
+_lib.runmd_last_error.argtypes = []
+_lib.runmd_last_error.restype = ctypes.c_void_p
+_lib.runmd_string_free.argtypes = [ctypes.c_void_p]
+_lib.runmd_string_free.restype = None
+
+
+class RunmdError(Exception):
+    pass
+
+
+def _take_string(ptr: Optional[int]) -> str:
+    if not ptr:
+        error = _lib.runmd_last_error()
+        message = ctypes.string_at(error).decode() if error else "unknown error"
+        _lib.runmd_string_free(error)
+        raise RunmdError(message)
+    try:
+        return ctypes.string_at(ptr).decode()
+    finally:
+        _lib.runmd_string_free(ptr)
+
+
+def _encode(value: Optional[str]) -> Optional[bytes]:
+    return None if value is None else value.encode()
+
+
+def clear_outputs(text: str, config: Optional[str] = None) -> str:
+    """Remove output sections. ``config`` is a YAML config document; by default
+    the user's ``languages.config`` is used."""
+    return _take_string(_lib.runmd_clear_outputs(text.encode(), _encode(config)))
+
+
+def process(
+    text: str,
+    execute: Callable[[str, str], Tuple[str, int]],
+    config: Optional[str] = None,
+) -> str:
+    """Call ``execute(language, code)`` for each runnable block in document order
+    and return the document with its ``(output, exit_code)`` attached."""
+
+    errors = []
+
+    def callback(_user_data, language, code, result):
+        try:
+            output, exit_code = execute(language.decode(), code.decode())
+        except Exception as e:  # exceptions must not cross the C boundary
+            errors.append(e)
+            output, exit_code = f"[error] {e}", 1
+        _lib.runmd_result_set_output(result, output.encode())
+        return exit_code
+
+    ptr = _lib.runmd_process(text.encode(), _encode(config), _EXECUTE_FN(callback), None)
+    if errors:
+        _lib.runmd_string_free(ptr)
+        raise errors[0]
+    return _take_string(ptr)
@@ -0,0 +1,49 @@
+"""Tests for runmd_ffi against the built library.
+
+Run with ``python3 -m unittest test_runmd_ffi`` from this directory after
+``cargo build``, setting ``RUNMD_LIB`` to the library if it isn't in
+``target/release``. ``cargo test`` runs them against ``target/debug``.
+"""
+
+import unittest
+
+import runmd_ffi
+
+CONFIG = "languages:\n  python: python3 {file}\n  bash: bash {file}\n"
+
+
+class ClearOutputsTest(unittest.TestCase):
+    def test_removes_output_sections(self):
+        text = "```bash\necho hi\n```\n**Output**\n```\nhi\n```\n"
+        self.assertEqual(runmd_ffi.clear_outputs(text, CONFIG), "```bash\necho hi\n```\n")
+
+    def test_invalid_config_raises(self):
+        with self.assertRaises(runmd_ffi.RunmdError):
+            runmd_ffi.clear_outputs("text\n", "languages: [")
+
+
+class ProcessTest(unittest.TestCase):
+    def test_attaches_outputs_in_document_order(self):
+        calls = []
+
+        def execute(language, code):
+            calls.append((language, code))
+            return f"{language}: {code.upper()}", 0
+
+        text = "```python\nprint(1)\n```\n\n```bash -nr\nskipped\n```\n\n```bash\necho 2\n```\n"
+        result = runmd_ffi.process(text, execute, CONFIG)
+        self.assertEqual(calls, [("python", "print(1)"), ("bash", "echo 2")])
+        self.assertIn("```\npython: PRINT(1)\n```", result)
+        self.assertIn("```\nbash: ECHO 2\n```", result)
+        self.assertEqual(result.count("**Output**"), 2)
+
+    def test_callback_exception_propagates(self):
+        def execute(language, code):
+            raise ValueError("backend unavailable")
+
+        with self.assertRaisesRegex(ValueError, "backend unavailable"):
+            runmd_ffi.process("```bash\necho hi\n```\n", execute, CONFIG)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,19 @@
+//! Clears outputs from arbitrary documents, checking that nothing panics and
+//! that the result is no longer than the input.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use runmd::config::Config;
+use runmd::core::clear_outputs;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(content) = std::str::from_utf8(data) else {
+        return;
+    };
+    let config = Config::default();
+    if let Ok(cleared) = clear_outputs(content, &config) {
+        // Each removed section is longer than the blank line it may leave
+        assert!(cleared.len() <= content.len());
+    }
+});
@@ -0,0 +1,43 @@
+//! Finds blocks and their outputs in arbitrary documents, then renders them
+//! with every block "run", checking that nothing panics.
+
+#![no_main]
+
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use libfuzzer_sys::fuzz_target;
+use runmd::config::Config;
+use runmd::core::{block_outputs, find_orphaned_outputs, process_with, CodeBlock};
+use runmd::executor::{Executor, RunResult};
+
+/// Prints each block's code back, with multi-byte text and a CRLF ending.
+struct Echo;
+
+impl Executor for Echo {
+    async fn execute(&self, block: &CodeBlock) -> anyhow::Result<RunResult> {
+        Ok(RunResult {
+            output: format!("{}\r\né🦀", block.code),
+            exit_code: Some(0),
+            stderr: None,
+        })
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(content) = std::str::from_utf8(data) else {
+        return;
+    };
+    let config = Config::default();
+    block_outputs(content, &config);
+    find_orphaned_outputs(content, &config);
+
+    // Echo never waits, so the future completes on its first poll
+    let mut future = pin!(process_with(content, &config, &Echo));
+    let Poll::Ready(result) = future.as_mut().poll(&mut Context::from_waker(Waker::noop()))
+    else {
+        panic!("process_with waited on an executor that never does");
+    };
+    let _ = result;
+});
@@ -0,0 +1,203 @@
+use anyhow::{Context, Result};
+use colored::Colorize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::config::Config;
+use crate::core::{block_languages, block_stdin, CodeBlock};
+use crate::executor::{Executor, RunResult};
+use crate::languages::Languages;
+use crate::runner::create_temp_file;
+
+/// Runs blocks one at a time, each in its own window of a tmux session that
+/// can be attached to while the document runs, so blocks can be watched and
+/// typed into. A block's output is everything its window shows once it exits.
+pub struct Attached {
+    session: String,
+    languages: Languages,
+    /// Directory of the document, which `stdin-file=` paths are relative to
+    base_dir: PathBuf,
+    /// Directory blocks run in, as they would without `--attach`
+    cwd: PathBuf,
+}
+
+/// Attributes with no meaning for a block someone may be typing into, which are
+/// ignored with a warning.
+const UNSUPPORTED: [&str; 5] = ["timeout", "capture", "matrix", "session", "stable"];
+
+impl Attached {
+    /// Start a detached tmux session named after `document`, for blocks run
+    /// with the commands in `config`.
+    pub async fn start(document: &Path, config: &Config) -> Result<Self> {
+        let stem: String = document
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '-' })
+            .collect();
+        let session = format!("runmd-{}-{}", stem, std::process::id());
+        tmux(&["new-session", "-d", "-s", &session, "-x", "200", "-y", "50"])
+            .await
+            .context("Failed to start a tmux session")?;
+        Ok(Attached {
+            session,
+            languages: Languages::new(
+                config.languages.clone(),
+                config.aliases.clone(),
+                config.invoke.clone(),
+            ),
+            base_dir: document.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            cwd: std::env::current_dir()?,
+        })
+    }
+
+    /// Name to pass to `tmux attach -t`.
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        let _ = std::process::Command::new("tmux")
+            .args(["kill-session", "-t", &self.session])
+            .output();
+    }
+}
+
+impl Executor for Attached {
+    async fn execute(&self, block: &CodeBlock) -> Result<RunResult> {
+        for name in UNSUPPORTED {
+            if block.attrs.get(name).is_some() || block.attrs.flag(name) {
+                eprintln!(
+                    "{} {} is ignored with --attach (line {})",
+                    "warning:".yellow().bold(),
+                    name,
+                    block.line
+                );
+            }
+        }
+
+        // Commands are built as for any other run: `cmd=` and `with=` replace the
+        // language's, `args=` are appended and `env=` is set
+        let overridden = match block_languages(block, &self.languages) {
+            Ok(overridden) => overridden,
+            Err(message) => return Ok(RunResult::error(message)),
+        };
+        let stdin = match block_stdin(block, Some(&self.base_dir)) {
+            Ok(stdin) => stdin,
+            Err(message) => return Ok(RunResult::error(message)),
+        };
+        let languages = overridden.as_ref().unwrap_or(&self.languages);
+        let language = languages.resolve(&block.language);
+        let temp_dir = tempfile::Builder::new()
+            .prefix("runmd-")
+            .tempdir()
+            .context("Failed to create temporary directory")?;
+        let temp_file = create_temp_file(language, &block.code, temp_dir.path())?;
+        let out_path = temp_dir.path().join("out");
+        let Some(mut command) = languages.get_command(
+            language,
+            &temp_file.path().to_string_lossy(),
+            &out_path.to_string_lossy(),
+        ) else {
+            return Ok(RunResult::error(format!(
+                "No command configured for language '{}'",
+                block.language
+            )));
+        };
+        command.extend(block.attrs.words("args"));
+        let mut shell = format!("exec {}", shell_words::join(&command));
+        if let Some(stdin) = stdin {
+            let stdin_path = temp_dir.path().join("stdin");
+            std::fs::write(&stdin_path, stdin)?;
+            let stdin_path = stdin_path.to_string_lossy();
+            shell.push_str(&format!(" < {}", shell_words::quote(&stdin_path)));
+        }
+
+        // The block waits for its window to be set to stay open once it exits,
+        // so what it printed can be read
+        let start = format!("{}-line-{}", self.session, block.line);
+        let target = format!("{}:", self.session);
+        let name = format!("line-{}", block.line);
+        let cwd = self.cwd.to_string_lossy();
+        let env: Vec<String> = block
+            .attrs
+            .env()
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        let script = format!("tmux wait-for {}; {}", start, shell);
+        let mut args = vec!["new-window", "-P", "-F", "#{pane_id}", "-t", &target];
+        args.extend(["-n", &name, "-c", &cwd]);
+        for assignment in &env {
+            args.extend(["-e", assignment]);
+        }
+        args.push(&script);
+        let pane = tmux(&args).await?;
+        let pane = pane.trim();
+        tmux(&["set-option", "-p", "-t", pane, "remain-on-exit", "on"]).await?;
+        tmux(&["wait-for", "-S", &start]).await?;
+
+        // Blocks may wait for someone to type into them, so there is no time limit
+        let exit_code = loop {
+            let state = tmux(&[
+                "list-panes",
+                "-s",
+                "-t",
+                &self.session,
+                "-F",
+                "#{pane_id} #{pane_dead} #{pane_dead_status}",
+            ])
+            .await?;
+            let Some(state) = state
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{} ", pane)))
+            else {
+                return Ok(RunResult::error(
+                    "tmux window closed before the block finished",
+                ));
+            };
+            if let Some(status) = state.trim().strip_prefix('1') {
+                break status.trim().parse().ok();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+
+        let screen = tmux(&["capture-pane", "-p", "-J", "-S", "-", "-t", pane]).await?;
+        Ok(RunResult {
+            output: transcript(&screen),
+            exit_code,
+            stderr: None,
+        })
+    }
+}
+
+/// The lines a window showed, without tmux's note that its program exited or
+/// the empty rows below the last line written.
+fn transcript(screen: &str) -> String {
+    let lines: Vec<&str> = screen
+        .lines()
+        .filter(|line| !line.starts_with("Pane is dead"))
+        .map(str::trim_end)
+        .collect();
+    lines.join("\n").trim_end().to_string()
+}
+
+async fn tmux(args: &[&str]) -> Result<String> {
+    let output = Command::new("tmux")
+        .args(args)
+        .output()
+        .await
+        .context("Failed to run tmux; is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "tmux {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
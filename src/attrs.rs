@@ -0,0 +1,377 @@
+use std::time::Duration;
+
+/// The type of value an attribute accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrKind {
+    /// A bare word such as `-nr`
+    Flag,
+    /// `key=30s`, `key=500ms`, `key=2m` or plain seconds
+    Duration,
+    /// `key=true|false|yes|no|on|off|1|0`
+    Bool,
+    /// Any value, quoted when it contains whitespace
+    Text,
+    /// One of a fixed set of words
+    Choice(&'static [&'static str]),
+    /// Comma-separated `NAME=value` pairs
+    Assignments,
+    /// Words split like a shell command line, e.g. `"-v 'my file.csv'"`
+    Words,
+    /// Letters, digits, `-` and `_`, usable as an HTML id, e.g. `install-step`
+    Identifier,
+}
+
+/// Every attribute runmd understands in a fence info string.
+pub const SCHEMA: &[(&str, AttrKind)] = &[
+    ("-nr", AttrKind::Flag),
+    ("args", AttrKind::Words),
+    ("--no-run", AttrKind::Flag),
+    ("cache", AttrKind::Bool),
+    ("capture", AttrKind::Choice(&["merged", "split", "pty"])),
+    ("cmd", AttrKind::Text),
+    ("consumes", AttrKind::Text),
+    ("env", AttrKind::Assignments),
+    ("exit-code", AttrKind::Flag),
+    ("expect", AttrKind::Text),
+    ("expect-file", AttrKind::Text),
+    ("hide", AttrKind::Flag),
+    ("hide-code", AttrKind::Flag),
+    ("hide-output", AttrKind::Flag),
+    ("id", AttrKind::Identifier),
+    ("interactive", AttrKind::Flag),
+    ("matrix", AttrKind::Text),
+    ("name", AttrKind::Text),
+    ("output", AttrKind::Choice(&["fenced", "raw", "markdown"])),
+    ("output-lang", AttrKind::Text),
+    ("per-os", AttrKind::Flag),
+    ("produces", AttrKind::Text),
+    ("progress", AttrKind::Choice(&["final", "frames"])),
+    ("quiet", AttrKind::Flag),
+    ("record", AttrKind::Flag),
+    ("run", AttrKind::Flag),
+    ("session", AttrKind::Text),
+    ("silent", AttrKind::Flag),
+    ("skip", AttrKind::Bool),
+    ("stable", AttrKind::Bool),
+    ("stdin", AttrKind::Text),
+    ("stdin-file", AttrKind::Text),
+    ("tags", AttrKind::Text),
+    ("timeout", AttrKind::Duration),
+    ("with", AttrKind::Text),
+    ("xfail", AttrKind::Flag),
+];
+
+/// Prefix of attribute names users define for their own blocks.
+const CUSTOM_PREFIX: &str = "x-";
+
+fn kind_of(name: &str) -> Option<AttrKind> {
+    SCHEMA
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, kind)| *kind)
+}
+
+/// Attributes parsed from the part of a fence info string after the language.
+#[derive(Debug, Clone, Default)]
+pub struct Attrs {
+    pub flags: Vec<String>,
+    pub values: Vec<(String, String)>,
+    /// Attributes meant for other tools, such as `{highlight=3-5}` groups or
+    /// keys listed in `foreign_attributes`; kept in the fence but otherwise ignored
+    pub foreign: Vec<String>,
+}
+
+impl Attrs {
+    /// Parse attribute tokens, returning the valid attributes together with a
+    /// message for each unknown or malformed one. Keys in `foreign_keys` belong
+    /// to other tools and are accepted without validation, as are user-defined
+    /// keys starting with `x-`.
+    pub fn parse(input: &str, foreign_keys: &[String]) -> (Attrs, Vec<String>) {
+        let mut attrs = Attrs::default();
+        // A malformed token is reported and dropped on its own, so the others,
+        // such as `-nr`, still apply
+        let (tokens, mut errors) = tokenize(input);
+
+        for (key, value) in tokens {
+            if key.starts_with('{') || foreign_keys.contains(&key) {
+                attrs.foreign.push(match value {
+                    Some(value) => format!("{}={}", key, value),
+                    None => key,
+                });
+                continue;
+            }
+
+            if key.len() > CUSTOM_PREFIX.len() && key.starts_with(CUSTOM_PREFIX) {
+                match value {
+                    Some(value) => attrs.values.push((key, value)),
+                    None => errors.push(format!("attribute '{}' requires a value", key)),
+                }
+                continue;
+            }
+
+            let Some(kind) = kind_of(&key) else {
+                errors.push(format!("unknown attribute '{}'", key));
+                continue;
+            };
+
+            match (kind, value) {
+                (AttrKind::Flag, None) => attrs.flags.push(key),
+                (AttrKind::Flag, Some(_)) => {
+                    errors.push(format!("attribute '{}' does not take a value", key))
+                }
+                (_, None) => errors.push(format!("attribute '{}' requires a value", key)),
+                (kind, Some(value)) => match validate(kind, &value) {
+                    Ok(()) => attrs.values.push((key, value)),
+                    Err(e) => errors.push(format!("attribute '{}': {}", key, e)),
+                },
+            }
+        }
+
+        (attrs, errors)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The comma-separated items of a value such as `produces=a.csv,b.csv`.
+    pub fn list(&self, key: &str) -> Vec<&str> {
+        self.get(key)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Environment variables the block runs with: those set by `env=FOO=1,BAR=2`,
+    /// after user-defined `x-` attributes, which `x-retries=3` turns into
+    /// `RUNMD_ATTR_RETRIES=3`.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let custom = self.values.iter().filter_map(|(key, value)| {
+            let name = key.strip_prefix(CUSTOM_PREFIX)?;
+            Some((
+                format!("RUNMD_ATTR_{}", name.to_uppercase().replace('-', "_")),
+                value.clone(),
+            ))
+        });
+        let assigned = self
+            .list("env")
+            .into_iter()
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name.trim().to_string(), value.to_string()));
+        custom.chain(assigned).collect()
+    }
+
+    /// The words of a value such as `args="-v 'my file.csv'"`, split like a shell
+    /// command line.
+    pub fn words(&self, key: &str) -> Vec<String> {
+        self.get(key)
+            .and_then(|value| shell_words::split(value).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn duration(&self, key: &str) -> Option<Duration> {
+        self.get(key).and_then(|v| parse_duration(v).ok())
+    }
+
+    pub fn bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(|v| parse_bool(v).ok())
+    }
+}
+
+fn validate(kind: AttrKind, value: &str) -> Result<(), String> {
+    match kind {
+        AttrKind::Duration => parse_duration(value).map(|_| ()),
+        AttrKind::Bool => parse_bool(value).map(|_| ()),
+        AttrKind::Choice(choices) if !choices.contains(&value) => Err(format!(
+            "expected one of {}, got '{}'",
+            choices.join(", "),
+            value
+        )),
+        AttrKind::Assignments => match value
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .find(|pair| {
+                pair.split_once('=')
+                    .is_none_or(|(name, _)| name.trim().is_empty())
+            }) {
+            Some(pair) => Err(format!("expected NAME=value, got '{}'", pair)),
+            None => Ok(()),
+        },
+        AttrKind::Words => shell_words::split(value)
+            .map(|_| ())
+            .map_err(|_| format!("unbalanced quotes in '{}'", value)),
+        AttrKind::Identifier
+            if !value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            Err(format!(
+                "expected letters, digits, '-' and '_', got '{}'",
+                value
+            ))
+        }
+        AttrKind::Flag | AttrKind::Text | AttrKind::Choice(_) | AttrKind::Identifier => Ok(()),
+    }
+}
+
+/// Split an info string into `key` / `key=value` tokens. Values may be wrapped
+/// in single or double quotes to include whitespace; double-quoted values
+/// understand the `\n`, `\t`, `\"` and `\\` escapes. A `{...}` group is returned
+/// whole as a single key. Malformed tokens are left out with a message each; what
+/// follows an unterminated quote or group is read as ordinary tokens.
+fn tokenize(input: &str) -> (Vec<(String, Option<String>)>, Vec<String>) {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        if chars.peek() == Some(&'{') {
+            let mut group = String::new();
+            for c in chars.by_ref() {
+                group.push(c);
+                if c == '}' {
+                    break;
+                }
+            }
+            if !group.ends_with('}') {
+                errors.push(format!("unterminated attribute group '{}'", group));
+                retokenize(&group[1..], &mut tokens, &mut errors);
+                continue;
+            }
+            tokens.push((group, None));
+            continue;
+        }
+
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == '=' {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+
+        if chars.peek() != Some(&'=') {
+            tokens.push((key, None));
+            continue;
+        }
+        chars.next();
+
+        let mut value = String::new();
+        match chars.peek() {
+            Some(&quote) if quote == '"' || quote == '\'' => {
+                chars.next();
+                let mut closed = false;
+                let mut raw = String::new();
+                while let Some(c) = chars.next() {
+                    if c == quote {
+                        closed = true;
+                        break;
+                    }
+                    raw.push(c);
+                    if c == '\\' && quote == '"' {
+                        let Some(escaped) = chars.next() else {
+                            break;
+                        };
+                        raw.push(escaped);
+                        value.push(match escaped {
+                            'n' => '\n',
+                            't' => '\t',
+                            other => other,
+                        });
+                    } else {
+                        value.push(c);
+                    }
+                }
+                if !closed {
+                    errors.push(format!("unterminated quote in attribute '{}'", key));
+                    retokenize(&raw, &mut tokens, &mut errors);
+                    continue;
+                }
+            }
+            _ => {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+
+        if key.is_empty() {
+            errors.push("attribute value without a name".to_string());
+        } else if value.is_empty() {
+            errors.push(format!("attribute '{}' has an empty value", key));
+        } else {
+            tokens.push((key, Some(value)));
+        }
+    }
+
+    (tokens, errors)
+}
+
+/// Read the rest of a malformed token as ordinary tokens.
+fn retokenize(rest: &str, tokens: &mut Vec<(String, Option<String>)>, errors: &mut Vec<String>) {
+    let (more, more_errors) = tokenize(rest);
+    tokens.extend(more);
+    errors.extend(more_errors);
+}
+
+/// Parse `500ms`, `30s`, `2m`, `1h`, `7d` or a plain number of seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+
+    let seconds = |per_unit: u64| {
+        number
+            .checked_mul(per_unit)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("duration '{}' is too long", value))
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => seconds(1),
+        "m" => seconds(60),
+        "h" => seconds(3600),
+        "d" => seconds(86400),
+        _ => Err(format!("invalid duration '{}'", value)),
+    }
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("invalid boolean '{}'", value)),
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::core::{clear_outputs, find_all_code_blocks, render_blocks};
+
+/// How much slower than its baseline a stage may get before `compare` reports it.
+pub const TOLERANCE: f64 = 0.25;
+
+/// Best time of each stage over a number of runs, keyed by stage name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    pub blocks: usize,
+    pub bytes: usize,
+    pub stages_ms: BTreeMap<String, f64>,
+}
+
+/// A document of `blocks` Python blocks under headings, each followed by a
+/// five-line output section.
+pub fn document(blocks: usize) -> String {
+    let mut doc = String::from("# Benchmark\n\n");
+    for i in 0..blocks {
+        doc.push_str(&format!(
+            "## Block {}\n\nSome prose about the block.\n\n```python name=block-{}\nprint({})\n```\n**Output**\n```\n",
+            i, i, i
+        ));
+        for line in 0..5 {
+            doc.push_str(&format!("output {} line {}\n", i, line));
+        }
+        doc.push_str("```\n\n");
+    }
+    doc
+}
+
+/// Time parsing, clearing and reconstructing `document(blocks)`, keeping the
+/// best of `runs` runs of each stage.
+pub fn run(blocks: usize, runs: usize) -> Result<Timings> {
+    let config = Config::default();
+    let doc = document(blocks);
+    let cleared = clear_outputs(&doc, &config)?;
+    let code_blocks = find_all_code_blocks(&cleared, &config);
+    let outputs: Vec<Option<String>> = code_blocks
+        .iter()
+        .map(|block| Some(format!("output of line {}", block.line)))
+        .collect();
+
+    let mut stages_ms = BTreeMap::new();
+    let mut time = |stage: &str, f: &mut dyn FnMut() -> Result<()>| -> Result<()> {
+        let mut best = Duration::MAX;
+        for _ in 0..runs.max(1) {
+            let start = Instant::now();
+            f()?;
+            best = best.min(start.elapsed());
+        }
+        stages_ms.insert(stage.to_string(), best.as_secs_f64() * 1000.0);
+        Ok(())
+    };
+
+    time("parse", &mut || {
+        black_box(find_all_code_blocks(black_box(&doc), &config));
+        Ok(())
+    })?;
+    time("clear", &mut || {
+        black_box(clear_outputs(black_box(&doc), &config)?);
+        Ok(())
+    })?;
+    time("render", &mut || {
+        black_box(render_blocks(
+            black_box(&cleared),
+            &code_blocks,
+            &outputs,
+            &[],
+            &[],
+            &config,
+        ));
+        Ok(())
+    })?;
+
+    Ok(Timings {
+        blocks,
+        bytes: doc.len(),
+        stages_ms,
+    })
+}
+
+impl Timings {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read benchmark baseline {}", path.display()))?;
+        serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid benchmark baseline {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_yaml::to_string(self)?)
+            .with_context(|| format!("Failed to write benchmark baseline {}", path.display()))
+    }
+
+    /// Stages more than `TOLERANCE` slower than in `baseline`, described for
+    /// the user. Stages missing from either side are not compared.
+    pub fn regressions(&self, baseline: &Timings) -> Vec<String> {
+        self.stages_ms
+            .iter()
+            .filter_map(|(stage, &ms)| {
+                let &before = baseline.stages_ms.get(stage)?;
+                (ms > before * (1.0 + TOLERANCE)).then(|| {
+                    format!(
+                        "{}: {:.2}ms, baseline {:.2}ms (+{:.0}%)",
+                        stage,
+                        ms,
+                        before,
+                        (ms / before - 1.0) * 100.0
+                    )
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+use tokio::process::Command;
+
+use crate::config::CacheConfig;
+
+/// A cached block result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub output: String,
+    pub success: bool,
+}
+
+/// Block outputs keyed by `hash::block_key`, stored in a local directory and
+/// optionally in a shared HTTP/S3 location that is read through on local misses.
+#[derive(Debug, Clone)]
+pub struct OutputCache {
+    dir: PathBuf,
+    remote: Option<RemoteCache>,
+}
+
+#[derive(Debug, Clone)]
+struct RemoteCache {
+    url: String,
+    write: bool,
+    s3_region: Option<String>,
+}
+
+impl OutputCache {
+    pub fn new(config: &CacheConfig) -> Result<Self> {
+        let dir = match &config.dir {
+            Some(dir) => dir.clone(),
+            None => dirs::cache_dir()
+                .context("Could not determine cache directory")?
+                .join("runmd")
+                .join("outputs"),
+        };
+
+        let remote = config.remote.as_ref().map(|remote| RemoteCache {
+            url: remote.url.trim_end_matches('/').to_string(),
+            write: remote.write,
+            s3_region: remote.s3_region.clone(),
+        });
+
+        Ok(OutputCache { dir, remote })
+    }
+
+    fn local_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.yml", key))
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CacheEntry> {
+        if let Some(entry) = std::fs::read_to_string(self.local_path(key))
+            .ok()
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+        {
+            return Some(entry);
+        }
+
+        let remote = self.remote.as_ref()?;
+        let content = remote.download(key).await?;
+        let entry: CacheEntry = serde_yaml::from_str(&content).ok()?;
+
+        // Keep a local copy so the next run doesn't go over the network
+        let _ = self.store_local(key, &content);
+        Some(entry)
+    }
+
+    pub async fn put(&self, key: &str, entry: &CacheEntry) -> Result<()> {
+        let content = serde_yaml::to_string(entry)?;
+        self.store_local(key, &content)?;
+
+        if let Some(remote) = self.remote.as_ref().filter(|r| r.write) {
+            remote.upload(key, &content).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Entries are replaced in a single rename, so runs sharing the cache, such
+    /// as a nested runmd, never read one half written.
+    fn store_local(&self, key: &str, content: &str) -> Result<()> {
+        use std::io::Write;
+
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.local_path(key);
+        tempfile::NamedTempFile::new_in(&self.dir)
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                file.persist(&path).map_err(|e| e.error)
+            })
+            .with_context(|| format!("Failed to write cache entry: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+impl RemoteCache {
+    /// Requests go through curl, which handles HTTPS and, when `s3_region` is set,
+    /// signs them for S3 using `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY`.
+    /// Credentials go in a config file only the user can read, rather than on the
+    /// command line where other users see them; it must outlive the request.
+    fn curl(&self, key: &str) -> Result<(Command, Option<NamedTempFile>)> {
+        use std::io::Write;
+
+        let mut cmd = Command::new("curl");
+        cmd.arg("--silent").arg("--fail").arg("--location");
+
+        let mut secrets = None;
+        if let Some(region) = &self.s3_region {
+            let access_key = std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default();
+            let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default();
+            let mut config = format!(
+                "user = {}\n",
+                curl_quote(&format!("{}:{}", access_key, secret_key))
+            );
+            if let Ok(token) = std::env::var("AWS_SESSION_TOKEN") {
+                config.push_str(&format!(
+                    "header = {}\n",
+                    curl_quote(&format!("x-amz-security-token: {}", token))
+                ));
+            }
+            let mut file = tempfile::Builder::new()
+                .prefix("runmd-curl-")
+                .tempfile()
+                .context("Failed to write curl config for the remote cache")?;
+            file.write_all(config.as_bytes())?;
+
+            cmd.arg("--aws-sigv4")
+                .arg(format!("aws:amz:{}:s3", region))
+                .arg("--config")
+                .arg(file.path());
+            secrets = Some(file);
+        }
+
+        cmd.arg(format!("{}/{}.yml", self.url, key));
+        Ok((cmd, secrets))
+    }
+
+    async fn download(&self, key: &str) -> Option<String> {
+        let (mut cmd, _secrets) = self.curl(key).ok()?;
+        let output = cmd.output().await.ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout).ok()
+    }
+
+    async fn upload(&self, key: &str, content: &str) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut cmd, _secrets) = self.curl(key)?;
+        let mut child = cmd
+            .arg("--upload-file")
+            .arg("-")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .context("Failed to run curl for the remote cache")?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(content.as_bytes()).await?;
+        }
+
+        let status = child.wait().await?;
+        if !status.success() {
+            anyhow::bail!("Failed to upload cache entry {} to {}", key, self.url);
+        }
+
+        Ok(())
+    }
+}
+
+/// `value` as a double-quoted curl config string.
+fn curl_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
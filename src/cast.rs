@@ -0,0 +1,73 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::json;
+
+/// An asciinema (asciicast v2) recording of what a block wrote to its
+/// terminal, with the time each write happened.
+pub struct Recorder {
+    started: Instant,
+    events: Vec<(f64, String)>,
+    /// Bytes of a character split between two writes
+    pending: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct Header {
+    version: u32,
+    width: u16,
+    height: u16,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            started: Instant::now(),
+            events: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            // An incomplete character at the end waits for the next write
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.pending.len(),
+        };
+        let text = String::from_utf8_lossy(&self.pending[..valid]).into_owned();
+        self.pending.drain(..valid);
+        if !text.is_empty() {
+            let time = self.started.elapsed().as_secs_f64();
+            self.events.push(((time * 1e6).round() / 1e6, text));
+        }
+    }
+
+    /// Write the recording to `path` for a terminal of `width` by `height`.
+    pub fn write(&self, path: &Path, width: u16, height: u16) -> Result<()> {
+        let mut cast = json::to_string(&Header {
+            version: 2,
+            width,
+            height,
+        })?;
+        cast.push('\n');
+        for (time, text) in &self.events {
+            cast.push_str(&json::to_string(&(time, "o", text))?);
+            cast.push('\n');
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, cast)
+            .with_context(|| format!("Failed to write recording {}", path.display()))
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
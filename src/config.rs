@@ -3,11 +3,383 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::glob::glob_match;
+
+/// The output section header runmd writes unless `output_header` says otherwise.
+pub const DEFAULT_OUTPUT_HEADER: &str = "**Output**";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub languages: HashMap<String, String>,
+    /// Alternative fence names, e.g. `c++: cpp`, mapped to a configured language.
+    pub aliases: HashMap<String, String>,
+    /// How each language receives its code, when not through a temporary file.
+    pub invoke: HashMap<String, InvokeMode>,
+    /// Only blocks in these languages (or their aliases) execute; others are skipped.
+    pub allowed_languages: Option<Vec<String>>,
+    /// Only blocks with one of these `tags=` execute; others are skipped.
+    pub tags: Option<Vec<String>>,
+    /// Blocks with any of these `tags=` are skipped.
+    pub exclude_tags: Vec<String>,
+    /// Guess the language of fences without one from a shebang or keywords.
+    pub detect_languages: bool,
+    pub ignore_markers: IgnoreMarkers,
+    /// Heading patterns (`*` and `?` wildcards) whose blocks are never executed.
+    pub skip_sections: Vec<String>,
+    /// Always execute blocks one at a time in document order.
+    pub ordered: bool,
+    /// Only execute blocks marked `run`, leaving the rest as illustrations.
+    pub explicit_run: bool,
+    pub parallel: ParallelMode,
+    /// Run every block of a language in one shared interpreter per document, as
+    /// if each had `session=default`, for languages sessions support.
+    pub sessions: bool,
+    /// Directory (relative to the document) copied into the workspace blocks run in.
+    pub fixtures: Option<PathBuf>,
+    /// Keep each block's temporary source file and build artifacts after it runs.
+    pub keep_temp: bool,
+    /// Record an asciinema cast of every block rather than only those marked `record`.
+    pub record_all: bool,
+    /// Directory, next to each document, its blocks' recordings are written to.
+    pub record_dir: String,
+    /// Documents larger than this many bytes are only processed with `--force`.
+    pub max_file_size: u64,
+    /// Stop running blocks when the temporary directory, or the workspace blocks
+    /// run in, has fewer than this many bytes free.
+    pub min_free_space: Option<u64>,
+    /// Ask for confirmation before running more than this many blocks.
+    pub confirm_blocks: usize,
+    /// Most runnable blocks a document may have before lint and check flag it.
+    pub max_blocks: Option<usize>,
+    /// Longest a block may run before check flags it, e.g. `30s`.
+    pub max_block_time: Option<String>,
+    /// Fence attribute names used by other tools, preserved without warnings.
+    pub foreign_attributes: Vec<String>,
+    /// Line introducing each output section, e.g. `**Ausgabe**`.
+    pub output_header: String,
+    /// Append each block's exit status to its output header, as `exit-code` does.
+    pub exit_codes: bool,
+    /// Show an output identical to that of an earlier named block as a reference
+    /// to it, e.g. `*(same output as block "setup")*`.
+    pub dedup_outputs: bool,
+    /// Put a one-line summary of the first error above the output of failed
+    /// blocks, with the line it points to in the document.
+    pub error_summary: bool,
+    /// Whether output headers carry a label, e.g. `**Output 2**`.
+    pub output_labels: OutputLabels,
+    /// Built-in filters removing boilerplate warnings from outputs, per language,
+    /// e.g. `python: [python-deprecation]`.
+    pub output_filters: HashMap<String, Vec<String>>,
+    /// Most blocks running at once in parallel mode; defaults to the number of CPUs.
+    pub max_parallel: Option<usize>,
+    /// Time limit for blocks without a `timeout` attribute, e.g. `30s`. When unset,
+    /// blocks get 5s, or 10s if their code is over 1000 bytes.
+    pub timeout: Option<String>,
+    /// How long a timed-out block gets to exit after SIGTERM before it is killed, e.g. `2s`.
+    pub timeout_grace: String,
+    /// Run every block with the stable output environment, as `stable=true` does,
+    /// unless it sets `stable=false`.
+    pub hermetic: bool,
+    /// Names of blocks the `timeout` and `max_block_time` limits don't apply to,
+    /// e.g. `[full-benchmark]`; they only stop at a `timeout` attribute of their own.
+    pub exempt: Vec<String>,
+    pub env_policy: EnvPolicy,
+    /// Scheduling priority of executed blocks.
+    pub priority: Priority,
+    /// CPUs executed blocks are restricted to (Linux only).
+    pub cpu_affinity: Vec<usize>,
+    pub nix: NixConfig,
+    /// Run the interpreter versions pinned by asdf's `.tool-versions` or mise's
+    /// config next to the document rather than the first ones on PATH.
+    pub tool_versions: bool,
+    /// Program used for documents that set a container `image` in their front matter.
+    pub container_runtime: String,
+    /// Log every block result to the history queried by `runmd history`.
+    pub history: bool,
+    /// How deeply runmd may run inside blocks of another runmd.
+    pub max_depth: usize,
+    pub cache: CacheConfig,
+    pub service: ServiceConfig,
+    pub scan: ScanConfig,
+}
+
+/// Reuse of block outputs across runs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Serve outputs of unchanged blocks from the cache instead of running them.
+    pub enabled: bool,
+    /// Local cache directory, defaulting to ~/.cache/runmd/outputs.
+    pub dir: Option<PathBuf>,
+    /// Shared cache read on local misses, e.g. for CI machines.
+    pub remote: Option<RemoteCacheConfig>,
+    /// Block selectors (`name=...`, `lang=...`, `line=...`) that always bypass the cache.
+    pub rerun: Vec<String>,
+}
+
+/// Which environment variables executed blocks see.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvPolicy {
+    /// Pass the whole parent environment through, apart from `deny`.
+    pub inherit: bool,
+    /// Variables (`*` and `?` wildcards) passed through when `inherit` is off.
+    pub allow: Vec<String>,
+    /// Variables never passed through, e.g. `AWS_*`.
+    pub deny: Vec<String>,
+    /// Variables set for every block.
+    pub set: HashMap<String, String>,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        EnvPolicy {
+            inherit: true,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            set: HashMap::new(),
+        }
+    }
+}
+
+impl EnvPolicy {
+    /// Whether blocks start from a filtered copy of the parent environment
+    /// rather than inheriting it unchanged.
+    pub fn filters(&self) -> bool {
+        !self.inherit || !self.deny.is_empty()
+    }
+
+    /// Whether the parent's variable `name` is passed to blocks.
+    pub fn passes(&self, name: &str) -> bool {
+        let matches = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, name));
+        (self.inherit || matches(&self.allow)) && !matches(&self.deny)
+    }
+}
+
+/// Run language commands inside `nix shell` so toolchains come from Nix
+/// instead of whatever is installed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NixConfig {
+    pub enabled: bool,
+    /// Command the packages and `--command` are appended to.
+    pub command: String,
+    /// Installable (flake ref) providing each language's interpreter, e.g.
+    /// `python: nixpkgs#python312`.
+    pub packages: HashMap<String, String>,
+}
+
+impl Default for NixConfig {
+    fn default() -> Self {
+        NixConfig {
+            enabled: false,
+            command: "nix shell".to_string(),
+            packages: HashMap::new(),
+        }
+    }
+}
+
+/// Opt-in check of blocks for suspicious commands before anything executes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    pub enabled: bool,
+    pub action: ScanAction,
+    /// Patterns to look for; built-in rules (recursive deletes, `curl | sh`,
+    /// network access and others) when not set.
+    pub rules: Option<Vec<ScanRule>>,
+    /// Run documents with suspicious blocks anyway, set by `--allow-dangerous`.
+    #[serde(skip)]
+    pub allow_dangerous: bool,
+}
+
+/// What happens to a document with suspicious blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanAction {
+    /// Refuse to run the document without `--allow-dangerous`
+    #[default]
+    Block,
+    /// Print warnings and run it
+    Warn,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScanRule {
+    pub name: String,
+    /// Regular expression matched against each line of a block
+    pub pattern: String,
+}
+
+/// Limits for `runmd serve` and `runmd daemon`, so bursts of requests can't
+/// overwhelm the host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// Most requests executing blocks at once.
+    pub max_concurrent: usize,
+    /// Requests allowed to wait for a free slot; further requests are refused.
+    pub queue_size: usize,
+    /// Requests per minute accepted from each client, by IP address or user id.
+    pub rate_limit: Option<u32>,
+    /// Most connections `serve` keeps open at once; further ones are closed.
+    pub max_connections: usize,
+    /// Languages documents sent to `serve` may run, in place of
+    /// `allowed_languages`; `null` to use that setting.
+    pub allowed_languages: Option<Vec<String>>,
+    /// Which environment variables blocks of documents sent to `serve` see, in
+    /// place of `env_policy`.
+    pub env_policy: EnvPolicy,
+    /// Container image documents sent to `serve` run in, whatever they name; `serve`
+    /// refuses to start without one unless `--dangerously-run-on-host` is given.
+    pub image: Option<String>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            max_concurrent: 4,
+            queue_size: 32,
+            rate_limit: Some(120),
+            max_connections: 64,
+            allowed_languages: Some(
+                ["bash", "sh", "python", "javascript"]
+                    .map(String::from)
+                    .to_vec(),
+            ),
+            env_policy: EnvPolicy {
+                inherit: false,
+                allow: ["PATH", "HOME", "LANG", "LC_*", "TERM", "TZ", "TMPDIR"]
+                    .map(String::from)
+                    .to_vec(),
+                ..EnvPolicy::default()
+            },
+            image: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteCacheConfig {
+    /// Base HTTP(S) URL; entries live at `<url>/<key>.yml`.
+    pub url: String,
+    /// Upload new entries as well as reading existing ones.
+    #[serde(default)]
+    pub write: bool,
+    /// Sign requests for S3 in this region using the standard AWS credential variables.
+    #[serde(default)]
+    pub s3_region: Option<String>,
+}
+
+/// Settings a caller may change for one document, such as an editor sending it
+/// to the daemon; unset fields keep the configured value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigOverrides {
+    pub ordered: Option<bool>,
+    pub parallel: Option<ParallelMode>,
+    pub allowed_languages: Option<Vec<String>>,
+    pub skip_sections: Option<Vec<String>>,
+    pub output_header: Option<String>,
 }
 
+impl ConfigOverrides {
+    /// `config` with these overrides applied.
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(ordered) = self.ordered {
+            config.ordered = ordered;
+        }
+        if let Some(parallel) = self.parallel {
+            config.parallel = parallel;
+        }
+        if let Some(languages) = &self.allowed_languages {
+            config.allowed_languages = Some(languages.clone());
+        }
+        if let Some(sections) = &self.skip_sections {
+            config.skip_sections = sections.clone();
+        }
+        if let Some(header) = &self.output_header {
+            config.output_header = header.clone();
+        }
+        config
+    }
+}
+
+/// When blocks of a document may execute concurrently.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParallelMode {
+    /// Always run blocks sequentially
+    #[default]
+    Off,
+    /// Run in parallel whenever more than one block is runnable
+    On,
+    /// Run in parallel once a document has 4 or more runnable blocks
+    Auto,
+}
+
+/// What labels output headers so readers can refer to a particular output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputLabels {
+    /// Plain headers
+    #[default]
+    Off,
+    /// Number outputs in document order: `**Output 1**`, `**Output 2**`, ...
+    Number,
+    /// Use the block's `name`, as in `**Output: setup**`, numbering unnamed blocks
+    Name,
+}
+
+/// How a block's code is handed to its interpreter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvokeMode {
+    /// Write the code to a temporary file substituted for `{file}`
+    #[default]
+    File,
+    /// Pass the code as a command-line argument substituted for `{code}`
+    Arg,
+    /// Pipe the code to the interpreter's standard input
+    Stdin,
+}
+
+/// CPU and I/O scheduling priority of executed blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Same priority as runmd itself
+    #[default]
+    Normal,
+    /// Yield to other work: niceness 10 on Unix plus the lowest best-effort I/O
+    /// priority on Linux, the below-normal priority class on Windows
+    Low,
+}
+
+/// Comment lines that switch processing off and back on for a region of the document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IgnoreMarkers {
+    pub off: String,
+    pub on: String,
+}
+
+impl Default for IgnoreMarkers {
+    fn default() -> Self {
+        IgnoreMarkers {
+            off: "<!-- runmd:off -->".to_string(),
+            on: "<!-- runmd:on -->".to_string(),
+        }
+    }
+}
+
+/// CPUs an affinity mask can name (Linux's `CPU_SETSIZE`).
+const MAX_CPUS: usize = 1024;
+
 impl Config {
     pub fn load() -> Result<Self> {
         let config_path = Self::default_config_path()?;
@@ -17,15 +389,79 @@ impl Config {
                 format!("Failed to read config file: {}", config_path.display())
             })?;
 
-            let languages: HashMap<String, String> =
-                serde_yaml::from_str(&content).with_context(|| "Failed to parse config file")?;
-
-            Ok(Config { languages })
+            Self::parse(&content)
         } else {
             Ok(Self::default())
         }
     }
 
+    /// Parse a config file. Older files are a flat `language: command` map and are
+    /// still accepted; newer files nest those under a `languages` key.
+    pub fn parse(content: &str) -> Result<Self> {
+        let value: serde_yaml::Value =
+            serde_yaml::from_str(content).with_context(|| "Failed to parse config file")?;
+
+        if value.is_null() {
+            return Ok(Self::default());
+        }
+
+        match serde_yaml::from_value::<Config>(value.clone()) {
+            Ok(config) => config.validated(),
+            Err(structured_error) => match serde_yaml::from_value(value) {
+                Ok(languages) => Ok(Config {
+                    languages,
+                    ..Self::default()
+                }),
+                Err(_) => Err(structured_error).with_context(|| "Failed to parse config file"),
+            },
+        }
+    }
+
+    /// Reject settings that parse but can't be applied.
+    fn validated(self) -> Result<Self> {
+        if let Some(cpu) = self.cpu_affinity.iter().find(|&&cpu| cpu >= MAX_CPUS) {
+            anyhow::bail!(
+                "cpu_affinity lists CPU {}, but CPUs are numbered below {}",
+                cpu,
+                MAX_CPUS
+            );
+        }
+        Ok(self)
+    }
+
+    /// Output filters for blocks in `language` or an alias of it.
+    pub fn output_filters_for(&self, language: &str) -> &[String] {
+        self.output_filters
+            .get(language)
+            .or_else(|| {
+                let resolved = self.aliases.get(language)?;
+                self.output_filters.get(resolved)
+            })
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Whether `allowed_languages` permits blocks fenced as `language`.
+    pub fn language_allowed(&self, language: &str) -> bool {
+        let Some(allowed) = &self.allowed_languages else {
+            return true;
+        };
+        let resolved = self.aliases.get(language).map(String::as_str);
+        allowed
+            .iter()
+            .any(|name| name == language || Some(name.as_str()) == resolved)
+    }
+
+    /// Whether `tags` and `exclude_tags` let a block tagged with `block_tags` run.
+    pub fn tags_allowed(&self, block_tags: &[&str]) -> bool {
+        let tagged = |names: &[String]| {
+            block_tags
+                .iter()
+                .any(|tag| names.iter().any(|name| name == tag))
+        };
+        self.tags.as_deref().is_none_or(tagged) && !tagged(&self.exclude_tags)
+    }
+
     pub fn default_config_path() -> Result<PathBuf> {
         let config_dir = dirs::config_dir()
             .context("Could not determine config directory")?
@@ -39,8 +475,7 @@ impl Config {
             std::fs::create_dir_all(parent)?;
         }
 
-        let default_config = Self::default();
-        let content = serde_yaml::to_string(&default_config.languages)?;
+        let content = crate::schema::describe(&Self::default())?;
         std::fs::write(path, content)?;
 
         Ok(())
@@ -65,22 +500,87 @@ impl Default for Config {
         languages.insert("r".to_string(), "Rscript {file}".to_string());
         languages.insert(
             "rust".to_string(),
-            "sh -c 'rustc {file} -o /tmp/runmd_rust && /tmp/runmd_rust'".to_string(),
+            "sh -c 'rustc {file} -o {out} && {out} \"$@\"' sh".to_string(),
         );
         languages.insert("go".to_string(), "go run {file}".to_string());
         languages.insert(
             "java".to_string(),
-            "sh -c 'javac {file} && java $(basename {file} .java)'".to_string(),
+            "sh -c 'javac {file} && java -cp $(dirname {file}) $(basename {file} .java) \"$@\"' sh"
+                .to_string(),
         );
         languages.insert(
             "cpp".to_string(),
-            "sh -c 'g++ {file} -o /tmp/runmd_cpp && /tmp/runmd_cpp'".to_string(),
+            "sh -c 'g++ {file} -o {out} && {out} \"$@\"' sh".to_string(),
         );
         languages.insert(
             "c".to_string(),
-            "sh -c 'gcc {file} -o /tmp/runmd_c && /tmp/runmd_c'".to_string(),
+            "sh -c 'gcc {file} -o {out} && {out} \"$@\"' sh".to_string(),
         );
 
-        Config { languages }
+        let mut aliases = HashMap::new();
+        for (alias, language) in [
+            ("c++", "cpp"),
+            ("cxx", "cpp"),
+            ("node", "javascript"),
+            ("node.js", "javascript"),
+            ("nodejs", "javascript"),
+            ("python3", "python"),
+            ("shell", "bash"),
+        ] {
+            aliases.insert(alias.to_string(), language.to_string());
+        }
+
+        Config {
+            languages,
+            aliases,
+            invoke: HashMap::new(),
+            allowed_languages: None,
+            tags: None,
+            exclude_tags: Vec::new(),
+            detect_languages: false,
+            ignore_markers: IgnoreMarkers::default(),
+            skip_sections: Vec::new(),
+            ordered: false,
+            explicit_run: false,
+            parallel: ParallelMode::Off,
+            sessions: false,
+            fixtures: None,
+            keep_temp: false,
+            record_all: false,
+            record_dir: "casts".to_string(),
+            max_file_size: 10 * 1024 * 1024,
+            min_free_space: None,
+            confirm_blocks: 100,
+            max_blocks: None,
+            max_block_time: None,
+            foreign_attributes: vec![
+                "title".to_string(),
+                "linenos".to_string(),
+                "hl_lines".to_string(),
+                "showLineNumbers".to_string(),
+            ],
+            output_header: DEFAULT_OUTPUT_HEADER.to_string(),
+            exit_codes: false,
+            dedup_outputs: false,
+            error_summary: false,
+            output_labels: OutputLabels::Off,
+            output_filters: HashMap::new(),
+            max_parallel: None,
+            timeout: None,
+            timeout_grace: "2s".to_string(),
+            hermetic: false,
+            exempt: Vec::new(),
+            env_policy: EnvPolicy::default(),
+            priority: Priority::Normal,
+            cpu_affinity: Vec::new(),
+            nix: NixConfig::default(),
+            tool_versions: false,
+            container_runtime: "docker".to_string(),
+            history: true,
+            max_depth: 3,
+            cache: CacheConfig::default(),
+            service: ServiceConfig::default(),
+            scan: ScanConfig::default(),
+        }
     }
 }
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A long-running container that every block of a document executes in, so
+/// blocks share its filesystem. Removed when dropped.
+pub struct Container {
+    runtime: String,
+    id: String,
+    pub image: String,
+    /// Programs started so far, numbering the files their process IDs are kept in
+    execs: AtomicUsize,
+}
+
+/// How to run one program inside a container, and how to stop it there.
+pub struct Exec {
+    /// Command prefix running the program
+    pub prefix: Vec<String>,
+    /// Command killing the program, which signals to the local `exec` client
+    /// don't reach
+    pub stop: Vec<String>,
+}
+
+impl Container {
+    /// Start `image` with `runtime` (`docker`, `podman`, ...), mounting `workspace`
+    /// at the same path so block source files can be found inside it.
+    pub fn start(runtime: &str, image: &str, workspace: &Path) -> Result<Self> {
+        // Documents name the image, so it must not be read as one of `run`'s options
+        if !is_image_reference(image) {
+            anyhow::bail!("{:?} is not a container image reference", image);
+        }
+        let workspace = workspace.to_string_lossy();
+        let output = Command::new(runtime)
+            .args(["run", "-d", "--rm", "--init"])
+            .args(["-v", &format!("{}:{}", workspace, workspace)])
+            .args(["-w", &workspace])
+            .args(["--entrypoint", "sleep", "--", image, "infinity"])
+            .output()
+            .with_context(|| format!("Failed to run {}", runtime))?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "Failed to start container from {}: {}",
+                image,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(Container {
+            runtime: runtime.to_string(),
+            id: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            image: image.to_string(),
+            execs: AtomicUsize::new(0),
+        })
+    }
+
+    /// Run a program inside the container in `cwd`, passing it the variables named
+    /// by `env`. Only the names appear on the command line: the runtime reads the
+    /// values from its own environment, which the caller sets.
+    pub fn exec(&self, cwd: &Path, env: &[(String, String)], tty: bool) -> Exec {
+        let pid_file = format!(
+            "/tmp/runmd-{}.pid",
+            self.execs.fetch_add(1, Ordering::SeqCst)
+        );
+        let mut prefix = vec![
+            self.runtime.clone(),
+            "exec".to_string(),
+            if tty { "-it" } else { "-i" }.to_string(),
+            "-w".to_string(),
+            cwd.to_string_lossy().to_string(),
+        ];
+        for (key, _) in env {
+            prefix.push("-e".to_string());
+            prefix.push(key.clone());
+        }
+        prefix.push(self.id.clone());
+        // The program records its process ID, then takes the shell's place
+        prefix.extend(["sh", "-c", "echo $$ > \"$0\" && exec \"$@\"", &pid_file].map(String::from));
+
+        let stop = [
+            &self.runtime,
+            "exec",
+            &self.id,
+            "sh",
+            "-c",
+            "kill -KILL \"$(cat \"$0\")\" 2>/dev/null",
+            &pid_file,
+        ]
+        .map(String::from)
+        .to_vec();
+        Exec { prefix, stop }
+    }
+}
+
+/// Whether `image` is a reference such as `python:3.12`, `ghcr.io/org/env:v1`
+/// or `alpine@sha256:<digest>`: an optional registry host and port, slash-separated
+/// lowercase path components, then an optional tag and digest.
+fn is_image_reference(image: &str) -> bool {
+    let host = r"[a-zA-Z0-9](?:[a-zA-Z0-9.-]*[a-zA-Z0-9])?(?::[0-9]+)?";
+    let component = r"[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*";
+    let tag = r"\w[\w.-]{0,127}";
+    let digest = r"[a-z0-9]+(?:[.+_-][a-z0-9]+)*:[0-9a-fA-F]{32,}";
+    let pattern = format!("^(?:{host}/)?{component}(?:/{component})*(?::{tag})?(?:@{digest})?$");
+    Regex::new(&pattern)
+        .expect("valid image pattern")
+        .is_match(image)
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        let _ = Command::new(&self.runtime)
+            .args(["rm", "-f", &self.id])
+            .output();
+    }
+}
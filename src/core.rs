@@ -1,58 +1,593 @@
 use anyhow::Result;
+use colored::Colorize;
 
-use crate::config::Config;
-use crate::languages::Languages;
-use crate::runner::run_code;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::attrs::Attrs;
+use crate::config::{Config, IgnoreMarkers, OutputLabels, ScanAction, DEFAULT_OUTPUT_HEADER};
+use crate::deps::run_order;
+use crate::detect::detect_language;
+use crate::executor::{Executor, RunResult};
+use crate::filters;
+use crate::glob::glob_match;
+use crate::hash::block_identity;
+use crate::report::{BlockRun, Report};
+use crate::scan::{default_rules, scan};
+use crate::summary;
+
+#[cfg(feature = "native")]
+use {
+    crate::attrs::parse_duration,
+    crate::cache::{CacheEntry, OutputCache},
+    crate::config::{NixConfig, ParallelMode, Priority},
+    crate::container::Container,
+    crate::deps::dependencies,
+    crate::disk,
+    crate::frontmatter::document_options,
+    crate::hash::{block_key, cache_key},
+    crate::nesting,
+    crate::progress::Progress,
+    crate::languages::Languages,
+    crate::runner::{run_code, CaptureMode, RunOptions, Sessions},
+    crate::select::Selector,
+    crate::timings::Timings,
+    crate::toolchain,
+    crate::workspace::Workspace,
+    anyhow::Context,
+    std::collections::HashMap,
+    std::path::{Path, PathBuf},
+    std::time::Instant,
+};
 
 #[derive(Debug, Clone)]
-struct CodeBlock {
-    language: String,
-    code: String,
+pub struct CodeBlock {
+    pub language: String,
+    pub code: String,
     start_pos: usize,
     end_pos: usize,
-    skip: bool,
+    /// 1-based line of the opening fence
+    pub line: usize,
+    pub skip: bool,
+    /// Why the block is skipped, when it is
+    pub skip_reason: Option<SkipReason>,
+    /// The backtick or tilde run that opened the block
+    fence: String,
     fence_info: String,
+    /// Spaces before the opening fence, e.g. inside a list item, also written
+    /// before its code and outputs
+    indent: usize,
+    pub attrs: Attrs,
+    /// Problems found while parsing the fence attributes
+    pub attr_errors: Vec<String>,
+    /// `**Output (<os>)**` sections following the block, by platform
+    os_outputs: Vec<(String, String)>,
+    /// End of the block including its per-platform output sections
+    outputs_end: usize,
+    /// Line break ending the opening fence, `\n` or `\r\n`, used when rewriting it
+    newline: &'static str,
+    /// The fence had no language; `language` was guessed from the code
+    pub detected: bool,
+}
+
+/// Why a block is not executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Marked `-nr`, `--no-run` or `skip=true`
+    NoRun,
+    /// Not marked `run` while `explicit_run` is on
+    NotMarked,
+    /// Its `tags=` don't match `tags` or match `exclude_tags`
+    Tags,
+    /// Its language is not in `allowed_languages`
+    NotAllowed,
+    /// It sets `cmd=`, `with=` or `matrix=` while `allowed_languages` is set, which
+    /// would run its code with a program the allowlist doesn't vouch for
+    Overridden,
+    /// It is under a heading matching `skip_sections`
+    SkippedSection,
+    /// Only blocks matching other selectors run, see `RunContext::select_blocks`
+    NotSelected,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SkipReason::NoRun => "marked -nr",
+            SkipReason::NotMarked => "not marked run",
+            SkipReason::Tags => "filtered out by tags",
+            SkipReason::NotAllowed => "language not in allowed_languages",
+            SkipReason::Overridden => "cmd=, with= or matrix= while allowed_languages is set",
+            SkipReason::SkippedSection => "under a skipped section",
+            SkipReason::NotSelected => "not selected",
+        })
+    }
+}
+
+impl CodeBlock {
+    /// How the block is referred to in messages: its line, language and name.
+    pub fn label(&self) -> String {
+        match self.attrs.get("name") {
+            Some(name) => format!("line {} {} \"{}\"", self.line, self.language, name),
+            None => format!("line {} {}", self.line, self.language),
+        }
+    }
+
+    /// `hash::block_identity` of the block: its name, or its language and code.
+    pub fn identity(&self) -> String {
+        block_identity(self.attrs.get("name"), &self.language, &self.code)
+    }
+
+    /// Whether the block runs without an output section: `quiet`, or its
+    /// synonyms `silent` and `hide-output`.
+    pub fn quiet(&self) -> bool {
+        ["quiet", "silent", "hide-output"]
+            .iter()
+            .any(|name| self.attrs.flag(name))
+    }
+}
+
+/// Hidden comment following a customised output header, so the section is still
+/// recognised after `output_header` changes.
+const OUTPUT_MARKER: &str = "<!-- runmd:output -->";
+
+/// Lines around the output of an `output=raw` block, which is inserted as Markdown
+/// rather than in a fence.
+const RAW_OPEN: &str = "<!-- runmd:raw -->";
+const RAW_CLOSE: &str = "<!-- /runmd:raw -->";
+
+/// The line standing in for an output identical to that of an earlier named
+/// block, with `dedup_outputs`: `*(same output as block "setup")*`.
+fn same_output_line(name: &str) -> String {
+    format!("*(same output as block \"{}\")*", name)
+}
+
+/// The block name in a line written by [`same_output_line`].
+fn same_output_name(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("*(same output as block \"")?
+        .strip_suffix("\")*")
+}
+
+/// Anchor starting the output header of a block with `id=`, so links can point
+/// at its output: `<a id="install-step"></a>**Output**`.
+fn output_anchor(id: &str) -> String {
+    format!("<a id=\"{}\"></a>", id)
+}
+
+/// `line` without an anchor written by [`output_anchor`].
+fn strip_output_anchor(line: &str) -> &str {
+    line.strip_prefix("<a id=\"")
+        .and_then(|rest| rest.split_once("\"></a>"))
+        .map_or(line, |(_, rest)| rest)
+}
+
+/// Link to a block's asciinema recording, on the line after its output.
+fn recording_link(path: &str) -> String {
+    format!("[▶ Recording]({})", path)
+}
+
+fn is_recording_link(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("[▶ Recording](") && line.ends_with(')')
+}
+
+/// File name of a block's recording: its name, or a key of its code.
+#[cfg(feature = "native")]
+fn recording_file(block: &CodeBlock) -> String {
+    match block.attrs.get("name") {
+        Some(name) => format!("{}.cast", name.replace(['/', '\\'], "-")),
+        None => format!("{}.cast", block_key(&block.language, &block.code)),
+    }
+}
+
+/// Lines wrapping a `hide-code` block in an HTML comment, so rendered Markdown
+/// shows only its output while runmd can still run it.
+const HIDE_OPEN: &str = "<!-- runmd:hide-code";
+const HIDE_CLOSE: &str = "-->";
+
+/// The line introducing an output section under `header`, for the output recorded
+/// on `os` by a `per-os` block: `**Output (linux)**` for `**Output**`.
+fn section_header(header: &str, os: Option<&str>) -> String {
+    let text = match os {
+        Some(os) => match header.strip_prefix("**").and_then(|h| h.strip_suffix("**")) {
+            Some(inner) => format!("**{} ({})**", inner, os),
+            None => format!("{} ({})", header, os),
+        },
+        None => header.to_string(),
+    };
+    if header == DEFAULT_OUTPUT_HEADER {
+        text
+    } else {
+        format!("{} {}", text, OUTPUT_MARKER)
+    }
+}
+
+/// The line introducing an output section under `header` with a `label` such as
+/// ` 2` or `: setup`: `**Output 2**` for `**Output**`.
+fn labeled_section_header(header: &str, label: &str) -> String {
+    let text = match header.strip_prefix("**").and_then(|h| h.strip_suffix("**")) {
+        Some(inner) => format!("**{}{}**", inner, label),
+        None => format!("{}{}", header, label),
+    };
+    if header == DEFAULT_OUTPUT_HEADER {
+        text
+    } else {
+        format!("{} {}", text, OUTPUT_MARKER)
+    }
+}
+
+/// Whether `text` is `header` with a label, whatever `output_labels` is set to now.
+fn is_labeled(text: &str, header: &str) -> bool {
+    let (start, end) = match header.strip_prefix("**").and_then(|h| h.strip_suffix("**")) {
+        Some(inner) => (format!("**{}", inner), "**"),
+        None => (header.to_string(), ""),
+    };
+    let Some(label) = text
+        .strip_prefix(start.as_str())
+        .and_then(|rest| rest.strip_suffix(end))
+    else {
+        return false;
+    };
+    let number = label
+        .strip_prefix(' ')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    let name = label
+        .strip_prefix(": ")
+        .is_some_and(|n| !n.trim().is_empty());
+    number || name
+}
+
+/// `section` with the exit status `code` after its text, e.g. `**Output** (exit 1)`.
+fn with_exit_code(section: &str, code: i32) -> String {
+    match section.strip_suffix(OUTPUT_MARKER) {
+        Some(text) => format!("{} (exit {}) {}", text.trim_end(), code, OUTPUT_MARKER),
+        None => format!("{} (exit {})", section, code),
+    }
+}
+
+/// `text` without an exit status added by [`with_exit_code`], and the status.
+fn split_exit_code(text: &str) -> (&str, Option<i32>) {
+    text.strip_suffix(')')
+        .and_then(|rest| rest.rsplit_once(" (exit "))
+        .and_then(|(rest, code)| Some((rest, Some(code.parse().ok()?))))
+        .unwrap_or((text, None))
+}
+
+/// The exit status shown in an output header line, if any.
+fn header_exit_code(line: &str) -> Option<i32> {
+    let line = line.trim_end();
+    split_exit_code(line.strip_suffix(OUTPUT_MARKER).unwrap_or(line).trim_end()).1
+}
+
+/// Whether `line` introduces an output section written with `header`, the default
+/// header or a marker, and if so the platform a `per-os` output was recorded on.
+pub(crate) fn parse_section_header<'a>(line: &'a str, header: &str) -> Option<Option<&'a str>> {
+    let line = strip_output_anchor(line.trim());
+    let (text, marked) = match line.strip_suffix(OUTPUT_MARKER) {
+        Some(text) => (text.trim_end(), true),
+        None => (line, false),
+    };
+    let (text, _) = split_exit_code(text);
+    // Labels are checked first, as a name may end in parentheses like a platform
+    if [DEFAULT_OUTPUT_HEADER, header]
+        .iter()
+        .any(|h| is_labeled(text, h))
+    {
+        return Some(None);
+    }
+    let written = |os: Option<&str>| {
+        marked
+            || [DEFAULT_OUTPUT_HEADER, header]
+                .iter()
+                .any(|h| section_header(h, os).trim_end_matches(OUTPUT_MARKER).trim_end() == text)
+    };
+
+    let os = text
+        .trim_end_matches('*')
+        .strip_suffix(')')
+        .and_then(|text| text.rsplit_once(" ("))
+        .map(|(_, os)| os);
+    match os {
+        Some(os) if written(Some(os)) => Some(Some(os)),
+        _ if written(None) => Some(None),
+        _ => None,
+    }
+}
+
+/// Parse an ATX heading (`## Title`) into its level and title text.
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') && !rest.starts_with('\t') {
+        return None;
+    }
+
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+/// Parse an opening code fence into its indentation, fence run and info string.
+/// Lines indented by four or more spaces are indented code, not fences.
+pub(crate) fn parse_fence_open(line: &str) -> Option<(usize, &str, &str)> {
+    parse_fence_open_within(line, 0)
+}
+
+/// [`parse_fence_open`] for a line inside a list item whose content starts at
+/// column `base`, where the four-space rule counts from that column.
+pub(crate) fn parse_fence_open_within(line: &str, base: usize) -> Option<(usize, &str, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent < base || indent - base > 3 {
+        return None;
+    }
+    let rest = &line[indent..];
+
+    let fence_char = rest.chars().next()?;
+    if fence_char != '`' && fence_char != '~' {
+        return None;
+    }
+
+    let run = rest.len() - rest.trim_start_matches(fence_char).len();
+    if run < 3 {
+        return None;
+    }
+
+    // Backticks in a backtick fence's info string mean this is inline code
+    let info = rest[run..].trim();
+    if fence_char == '`' && info.contains('`') {
+        return None;
+    }
+
+    Some((indent, &rest[..run], info))
+}
+
+/// CommonMark closing fence: at most 3 spaces of indentation, a run of the
+/// opening character at least as long as the opening fence, then only whitespace.
+/// Inside a list item the indentation counts from `base`, where its content starts.
+pub(crate) fn is_fence_close(line: &str, fence: &str, base: usize) -> bool {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent < base || indent - base > 3 {
+        return false;
+    }
+
+    let rest = line[indent..].trim_end();
+    let fence_char = fence.chars().next();
+    rest.len() >= fence.len() && rest.chars().all(|c| Some(c) == fence_char)
+}
+
+/// Content columns of the list items enclosing the line being read, so fences in
+/// a list item are recognized up to three spaces past where its text starts.
+#[derive(Default)]
+pub(crate) struct ListItems {
+    indents: Vec<usize>,
 }
 
-fn find_all_code_blocks(content: &str) -> Vec<CodeBlock> {
+impl ListItems {
+    /// Account for `line`, read outside any fence, and return the column fences
+    /// on it are measured from.
+    pub(crate) fn base(&mut self, line: &str) -> usize {
+        if !line.trim().is_empty() {
+            // A line indented less than an item's content ends that item
+            let indent = line.len() - line.trim_start_matches(' ').len();
+            while self.indents.last().is_some_and(|&item| indent < item) {
+                self.indents.pop();
+            }
+            if let Some(item) = list_item_indent(line, self.current()) {
+                self.indents.push(item);
+            }
+        }
+        self.current()
+    }
+
+    fn current(&self) -> usize {
+        self.indents.last().copied().unwrap_or(0)
+    }
+}
+
+/// Column the content of the list item opened by `line` starts at, for a bullet
+/// (`-`, `*`, `+`) or ordered (`1.`, `1)`) marker at most three spaces past `base`.
+fn list_item_indent(line: &str, base: usize) -> Option<usize> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent < base || indent - base > 3 {
+        return None;
+    }
+    let rest = &line[indent..];
+    let digits = rest.chars().take_while(char::is_ascii_digit).count();
+    let marker = match rest[digits..].chars().next()? {
+        '-' | '*' | '+' if digits == 0 => 1,
+        '.' | ')' if (1..=9).contains(&digits) => digits + 1,
+        _ => return None,
+    };
+    let text = &rest[marker..];
+    let spaces = text.len() - text.trim_start_matches(' ').len();
+    if text.trim().is_empty() {
+        return Some(indent + marker + 1);
+    }
+    match spaces {
+        0 => None,
+        // Five or more spaces start indented code one space after the marker
+        1..=4 => Some(indent + marker + spaces),
+        _ => Some(indent + marker + 1),
+    }
+}
+
+/// `line` inside a fence indented by `indent` spaces, without up to that many
+/// spaces of its own indentation, as CommonMark reads fenced content.
+fn dedent(line: &str, indent: usize) -> &str {
+    let spaces = line.len() - line.trim_start_matches(' ').len();
+    &line[spaces.min(indent)..]
+}
+
+/// `text` with every non-empty line indented by `indent` spaces.
+fn indent_lines(text: &str, indent: usize) -> String {
+    let prefix = " ".repeat(indent);
+    text.split_inclusive('\n')
+        .map(|line| {
+            if line.trim_end_matches(['\n', '\r']).is_empty() {
+                line.to_string()
+            } else {
+                format!("{}{}", prefix, line)
+            }
+        })
+        .collect()
+}
+
+/// Byte offset of the start of each line of `content` as split by `str::lines`,
+/// followed by the end of the content. Line breaks may be `\n` or `\r\n`, so
+/// offsets can't be derived from the lengths `lines` returns.
+fn line_starts(content: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(
+        content
+            .match_indices('\n')
+            .map(|(i, _)| i + 1)
+            .filter(|&start| start < content.len()),
+    );
+    starts.push(content.len());
+    starts
+}
+
+pub(crate) fn find_all_code_blocks(content: &str, config: &Config) -> Vec<CodeBlock> {
+    let markers = &config.ignore_markers;
     let mut blocks = Vec::new();
     let lines: Vec<&str> = content.lines().collect();
+    let line_starts = line_starts(content);
     let mut i = 0;
+    let mut enabled = true;
+
+    // Headings enclosing the current line, outermost first
+    let mut sections: Vec<(usize, &str)> = Vec::new();
+    // Whether a search for the end of raw output found none
+    let mut raw_unclosed = false;
+    let mut list_items = ListItems::default();
 
     while i < lines.len() {
         let line = lines[i].trim();
+        let base = list_items.base(lines[i]);
+
+        // Raw output is what a block printed, not part of the document
+        if line == RAW_OPEN
+            && !raw_unclosed
+            && i > 0
+            && parse_section_header(lines[i - 1], &config.output_header).is_some()
+        {
+            match lines[i + 1..].iter().position(|l| l.trim() == RAW_CLOSE) {
+                Some(len) => {
+                    i += len + 2;
+                    continue;
+                }
+                None => raw_unclosed = true,
+            }
+        }
+
+        if let Some((level, title)) = parse_heading(line) {
+            while sections.last().is_some_and(|(l, _)| *l >= level) {
+                sections.pop();
+            }
+            sections.push((level, title));
+            i += 1;
+            continue;
+        }
+
+        // Blocks between ignore markers are left untouched
+        if line == markers.off {
+            enabled = false;
+            i += 1;
+            continue;
+        }
+        if line == markers.on {
+            enabled = true;
+            i += 1;
+            continue;
+        }
+        if !enabled {
+            i += 1;
+            continue;
+        }
 
         // Check if this line starts a code block
-        if line.starts_with("```") && line.len() > 3 {
-            let info = line[3..].trim();
+        if let Some((indent, fence, info)) = parse_fence_open_within(lines[i], base) {
             let fence_info = info.to_string();
 
-            // Split info string by whitespace to detect modifiers
-            let mut skip = false;
-            let mut language = String::new();
-            if !info.is_empty() {
-                let parts: Vec<&str> = info.split_whitespace().collect();
-                if !parts.is_empty() {
-                    language = parts[0].to_string();
-                    for p in &parts[1..] {
-                        if *p == "-nr" || *p == "--no-run" {
-                            skip = true;
-                        }
-                    }
-                }
-            }
+            // The first word is the language, the rest are attributes
+            let language_end = info
+                .find(|c: char| c.is_whitespace() || c == '{')
+                .unwrap_or(info.len());
+            let (language, rest) = info.split_at(language_end);
+
+            // Unlabeled fences may get a language guessed from their code, unless
+            // they hold a block's output
+            let is_output = i > 0
+                && parse_section_header(lines[i - 1], &config.output_header).is_some();
+            let detected = if info.is_empty() && config.detect_languages && !is_output {
+                let body: Vec<&str> = lines[i + 1..]
+                    .iter()
+                    .take_while(|line| !is_fence_close(line, fence, base))
+                    .copied()
+                    .collect();
+                detect_language(&body.join("\n"))
+            } else {
+                None
+            };
+            let language = detected.unwrap_or(language);
 
-            // Validate language token (must exist)
+            // Validate language token (must exist); other fences are passed over whole
             if language.is_empty()
                 || !language
                     .chars()
-                    .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+                    .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '+' | '.' | '#'))
             {
+                i += 1;
+                while i < lines.len() && !is_fence_close(lines[i], fence, base) {
+                    i += 1;
+                }
                 i += 1;
                 continue;
             }
 
+            let (attrs, attr_errors) = Attrs::parse(rest, &config.foreign_attributes);
+            let mut skip_reason = None;
+            if attrs.flag("-nr") || attrs.flag("--no-run") || attrs.bool("skip").unwrap_or(false)
+            {
+                skip_reason = Some(SkipReason::NoRun);
+            }
+            if skip_reason.is_none() && config.explicit_run && !attrs.flag("run") {
+                skip_reason = Some(SkipReason::NotMarked);
+            }
+            if skip_reason.is_none() && !config.tags_allowed(&attrs.list("tags")) {
+                skip_reason = Some(SkipReason::Tags);
+            }
+
+            // Languages outside the allowlist and blocks under a skipped section
+            // are treated like -nr
+            if skip_reason.is_none() && !config.language_allowed(language) {
+                skip_reason = Some(SkipReason::NotAllowed);
+            }
+            if skip_reason.is_none()
+                && config.allowed_languages.is_some()
+                && ["cmd", "with", "matrix"]
+                    .iter()
+                    .any(|key| attrs.get(key).is_some())
+            {
+                skip_reason = Some(SkipReason::Overridden);
+            }
+            if skip_reason.is_none()
+                && sections.iter().any(|(_, title)| {
+                    config
+                        .skip_sections
+                        .iter()
+                        .any(|pattern| glob_match(pattern, title))
+                })
+            {
+                skip_reason = Some(SkipReason::SkippedSection);
+            }
+
             let start_line = i;
+            let wrapped = i > 0 && lines[i - 1].trim() == HIDE_OPEN;
             i += 1; // Move past the opening fence
 
             // Find the closing fence
@@ -61,38 +596,81 @@ fn find_all_code_blocks(content: &str) -> Vec<CodeBlock> {
             let mut end_line = i;
 
             while i < lines.len() {
-                if lines[i].trim() == "```" {
+                if is_fence_close(lines[i], fence, base) {
                     found_closing = true;
                     end_line = i;
                     break;
                 }
-                code_lines.push(lines[i]);
+                code_lines.push(dedent(lines[i], indent));
                 i += 1;
             }
 
             if found_closing {
                 let code = code_lines.join("\n");
 
-                // Calculate character positions (approximate)
-                let start_pos = lines[..start_line]
-                    .iter()
-                    .map(|l| l.len() + 1)
-                    .sum::<usize>();
-                let end_pos = lines[..=end_line]
-                    .iter()
-                    .map(|l| l.len() + 1)
-                    .sum::<usize>();
+                // The comment hiding a block belongs to it, so rendering can drop or
+                // rewrite it along with the fence
+                let wrapped = wrapped
+                    && lines
+                        .get(end_line + 1)
+                        .is_some_and(|line| line.trim() == HIDE_CLOSE);
+                if wrapped {
+                    end_line += 1;
+                }
+                let first_line = start_line - usize::from(wrapped);
+
+                // Outputs recorded on other platforms directly follow the block
+                let mut os_outputs = Vec::new();
+                let mut outputs_end_line = end_line;
+                while let Some(Some(os)) = lines
+                    .get(outputs_end_line + 1)
+                    .and_then(|line| parse_section_header(line, &config.output_header))
+                {
+                    let Some((os_indent, fence, _)) = lines
+                        .get(outputs_end_line + 2)
+                        .and_then(|line| parse_fence_open_within(line, base))
+                    else {
+                        break;
+                    };
+                    let body_start = outputs_end_line + 3;
+                    let Some(len) = lines[body_start.min(lines.len())..]
+                        .iter()
+                        .position(|line| is_fence_close(line, fence, base))
+                    else {
+                        break;
+                    };
+                    let body: Vec<&str> = lines[body_start..body_start + len]
+                        .iter()
+                        .map(|line| dedent(line, os_indent))
+                        .collect();
+                    os_outputs.push((os.to_string(), body.join("\n")));
+                    outputs_end_line = body_start + len;
+                }
 
                 blocks.push(CodeBlock {
                     language: language.to_string(),
                     code,
-                    start_pos,
-                    end_pos: end_pos.min(content.len()),
-                    skip,
+                    start_pos: line_starts[first_line],
+                    end_pos: line_starts[end_line + 1],
+                    line: start_line + 1,
+                    skip: skip_reason.is_some(),
+                    skip_reason,
+                    fence: fence.to_string(),
                     fence_info,
+                    indent,
+                    attrs,
+                    attr_errors,
+                    os_outputs,
+                    outputs_end: line_starts[outputs_end_line + 1],
+                    newline: if content[..line_starts[start_line + 1]].ends_with("\r\n") {
+                        "\r\n"
+                    } else {
+                        "\n"
+                    },
+                    detected: detected.is_some(),
                 });
 
-                i += 1; // Move past the closing fence
+                i = outputs_end_line + 1; // Move past the closing fence and outputs
             } else {
                 // No closing fence found, skip this block
                 break;
@@ -105,279 +683,1642 @@ fn find_all_code_blocks(content: &str) -> Vec<CodeBlock> {
     blocks
 }
 
-/// Process markdown by executing code blocks and attaching outputs.
-/// If force_parallel is true, parallel execution is used when more than one runnable block exists.
-pub async fn process_markdown(content: &str, force_parallel: bool) -> Result<String> {
-    let config = Config::load()?;
-    let languages = Languages::new(config.languages);
+/// Number of blocks per language that would execute if the document were processed.
+pub fn runnable_blocks_by_language(
+    content: &str,
+    config: &Config,
+) -> Result<BTreeMap<String, usize>> {
+    let content = clear_outputs(content, config)?;
+    let mut counts = BTreeMap::new();
 
-    // Step 1: sanitize content by stripping outputs
-    let content = clear_outputs(content)?;
+    for block in find_all_code_blocks(&content, config) {
+        if !block.skip {
+            *counts.entry(block.language).or_insert(0) += 1;
+        }
+    }
 
-    // Step 2: find all code blocks
-    let code_blocks = find_all_code_blocks(&content);
+    Ok(counts)
+}
 
-    if code_blocks.is_empty() {
-        return Ok(content);
+/// Everything the blocks of a run share while executing; a directory run reuses
+/// one context for every document.
+#[cfg(feature = "native")]
+pub struct RunContext {
+    languages: Languages,
+    /// Directory of the documents, which paths in attributes are relative to
+    base_dir: PathBuf,
+    /// Directory blocks run in, when the document uses a workspace
+    workspace: Option<Workspace>,
+    /// Directory blocks run in otherwise; runmd's own when unset
+    cwd: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    /// Start blocks from `env` alone instead of the inherited environment
+    clear_env: bool,
+    priority: Priority,
+    cpu_affinity: Vec<usize>,
+    keep_temp: bool,
+    /// Most blocks running at once in parallel mode
+    max_parallel: usize,
+    timings: Timings,
+    /// Canonical path of the current document, which its blocks' timings are kept under
+    document: Option<PathBuf>,
+    cache: Option<OutputCache>,
+    /// Blocks use the cache unless they set `cache=false`; otherwise only those
+    /// setting `cache=true` do
+    cache_all: bool,
+    /// Interpreter versions per language, gathered when the cache is enabled
+    versions: HashMap<String, String>,
+    /// Blocks that skip the cache lookup
+    rerun: Vec<Selector>,
+    /// Blocks that run when not empty; the others keep their outputs
+    select: Vec<Selector>,
+    /// Time limit for blocks without a `timeout` attribute
+    timeout: Option<Duration>,
+    timeout_grace: Duration,
+    /// Names of blocks without a time limit unless they set one themselves
+    exempt: Vec<String>,
+    /// Bytes that must be free where blocks write before each one runs
+    min_free_space: Option<u64>,
+    /// Blocks get `STABLE_ENV` unless they set `stable=false`
+    hermetic: bool,
+    container_runtime: String,
+    /// Image documents without an `image` of their own run in
+    image: Option<String>,
+    /// Documents come from clients that mustn't choose their own image or read
+    /// files with `stdin-file=`
+    untrusted: bool,
+    /// Container the current document's blocks run in, from its front matter
+    container: Option<Container>,
+    nix: NixConfig,
+    /// Nix installables the current document's front matter asks for
+    document_packages: Vec<String>,
+    /// Where `expect-file=` outputs are kept; inlined like any other output when unset
+    expect_files: Option<ExpectFiles>,
+    /// Directory receiving the execution traces of the current document's blocks
+    trace: Option<PathBuf>,
+    /// Directory receiving asciinema recordings of the current document's blocks
+    recordings: Option<PathBuf>,
+    /// Record every block rather than only those marked `record`
+    record_all: bool,
+    /// Interpreters shared by the current document's `session=` blocks
+    sessions: Sessions,
+    /// Blocks without `session=` share the session `default` when their language supports it
+    default_session: bool,
+}
+
+/// Golden files holding the outputs of `expect-file=` blocks.
+#[cfg(feature = "native")]
+struct ExpectFiles {
+    /// Directory the attribute's paths are relative to
+    dir: std::path::PathBuf,
+    /// Write new outputs to the files rather than comparing them
+    update: bool,
+}
+
+#[cfg(feature = "native")]
+impl RunContext {
+    /// Relative paths in the config are resolved against `base_dir`.
+    pub fn new(config: &Config, base_dir: &Path) -> Result<Self> {
+        let mut context = RunContext {
+            languages: Languages::new(
+                config.languages.clone(),
+                config.aliases.clone(),
+                config.invoke.clone(),
+            ),
+            base_dir: base_dir.to_path_buf(),
+            workspace: None,
+            cwd: None,
+            env: Vec::new(),
+            clear_env: config.env_policy.filters(),
+            priority: config.priority,
+            cpu_affinity: config.cpu_affinity.clone(),
+            keep_temp: config.keep_temp,
+            max_parallel: config.max_parallel.unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(4)
+            }),
+            timings: Timings::load(),
+            document: None,
+            cache: None,
+            cache_all: config.cache.enabled,
+            versions: HashMap::new(),
+            rerun: Selector::parse_all(&config.cache.rerun)?,
+            select: Vec::new(),
+            timeout: config
+                .timeout
+                .as_deref()
+                .map(|timeout| parse_duration(timeout).map_err(anyhow::Error::msg))
+                .transpose()
+                .context("Invalid timeout")?,
+            timeout_grace: parse_duration(&config.timeout_grace)
+                .map_err(anyhow::Error::msg)
+                .context("Invalid timeout_grace")?,
+            exempt: config.exempt.clone(),
+            min_free_space: config.min_free_space,
+            hermetic: config.hermetic,
+            container_runtime: config.container_runtime.clone(),
+            image: None,
+            untrusted: false,
+            container: None,
+            nix: config.nix.clone(),
+            document_packages: Vec::new(),
+            expect_files: None,
+            trace: None,
+            recordings: None,
+            record_all: config.record_all,
+            sessions: Sessions::default(),
+            default_session: config.sessions,
+        };
+
+        // Blocks can opt into the cache with `cache=true` when it is off
+        context.cache = if config.cache.enabled {
+            Some(OutputCache::new(&config.cache)?)
+        } else {
+            OutputCache::new(&config.cache).ok()
+        };
+
+        let policy = &config.env_policy;
+        if context.clear_env {
+            context
+                .env
+                .extend(std::env::vars().filter(|(key, _)| policy.passes(key)));
+        }
+        context
+            .env
+            .extend(policy.set.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        // Pinned tool versions go in front of whatever PATH blocks would get
+        if config.tool_versions {
+            let bins = toolchain::bin_dirs(&toolchain::pinned_versions(base_dir));
+            if !bins.is_empty() {
+                let path = context
+                    .env
+                    .iter()
+                    .rev()
+                    .find(|(key, _)| key == "PATH")
+                    .map(|(_, value)| value.into())
+                    .or_else(|| std::env::var_os("PATH").filter(|_| !context.clear_env))
+                    .unwrap_or_default();
+                let path =
+                    std::env::join_paths(bins.into_iter().chain(std::env::split_paths(&path)))
+                        .context("Invalid PATH for pinned tool versions")?;
+                context
+                    .env
+                    .push(("PATH".to_string(), path.to_string_lossy().to_string()));
+            }
+        }
+
+        // Fixtures are copied into a fresh workspace so blocks can't modify the originals
+        if let Some(fixtures) = &config.fixtures {
+            let fixtures = base_dir.join(fixtures);
+            let workspace = Workspace::new()?;
+            workspace.copy_fixtures(&fixtures)?;
+
+            let fixtures = fixtures.canonicalize().unwrap_or(fixtures);
+            context.env.push((
+                "RUNMD_FIXTURES".to_string(),
+                fixtures.to_string_lossy().to_string(),
+            ));
+            context.workspace = Some(workspace);
+        }
+
+        Ok(context)
     }
 
-    // Count runnable (non-skipped) blocks
-    let runnable_count = code_blocks.iter().filter(|b| !b.skip).count();
+    /// The session `block` runs in, if any. Blocks with their own command
+    /// don't join the default session.
+    fn session<'a>(&self, block: &'a CodeBlock) -> Option<&'a str> {
+        block.attrs.get("session").or_else(|| {
+            (self.default_session
+                && !overrides_command(block)
+                && Sessions::supports(&self.languages, &block.language))
+            .then_some("default")
+        })
+    }
 
-    // Decide execution strategy
-    if runnable_count > 1 && (force_parallel || runnable_count >= 4) {
-        return process_markdown_parallel(&content, &code_blocks, &languages).await;
+    /// Run documents that don't name an image in a container from `image`.
+    pub fn use_image(&mut self, image: &str) {
+        self.image = Some(image.to_string());
     }
-    return process_markdown_sequential(&content, &code_blocks, &languages).await;
 
-    // Parallel execution (disabled for now)
-    /*
-    // If only one block, process sequentially for simplicity
-    if code_blocks.len() == 1 {
-        return process_markdown_sequential(&content, &languages).await;
+    /// Resolve the next documents' attribute paths against `base_dir` and run
+    /// their blocks in `cwd`, for a daemon working on behalf of clients started
+    /// elsewhere. Blocks run in runmd's own directory when `cwd` is `None`.
+    pub fn set_directories(&mut self, base_dir: &Path, cwd: Option<&Path>) {
+        self.base_dir = base_dir.to_path_buf();
+        self.cwd = cwd.map(Path::to_path_buf);
     }
 
-    // Step 3: execute all code blocks in parallel using futures::future::join_all
-    use futures::future::join_all;
+    /// Treat the next documents as submitted by clients: the `image` in their
+    /// front matter is ignored, so they run wherever `use_image` put them, and
+    /// blocks with `stdin-file=` fail rather than read files of the host.
+    pub fn distrust_documents(&mut self) {
+        self.untrusted = true;
+    }
 
-    let tasks: Vec<_> = code_blocks.iter().map(|block| {
-        let block_clone = block.clone();
-        let languages_clone = languages.clone();
+    /// Run blocks in a scratch workspace instead of the current directory, if
+    /// they don't already have one.
+    pub fn use_workspace(&mut self) -> Result<()> {
+        if self.workspace.is_none() {
+            self.workspace = Some(Workspace::new()?);
+        }
+        Ok(())
+    }
 
-        async move {
-            let output = run_code(&block_clone.language, &block_clone.code, &languages_clone, 10).await?;
-            Ok::<(usize, String), anyhow::Error>((block_clone.block_id, output))
+    /// Keep the outputs of `expect-file=` blocks in files relative to `dir` instead
+    /// of the document, writing them when `update` is set and otherwise comparing
+    /// them with each new output.
+    pub fn use_expect_files(&mut self, dir: &Path, update: bool) {
+        self.expect_files = Some(ExpectFiles {
+            dir: dir.to_path_buf(),
+            update,
+        });
+    }
+
+    /// Run every block, even those marked `cache=true`.
+    pub fn disable_cache(&mut self) {
+        self.cache = None;
+    }
+
+    /// The cache `block` reads and writes its output through, if it uses one.
+    fn cache(&self, block: &CodeBlock) -> Option<&OutputCache> {
+        let enabled = block.attrs.bool("cache").unwrap_or(self.cache_all);
+        self.cache.as_ref().filter(|_| enabled)
+    }
+
+    /// Save what each block of the next documents ran and produced under `dir`,
+    /// one directory per block, or stop tracing with `None`.
+    pub fn trace_into(&mut self, dir: Option<PathBuf>) {
+        self.trace = dir;
+    }
+
+    /// Write asciinema recordings of the next documents' blocks to `dir`.
+    pub fn record_into(&mut self, dir: Option<PathBuf>) {
+        self.recordings = dir;
+    }
+
+    /// Where the recording of `block` goes, when it is recorded. Blocks in a
+    /// session, in a matrix or captured without a terminal aren't.
+    fn recording(&self, block: &CodeBlock) -> Option<PathBuf> {
+        let recorded = self.record_all || block.attrs.flag("record");
+        let capture = block.attrs.get("capture");
+        if !recorded
+            || self.session(block).is_some()
+            || block.attrs.get("matrix").is_some()
+            || capture.is_some_and(|capture| capture != "pty")
+        {
+            return None;
         }
-    }).collect();
+        Some(self.recordings.as_ref()?.join(recording_file(block)))
+    }
 
-    let results: Result<Vec<_>> = join_all(tasks).await.into_iter().collect();
-    let results = results?;
+    /// Remember the durations of the next documents' blocks under `path`; blocks
+    /// of documents without one aren't timed, as they can't be told apart.
+    pub fn set_document(&mut self, path: Option<&Path>) {
+        self.document = path.map(|path| std::fs::canonicalize(path).unwrap_or(path.to_path_buf()));
+    }
 
-    // Step 4: sort results by block_id and extract outputs
-    let mut sorted_results = results;
-    sorted_results.sort_by_key(|(block_id, _)| *block_id);
-    let outputs: Vec<String> = sorted_results.into_iter().map(|(_, output)| output).collect();
+    /// The key of `block` in the timing history, if its document has a path.
+    fn timing_key(&self, block: &CodeBlock) -> Option<String> {
+        let document = self.document.as_ref()?;
+        Some(format!("{}#{}", document.display(), block.identity()))
+    }
 
-    // Step 5: reconstruct content with outputs
-    let mut result = String::new();
-    let mut last_pos = 0;
+    /// Run only the blocks of the next documents that match one of `selectors`,
+    /// leaving the outputs of the others as they are. Empty runs every block.
+    pub fn select_blocks(&mut self, selectors: Vec<Selector>) {
+        self.select = selectors;
+    }
 
-    for (i, block) in code_blocks.iter().enumerate() {
-        // Add content before this block
-        result.push_str(&content[last_pos..block.start_pos]);
+    /// Set environment variables for the blocks of the next documents.
+    pub fn add_env(&mut self, vars: impl IntoIterator<Item = (String, String)>) {
+        self.env.extend(vars);
+    }
 
-        // Add the code block
-        let code_fence = if block.code.ends_with('\n') {
-            format!("```{}\n{}```", block.language, block.code)
-        } else {
-            format!("```{}\n{}\n```", block.language, block.code)
+    /// Let blocks that run runmd themselves know how deeply they are nested and
+    /// that `documents` are in progress, for `nesting::check`.
+    pub fn mark_nested(&mut self, documents: &[PathBuf]) {
+        self.env.extend(nesting::child_env(documents));
+    }
+
+    /// `nix shell` invocation providing the toolchain for `language`, when Nix is
+    /// enabled or the document lists packages.
+    fn nix_prefix(&self, language: &str) -> Result<Option<Vec<String>>> {
+        let mut packages = self.document_packages.clone();
+        if self.nix.enabled {
+            let language = self.languages.resolve(language);
+            packages.extend(self.nix.packages.get(language).cloned());
+        }
+        if packages.is_empty() {
+            return Ok(None);
+        }
+
+        let mut prefix = shell_words::split(&self.nix.command)
+            .with_context(|| format!("Invalid nix command '{}'", self.nix.command))?;
+        prefix.extend(packages);
+        prefix.push("--command".to_string());
+        Ok(Some(prefix))
+    }
+
+    /// Run the blocks of a setup document in order before any other document.
+    /// Blocks may append `KEY=VALUE` lines to the file named by `RUNMD_ENV`; those
+    /// variables, and the workspace the blocks ran in, are kept for every later block.
+    pub async fn setup(&mut self, content: &str, config: &Config) -> Result<(String, Report)> {
+        self.use_workspace()?;
+
+        let env_file = tempfile::NamedTempFile::with_prefix("runmd-env-")
+            .context("Failed to create setup environment file")?;
+        self.env.push((
+            "RUNMD_ENV".to_string(),
+            env_file.path().to_string_lossy().to_string(),
+        ));
+
+        let config = Config {
+            ordered: true,
+            ..config.clone()
         };
+        let result = process_markdown(content, &config, self).await;
+        self.env.retain(|(key, _)| key != "RUNMD_ENV");
+        let (result, report) = result?;
 
-        // Add the output
-        let output_text = outputs[i].trim_end_matches('\n');
-        let output_block = format!("**Output**\n```\n{}\n```", output_text);
+        if let Some(failed) = report.blocks.iter().find(|b| b.success == Some(false)) {
+            anyhow::bail!("Setup block at line {} failed", failed.line);
+        }
 
-        result.push_str(&format!("{}\n{}", code_fence, output_block));
-        last_pos = block.end_pos;
+        let exported = std::fs::read_to_string(env_file.path())?;
+        for line in exported.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                self.env.push((key.trim().to_string(), value.to_string()));
+            }
+        }
+
+        Ok((result, report))
     }
+}
 
-    // Add any remaining content
-    result.push_str(&content[last_pos..]);
+/// The output section attached to each block found in `content`, in document order.
+pub fn block_outputs(content: &str, config: &Config) -> Vec<Option<String>> {
+    recorded_outputs(content, config)
+        .into_iter()
+        .map(|output| output.map(|(text, _)| text))
+        .collect()
+}
 
-    Ok(result)
-    */
+/// Like [`block_outputs`], with the exit status shown in each section's header.
+fn recorded_outputs(content: &str, config: &Config) -> Vec<Option<(String, Option<i32>)>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let blocks = find_all_code_blocks(content, config);
+    // Each section's text, or the name of the block whose output it repeats
+    let sections: Vec<_> = blocks
+        .iter()
+        .map(|block| {
+            if block.attrs.flag("per-os") {
+                return block
+                    .os_outputs
+                    .iter()
+                    .find(|(os, _)| os == std::env::consts::OS)
+                    .map(|(_, output)| (Ok(output.clone()), None));
+            }
+            let before = &content[..block.end_pos];
+            let close = before.matches('\n').count() - usize::from(before.ends_with('\n'));
+            let header = lines.get(close + 1)?;
+            if parse_section_header(header, &config.output_header) != Some(None) {
+                return None;
+            }
+            let open = lines.get(close + 2)?;
+            if let Some(name) = same_output_name(open) {
+                return Some((Err(name), header_exit_code(header)));
+            }
+            let body: Vec<&str> = if open.trim() == RAW_OPEN {
+                let indent = open.len() - open.trim_start_matches(' ').len();
+                lines[close + 3..]
+                    .iter()
+                    .take_while(|line| line.trim() != RAW_CLOSE)
+                    .map(|line| dedent(line, indent))
+                    .collect()
+            } else {
+                // Outputs sit at the indentation of their header, which may be in a list item
+                let base = header.len() - header.trim_start_matches(' ').len();
+                let (indent, fence, _) = parse_fence_open_within(open, base)?;
+                lines[close + 3..]
+                    .iter()
+                    .take_while(|line| !is_fence_close(line, fence, base))
+                    .map(|line| dedent(line, indent))
+                    .collect()
+            };
+            Some((Ok(body.join("\n")), header_exit_code(header)))
+        })
+        .collect();
+
+    // A reference to a named block's output stands for that output
+    let mut outputs: Vec<Option<(String, Option<i32>)>> = Vec::with_capacity(sections.len());
+    for section in sections {
+        let output = section.map(|(body, exit_code)| match body {
+            Ok(text) => (text, exit_code),
+            Err(name) => {
+                let text = blocks
+                    .iter()
+                    .zip(&outputs)
+                    .find(|(block, _)| block.attrs.get("name") == Some(name))
+                    .and_then(|(_, output)| output.as_ref())
+                    .map(|(text, _)| text.clone())
+                    .unwrap_or_else(|| same_output_line(name));
+                (text, exit_code)
+            }
+        });
+        outputs.push(output);
+    }
+    outputs
 }
 
-/// Sequential processing optimized for performance
-async fn process_markdown_sequential(
-    content: &str,
-    code_blocks: &[CodeBlock],
-    languages: &Languages,
-) -> Result<String> {
-    if code_blocks.is_empty() {
-        return Ok(content.to_string());
+/// 1-based lines of `**Output**` sections that don't directly follow a block
+/// runmd executes, e.g. left behind after a block was deleted or marked `-nr`.
+pub fn find_orphaned_outputs(content: &str, config: &Config) -> Vec<usize> {
+    let markers = &config.ignore_markers;
+    let lines: Vec<&str> = content.lines().collect();
+
+    // 0-based lines of the closing fences of blocks that run
+    let runnable_ends: Vec<usize> = find_all_code_blocks(content, config)
+        .iter()
+        .filter(|block| !block.skip)
+        .map(|block| {
+            let before = &content[..block.end_pos];
+            before.matches('\n').count() - usize::from(before.ends_with('\n'))
+        })
+        .collect();
+
+    let mut orphans = Vec::new();
+    let mut enabled = true;
+    let mut raw_unclosed = false;
+    let mut list_items = ListItems::default();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        let base = list_items.base(lines[i]);
+        if line == RAW_OPEN && !raw_unclosed {
+            // Nothing in raw output is an output section either
+            match lines[i + 1..].iter().position(|l| l.trim() == RAW_CLOSE) {
+                Some(len) => i += len + 1,
+                None => raw_unclosed = true,
+            }
+        } else if line == markers.off {
+            enabled = false;
+        } else if line == markers.on {
+            enabled = true;
+        } else if let Some((_, fence, _)) = parse_fence_open_within(lines[i], base) {
+            // Nothing inside a fence is an output section
+            i += 1;
+            while i < lines.len() && !is_fence_close(lines[i], fence, base) {
+                i += 1;
+            }
+        } else if enabled
+            && parse_section_header(line, &config.output_header) == Some(None)
+            && lines.get(i + 1).is_some_and(|next| {
+                next.trim_start().starts_with("```")
+                    || next.trim() == RAW_OPEN
+                    || same_output_name(next).is_some()
+            })
+            && !(i > 0 && runnable_ends.contains(&(i - 1)))
+        {
+            orphans.push(i + 1);
+        }
+        i += 1;
     }
 
-    // Pre-allocate result string with estimated capacity
-    let mut result = String::with_capacity(content.len() * 2);
-    let mut last_pos = 0;
+    orphans
+}
 
-    for block in code_blocks {
-        // Add content before this block (using efficient slicing)
-        result.push_str(&content[last_pos..block.start_pos]);
-        if block.skip {
-            // Just reproduce the original block without running
-            result.push_str("```");
-            if !block.fence_info.is_empty() { result.push_str(&block.fence_info); } else { result.push_str(&block.language); }
-            result.push('\n');
-            result.push_str(&block.code);
-            if !block.code.ends_with('\n') {
-                result.push('\n');
+/// Strip previous outputs and find the blocks of a document, reporting attribute
+/// problems as warnings.
+fn prepare(content: &str, config: &Config) -> Result<(String, Vec<CodeBlock>, Report)> {
+    for line in find_orphaned_outputs(content, config) {
+        eprintln!(
+            "{} line {}: removed output not attached to a runnable block",
+            "warning:".yellow().bold(),
+            line
+        );
+    }
+    filters::validate(config.output_filters.values().flatten())?;
+    let content = clear_outputs(content, config)?;
+    let code_blocks = find_all_code_blocks(&content, config);
+    check_dangerous(&code_blocks, config)?;
+
+    let report = Report::new(&code_blocks);
+    for block in &code_blocks {
+        if !block.os_outputs.is_empty() && !block.attrs.flag("per-os") {
+            eprintln!(
+                "{} {}: removed per-platform outputs of a block without per-os",
+                "warning:".yellow().bold(),
+                block.label()
+            );
+        }
+        for error in &block.attr_errors {
+            eprintln!("{} line {}: {}", "warning:".yellow().bold(), block.line, error);
+        }
+        if let Some(id) = block.attrs.get("id") {
+            if let Some(first) = code_blocks
+                .iter()
+                .take_while(|other| other.line < block.line)
+                .find(|other| other.attrs.get("id") == Some(id))
+            {
+                eprintln!(
+                    "{} line {}: id '{}' is already used by the block at line {}",
+                    "warning:".yellow().bold(),
+                    block.line,
+                    id,
+                    first.line
+                );
             }
-            result.push_str("```");
-        } else {
-            // Run the code snippet with optimized timeout
-            let timeout = if block.code.len() > 1000 { 10 } else { 5 }; // Shorter timeout for small code
-            let output = run_code(&block.language, &block.code, languages, timeout).await?;
+        }
+        if let Some(reason @ (SkipReason::NotAllowed | SkipReason::Overridden)) = block.skip_reason
+        {
+            eprintln!(
+                "{} line {}: skipped {} block, {}",
+                "note:".cyan().bold(),
+                block.line,
+                block.language,
+                reason
+            );
+        }
+    }
 
-            // Build output more efficiently
-            result.push_str("```");
-            if !block.fence_info.is_empty() { result.push_str(&block.fence_info); } else { result.push_str(&block.language); }
-            result.push('\n');
-            result.push_str(&block.code);
-            if !block.code.ends_with('\n') {
-                result.push('\n');
+    Ok((content, code_blocks, report))
+}
+
+/// With `scan.enabled`, warn about blocks matching the scan rules and refuse to go
+/// on unless the action is `warn` or `--allow-dangerous` was given.
+fn check_dangerous(code_blocks: &[CodeBlock], config: &Config) -> Result<()> {
+    if !config.scan.enabled {
+        return Ok(());
+    }
+
+    let rules = config.scan.rules.clone().unwrap_or_else(default_rules);
+    let findings = scan(code_blocks, &rules)?;
+    for finding in &findings {
+        eprintln!(
+            "{} line {}: suspicious {} code ({}): {}",
+            "warning:".yellow().bold(),
+            finding.line,
+            finding.language,
+            finding.rule,
+            finding.text
+        );
+    }
+
+    if !findings.is_empty()
+        && config.scan.action == ScanAction::Block
+        && !config.scan.allow_dangerous
+    {
+        anyhow::bail!(
+            "Refusing to run {} suspicious line(s); review them and rerun with --allow-dangerous",
+            findings.len()
+        );
+    }
+    Ok(())
+}
+
+/// Rebuild `content` with the output of each run attached to its block. `quiet`
+/// blocks get no output section; when one fails, its output goes to stderr instead.
+/// `output` as a terminal would leave it: text after a carriage return
+/// overwrites the start of its line, and `ESC[K` / `ESC[2K` erase it, so progress
+/// bars redrawn in place keep only their final state.
+fn collapse_progress(output: &str) -> String {
+    if !output.contains('\r') {
+        return output.to_string();
+    }
+    output
+        .split('\n')
+        .map(|line| {
+            let mut screen: Vec<char> = Vec::new();
+            let mut column = 0;
+            let mut rest = line;
+            while let Some(c) = rest.chars().next() {
+                if let Some(after) = rest.strip_prefix("\x1b[2K") {
+                    screen.clear();
+                    rest = after;
+                    continue;
+                }
+                if let Some(after) = rest
+                    .strip_prefix("\x1b[K")
+                    .or_else(|| rest.strip_prefix("\x1b[0K"))
+                {
+                    screen.truncate(column);
+                    rest = after;
+                    continue;
+                }
+                rest = &rest[c.len_utf8()..];
+                if c == '\r' {
+                    column = 0;
+                    continue;
+                }
+                match screen.get_mut(column) {
+                    Some(cell) => *cell = c,
+                    None => screen.push(c),
+                }
+                column += 1;
             }
-            result.push_str("```\n**Output**\n```\n");
+            screen.into_iter().collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-            let output_text = output.trim_end_matches('\n');
-            result.push_str(output_text);
-            result.push_str("\n```");
+/// Point the errors of failed runs at lines of the document rather than of the
+/// files the blocks ran from.
+fn remap_error_lines(code_blocks: &[CodeBlock], runs: &mut [BlockRun]) {
+    for run in runs.iter_mut().filter(|run| run.exit_code != Some(0)) {
+        let block = &code_blocks[run.index];
+        // Racket blocks without `#lang` run with one added
+        let prelude = usize::from(
+            block.language.eq_ignore_ascii_case("racket")
+                && !block.code.trim_start().starts_with("#lang"),
+        );
+        run.output = summary::remap(&run.output, block.line + 1, prelude);
+        if let Some(stderr) = &mut run.stderr {
+            *stderr = summary::remap(stderr, block.line + 1, prelude);
         }
+    }
+}
 
-        last_pos = block.end_pos;
+fn attach_outputs(
+    content: &str,
+    code_blocks: &[CodeBlock],
+    runs: Vec<BlockRun>,
+    recordings: &[Option<String>],
+    config: &Config,
+) -> String {
+    let mut outputs: Vec<Option<String>> = vec![None; code_blocks.len()];
+    let mut exit_codes = vec![None; code_blocks.len()];
+    for run in runs {
+        let block = &code_blocks[run.index];
+        exit_codes[run.index] = run.exit_code;
+        if !block.quiet() {
+            let output = match block.attrs.get("progress") {
+                Some("frames") => run.output.replace("\r\n", "\n").replace('\r', "\n"),
+                _ => collapse_progress(&run.output),
+            };
+            let mut output = filters::apply(&output, config.output_filters_for(&block.language));
+            if config.error_summary && run.exit_code != Some(0) {
+                let errors = run.stderr.as_deref().filter(|stderr| !stderr.is_empty());
+                if let Some(summary) = summary::summarize(errors.unwrap_or(&output)) {
+                    output = format!("{}\n\n{}", summary, output);
+                }
+            }
+            outputs[run.index] = Some(output);
+        } else if !run.success {
+            eprintln!(
+                "{} {}: quiet block failed:\n{}",
+                "error:".red().bold(),
+                block.label(),
+                run.output.trim_end()
+            );
+        }
     }
 
-    // Add any remaining content
-    result.push_str(&content[last_pos..]);
+    render_blocks(
+        content,
+        code_blocks,
+        &outputs,
+        &exit_codes,
+        recordings,
+        config,
+    )
+}
 
-    Ok(result)
+/// Process markdown with blocks run one at a time, in document order, by
+/// `executor`. Run durations are not measured, as wasm32 has no clock.
+pub async fn process_with<E: Executor>(
+    content: &str,
+    config: &Config,
+    executor: &E,
+) -> Result<(String, Report)> {
+    let (content, code_blocks, mut report) = prepare(content, config)?;
+
+    let mut runs = Vec::new();
+    for index in run_order(&code_blocks)? {
+        let block = &code_blocks[index];
+        let result = executor.execute(block).await?;
+        runs.push(BlockRun {
+            index,
+            success: passed(block, &result),
+            output: result.output,
+            exit_code: result.exit_code,
+            cached: false,
+            duration: Duration::ZERO,
+            stderr: result.stderr,
+        });
+    }
+
+    remap_error_lines(&code_blocks, &mut runs);
+    report.record(false, &runs);
+    Ok((
+        attach_outputs(&content, &code_blocks, runs, &[], config),
+        report,
+    ))
+}
+
+/// Process markdown by executing code blocks and attaching outputs.
+/// Blocks run in parallel only when the config opts in via `parallel`.
+#[cfg(feature = "native")]
+pub async fn process_markdown(
+    content: &str,
+    config: &Config,
+    context: &mut RunContext,
+) -> Result<(String, Report)> {
+    // Blocks left out by the selection keep the outputs they have
+    let kept = if context.select.is_empty() {
+        Vec::new()
+    } else {
+        recorded_outputs(content, config)
+    };
+    let (content, mut code_blocks, mut report) = prepare(content, config)?;
+    if code_blocks.is_empty() {
+        return Ok((content, report));
+    }
+    if !context.select.is_empty() {
+        for (block, entry) in code_blocks.iter_mut().zip(&mut report.blocks) {
+            if !block.skip && !context.select.iter().any(|s| s.matches(block)) {
+                block.skip = true;
+                block.skip_reason = Some(SkipReason::NotSelected);
+                entry.skipped = true;
+            }
+        }
+    }
+
+    let options = document_options(&content)?;
+    context.document_packages = options.nix;
+
+    // One container instance per document, so its blocks share a filesystem
+    let image = match options.image {
+        Some(image) if !context.untrusted => Some(image),
+        _ => context.image.clone(),
+    };
+    if let Some(image) = image {
+        context.use_workspace()?;
+        let workspace = context
+            .workspace
+            .as_ref()
+            .map(|w| w.path())
+            .unwrap_or(Path::new("."));
+        context.container = Some(Container::start(
+            &context.container_runtime,
+            &image,
+            workspace,
+        )?);
+    }
+    let result = run_blocks(&content, &code_blocks, &kept, config, context, &mut report).await;
+    context.container = None;
+    context.document_packages.clear();
+    Ok((result?, report))
 }
 
-/// Parallel processing for multiple code blocks
-async fn process_markdown_parallel(
+#[cfg(feature = "native")]
+async fn run_blocks(
     content: &str,
     code_blocks: &[CodeBlock],
-    languages: &Languages,
+    kept: &[Option<(String, Option<i32>)>],
+    config: &Config,
+    context: &mut RunContext,
+    report: &mut Report,
 ) -> Result<String> {
-    use futures::future::join_all;
+    // Count runnable (non-skipped) blocks
+    let runnable_count = code_blocks.iter().filter(|b| !b.skip).count();
 
-    // Execute all code blocks in parallel
-    let tasks: Vec<_> = code_blocks
-        .iter()
-        .enumerate()
-        .filter(|(_, b)| !b.skip)
-        .map(|(i, block)| {
-            let languages_clone = languages.clone();
-            let block_clone = block.clone();
-            async move {
-                let timeout = if block_clone.code.len() > 1000 { 10 } else { 5 };
-                let output = run_code(
-                    &block_clone.language,
-                    &block_clone.code,
-                    &languages_clone,
-                    timeout,
-                )
-                .await?;
-                Ok::<(usize, String), anyhow::Error>((i, output))
+    // Decide execution strategy; ordered runs always follow document order, as do
+    // documents with sessions, whose blocks build on the ones before them
+    let parallel = !config.ordered
+        && runnable_count > 1
+        && !code_blocks
+            .iter()
+            .any(|b| !b.skip && context.session(b).is_some())
+        && match config.parallel {
+            ParallelMode::Off => false,
+            ParallelMode::On => true,
+            ParallelMode::Auto => runnable_count >= 4,
+        };
+
+    if code_blocks.iter().any(|b| context.cache(b).is_some()) {
+        for block in code_blocks.iter().filter(|b| !b.skip) {
+            if !context.versions.contains_key(&block.language) {
+                let version = context.languages.version(&block.language).await;
+                context
+                    .versions
+                    .insert(block.language.clone(), version.unwrap_or_default());
             }
+        }
+    }
+    let runs = if parallel {
+        execute_parallel(code_blocks, context).await
+    } else {
+        execute_sequential(code_blocks, context).await
+    };
+    context.sessions.close().await;
+    let mut runs = runs?;
+
+    remap_error_lines(code_blocks, &mut runs);
+    report.record(parallel, &runs);
+
+    // Remember how long each block took to schedule the next parallel run
+    for run in runs.iter().filter(|run| !run.cached) {
+        if let Some(key) = context.timing_key(&code_blocks[run.index]) {
+            context.timings.record(key, run.duration);
+        }
+    }
+    if let Err(e) = context.timings.save() {
+        eprintln!("{} {:#}", "warning:".yellow().bold(), e);
+    }
+
+    let mut runs = match &context.expect_files {
+        Some(files) => files.apply(code_blocks, runs, report)?,
+        None => runs,
+    };
+    for (index, block) in code_blocks.iter().enumerate() {
+        if block.skip_reason != Some(SkipReason::NotSelected) {
+            continue;
+        }
+        if let Some((output, exit_code)) = kept.get(index).cloned().flatten() {
+            runs.push(BlockRun {
+                index,
+                output,
+                exit_code,
+                success: true,
+                cached: false,
+                duration: Duration::ZERO,
+                stderr: None,
+            });
+        }
+    }
+    // Links to the recordings of blocks recorded now or, when cached, before
+    let recordings: Vec<Option<String>> = code_blocks
+        .iter()
+        .map(|block| {
+            context
+                .recording(block)
+                .filter(|cast| cast.exists())
+                .map(|_| {
+                    let dir = config.record_dir.trim_end_matches('/');
+                    format!("{}/{}", dir, recording_file(block))
+                })
         })
         .collect();
+    Ok(attach_outputs(
+        content,
+        code_blocks,
+        runs,
+        &recordings,
+        config,
+    ))
+}
 
-    let results: Result<Vec<_>> = join_all(tasks).await.into_iter().collect();
-    let results = results?;
+#[cfg(feature = "native")]
+impl ExpectFiles {
+    /// Write or compare the outputs of `expect-file=` blocks, returning the runs
+    /// whose output still goes into the document.
+    fn apply(
+        &self,
+        code_blocks: &[CodeBlock],
+        runs: Vec<BlockRun>,
+        report: &mut Report,
+    ) -> Result<Vec<BlockRun>> {
+        let mut inline = Vec::new();
+        for run in runs {
+            let block = &code_blocks[run.index];
+            let Some(name) = block.attrs.get("expect-file") else {
+                inline.push(run);
+                continue;
+            };
+            let path = self.dir.join(name);
+            let output = format!("{}\n", run.output.trim_end_matches('\n'));
+            let existing = std::fs::read_to_string(&path).ok();
 
-    // Sort results by original order and extract outputs
-    let mut sorted_results = results;
-    sorted_results.sort_by_key(|(index, _)| *index);
-    let outputs: Vec<String> = sorted_results
-        .into_iter()
-        .map(|(_, output)| output)
+            if self.update {
+                if existing.as_deref() != Some(output.as_str()) {
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::write(&path, &output)
+                        .with_context(|| format!("Failed to write {}", path.display()))?;
+                }
+            } else {
+                let matched = existing.as_deref() == Some(output.as_str());
+                if !matched {
+                    eprintln!(
+                        "{} {}: output differs from {}",
+                        "warning:".yellow().bold(),
+                        block.label(),
+                        path.display()
+                    );
+                }
+                report.blocks[run.index].expect_matched = Some(matched);
+            }
+        }
+        Ok(inline)
+    }
+}
+
+/// Run a single block given by its fence info string (language and attributes)
+/// and code, outside of any document.
+#[cfg(feature = "native")]
+pub async fn run_snippet(
+    info: &str,
+    code: &str,
+    config: &Config,
+    context: &RunContext,
+) -> Result<BlockRun> {
+    let content = format!("```{}\n{}\n```\n", info.trim(), code.trim_end_matches('\n'));
+    let Some(block) = find_all_code_blocks(&content, config).into_iter().next() else {
+        anyhow::bail!("Invalid block language '{}'", info.trim());
+    };
+    if let Some(reason) = block.skip_reason {
+        anyhow::bail!("Block not run: {}", reason);
+    }
+
+    execute_block(0, &block, context).await
+}
+
+/// Sequential execution in document order, with consumers after their producers
+#[cfg(feature = "native")]
+async fn execute_sequential(
+    code_blocks: &[CodeBlock],
+    context: &RunContext,
+) -> Result<Vec<BlockRun>> {
+    let mut runs = Vec::new();
+
+    for i in run_order(code_blocks)? {
+        runs.push(execute_block(i, &code_blocks[i], context).await?);
+    }
+
+    Ok(runs)
+}
+
+/// Parallel execution for multiple code blocks; runs are returned in completion order.
+/// Blocks that took longest last time start first so they don't hold up the end of
+/// the run, with blocks that have no recorded timing treated as the slowest. A block
+/// consuming files starts only once the blocks producing them have finished.
+#[cfg(feature = "native")]
+async fn execute_parallel(
+    code_blocks: &[CodeBlock],
+    context: &RunContext,
+) -> Result<Vec<BlockRun>> {
+    use futures::stream::{FuturesUnordered, StreamExt};
+
+    // Rejects dependency cycles before anything starts
+    run_order(code_blocks)?;
+    let dependencies = dependencies(code_blocks);
+
+    let mut queue: Vec<usize> = (0..code_blocks.len())
+        .filter(|&i| !code_blocks[i].skip)
         .collect();
+    queue.sort_by_key(|&i| {
+        let previous = context
+            .timing_key(&code_blocks[i])
+            .and_then(|key| context.timings.get(&key));
+        std::cmp::Reverse(previous.unwrap_or(Duration::MAX))
+    });
 
-    // Reconstruct content with outputs
-    let mut result = String::with_capacity(content.len() * 2);
-    let mut last_pos = 0;
+    let progress = Progress::new(queue.len());
+    let start = |i: usize| {
+        let progress = progress.as_ref();
+        let block = &code_blocks[i];
+        async move {
+            if let Some(progress) = progress {
+                progress.start(i, block.label());
+            }
+            let run = execute_block(i, block, context).await;
+            if let Some(progress) = progress {
+                progress.finish(i);
+            }
+            run
+        }
+    };
 
-    for (i, block) in code_blocks.iter().enumerate() {
-        // Add content before this block
-        result.push_str(&content[last_pos..block.start_pos]);
-        if block.skip {
-            // Reproduce original skipped block
-            result.push_str("```");
-            if !block.fence_info.is_empty() { result.push_str(&block.fence_info); } else { result.push_str(&block.language); }
-            result.push('\n');
-            result.push_str(&block.code);
-            if !block.code.ends_with('\n') {
-                result.push('\n');
+    let collect = async {
+        let mut runs = Vec::new();
+        let mut finished = vec![false; code_blocks.len()];
+        let mut tasks = FuturesUnordered::new();
+        loop {
+            while tasks.len() < context.max_parallel.max(1) {
+                let Some(next) = queue
+                    .iter()
+                    .position(|&i| dependencies[i].iter().all(|&j| finished[j]))
+                else {
+                    break;
+                };
+                tasks.push(start(queue.remove(next)));
             }
-            result.push_str("```");
-        } else {
-            // Determine the index in outputs vector based on non-skipped blocks
-            // We built outputs only for non-skipped blocks, preserve order.
-            // Map i -> position among non-skipped indices
-            let non_skipped_index = code_blocks.iter().take(i + 1).filter(|b| !b.skip).count() - 1;
-            result.push_str("```");
-            if !block.fence_info.is_empty() { result.push_str(&block.fence_info); } else { result.push_str(&block.language); }
-            result.push('\n');
-            result.push_str(&block.code);
-            if !block.code.ends_with('\n') {
-                result.push('\n');
+            let Some(run) = tasks.next().await else {
+                break;
+            };
+            let run = run?;
+            finished[run.index] = true;
+            runs.push(run);
+        }
+        Ok(runs)
+    };
+    match &progress {
+        Some(progress) => progress.run_until(collect).await,
+        None => collect.await,
+    }
+}
+
+/// Environment of `stable` blocks and of every block with `--hermetic`, so sort
+/// orders, column layouts and terminal handling don't depend on the machine.
+#[cfg(feature = "native")]
+const STABLE_ENV: [(&str, &str); 3] = [("LC_ALL", "C"), ("COLUMNS", "80"), ("TERM", "dumb")];
+
+#[cfg(feature = "native")]
+async fn execute_block(index: usize, block: &CodeBlock, context: &RunContext) -> Result<BlockRun> {
+    let started = Instant::now();
+    let nix = context.nix_prefix(&block.language)?;
+
+    // Inside a container or nix shell, the image or packages determine the interpreter
+    let nix_packages = nix.as_ref().map(|prefix| prefix.join(" "));
+    let matrix = block.attrs.get("matrix");
+    let overridden = match block_languages(block, &context.languages) {
+        Ok(overridden) => overridden,
+        Err(message) => {
+            return Ok(BlockRun {
+                index,
+                output: RunResult::error(message).output,
+                exit_code: None,
+                success: false,
+                cached: false,
+                duration: started.elapsed(),
+                stderr: None,
+            })
+        }
+    };
+    let languages = overridden.as_ref().unwrap_or(&context.languages);
+    // Any attribute may change what the block prints, apart from the one opting into the cache
+    let mut template = languages
+        .template(&block.language)
+        .unwrap_or("")
+        .to_string();
+    for flag in &block.attrs.flags {
+        template.push_str(&format!(" {}", flag));
+    }
+    for (name, value) in &block.attrs.values {
+        if name == "cache" {
+            continue;
+        }
+        template.push_str(&format!(" {}={}", name, value));
+    }
+    // So do the input it reads, wherever it comes from, and `--hermetic`
+    let base_dir = (!context.untrusted).then_some(context.base_dir.as_path());
+    let stdin = block_stdin(block, base_dir);
+    if let Ok(Some(text)) = &stdin {
+        template.push_str(&format!(" stdin:{}", text));
+    }
+    let stable = block.attrs.bool("stable").unwrap_or(context.hermetic);
+    if stable {
+        template.push_str(" stable");
+    }
+    let attr_env = block.attrs.env();
+    // The stable environment comes first, so variables set explicitly override it
+    let stable_env = STABLE_ENV
+        .iter()
+        .filter(|_| stable)
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+    let args = block.attrs.words("args");
+    let key = cache_key(
+        &block.language,
+        &block.code,
+        &template,
+        match (&context.container, &nix_packages) {
+            (Some(container), _) => &container.image,
+            (None, Some(packages)) => packages,
+            (None, None) => context
+                .versions
+                .get(&block.language)
+                .map(String::as_str)
+                .unwrap_or(""),
+        },
+    );
+    // A session block's output depends on the blocks before it, not just its code
+    let session = context.session(block);
+    let rerun = session.is_some() || context.rerun.iter().any(|selector| selector.matches(block));
+    if let Some(cache) = context.cache(block).filter(|_| !rerun) {
+        if let Some(entry) = cache.get(&key).await {
+            // Only successes are cached, which an `xfail` block shouldn't have
+            return Ok(BlockRun {
+                index,
+                success: entry.success != block.attrs.flag("xfail")
+                    && output_expected(block, &entry.output),
+                output: entry.output,
+                exit_code: Some(0),
+                cached: true,
+                duration: started.elapsed(),
+                stderr: None,
+            });
+        }
+    }
+
+    // Run the code snippet with optimized timeout unless the block or config sets one,
+    // or the block is exempt from the config's limit
+    let exempt = block
+        .attrs
+        .get("name")
+        .is_some_and(|name| context.exempt.iter().any(|exempt| exempt == name));
+    let timeout = block
+        .attrs
+        .duration("timeout")
+        .or(exempt.then_some(Duration::MAX))
+        .or(context.timeout)
+        .unwrap_or_else(|| {
+            // Shorter timeout for small code
+            Duration::from_secs(if block.code.len() > 1000 { 10 } else { 5 })
+        });
+    // `interactive` is shorthand for `capture=pty`, which recording also needs
+    let interactive = block.attrs.flag("interactive");
+    let cast = context.recording(block);
+    let capture = match block.attrs.get("capture") {
+        _ if interactive || cast.is_some() => CaptureMode::Pty,
+        value => value.and_then(CaptureMode::parse).unwrap_or_default(),
+    };
+    let cwd = context
+        .workspace
+        .as_ref()
+        .map(|w| w.path().to_path_buf())
+        .or_else(|| context.cwd.clone());
+    let env: Vec<(String, String)> = stable_env
+        .chain(context.env.iter().cloned())
+        .chain(attr_env)
+        .collect();
+    // Container blocks keep their scratch files in the workspace it mounts
+    let (prefix, stop, scratch_dir) = match (&context.container, &cwd) {
+        (Some(container), Some(cwd)) => {
+            let exec = container.exec(cwd, &env, capture == CaptureMode::Pty);
+            (exec.prefix, exec.stop, Some(cwd.clone()))
+        }
+        _ => (nix.unwrap_or_default(), Vec::new(), None),
+    };
+    let stdin = match stdin {
+        Ok(stdin) => stdin,
+        Err(message) => {
+            return Ok(BlockRun {
+                index,
+                output: RunResult::error(message).output,
+                exit_code: None,
+                success: false,
+                cached: false,
+                duration: started.elapsed(),
+                stderr: None,
+            })
+        }
+    };
+    // Stop before blocks fail for want of room for their files and build artifacts
+    if let Some(minimum) = context.min_free_space {
+        let temp = scratch_dir.clone().unwrap_or_else(std::env::temp_dir);
+        for dir in std::iter::once(&temp).chain(&cwd) {
+            disk::ensure_free(dir, minimum)?;
+        }
+    }
+    let options = RunOptions {
+        timeout,
+        grace: context.timeout_grace,
+        capture,
+        cwd,
+        env,
+        clear_env: context.clear_env,
+        priority: context.priority,
+        cpu_affinity: context.cpu_affinity.clone(),
+        keep_temp: context.keep_temp,
+        prefix,
+        stop,
+        scratch_dir,
+        stdin,
+        args,
+        cast,
+        trace: context
+            .trace
+            .as_ref()
+            .map(|dir| dir.join(format!("line-{}-{}", block.line, block.language))),
+    };
+    let result = match (matrix, session) {
+        _ if interactive && block.attrs.get("capture").is_some_and(|c| c != "pty") => {
+            RunResult::error("interactive cannot be combined with capture=merged or capture=split")
+        }
+        (Some(_), _) | (_, Some(_)) if overridden.is_some() => {
+            RunResult::error("cmd= and with= cannot be combined with matrix= or session=")
+        }
+        (Some(_), Some(_)) => RunResult::error("session= cannot be combined with matrix="),
+        (None, Some(_)) if options.stdin.is_some() => {
+            RunResult::error("session= cannot be combined with stdin= or stdin-file=")
+        }
+        (None, Some(_)) if !matches!(capture, CaptureMode::Auto | CaptureMode::Merged) => {
+            RunResult::error("session= blocks always capture stdout and stderr together")
+        }
+        (None, Some(session)) => {
+            context
+                .sessions
+                .run(session, &block.language, &block.code, languages, &options)
+                .await?
+        }
+        (Some(matrix), None) => run_matrix(block, matrix, context, &options).await?,
+        (None, None) => run_code(&block.language, &block.code, languages, &options).await?,
+    };
+
+    // Only successful runs are cached; failures may be transient
+    if let Some(cache) = context
+        .cache(block)
+        .filter(|_| session.is_none() && result.success())
+    {
+        let entry = CacheEntry {
+            output: result.output.clone(),
+            success: true,
+        };
+        if let Err(e) = cache.put(&key, &entry).await {
+            eprintln!("{} {:#}", "warning:".yellow().bold(), e);
+        }
+    }
+
+    Ok(BlockRun {
+        index,
+        success: passed(block, &result),
+        output: result.output,
+        exit_code: result.exit_code,
+        cached: false,
+        duration: started.elapsed(),
+        stderr: result.stderr,
+    })
+}
+
+/// Input for `block` from its `stdin=` text or the file named by `stdin-file=`,
+/// which must lie inside `base_dir`. Without a `base_dir` no file may be read.
+#[cfg(feature = "native")]
+pub(crate) fn block_stdin(
+    block: &CodeBlock,
+    base_dir: Option<&Path>,
+) -> Result<Option<String>, String> {
+    match (block.attrs.get("stdin"), block.attrs.get("stdin-file")) {
+        (Some(_), Some(_)) => Err("give either stdin= or stdin-file=, not both".to_string()),
+        (Some(text), None) => Ok(Some(text.to_string())),
+        (None, Some(name)) => {
+            let Some(base_dir) = base_dir else {
+                return Err("stdin-file= can't be used in documents sent to the server".to_string());
+            };
+            let path = base_dir.join(name);
+            let failed =
+                |e: std::io::Error| format!("Failed to read stdin-file {}: {}", path.display(), e);
+            // Symlinks and `..` are resolved before checking where the file is
+            let resolved = path.canonicalize().map_err(failed)?;
+            let root = base_dir.canonicalize().map_err(failed)?;
+            if !resolved.starts_with(&root) {
+                return Err(format!(
+                    "stdin-file {} is outside the document directory {}",
+                    name,
+                    base_dir.display()
+                ));
             }
-            result.push_str("```\n**Output**\n```\n");
-            let output_text = outputs[non_skipped_index].trim_end_matches('\n');
-            result.push_str(output_text);
-            result.push_str("\n```");
+            std::fs::read_to_string(&resolved).map(Some).map_err(failed)
         }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Whether `block` replaces its language's command with `cmd=` or `with=`.
+#[cfg(feature = "native")]
+fn overrides_command(block: &CodeBlock) -> bool {
+    block.attrs.get("cmd").is_some() || block.attrs.get("with").is_some()
+}
 
-        last_pos = block.end_pos;
+/// The languages `block` runs with when it overrides its command: a copy in which
+/// its language runs the `cmd=` command, or the `with=` program in place of its
+/// interpreter. A versioned fence such as `python3.12` works like `with=python3.12`.
+#[cfg(feature = "native")]
+pub(crate) fn block_languages(
+    block: &CodeBlock,
+    languages: &Languages,
+) -> Result<Option<Languages>, String> {
+    match (block.attrs.get("cmd"), block.attrs.get("with")) {
+        (Some(_), Some(_)) => Err("give either cmd= or with=, not both".to_string()),
+        (Some(command), None) => Ok(Some(languages.with_command(&block.language, command))),
+        (None, Some(_)) if languages.interpreter(&block.language).is_none() => Err(format!(
+            "with= needs a configured command for '{}' to change; use cmd= instead",
+            block.language
+        )),
+        (None, Some(program)) => Ok(Some(languages.with_interpreter(&block.language, program))),
+        (None, None) => Ok(languages
+            .versioned(&block.language)
+            .filter(|language| languages.interpreter(language).is_some())
+            .map(|_| languages.with_interpreter(&block.language, &block.language))),
     }
+}
 
-    // Add any remaining content
-    result.push_str(&content[last_pos..]);
+/// Run `block` once for each comma-separated variant of `matrix`, showing each
+/// output under a `[variant]` heading. A variant naming a configured language runs
+/// the code as that language; any other replaces the interpreter, e.g. `python3.11`.
+/// The combined result fails with the first variant that did.
+#[cfg(feature = "native")]
+async fn run_matrix(
+    block: &CodeBlock,
+    matrix: &str,
+    context: &RunContext,
+    options: &RunOptions,
+) -> Result<RunResult> {
+    let mut outputs = Vec::new();
+    let mut exit_code = Some(0);
+    for variant in matrix.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+        let options = RunOptions {
+            trace: options.trace.as_ref().map(|dir| dir.join(variant)),
+            ..options.clone()
+        };
+        let result = if context.languages.template(variant).is_some() {
+            run_code(variant, &block.code, &context.languages, &options).await?
+        } else {
+            let languages = context.languages.with_interpreter(&block.language, variant);
+            run_code(&block.language, &block.code, &languages, &options).await?
+        };
+        outputs.push(format!("[{}]\n{}", variant, result.output.trim_end_matches('\n')));
+        if exit_code == Some(0) {
+            exit_code = result.exit_code;
+        }
+    }
 
-    Ok(result)
+    Ok(RunResult {
+        output: outputs.join("\n"),
+        exit_code,
+        stderr: None,
+    })
 }
 
-pub fn clear_outputs(content: &str) -> Result<String> {
-    // Use simple string replacements for speed - much faster than line parsing
-    let mut result = content.to_string();
+/// Whether `block` did what it should, warning when an `xfail` block succeeded.
+fn passed(block: &CodeBlock, result: &RunResult) -> bool {
+    let xfail = block.attrs.flag("xfail");
+    if xfail && result.success() {
+        eprintln!(
+            "{} {}: expected to fail but succeeded",
+            "error:".red().bold(),
+            block.label()
+        );
+    }
+    result.passed(xfail) && output_expected(block, &result.output)
+}
 
-    // Remove output blocks - pattern: code block + output block
-    // This is a simplified approach that should be very fast
-    loop {
-        let original_len = result.len();
+/// Whether `output` contains the text of the block's `expect=` attribute, if it
+/// has one, reporting the mismatch when it doesn't.
+fn output_expected(block: &CodeBlock, output: &str) -> bool {
+    let Some(expected) = block.attrs.get("expect") else {
+        return true;
+    };
+    if output.contains(expected) {
+        return true;
+    }
+    eprintln!(
+        "{} {}: output does not contain {:?}",
+        "error:".red().bold(),
+        block.label(),
+        expected
+    );
+    false
+}
 
-        // Find and remove pattern: ```\n**Output**\n```\n...\n```
-        if let Some(output_start) = result.find("\n**Output**\n```") {
-            // Work backwards to find the code block end
-            let mut code_end = output_start;
-            while code_end > 0 && !result[..code_end].ends_with("```") {
-                code_end -= 1;
-            }
+/// Rebuild the document, attaching an output section after every block that ran.
+pub(crate) fn render_blocks(
+    content: &str,
+    code_blocks: &[CodeBlock],
+    outputs: &[Option<String>],
+    exit_codes: &[Option<i32>],
+    recordings: &[Option<String>],
+    config: &Config,
+) -> String {
+    let header = &config.output_header;
+    // Pre-allocate result string with estimated capacity
+    let mut result = String::with_capacity(content.len() * 2);
+    let mut last_pos = 0;
+    let mut numbered = 0;
+    // Names and outputs of the named blocks shown so far, for `dedup_outputs`
+    let mut shown: Vec<(&str, &str)> = Vec::new();
 
-            if code_end > 0 {
-                // Find the end of the output block
-                let search_start = output_start + 13; // Skip "\n**Output**\n```"
-                if let Some(output_end_rel) = result[search_start..].find("\n```") {
-                    let output_end = search_start + output_end_rel + 4; // Include "\n```"
+    for (index, (block, output)) in code_blocks.iter().zip(outputs).enumerate() {
+        // Add content before this block (using efficient slicing)
+        result.push_str(&content[last_pos..block.start_pos]);
 
-                    // Remove the output block (keep the code block)
-                    result = format!("{}{}", &result[..output_start], &result[output_end..]);
-                    continue;
+        // Reproduce the original block, with the line breaks it was written with
+        let mut text = String::new();
+        let hidden = block.attrs.flag("hide") || block.attrs.flag("hide-code");
+        if hidden {
+            text.push_str(HIDE_OPEN);
+            text.push('\n');
+        }
+        text.push_str(&block.fence);
+        if !block.fence_info.is_empty() {
+            text.push_str(&block.fence_info);
+        } else if !block.detected {
+            text.push_str(&block.language);
+        }
+        text.push('\n');
+        text.push_str(&block.code);
+        if !block.code.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(&block.fence);
+        if hidden {
+            text.push('\n');
+            text.push_str(HIDE_CLOSE);
+        }
+
+        // `output-lang=json` gives output fences a language for syntax highlighting
+        let output_fence = format!("```{}", block.attrs.get("output-lang").unwrap_or(""));
+        if block.attrs.flag("per-os") {
+            // This platform's output joins those recorded elsewhere, in a stable order
+            let mut sections = block.os_outputs.clone();
+            if let Some(output) = output {
+                sections.push((std::env::consts::OS.to_string(), output.clone()));
+            }
+            sections.sort();
+            for (os, output) in sections {
+                text.push_str(&format!(
+                    "\n{}\n{}\n",
+                    section_header(header, Some(&os)),
+                    output_fence
+                ));
+                text.push_str(output.trim_end_matches('\n'));
+                text.push_str("\n```");
+            }
+        } else if let Some(output) = output {
+            numbered += 1;
+            let section = match (config.output_labels, block.attrs.get("name")) {
+                (OutputLabels::Off, _) => section_header(header, None),
+                (OutputLabels::Name, Some(name)) => {
+                    labeled_section_header(header, &format!(": {}", name))
+                }
+                _ => labeled_section_header(header, &format!(" {}", numbered)),
+            };
+            let section = match exit_codes.get(index).copied().flatten() {
+                Some(code) if config.exit_codes || block.attrs.flag("exit-code") => {
+                    with_exit_code(&section, code)
                 }
+                _ => section,
+            };
+            let section = match block.attrs.get("id") {
+                Some(id) => format!("{}{}", output_anchor(id), section),
+                None => section,
+            };
+            let output_text = output.trim_end_matches('\n');
+            let same_as = shown
+                .iter()
+                .filter(|_| config.dedup_outputs && !output_text.is_empty())
+                .find(|(_, shown)| *shown == output_text)
+                .map(|(name, _)| *name);
+            if let Some(name) = block.attrs.get("name") {
+                shown.push((name, output_text));
             }
+            if let Some(name) = same_as {
+                text.push_str(&format!("\n{}\n{}", section, same_output_line(name)));
+            } else if matches!(block.attrs.get("output"), Some("raw" | "markdown")) {
+                text.push_str(&format!("\n{}\n{}\n", section, RAW_OPEN));
+                text.push_str(output_text);
+                text.push_str(&format!("\n{}", RAW_CLOSE));
+            } else {
+                text.push_str(&format!("\n{}\n{}\n", section, output_fence));
+                text.push_str(output_text);
+                text.push_str("\n```");
+            }
+            if let Some(Some(path)) = recordings.get(index) {
+                text.push('\n');
+                text.push_str(&recording_link(path));
+            }
+        }
+        if block.indent > 0 {
+            text = indent_lines(&text, block.indent);
         }
+        if block.newline != "\n" {
+            text = text.replace("\r\n", "\n").replace('\n', block.newline);
+        }
+        result.push_str(&text);
 
-        // Also handle pattern without leading newline: **Output**\n```
-        if let Some(output_start) = result.find("**Output**\n```") {
-            let search_start = output_start + 12; // Skip "**Output**\n```"
-            if let Some(output_end_rel) = result[search_start..].find("\n```") {
-                let output_end = search_start + output_end_rel + 4; // Include "\n```"
-                result = format!("{}{}", &result[..output_start], &result[output_end..]);
-                continue;
-            }
+        // Keep the line break that ended the block
+        let before = &content[..block.outputs_end];
+        if before.ends_with("\r\n") {
+            result.push_str("\r\n");
+        } else if before.ends_with('\n') {
+            result.push('\n');
         }
+        last_pos = block.outputs_end;
+    }
+
+    // Add any remaining content
+    result.push_str(&content[last_pos..]);
+
+    result
+}
+
+/// Split content into byte ranges, flagging whether each range is processed (`true`)
+/// or sits between ignore markers (`false`). Marker lines belong to the ignored range.
+fn split_regions(content: &str, markers: &IgnoreMarkers) -> Vec<(Range<usize>, bool)> {
+    let mut regions = Vec::new();
+    let mut region_start = 0;
+    let mut enabled = true;
+    let mut pos = 0;
 
-        // If no changes made, we're done
-        if result.len() == original_len {
-            break;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if enabled && trimmed == markers.off {
+            regions.push((region_start..pos, true));
+            region_start = pos;
+            enabled = false;
+        } else if !enabled && trimmed == markers.on {
+            regions.push((region_start..pos + line.len(), false));
+            region_start = pos + line.len();
+            enabled = true;
         }
+        pos += line.len();
     }
+    regions.push((region_start..content.len(), enabled));
 
-    // Clean up any consecutive fences (much faster than regex)
-    result = result.replace("``````", "```\n\n```");
-    result = result.replace("```\n```", "```\n\n```");
+    regions
+}
 
+pub fn clear_outputs(content: &str, config: &Config) -> Result<String> {
+    let mut result = String::with_capacity(content.len());
+    for (range, enabled) in split_regions(content, &config.ignore_markers) {
+        if enabled {
+            clear_region(&content[range], &config.output_header, &mut result);
+        } else {
+            result.push_str(&content[range]);
+        }
+    }
     Ok(result)
 }
+
+/// Append `content` to `result` without its output sections: a header line written
+/// under any setting (or this platform's `per-os` header) directly followed by a
+/// closed backtick fence, by raw output up to its closing marker, or by a reference
+/// to another block's output. Unclosed sections are kept as they are.
+///
+/// This is a single forward pass. A search for a closing fence that fails is
+/// remembered, so a later section that could only close where it failed is not
+/// searched for again and adversarial input stays linear.
+fn clear_region(content: &str, header: &str, result: &mut String) {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let is_header = |line: &str| {
+        parse_section_header(line, header)
+            .is_some_and(|os| os.is_none_or(|os| os == std::env::consts::OS))
+    };
+    let is_fence = |line: &str| line.trim_start_matches(' ').starts_with("```");
+
+    // Fence length of the narrowest search that found no close, and whether a
+    // search for the end of raw output found none
+    let mut unclosed: Option<usize> = None;
+    let mut raw_unclosed = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let next = lines.get(i + 1).filter(|_| is_header(line));
+        let raw = next.is_some_and(|next| next.trim() == RAW_OPEN);
+        let same = next.is_some_and(|next| same_output_name(next).is_some());
+        let base = line.len() - line.trim_start_matches(' ').len();
+        let open = next
+            .and_then(|next| parse_fence_open_within(next.trim_end(), base))
+            .filter(|(_, fence, _)| fence.starts_with('`'));
+        let close = if same {
+            Some(i + 1)
+        } else if raw_unclosed && raw {
+            None
+        } else if raw {
+            let close = (i + 2..lines.len()).find(|&j| lines[j].trim() == RAW_CLOSE);
+            raw_unclosed = close.is_none();
+            close
+        } else {
+            open.and_then(|(_, fence, _)| {
+                if unclosed.is_some_and(|len| fence.len() >= len) {
+                    return None;
+                }
+                let close = (i + 2..lines.len()).find(|&j| is_fence_close(lines[j], fence, base));
+                if close.is_none() {
+                    unclosed = Some(fence.len());
+                }
+                close
+            })
+        };
+        let Some(mut close) = close else {
+            result.push_str(line);
+            i += 1;
+            continue;
+        };
+        if lines
+            .get(close + 1)
+            .is_some_and(|next| is_recording_link(next))
+        {
+            close += 1;
+        }
+
+        i = close + 1;
+        let newline = if result.ends_with("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        if !lines[close].ends_with('\n') && result.ends_with('\n') {
+            // The section ended the document: so does the block before it
+            result.truncate(result.len() - newline.len());
+        } else if lines.get(i).is_some_and(|next| is_fence(next))
+            && result.lines().next_back().is_some_and(is_fence)
+        {
+            // Keep the block before apart from a fence directly following the section
+            result.push_str(newline);
+        }
+    }
+}
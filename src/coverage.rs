@@ -0,0 +1,67 @@
+use anyhow::Result;
+
+use crate::config::Config;
+use crate::core::{clear_outputs, find_all_code_blocks, SkipReason};
+
+/// Why a block of a document never runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotRun {
+    Skipped(SkipReason),
+    /// No command is configured for its language
+    UnknownLanguage,
+}
+
+impl std::fmt::Display for NotRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotRun::Skipped(reason) => reason.fmt(f),
+            NotRun::UnknownLanguage => f.write_str("no command configured for the language"),
+        }
+    }
+}
+
+/// A code block and whether processing the document would execute it.
+#[derive(Debug, Clone)]
+pub struct BlockCoverage {
+    /// 1-based line of the opening fence
+    pub line: usize,
+    pub language: String,
+    pub not_run: Option<NotRun>,
+}
+
+/// Every code block of `content`, with the reason for each one that would not be
+/// executed. Fences without a language are text, not code, and are left out.
+pub fn blocks(content: &str, config: &Config) -> Result<Vec<BlockCoverage>> {
+    let content = clear_outputs(content, config)?;
+    Ok(find_all_code_blocks(&content, config)
+        .into_iter()
+        .map(|block| {
+            let configured = block.attrs.get("cmd").is_some()
+                || config.languages.contains_key(&block.language)
+                || config
+                    .aliases
+                    .get(&block.language)
+                    .is_some_and(|language| config.languages.contains_key(language));
+            let not_run = match block.skip_reason {
+                Some(reason) => Some(NotRun::Skipped(reason)),
+                None if !configured => Some(NotRun::UnknownLanguage),
+                None => None,
+            };
+            BlockCoverage {
+                line: block.line,
+                language: block.language,
+                not_run,
+            }
+        })
+        .collect())
+}
+
+/// Share of `executed` out of `total` blocks as a percentage; a document without
+/// blocks is fully covered.
+pub fn percent(executed: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        executed as f64 * 100.0 / total as f64
+    }
+}
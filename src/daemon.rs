@@ -0,0 +1,346 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+use crate::config::{Config, ConfigOverrides};
+use crate::core::{block_outputs, clear_outputs, process_markdown, run_snippet, RunContext};
+use crate::json;
+use crate::limits::Limiter;
+use crate::metrics::{self, Metrics};
+use crate::report::Report;
+
+/// One request per line, as JSON (or single-line YAML) tagged by `method`.
+/// `cwd` is the client's working directory: relative paths are resolved
+/// against it and blocks run in it, as they would for runmd started there.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "kebab-case", deny_unknown_fields)]
+enum Request {
+    /// Process a file in place, or `content` when no path is given
+    Process {
+        path: Option<PathBuf>,
+        content: Option<String>,
+        cwd: Option<PathBuf>,
+    },
+    /// Clear the outputs of a file in place, or of `content`
+    Clear {
+        path: Option<PathBuf>,
+        content: Option<String>,
+        cwd: Option<PathBuf>,
+    },
+    /// Process `content` with `options` applied, returning the document and each
+    /// block's output without reading or writing any file
+    Render {
+        content: String,
+        #[serde(default)]
+        options: ConfigOverrides,
+        cwd: Option<PathBuf>,
+    },
+    /// Run a single block; `info` is everything after the opening fence
+    RunBlock {
+        info: String,
+        code: String,
+        cwd: Option<PathBuf>,
+    },
+    /// Execution metrics in the Prometheus text format
+    Metrics,
+}
+
+/// One response line per request.
+#[derive(Debug, Default, Serialize)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<Report>,
+    /// Output of each block by index, null where none was inserted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outputs: Option<Vec<Option<String>>>,
+}
+
+/// State kept between requests: the config, and the run context with its output
+/// cache, timing history and interpreter versions.
+struct Daemon {
+    config: Config,
+    context: Mutex<RunContext>,
+    limiter: Limiter,
+    metrics: Rc<Metrics>,
+}
+
+/// Where the daemon listens unless `--socket` is given.
+pub fn default_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("runmd.sock")
+}
+
+/// Serve requests on a Unix socket at `socket` until interrupted, and metrics over
+/// HTTP on `metrics_addr` if given.
+#[cfg(unix)]
+pub async fn serve(config: Config, socket: &Path, metrics_addr: Option<&str>) -> Result<()> {
+    use tokio::net::UnixListener;
+
+    // A socket left behind by a daemon that didn't shut down cleanly
+    if socket.exists() && std::os::unix::net::UnixStream::connect(socket).is_err() {
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    println!("Listening on {}", socket.display());
+
+    let context = RunContext::new(&config, Path::new("."))?;
+    let daemon = Rc::new(Daemon {
+        limiter: Limiter::new(&config.service),
+        metrics: Rc::new(Metrics::default()),
+        config,
+        context: Mutex::new(context),
+    });
+
+    // Connections are served on this thread and take turns with the run context
+    let connections = tokio::task::LocalSet::new();
+    let result = connections
+        .run_until(async move {
+            if let Some(addr) = metrics_addr {
+                let metrics = daemon.metrics.clone();
+                let addr = addr.to_string();
+                tokio::task::spawn_local(async move {
+                    if let Err(e) = metrics::listen(metrics, &addr).await {
+                        eprintln!("runmd daemon: {:#}", e);
+                    }
+                });
+            }
+
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (stream, _) = match accepted {
+                            Ok(connection) => connection,
+                            Err(e) => break Err(e.into()),
+                        };
+                        let daemon = daemon.clone();
+                        tokio::task::spawn_local(async move {
+                            // Clients are rate limited per user
+                            let client = stream
+                                .peer_cred()
+                                .map(|cred| cred.uid().to_string())
+                                .unwrap_or_default();
+                            let (reader, mut writer) = stream.into_split();
+                            let mut lines = BufReader::new(reader).lines();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line.trim().is_empty() {
+                                    continue;
+                                }
+                                let mut reply = daemon.respond(&line, &client).await;
+                                reply.push('\n');
+                                if writer.write_all(reply.as_bytes()).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                    _ = tokio::signal::ctrl_c() => break Ok(()),
+                }
+            }
+        })
+        .await;
+
+    let _ = std::fs::remove_file(socket);
+    result
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_config: Config, _socket: &Path, _metrics_addr: Option<&str>) -> Result<()> {
+    anyhow::bail!("runmd daemon is only supported on Unix")
+}
+
+impl Daemon {
+    /// Answer a line holding one request, or a JSON array of requests (a batch)
+    /// answered by an array of responses in the same order.
+    async fn respond(&self, line: &str, client: &str) -> String {
+        if !line.trim_start().starts_with('[') {
+            let response = self.handle(serde_yaml::from_str(line), client).await;
+            return json::to_string(&response).unwrap_or_default();
+        }
+
+        let requests = match serde_yaml::from_str::<Vec<serde_yaml::Value>>(line) {
+            Ok(requests) => requests,
+            Err(e) => {
+                let response = self.handle(Err(e), client).await;
+                return json::to_string(&response).unwrap_or_default();
+            }
+        };
+        let mut responses = Vec::new();
+        for request in requests {
+            responses.push(self.handle(serde_yaml::from_value(request), client).await);
+        }
+        json::to_string(&responses).unwrap_or_default()
+    }
+
+    async fn handle(
+        &self,
+        request: Result<Request, serde_yaml::Error>,
+        client: &str,
+    ) -> Response {
+        let result = match request {
+            Ok(Request::Metrics) => Ok(Response {
+                ok: true,
+                content: Some(self.metrics.render()),
+                ..Response::default()
+            }),
+            Ok(request) => match self.limiter.acquire(client).await {
+                Ok(_permit) => self.dispatch(request).await,
+                Err(e) => {
+                    self.metrics.record_rejection(e);
+                    Err(e.into())
+                }
+            },
+            Err(e) => Err(anyhow::anyhow!("Invalid request: {}", e)),
+        };
+
+        result.unwrap_or_else(|e| Response {
+            error: Some(format!("{:#}", e)),
+            ..Response::default()
+        })
+    }
+
+    async fn dispatch(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::Process { path, content, cwd } => {
+                let cwd = client_dir(cwd)?;
+                let (source, content) = read_source(path, content, cwd.as_deref())?;
+                let mut context = self.context.lock().await;
+                let base = base_dir(source.as_deref(), cwd.as_deref());
+                context.set_directories(&base, cwd.as_deref());
+                context.set_document(source.as_deref());
+                let (result, report) =
+                    process_markdown(&content, &self.config, &mut context).await?;
+                self.metrics
+                    .record_document(&report, self.config.cache.enabled);
+                Ok(Response {
+                    ok: true,
+                    content: write_source(source.as_deref(), result)?,
+                    report: Some(report),
+                    ..Response::default()
+                })
+            }
+            Request::Clear { path, content, cwd } => {
+                let cwd = client_dir(cwd)?;
+                let (source, content) = read_source(path, content, cwd.as_deref())?;
+                let result = clear_outputs(&content, &self.config)?;
+                Ok(Response {
+                    ok: true,
+                    content: write_source(source.as_deref(), result)?,
+                    ..Response::default()
+                })
+            }
+            Request::Render {
+                content,
+                options,
+                cwd,
+            } => {
+                let cwd = client_dir(cwd)?;
+                let config = options.apply(&self.config);
+                let mut context = self.context.lock().await;
+                context.set_directories(&base_dir(None, cwd.as_deref()), cwd.as_deref());
+                context.set_document(None);
+                let (result, report) = process_markdown(&content, &config, &mut context).await?;
+                self.metrics.record_document(&report, config.cache.enabled);
+                Ok(Response {
+                    ok: true,
+                    outputs: Some(block_outputs(&result, &config)),
+                    content: Some(result),
+                    report: Some(report),
+                    ..Response::default()
+                })
+            }
+            Request::Metrics => unreachable!("answered without a queue slot"),
+            Request::RunBlock { info, code, cwd } => {
+                let cwd = client_dir(cwd)?;
+                let mut context = self.context.lock().await;
+                context.set_directories(&base_dir(None, cwd.as_deref()), cwd.as_deref());
+                let run = run_snippet(&info, &code, &self.config, &context).await?;
+                let language = info.split_whitespace().next().unwrap_or_default();
+                self.metrics.record_block(
+                    language,
+                    run.success,
+                    run.cached,
+                    self.config.cache.enabled,
+                    run.duration,
+                );
+                Ok(Response {
+                    ok: true,
+                    output: Some(run.output),
+                    success: Some(run.success),
+                    ..Response::default()
+                })
+            }
+        }
+    }
+}
+
+/// The client's working directory, which has to be absolute to mean anything here.
+fn client_dir(cwd: Option<PathBuf>) -> Result<Option<PathBuf>> {
+    match cwd {
+        Some(cwd) if cwd.is_relative() => {
+            anyhow::bail!("'cwd' must be an absolute path, not {}", cwd.display())
+        }
+        cwd => Ok(cwd),
+    }
+}
+
+/// Directory attribute paths are relative to: the document's, as on the command
+/// line, or the client's for inline content.
+fn base_dir(source: Option<&Path>, cwd: Option<&Path>) -> PathBuf {
+    source
+        .and_then(Path::parent)
+        .or(cwd)
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+/// The document a request names, read from `path` relative to the client's `cwd`,
+/// or sent inline as `content`.
+fn read_source(
+    path: Option<PathBuf>,
+    content: Option<String>,
+    cwd: Option<&Path>,
+) -> Result<(Option<PathBuf>, String)> {
+    match (path, content) {
+        (Some(path), None) => {
+            let path = match cwd {
+                _ if path.is_absolute() => path,
+                Some(cwd) => cwd.join(path),
+                None => anyhow::bail!(
+                    "Relative path {} needs the client's 'cwd' to be resolved",
+                    path.display()
+                ),
+            };
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            Ok((Some(path), content))
+        }
+        (None, Some(content)) => Ok((None, content)),
+        _ => anyhow::bail!("Expected exactly one of 'path' or 'content'"),
+    }
+}
+
+/// Write the result back to the file it came from, or return it when the request
+/// sent the document inline.
+fn write_source(path: Option<&Path>, result: String) -> Result<Option<String>> {
+    match path {
+        Some(path) => {
+            std::fs::write(path, result)?;
+            Ok(None)
+        }
+        None => Ok(Some(result)),
+    }
+}
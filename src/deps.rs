@@ -0,0 +1,61 @@
+use anyhow::{bail, Result};
+
+use crate::core::CodeBlock;
+
+/// For each block, the runnable blocks it has to wait for: those declaring
+/// `produces=` for a file it declares in `consumes=`.
+pub fn dependencies(blocks: &[CodeBlock]) -> Vec<Vec<usize>> {
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| {
+            let consumes = block.attrs.list("consumes");
+            if block.skip || consumes.is_empty() {
+                return Vec::new();
+            }
+            blocks
+                .iter()
+                .enumerate()
+                .filter(|(j, producer)| {
+                    *j != i
+                        && !producer.skip
+                        && producer
+                            .attrs
+                            .list("produces")
+                            .iter()
+                            .any(|file| consumes.contains(file))
+                })
+                .map(|(j, _)| j)
+                .collect()
+        })
+        .collect()
+}
+
+/// Indices of the runnable blocks in document order, except that every block
+/// comes after the blocks producing the files it consumes.
+pub fn run_order(blocks: &[CodeBlock]) -> Result<Vec<usize>> {
+    let dependencies = dependencies(blocks);
+    let mut done = vec![false; blocks.len()];
+    let mut order = Vec::new();
+    let runnable = blocks.iter().filter(|block| !block.skip).count();
+
+    while order.len() < runnable {
+        let next = (0..blocks.len()).find(|&i| {
+            !done[i] && !blocks[i].skip && dependencies[i].iter().all(|&j| done[j])
+        });
+        let Some(next) = next else {
+            let stuck: Vec<String> = (0..blocks.len())
+                .filter(|&i| !done[i] && !blocks[i].skip)
+                .map(|i| blocks[i].line.to_string())
+                .collect();
+            bail!(
+                "Blocks at lines {} can't be ordered: their produces/consumes form a cycle",
+                stuck.join(", ")
+            );
+        };
+        done[next] = true;
+        order.push(next);
+    }
+
+    Ok(order)
+}
@@ -0,0 +1,77 @@
+/// Interpreters named in shebang lines, mapped to the language they run.
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("python", "python"),
+    ("bash", "bash"),
+    ("sh", "sh"),
+    ("zsh", "zsh"),
+    ("node", "javascript"),
+    ("ruby", "ruby"),
+    ("perl", "perl"),
+    ("php", "php"),
+    ("lua", "lua"),
+    ("julia", "julia"),
+    ("Rscript", "r"),
+];
+
+/// Line prefixes typical of each language, or whole lines for bare words such as
+/// `fi`; the language with the most matching lines wins.
+const KEYWORDS: &[(&str, &[&str])] = &[
+    ("python", &["def ", "import ", "from ", "print(", "elif ", "class "]),
+    ("bash", &["echo ", "export ", "fi", "done", "cd ", "if [", "set -e", "mkdir "]),
+    ("javascript", &["console.log(", "const ", "let ", "function ", "require("]),
+    ("ruby", &["puts ", "require '", "end", "def ", "attr_"]),
+    ("rust", &["fn ", "let mut ", "use std::", "println!(", "impl "]),
+    ("go", &["package ", "func ", "fmt."]),
+    ("c", &["#include <stdio.h>", "int main(", "printf("]),
+    ("cpp", &["#include <iostream>", "std::", "using namespace "]),
+    ("java", &["public class ", "public static void main", "System.out."]),
+    ("php", &["<?php"]),
+];
+
+/// Guess the language of an unlabeled block from a shebang line or, failing
+/// that, from keywords at the start of its lines. `None` when nothing stands out.
+pub fn detect_language(code: &str) -> Option<&'static str> {
+    if let Some(shebang) = code.lines().next().and_then(|line| line.strip_prefix("#!")) {
+        return shebang_language(shebang);
+    }
+
+    let mut scores: Vec<(&'static str, usize)> = KEYWORDS
+        .iter()
+        .map(|(language, prefixes)| {
+            let hits = code
+                .lines()
+                .map(str::trim_start)
+                .filter(|line| prefixes.iter().any(|prefix| starts_with_keyword(line, prefix)))
+                .count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    match scores.as_slice() {
+        [(language, best), (_, second), ..] if *best > 0 && best > second => Some(language),
+        _ => None,
+    }
+}
+
+fn starts_with_keyword(line: &str, keyword: &str) -> bool {
+    if keyword.chars().all(char::is_alphanumeric) {
+        line.trim_end() == keyword
+    } else {
+        line.starts_with(keyword)
+    }
+}
+
+/// The language run by the interpreter of `#!/usr/bin/env python3` and the like.
+fn shebang_language(shebang: &str) -> Option<&'static str> {
+    let mut words = shebang.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    // python3.12 runs Python like python3 does
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    INTERPRETERS
+        .iter()
+        .find(|(name, _)| *name == program)
+        .map(|(_, language)| *language)
+}
@@ -0,0 +1,71 @@
+use colored::Colorize;
+
+use crate::config::Config;
+use crate::core::block_outputs;
+
+/// How much the outputs of a document changed in a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffStat {
+    pub added: usize,
+    pub removed: usize,
+    /// Output sections that changed
+    pub blocks: usize,
+}
+
+impl DiffStat {
+    /// Compare the output sections of the same document before and after a run.
+    pub fn outputs(before: &str, after: &str, config: &Config) -> Self {
+        let before = block_outputs(before, config);
+        let after = block_outputs(after, config);
+
+        let mut stat = DiffStat::default();
+        for i in 0..before.len().max(after.len()) {
+            let old = before.get(i).cloned().flatten();
+            let new = after.get(i).cloned().flatten();
+            if old == new {
+                continue;
+            }
+            let old: Vec<&str> = old.as_deref().map(|o| o.lines().collect()).unwrap_or_default();
+            let new: Vec<&str> = new.as_deref().map(|o| o.lines().collect()).unwrap_or_default();
+            let common = common_lines(&old, &new);
+            stat.added += new.len() - common;
+            stat.removed += old.len() - common;
+            stat.blocks += 1;
+        }
+        stat
+    }
+}
+
+impl std::fmt::Display for DiffStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.blocks == 0 {
+            return write!(f, "no output changes");
+        }
+        write!(
+            f,
+            "{} {} lines across {} output block{}",
+            format!("+{}", self.added).green(),
+            format!("−{}", self.removed).red(),
+            self.blocks,
+            if self.blocks == 1 { "" } else { "s" }
+        )
+    }
+}
+
+/// Length of the longest common subsequence of two line lists.
+fn common_lines(a: &[&str], b: &[&str]) -> usize {
+    let mut row = vec![0; b.len() + 1];
+    for x in a {
+        let mut diagonal = 0;
+        for (j, y) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if x == y {
+                diagonal + 1
+            } else {
+                above.max(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,37 @@
+use anyhow::Result;
+use std::path::Path;
+
+/// Bytes available to unprivileged processes on the file system holding `path`,
+/// or `None` when that can't be determined.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Fail when the file system holding `dir` has less than `minimum` bytes free,
+/// rather than letting blocks fail halfway through writing their files.
+pub fn ensure_free(dir: &Path, minimum: u64) -> Result<()> {
+    match free_space(dir) {
+        Some(free) if free < minimum => anyhow::bail!(
+            "Only {} MiB free in {}, less than min_free_space ({} MiB); free up space before running blocks",
+            free / MIB,
+            dir.display(),
+            minimum / MIB
+        ),
+        _ => Ok(()),
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
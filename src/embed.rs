@@ -0,0 +1,288 @@
+use anyhow::Result;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::core::{
+    is_fence_close, parse_fence_open, parse_fence_open_within, parse_section_header, ListItems,
+};
+
+/// How a file holds the Markdown runmd processes, decided by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The whole file is Markdown
+    Markdown,
+    /// MDX, whose comments are `{/* ... */}` rather than HTML comments
+    Mdx,
+    /// Rust source, whose `///` and `//!` doc comments are Markdown
+    RustDoc,
+}
+
+impl Format {
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("mdx") => Format::Mdx,
+            Some("rs") => Format::RustDoc,
+            _ => Format::Markdown,
+        }
+    }
+}
+
+/// Line standing in for each line of Rust code in the Markdown extracted from
+/// doc comments, so every Markdown line keeps the line number it has in the file.
+const CODE_LINE: &str = "<!-- runmd:code -->";
+
+/// A run of consecutive doc comment lines in a Rust file.
+struct Segment {
+    /// Byte range of its lines in the host file
+    range: std::ops::Range<usize>,
+    /// Each line as written, e.g. `    /// text`, and its Markdown, `text`
+    lines: Vec<(String, String)>,
+}
+
+/// The Markdown embedded in a host file, which can be written back into it
+/// once processed. Line numbers in the Markdown are those of the host file.
+pub struct Embedded {
+    format: Format,
+    host: String,
+    markdown: String,
+    segments: Vec<Segment>,
+}
+
+/// Pull the Markdown out of `content`, a file in `format`.
+pub fn extract(content: &str, format: Format) -> Embedded {
+    let (markdown, segments) = match format {
+        Format::Markdown => (content.to_string(), Vec::new()),
+        Format::Mdx => (map_comments(content, mdx_to_html), Vec::new()),
+        Format::RustDoc => doc_comments(content),
+    };
+    Embedded {
+        format,
+        host: content.to_string(),
+        markdown,
+        segments,
+    }
+}
+
+impl Embedded {
+    pub fn markdown(&self) -> &str {
+        &self.markdown
+    }
+
+    /// The file as it was read.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The host file with its Markdown replaced by `markdown`, a processed
+    /// version of [`Embedded::markdown`].
+    pub fn restore(&self, markdown: &str, config: &Config) -> Result<String> {
+        match self.format {
+            Format::Markdown => Ok(markdown.to_string()),
+            Format::Mdx => Ok(map_comments(markdown, html_to_mdx)),
+            Format::RustDoc => self.restore_doc_comments(markdown, config),
+        }
+    }
+
+    fn restore_doc_comments(&self, markdown: &str, config: &Config) -> Result<String> {
+        let lines: Vec<&str> = markdown.lines().collect();
+        let parts: Vec<&[&str]> = lines
+            .split(|line| *line == CODE_LINE)
+            .filter(|part| !part.is_empty())
+            .collect();
+        if parts.len() != self.segments.len() {
+            anyhow::bail!("A code fence left open in a doc comment swallowed the code after it");
+        }
+
+        let newline = if self.host.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let mut result = String::with_capacity(self.host.len() + markdown.len());
+        let mut last = 0;
+        for (segment, part) in self.segments.iter().zip(parts) {
+            result.push_str(&self.host[last..segment.range.start]);
+            last = segment.range.end;
+            if part.iter().eq(segment.lines.iter().map(|(_, text)| text)) {
+                result.push_str(&self.host[segment.range.clone()]);
+                continue;
+            }
+
+            // Lines kept from the comment are written as they were; added ones get
+            // the marker of the line before them
+            let kept = common_lines(&segment.lines, part);
+            let mut prefix = doc_prefix(&segment.lines[0].0);
+            for (i, line) in part.iter().enumerate() {
+                if let Some(written) = kept[i] {
+                    result.push_str(written);
+                    result.push_str(newline);
+                    prefix = doc_prefix(written);
+                    continue;
+                }
+                // rustdoc would compile an output fence without a language as a doctest
+                let bare_output = i > 0
+                    && parse_section_header(part[i - 1], &config.output_header).is_some()
+                    && parse_fence_open(line).is_some_and(|(_, _, info)| info.is_empty());
+                result.push_str(prefix);
+                if !line.is_empty() {
+                    result.push(' ');
+                    result.push_str(line);
+                }
+                if bare_output {
+                    result.push_str("text");
+                }
+                result.push_str(newline);
+            }
+        }
+        result.push_str(&self.host[last..]);
+        Ok(result)
+    }
+}
+
+/// For each line of `part`, the comment line it was kept from, found as the
+/// longest common subsequence of the original and processed Markdown. Lines
+/// both start or end with are matched first, so the search only spans the
+/// region that changed.
+fn common_lines<'a>(original: &'a [(String, String)], part: &[&str]) -> Vec<Option<&'a str>> {
+    let same = |i: usize, j: usize| original[i].1 == part[j];
+    let head = (0..original.len().min(part.len()))
+        .take_while(|&k| same(k, k))
+        .count();
+    let tail = (0..original.len().min(part.len()) - head)
+        .take_while(|&k| same(original.len() - 1 - k, part.len() - 1 - k))
+        .count();
+    let (rows, columns) = (original.len() - head - tail, part.len() - head - tail);
+
+    // lengths[i][j]: longest common subsequence of the changed lines from i and j on
+    let mut lengths = vec![vec![0usize; columns + 1]; rows + 1];
+    for i in (0..rows).rev() {
+        for j in (0..columns).rev() {
+            lengths[i][j] = if same(head + i, head + j) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut kept = vec![None; part.len()];
+    for k in 0..head {
+        kept[k] = Some(original[k].0.as_str());
+    }
+    for k in 1..=tail {
+        kept[part.len() - k] = Some(original[original.len() - k].0.as_str());
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < rows && j < columns {
+        if same(head + i, head + j) {
+            kept[head + j] = Some(original[head + i].0.as_str());
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    kept
+}
+
+/// A doc comment line's indentation and marker, e.g. `    ///`.
+fn doc_prefix(line: &str) -> &str {
+    let indent = line.len() - line.trim_start().len();
+    &line[..indent + 3]
+}
+
+/// The Markdown in the `///` and `//!` comments of Rust source, with a
+/// [`CODE_LINE`] for every other line, and the runs of comment lines.
+fn doc_comments(content: &str) -> (String, Vec<Segment>) {
+    let mut markdown = String::with_capacity(content.len());
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut pos = 0;
+    let mut continues = false;
+    for line in content.split_inclusive('\n') {
+        let start = pos;
+        pos += line.len();
+        let text = line.trim_end_matches(['\n', '\r']);
+        let trimmed = text.trim_start();
+        let is_doc = (trimmed.starts_with("///") && !trimmed.starts_with("////"))
+            || trimmed.starts_with("//!");
+        if !is_doc {
+            markdown.push_str(CODE_LINE);
+            markdown.push('\n');
+            continues = false;
+            continue;
+        }
+
+        let body = &trimmed[3..];
+        let body = body.strip_prefix(' ').unwrap_or(body);
+        markdown.push_str(body);
+        markdown.push('\n');
+        let line = (text.to_string(), body.to_string());
+        match segments.last_mut() {
+            Some(segment) if continues => {
+                segment.range.end = pos;
+                segment.lines.push(line);
+            }
+            _ => segments.push(Segment {
+                range: start..pos,
+                lines: vec![line],
+            }),
+        }
+        continues = true;
+    }
+    (markdown, segments)
+}
+
+/// Apply `map` to every line of `content` outside code fences, keeping line breaks.
+fn map_comments(content: &str, map: fn(&str) -> String) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut fence: Option<(String, usize)> = None;
+    let mut list_items = ListItems::default();
+    for line in content.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\n', '\r']);
+        let newline = &line[text.len()..];
+        match &fence {
+            Some((open, base)) => {
+                if is_fence_close(text, open, *base) {
+                    fence = None;
+                }
+                result.push_str(line);
+            }
+            None => {
+                let base = list_items.base(text);
+                if let Some((_, open, _)) = parse_fence_open_within(text, base) {
+                    fence = Some((open.to_string(), base));
+                    result.push_str(line);
+                } else {
+                    result.push_str(&map(text));
+                    result.push_str(newline);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Turn MDX comments into the HTML comments runmd's markers are written as.
+fn mdx_to_html(line: &str) -> String {
+    if line.trim() == "*/}" {
+        return line.replace("*/}", "-->");
+    }
+    if !line.contains("{/*") {
+        return line.to_string();
+    }
+    line.replace("{/*", "<!--").replace("*/}", "-->")
+}
+
+/// Turn HTML comments back into MDX comments, which MDX requires. MDX can't
+/// contain HTML comments, so every one is a converted or inserted marker.
+fn html_to_mdx(line: &str) -> String {
+    if line.trim() == "-->" {
+        return line.replace("-->", "*/}");
+    }
+    if !line.contains("<!--") {
+        return line.to_string();
+    }
+    line.replace("<!--", "{/*").replace("-->", "*/}")
+}
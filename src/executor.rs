@@ -0,0 +1,45 @@
+use anyhow::Result;
+use std::future::Future;
+
+use crate::core::CodeBlock;
+
+/// What a block produced when it ran.
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    pub output: String,
+    /// Exit code, when the process ran to completion
+    pub exit_code: Option<i32>,
+    /// Standard error on its own, when it was captured apart from stdout
+    pub stderr: Option<String>,
+}
+
+impl RunResult {
+    pub fn error(message: impl std::fmt::Display) -> Self {
+        RunResult {
+            output: format!("[error] {}", message),
+            exit_code: None,
+            stderr: None,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    /// Whether a block behaved as intended: it exited with 0 or, when it is
+    /// expected to fail, ran to completion with a non-zero code.
+    pub fn passed(&self, expect_failure: bool) -> bool {
+        if expect_failure {
+            self.exit_code.is_some_and(|code| code != 0)
+        } else {
+            self.success()
+        }
+    }
+}
+
+/// Runs blocks for hosts that can't spawn local processes, such as a browser
+/// playground forwarding code to a backend. Everything around execution (which
+/// blocks run, attributes, clearing and rendering) stays with runmd.
+pub trait Executor {
+    fn execute(&self, block: &CodeBlock) -> impl Future<Output = Result<RunResult>>;
+}
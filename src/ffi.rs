@@ -0,0 +1,196 @@
+//! C interface to the processing core, built into the `cdylib`. Strings cross the
+//! boundary as NUL-terminated UTF-8; strings returned by runmd must be released
+//! with [`runmd_string_free`]. On failure functions return NULL and the message is
+//! available from [`runmd_last_error`]. Panics are caught and reported the same
+//! way, since unwinding into the host would abort it.
+
+use anyhow::{Context, Result};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::task::{Poll, Wake, Waker};
+
+use crate::config::Config;
+use crate::core::{clear_outputs, process_with, CodeBlock};
+use crate::executor::{Executor, RunResult};
+
+/// Called once per runnable block with its language and code. The callback stores
+/// the block's output with [`runmd_result_set_output`] and returns its exit code.
+pub type RunmdExecuteFn = extern "C" fn(
+    user_data: *mut c_void,
+    language: *const c_char,
+    code: *const c_char,
+    result: *mut RunmdResult,
+) -> c_int;
+
+/// Output of a block, filled in by an execute callback.
+#[derive(Default)]
+pub struct RunmdResult {
+    output: String,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+struct CallbackExecutor {
+    execute: RunmdExecuteFn,
+    user_data: *mut c_void,
+}
+
+impl Executor for CallbackExecutor {
+    async fn execute(&self, block: &CodeBlock) -> Result<RunResult> {
+        let language = CString::new(block.language.as_str())?;
+        let code = CString::new(block.code.as_str()).context("Block contains a NUL byte")?;
+        let mut result = RunmdResult::default();
+
+        let exit_code = (self.execute)(
+            self.user_data,
+            language.as_ptr(),
+            code.as_ptr(),
+            &mut result,
+        );
+
+        Ok(RunResult {
+            output: result.output,
+            exit_code: Some(exit_code),
+            stderr: None,
+        })
+    }
+}
+
+/// Remove the output sections from `content`.
+///
+/// # Safety
+///
+/// `content` must be a valid NUL-terminated string and `config_yaml` either NULL,
+/// to use the user's config file, or a NUL-terminated config document.
+#[no_mangle]
+pub unsafe extern "C" fn runmd_clear_outputs(
+    content: *const c_char,
+    config_yaml: *const c_char,
+) -> *mut c_char {
+    into_c_string(|| {
+        let content = read_str(content)?;
+        let config = read_config(config_yaml)?;
+        clear_outputs(content, &config)
+    })
+}
+
+/// Run the blocks of `content` through `execute`, one at a time in document order,
+/// and return the document with outputs attached.
+///
+/// # Safety
+///
+/// `content` and `config_yaml` follow [`runmd_clear_outputs`]. `execute` is called
+/// on the calling thread with `user_data` passed through unchanged.
+#[no_mangle]
+pub unsafe extern "C" fn runmd_process(
+    content: *const c_char,
+    config_yaml: *const c_char,
+    execute: RunmdExecuteFn,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    into_c_string(|| {
+        let content = read_str(content)?;
+        let config = read_config(config_yaml)?;
+        let executor = CallbackExecutor { execute, user_data };
+        let (result, _) = block_on(process_with(content, &config, &executor))?;
+        Ok(result)
+    })
+}
+
+/// Set the output of the block an execute callback was called for.
+///
+/// # Safety
+///
+/// `result` must be the pointer passed to the callback and `output` a valid
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn runmd_result_set_output(result: *mut RunmdResult, output: *const c_char) {
+    if let (Some(result), Ok(output)) = (result.as_mut(), read_str(output)) {
+        result.output = output.trim().to_string();
+    }
+}
+
+/// The error from the last failed call on this thread, or NULL.
+#[no_mangle]
+pub extern "C" fn runmd_last_error() -> *mut c_char {
+    LAST_ERROR.with(|error| match error.borrow().as_deref() {
+        Some(message) => CString::new(message).map_or(std::ptr::null_mut(), CString::into_raw),
+        None => std::ptr::null_mut(),
+    })
+}
+
+/// Release a string returned by runmd.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by a runmd function that has not been
+/// freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn runmd_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        anyhow::bail!("Unexpected NULL string");
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .context("String is not valid UTF-8")
+}
+
+unsafe fn read_config(config_yaml: *const c_char) -> Result<Config> {
+    if config_yaml.is_null() {
+        Config::load()
+    } else {
+        Config::parse(read_str(config_yaml)?)
+    }
+}
+
+fn into_c_string(f: impl FnOnce() -> Result<String>) -> *mut c_char {
+    let result = catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause");
+            Err(anyhow::anyhow!("runmd panicked: {}", message))
+        })
+        .and_then(|s| CString::new(s).context("Output contains a NUL byte"));
+    LAST_ERROR.with(|error| {
+        *error.borrow_mut() = result.as_ref().err().map(|e| format!("{:#}", e));
+    });
+    result.map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+/// Wakes the thread blocked in [`block_on`].
+struct Unpark(std::thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Drive `future` to completion on the calling thread, which the callbacks must
+/// run on. Callback executors finish within the first poll; anything that does
+/// suspend parks the thread until it is woken.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{bail, Result};
+use regex::Regex;
+
+/// Built-in filters removing boilerplate warnings from block outputs, by name.
+/// Each matches the first line of a warning and, with `continuation`, the line
+/// following it.
+struct Filter {
+    name: &'static str,
+    start: &'static str,
+    continuation: Option<&'static str>,
+}
+
+const FILTERS: &[Filter] = &[
+    // `/path/mod.py:3: DeprecationWarning: ...` followed by the indented source line
+    Filter {
+        name: "python-deprecation",
+        start: r"^\S.*:\d+: (Pending)?DeprecationWarning: ",
+        continuation: Some(r"^\s+\S"),
+    },
+    // `(node:123) ExperimentalWarning: ...` and the hint about --trace-warnings
+    Filter {
+        name: "node-experimental",
+        start: r"^\(node:\d+\) ExperimentalWarning: ",
+        continuation: Some(r"^\(Use `node --trace-warnings"),
+    },
+    Filter {
+        name: "jvm-java-options",
+        start: r"^Picked up (_JAVA_OPTIONS|JAVA_TOOL_OPTIONS|JDK_JAVA_OPTIONS): ",
+        continuation: None,
+    },
+];
+
+/// Names of the built-in filters.
+pub fn names() -> Vec<&'static str> {
+    FILTERS.iter().map(|filter| filter.name).collect()
+}
+
+/// Fail on filter names that aren't built in.
+pub fn validate<'a>(names: impl IntoIterator<Item = &'a String>) -> Result<()> {
+    for name in names {
+        if !FILTERS.iter().any(|filter| filter.name == name) {
+            bail!(
+                "Unknown output filter '{}' (expected one of {})",
+                name,
+                self::names().join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// `output` without the warnings matched by the filters called `names`.
+pub fn apply(output: &str, names: &[String]) -> String {
+    let filters: Vec<(Regex, Option<Regex>)> = FILTERS
+        .iter()
+        .filter(|filter| names.iter().any(|name| name == filter.name))
+        .map(|filter| {
+            (
+                Regex::new(filter.start).expect("valid filter pattern"),
+                filter
+                    .continuation
+                    .map(|pattern| Regex::new(pattern).expect("valid filter pattern")),
+            )
+        })
+        .collect();
+    if filters.is_empty() {
+        return output.to_string();
+    }
+
+    let mut kept = Vec::new();
+    let mut continuation: Option<&Regex> = None;
+    for line in output.lines() {
+        if continuation.take().is_some_and(|pattern| pattern.is_match(line)) {
+            continue;
+        }
+        if let Some((_, next)) = filters.iter().find(|(start, _)| start.is_match(line)) {
+            continuation = next.as_ref();
+            continue;
+        }
+        kept.push(line);
+    }
+    kept.join("\n")
+}
@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Settings a document gives itself under the `runmd` key of its YAML front matter:
+///
+/// ```markdown
+/// ---
+/// title: Tutorial
+/// runmd:
+///   image: ghcr.io/org/docs-env:1.2
+///   nix: [nixpkgs#jq]
+/// ---
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DocumentOptions {
+    /// Container image every block of the document runs in
+    pub image: Option<String>,
+    /// Nix installables every block of the document runs with
+    pub nix: Vec<String>,
+    /// The document's own `max_blocks` budget
+    pub max_blocks: Option<usize>,
+    /// The document's own `max_block_time` budget
+    pub max_block_time: Option<String>,
+}
+
+/// The YAML between a leading `---` line and the next `---` or `...` line.
+pub fn front_matter(content: &str) -> Option<&str> {
+    let rest = content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))?;
+
+    let mut pos = 0;
+    for line in rest.split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            return Some(&rest[..pos]);
+        }
+        pos += line.len();
+    }
+    None
+}
+
+/// Read the `runmd` settings of a document. Front matter that isn't a YAML
+/// mapping belongs to other tools and is ignored.
+pub fn document_options(content: &str) -> Result<DocumentOptions> {
+    let Some(yaml) = front_matter(content) else {
+        return Ok(DocumentOptions::default());
+    };
+    let Ok(serde_yaml::Value::Mapping(mapping)) = serde_yaml::from_str(yaml) else {
+        return Ok(DocumentOptions::default());
+    };
+
+    match mapping.get("runmd") {
+        Some(options) => serde_yaml::from_value(options.clone())
+            .context("Invalid runmd settings in front matter"),
+        None => Ok(DocumentOptions::default()),
+    }
+}
@@ -0,0 +1,55 @@
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is stable across Rust
+/// releases, so it can key data persisted between runs.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Hex key identifying a block by its language and code.
+pub fn block_key(language: &str, code: &str) -> String {
+    let mut data = Vec::with_capacity(language.len() + code.len() + 1);
+    data.extend_from_slice(language.as_bytes());
+    data.push(0);
+    data.extend_from_slice(code.as_bytes());
+    format!("{:016x}", fnv1a(&data))
+}
+
+/// Key that follows a block across edits when it has a name, and across moves
+/// within or between documents otherwise.
+pub fn block_identity(name: Option<&str>, language: &str, code: &str) -> String {
+    match name {
+        Some(name) => format!("name:{}", name),
+        None => block_key(language, code),
+    }
+}
+
+/// Hashes of a block's distinct non-blank lines, used to recognise a block
+/// after small edits to its code.
+pub fn line_sketch(code: &str) -> Vec<u32> {
+    let mut sketch: Vec<u32> = code
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| fnv1a(line.as_bytes()) as u32)
+        .collect();
+    sketch.sort_unstable();
+    sketch.dedup();
+    sketch.truncate(64);
+    sketch
+}
+
+/// Hex key for a cached block output. Besides the code it covers the command
+/// template and the interpreter version, so upgrading a toolchain or changing
+/// how a language is run invalidates earlier outputs.
+pub fn cache_key(language: &str, code: &str, template: &str, version: &str) -> String {
+    let mut data = Vec::new();
+    for part in [language, code, template, version] {
+        data.extend_from_slice(part.as_bytes());
+        data.push(0);
+    }
+    format!("{:016x}", fnv1a(&data))
+}
@@ -0,0 +1,406 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::hash::block_identity;
+use crate::report::Report;
+
+/// One block's result from one run, as kept in the history log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    /// Absolute path of the document
+    pub file: String,
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub language: String,
+    /// `hash::block_key` of the block's code
+    pub code: String,
+    pub success: bool,
+    pub cached: bool,
+    pub duration_ms: u64,
+    /// Hash of the block's output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// Identity that follows the block across edits: `name:<name>` for named
+    /// blocks, otherwise inherited from the run the block was matched to
+    #[serde(default)]
+    pub id: String,
+    /// `hash::line_sketch` of the code
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sketch: Vec<u32>,
+}
+
+impl Entry {
+    /// Older entries were recorded before identities and fall back to the code hash.
+    pub fn id(&self) -> &str {
+        if self.id.is_empty() {
+            &self.code
+        } else {
+            &self.id
+        }
+    }
+}
+
+/// Entries kept per document; the oldest beyond this are dropped, so a busy
+/// document can't push the history of others out of the log.
+const MAX_ENTRIES_PER_DOCUMENT: usize = 2_000;
+
+/// Entries a document may have beyond `MAX_ENTRIES_PER_DOCUMENT` before the log
+/// is pruned, so it is rewritten once in a while rather than on every run.
+const PRUNE_SLACK: usize = 500;
+
+/// Append-only log of block results across runs, one JSON object per line.
+/// Writers hold a lock on a file next to it, so concurrent runs don't lose entries.
+pub struct History;
+
+impl History {
+    pub fn path() -> Result<PathBuf> {
+        let data_dir = dirs::data_dir()
+            .context("Could not determine data directory")?
+            .join("runmd");
+
+        Ok(data_dir.join("history.jsonl"))
+    }
+
+    /// Entries for the blocks of `file` that ran according to `report`, with
+    /// identities carried over from `past` entries of the same document.
+    pub fn entries(file: &Path, report: &Report, past: &[Entry]) -> Vec<Entry> {
+        let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+        let file = file.to_string_lossy().to_string();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut entries: Vec<Entry> = report
+            .blocks
+            .iter()
+            .filter_map(|block| {
+                Some(Entry {
+                    time,
+                    file: file.clone(),
+                    line: block.line,
+                    name: block.name.clone(),
+                    language: block.language.clone(),
+                    code: block.code_key.clone(),
+                    success: block.success?,
+                    cached: block.cached,
+                    duration_ms: block.duration_ms.unwrap_or(0),
+                    output: block.output_key.clone(),
+                    id: String::new(),
+                    sketch: block.sketch.clone(),
+                })
+            })
+            .collect();
+
+        let past: Vec<&Entry> = past.iter().filter(|entry| entry.file == file).collect();
+        assign_ids(&mut entries, &past);
+        entries
+    }
+
+    /// Add `entries`, all from one document, to the log. `past` is what was loaded
+    /// before the run and decides whether the document has outgrown its share.
+    pub fn append(entries: &[Entry], past: &[Entry]) -> Result<()> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&crate::json::to_string(entry)?);
+            lines.push('\n');
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Pruning replaces the log, so the lock lives in a file that stays put
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))
+            .and_then(|lock| lock.lock().map(|_| lock))
+            .with_context(|| format!("Failed to lock history: {}", path.display()))?;
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut log| log.write_all(lines.as_bytes()))
+            .with_context(|| format!("Failed to write history: {}", path.display()))?;
+
+        let file = &entries[0].file;
+        let count = past.iter().filter(|entry| &entry.file == file).count() + entries.len();
+        let result = if count > MAX_ENTRIES_PER_DOCUMENT + PRUNE_SLACK {
+            Self::prune(&path)
+        } else {
+            Ok(())
+        };
+        drop(lock);
+        result
+    }
+
+    /// Keep only the newest `MAX_ENTRIES_PER_DOCUMENT` entries of each document,
+    /// replacing the log in one rename. Called with the lock held.
+    fn prune(path: &Path) -> Result<()> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read history: {}", path.display()))?;
+        let lines: Vec<&str> = content.split_inclusive('\n').collect();
+
+        // Walk from the newest entry back, counting each document's entries
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut keep = vec![true; lines.len()];
+        for (i, line) in lines.iter().enumerate().rev() {
+            if let Ok(entry) = serde_yaml::from_str::<Entry>(line) {
+                let count = counts.entry(entry.file).or_default();
+                *count += 1;
+                keep[i] = *count <= MAX_ENTRIES_PER_DOCUMENT;
+            }
+        }
+        if keep.iter().all(|&keep| keep) {
+            return Ok(());
+        }
+
+        let kept: String = lines
+            .iter()
+            .zip(&keep)
+            .filter(|(_, &keep)| keep)
+            .map(|(line, _)| *line)
+            .collect();
+        let temp = path.with_extension(format!("jsonl.{}", std::process::id()));
+        std::fs::write(&temp, kept)
+            .and_then(|_| std::fs::rename(&temp, path))
+            .with_context(|| format!("Failed to prune history: {}", path.display()))
+    }
+
+    /// Every recorded entry, oldest first. Unreadable lines are skipped.
+    pub fn load() -> Result<Vec<Entry>> {
+        let path = Self::path()?;
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read history: {}", path.display()))
+            }
+        };
+
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_yaml::from_str(line).ok())
+            .collect())
+    }
+}
+
+/// Share of distinct lines an edited block must keep to be recognised as the same block.
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// Give each new entry a stable identity. Named blocks are identified by their
+/// name. Unnamed blocks keep the identity of earlier runs of the same code, so
+/// moving them around the document doesn't matter, and otherwise of the most
+/// similar earlier block that no other block claimed, so small edits don't
+/// either. Anything else starts a new identity.
+fn assign_ids(entries: &mut [Entry], past: &[&Entry]) {
+    let mut claimed: Vec<String> = Vec::new();
+
+    for entry in entries.iter_mut() {
+        if entry.name.is_some() {
+            entry.id = block_identity(entry.name.as_deref(), &entry.language, &entry.code);
+        } else if let Some(previous) = past.iter().rev().find(|p| p.code == entry.code) {
+            entry.id = previous.id().to_string();
+        } else {
+            continue;
+        }
+        claimed.push(entry.id.clone());
+    }
+
+    for entry in entries.iter_mut().filter(|entry| entry.id.is_empty()) {
+        let best = past
+            .iter()
+            .rev()
+            .filter(|p| p.name.is_none() && p.language == entry.language)
+            .filter(|p| !claimed.iter().any(|id| id == p.id()))
+            .map(|p| (similarity(&p.sketch, &entry.sketch), p))
+            .filter(|(score, _)| *score >= MIN_SIMILARITY)
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+
+        entry.id = match best {
+            Some((_, previous)) => previous.id().to_string(),
+            None => entry.code.clone(),
+        };
+        claimed.push(entry.id.clone());
+    }
+}
+
+/// Jaccard similarity of two sorted line sketches.
+fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 0.0;
+    }
+    let shared = a.iter().filter(|hash| b.binary_search(hash).is_ok()).count();
+    shared as f64 / (a.len() + b.len() - shared) as f64
+}
+
+/// Recent executions of unchanged code considered when looking for flaky blocks.
+const FLAKY_WINDOW: usize = 10;
+
+/// Flag blocks of `report` whose recent results for the same code flip-flop: they
+/// went from passing to failing and back (or the reverse), or successful runs
+/// printed changing output more than once. `entries` must include this run of `file`.
+pub fn mark_flaky(entries: &[Entry], file: &Path, report: &mut Report) {
+    let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    let file = file.to_string_lossy();
+    for block in &mut report.blocks {
+        if block.success.is_none() {
+            continue;
+        }
+        let mut runs: Vec<&Entry> = entries
+            .iter()
+            .filter(|entry| entry.file == file && entry.code == block.code_key && !entry.cached)
+            .collect();
+        runs = runs.split_off(runs.len().saturating_sub(FLAKY_WINDOW));
+
+        let passed = runs.iter().filter(|run| run.success).count();
+        let status_flips = runs
+            .windows(2)
+            .filter(|pair| pair[0].success != pair[1].success)
+            .count();
+        let outputs: Vec<_> = runs
+            .iter()
+            .filter(|run| run.success)
+            .map(|run| &run.output)
+            .collect();
+        let output_flips = outputs.windows(2).filter(|pair| pair[0] != pair[1]).count();
+
+        block.flaky = if status_flips >= 2 {
+            Some(format!("passed {} of the last {} runs", passed, runs.len()))
+        } else if output_flips >= 2 {
+            Some(format!(
+                "output changed {} times in the last {} successful runs",
+                output_flips,
+                outputs.len()
+            ))
+        } else {
+            None
+        };
+    }
+}
+
+/// Which entries `runmd history` shows.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    /// Document, or directory of documents, the entries belong to
+    pub path: Option<PathBuf>,
+    pub failed_only: bool,
+    /// Only entries at most this old
+    pub since: Option<Duration>,
+    /// Only runs of the block with this name, or at this line in the latest run,
+    /// including runs from before it was edited or moved
+    pub block: Option<String>,
+}
+
+impl Query {
+    pub fn matches(&self, entry: &Entry, now: u64) -> bool {
+        if self.failed_only && entry.success {
+            return false;
+        }
+        if let Some(since) = self.since {
+            if entry.time + since.as_secs() < now {
+                return false;
+            }
+        }
+        match &self.path {
+            Some(path) => {
+                let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+                Path::new(&entry.file).starts_with(path)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Print the entries selected by `query`, oldest first.
+pub fn print(query: &Query) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let cwd = std::env::current_dir().unwrap_or_default();
+
+    let entries: Vec<Entry> = History::load()?
+        .into_iter()
+        .filter(|entry| query.matches(entry, now))
+        .collect();
+    let id = match &query.block {
+        Some(block) => {
+            let latest = entries.iter().rev().find(|entry| {
+                entry.name.as_deref() == Some(block.as_str()) || entry.line.to_string() == *block
+            });
+            match latest {
+                Some(entry) => Some(entry.id().to_string()),
+                None => anyhow::bail!("No recorded runs of block '{}'", block),
+            }
+        }
+        None => None,
+    };
+
+    for entry in entries
+        .iter()
+        .filter(|entry| id.as_ref().is_none_or(|id| entry.id() == id))
+    {
+        let file = Path::new(&entry.file);
+        let file = file.strip_prefix(&cwd).unwrap_or(file);
+        let status = if entry.success { "ok" } else { "FAILED" };
+        println!(
+            "{}  {}:{}  {}  {}{}  {:.2}s{}",
+            format_time(entry.time),
+            file.display(),
+            entry.line,
+            entry.language,
+            status,
+            entry
+                .name
+                .as_ref()
+                .map(|name| format!("  \"{}\"", name))
+                .unwrap_or_default(),
+            entry.duration_ms as f64 / 1000.0,
+            if entry.cached { "  (cached)" } else { "" }
+        );
+    }
+
+    Ok(())
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC.
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use crate::glob::glob_match;
+
+/// Files whose patterns exclude documents from directory runs.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".runmdignore"];
+
+/// One line of an ignore file.
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Directory of the file the rule came from; patterns are relative to it
+    base: PathBuf,
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    /// Patterns containing a `/` match the whole path from `base`, others
+    /// match the file name at any depth
+    anchored: bool,
+}
+
+/// `.gitignore` and `.runmdignore` patterns that apply to a directory.
+///
+/// This covers the parts of git's rules runmd needs, in place of the `ignore`
+/// crate, which the build does not have available: per-directory files,
+/// `.git/info/exclude`, negation, anchored and directory-only patterns, and `**`.
+/// The user's global `core.excludesFile` is not read.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Rules from the ignore files in `dir` and its parents, up to the root of
+    /// the git repository it is in. Outside a repository only `dir` is read.
+    pub fn for_dir(dir: &Path) -> Self {
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        let root = dir.ancestors().find(|d| d.join(".git").exists());
+        let dirs: Vec<&Path> = match root {
+            Some(root) => dir
+                .ancestors()
+                .take_while(|d| d.starts_with(root))
+                .collect(),
+            None => vec![dir.as_path()],
+        };
+
+        let mut rules = IgnoreRules::default();
+        if let Some(root) = root {
+            let root = root.to_path_buf();
+            if let Ok(content) = std::fs::read_to_string(root.join(".git/info/exclude")) {
+                rules.add(&root, &content);
+            }
+        }
+        // Outermost first, so rules closer to the documents win
+        for dir in dirs.into_iter().rev() {
+            rules.add_dir(dir);
+        }
+        rules
+    }
+
+    /// Add the rules of the ignore files directly inside `dir`.
+    pub fn add_dir(&mut self, dir: &Path) {
+        // Paths are compared canonicalized, so the rules' base must be as well
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        for name in IGNORE_FILES {
+            if let Ok(content) = std::fs::read_to_string(dir.join(name)) {
+                self.add(&dir, &content);
+            }
+        }
+    }
+
+    fn add(&mut self, base: &Path, content: &str) {
+        for line in content.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(rest) => (true, rest),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            self.rules.push(Rule {
+                base: base.to_path_buf(),
+                pattern: line.trim_start_matches('/').to_string(),
+                negated,
+                dir_only,
+                anchored,
+            });
+        }
+    }
+
+    /// Whether `path` is excluded. The last matching rule decides, so `!pattern`
+    /// re-includes what an earlier rule excluded.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.negated == ignored && rule.matches(&path, is_dir) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+impl Rule {
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        let segments: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+
+        if self.anchored {
+            let pattern: Vec<&str> = self.pattern.split('/').collect();
+            match_segments(&pattern, &segments)
+        } else {
+            segments
+                .last()
+                .is_some_and(|name| glob_match(&self.pattern, name))
+        }
+    }
+}
+
+/// Match path segments against pattern segments, where a `**` segment matches
+/// any number of directories.
+fn match_segments(pattern: &[&str], segments: &[String]) -> bool {
+    match pattern.split_first() {
+        None => segments.is_empty(),
+        Some((&"**", rest)) => {
+            (0..=segments.len()).any(|skip| match_segments(rest, &segments[skip..]))
+        }
+        Some((first, rest)) => segments
+            .split_first()
+            .is_some_and(|(name, others)| glob_match(first, name) && match_segments(rest, others)),
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_yaml::Value;
+
+/// Serialize `value` as compact single-line JSON. YAML remains the format of files
+/// runmd writes; JSON is for programs talking to it, and since JSON is valid YAML
+/// their requests are read with `serde_yaml`. This writer stands in for
+/// `serde_json`, which the build does not have available.
+pub fn to_string<T: Serialize>(value: &T) -> Result<String> {
+    let mut out = String::new();
+    write_value(&serde_yaml::to_value(value)?, &mut out);
+    Ok(out)
+}
+
+fn write_value(value: &Value, out: &mut String) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) if n.as_f64().is_some_and(|f| !f.is_finite()) => out.push_str("null"),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::String(s) => write_string(s, out),
+        Value::Sequence(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out);
+            }
+            out.push(']');
+        }
+        Value::Mapping(map) => {
+            out.push('{');
+            for (i, (key, item)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                match key {
+                    Value::String(key) => write_string(key, out),
+                    other => {
+                        let mut key = String::new();
+                        write_value(other, &mut key);
+                        write_string(key.trim_matches('"'), out);
+                    }
+                }
+                out.push(':');
+                write_value(item, out);
+            }
+            out.push('}');
+        }
+        Value::Tagged(tagged) => write_value(&tagged.value, out),
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
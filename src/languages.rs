@@ -1,24 +1,216 @@
 use std::collections::HashMap;
 use std::process::Command;
 
+use crate::config::InvokeMode;
+
+/// Flags that make an interpreter evaluate code given on the command line.
+const EVAL_FLAGS: &[(&str, &str)] = &[
+    ("python", "-c"),
+    ("python3", "-c"),
+    ("node", "-e"),
+    ("ruby", "-e"),
+    ("perl", "-e"),
+    ("bash", "-c"),
+    ("sh", "-c"),
+    ("zsh", "-c"),
+    ("php", "-r"),
+    ("lua", "-e"),
+    ("julia", "-e"),
+    ("Rscript", "-e"),
+];
+
+/// Arguments that make an interpreter read its program from standard input; an
+/// empty string means it does so without any.
+const STDIN_ARGS: &[(&str, &str)] = &[
+    ("python", "-"),
+    ("python3", "-"),
+    ("node", "-"),
+    ("ruby", "-"),
+    ("perl", "-"),
+    ("bash", "-s"),
+    ("sh", "-s"),
+    ("zsh", "-s"),
+    ("php", ""),
+    ("lua", "-"),
+    ("julia", ""),
+    ("Rscript", "-"),
+];
+
 #[derive(Clone)]
 pub struct Languages {
     pub mappings: HashMap<String, String>,
+    /// Alternative fence names mapped to the language they should run as
+    pub aliases: HashMap<String, String>,
+    /// Languages whose code is not passed through a temporary file
+    pub invoke: HashMap<String, InvokeMode>,
 }
 
 impl Languages {
-    pub fn new(mappings: HashMap<String, String>) -> Self {
-        Self { mappings }
+    pub fn new(
+        mappings: HashMap<String, String>,
+        aliases: HashMap<String, String>,
+        invoke: HashMap<String, InvokeMode>,
+    ) -> Self {
+        Self {
+            mappings,
+            aliases,
+            invoke,
+        }
+    }
+
+    pub fn invoke_mode(&self, language: &str) -> InvokeMode {
+        self.invoke.get(language).copied().unwrap_or_default()
+    }
+
+    /// The language a fence name runs as. A direct mapping wins over an alias,
+    /// and a versioned name such as `python3.12` runs as the language it versions.
+    pub fn resolve<'a>(&'a self, language: &'a str) -> &'a str {
+        if self.mappings.contains_key(language) {
+            return language;
+        }
+        if let Some(alias) = self.aliases.get(language) {
+            return alias;
+        }
+        self.versioned(language).unwrap_or(language)
+    }
+
+    /// The configured language `language` names a version of, like `python` for
+    /// `python3.12`, when it isn't configured or aliased itself.
+    pub fn versioned<'a>(&'a self, language: &'a str) -> Option<&'a str> {
+        let base = language.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+        let version = &language[base.len()..];
+        let configured =
+            self.mappings.contains_key(language) || self.aliases.contains_key(language);
+        if base.is_empty() || !version.contains('.') || configured {
+            return None;
+        }
+        let resolved = self.resolve(base);
+        self.mappings.contains_key(resolved).then_some(resolved)
     }
 
-    pub fn get_command(&self, language: &str, file_path: &str) -> Option<Vec<String>> {
+    /// Build the command for `language`, substituting `{file}` with the source file
+    /// and `{out}` with a scratch path for build artifacts.
+    pub fn get_command(
+        &self,
+        language: &str,
+        file_path: &str,
+        out_path: &str,
+    ) -> Option<Vec<String>> {
         self.mappings.get(language).map(|template| {
-            let command_str = template.replace("{file}", file_path);
+            let command_str = template
+                .replace("{file}", file_path)
+                .replace("{out}", out_path);
             shell_words::split(&command_str).unwrap_or_else(|_| vec![command_str])
         })
     }
 
-    pub fn check_dependency_exists(&self, command: &[String]) -> bool {
+    /// Build the command that passes `code` as an argument. A template without
+    /// `{code}` is adapted by replacing `{file}` with the interpreter's eval flag,
+    /// e.g. `python3 {file}` becomes `python3 -c {code}`; `None` means no flag is known.
+    pub fn get_arg_command(&self, language: &str, code: &str) -> Option<Vec<String>> {
+        let template = self.mappings.get(language)?;
+        let mut parts = shell_words::split(template).ok()?;
+
+        if !template.contains("{code}") {
+            let program = parts.first()?.rsplit('/').next()?;
+            let (_, flag) = EVAL_FLAGS.iter().find(|(name, _)| *name == program)?;
+            let file = parts.iter().position(|part| part == "{file}")?;
+            parts.splice(file..=file, [flag.to_string(), "{code}".to_string()]);
+        }
+
+        Some(
+            parts
+                .into_iter()
+                .map(|part| part.replace("{code}", code))
+                .collect(),
+        )
+    }
+
+    /// Build the command that reads the program from standard input. A template
+    /// without `{file}` is used as is; otherwise `{file}` is replaced with the
+    /// interpreter's stdin argument, e.g. `bash {file}` becomes `bash -s`.
+    pub fn get_stdin_command(&self, language: &str) -> Option<Vec<String>> {
+        let template = self.mappings.get(language)?;
+        let mut parts = shell_words::split(template).ok()?;
+
+        if let Some(file) = parts.iter().position(|part| part == "{file}") {
+            let program = parts.first()?.rsplit('/').next()?;
+            let (_, arg) = STDIN_ARGS.iter().find(|(name, _)| *name == program)?;
+            if arg.is_empty() {
+                parts.remove(file);
+            } else {
+                parts[file] = arg.to_string();
+            }
+        } else if template.contains("{file}") {
+            return None;
+        }
+
+        Some(parts)
+    }
+
+    /// The command template configured for `language`, after alias resolution.
+    pub fn template(&self, language: &str) -> Option<&str> {
+        self.mappings.get(self.resolve(language)).map(String::as_str)
+    }
+
+    /// The program that actually interprets or compiles `language`, looking inside
+    /// `sh -c '...'` wrappers.
+    pub fn interpreter(&self, language: &str) -> Option<String> {
+        let parts = shell_words::split(self.template(language)?).ok()?;
+
+        match parts.as_slice() {
+            [shell, flag, script, ..] if (shell == "sh" || shell == "bash") && flag == "-c" => {
+                shell_words::split(script).ok()?.into_iter().next()
+            }
+            [program, ..] => Some(program.clone()),
+            [] => None,
+        }
+    }
+
+    /// A copy in which `language` runs under `program` instead of its interpreter,
+    /// e.g. `python3.11 {file}` for `python3 {file}`.
+    pub fn with_interpreter(&self, language: &str, program: &str) -> Languages {
+        let mut languages = self.clone();
+        let language = self.resolve(language);
+        if let (Some(interpreter), Some(template)) =
+            (self.interpreter(language), languages.mappings.get_mut(language))
+        {
+            *template = template.replacen(&interpreter, program, 1);
+        }
+        languages
+    }
+
+    /// A copy in which `language` runs `command` instead of its configured command,
+    /// whether or not it has one.
+    pub fn with_command(&self, language: &str, command: &str) -> Languages {
+        let mut languages = self.clone();
+        languages
+            .mappings
+            .insert(self.resolve(language).to_string(), command.to_string());
+        languages
+    }
+
+    /// Ask the interpreter for `language` for its version, e.g. `Python 3.12.1`.
+    #[cfg(feature = "native")]
+    pub async fn version(&self, language: &str) -> Option<String> {
+        let interpreter = self.interpreter(language)?;
+        let output = tokio::process::Command::new(interpreter)
+            .arg("--version")
+            .output()
+            .await
+            .ok()?;
+
+        // Some tools print their version on stderr
+        let text = if output.stdout.is_empty() {
+            output.stderr
+        } else {
+            output.stdout
+        };
+        Some(String::from_utf8_lossy(&text).trim().to_string())
+    }
+
+    /// Whether the program of `command` can be found, on `path` if given.
+    pub fn check_dependency_exists(&self, command: &[String], path: Option<&str>) -> bool {
         if command.is_empty() {
             return false;
         }
@@ -31,44 +223,14 @@ impl Languages {
         }
 
         // Check if the command exists using 'which'
-        Command::new("which")
+        let mut which = Command::new("which");
+        if let Some(path) = path {
+            which.env("PATH", path);
+        }
+        which
             .arg(base_cmd)
             .output()
             .map(|output| output.status.success())
             .unwrap_or(false)
     }
 }
-
-// Simple shell word splitting - for more complex cases, use the shell-words crate
-mod shell_words {
-    pub fn split(input: &str) -> Result<Vec<String>, ()> {
-        let mut words = Vec::new();
-        let mut current_word = String::new();
-        let mut in_quotes = false;
-        let mut escape_next = false;
-
-        for ch in input.chars() {
-            if escape_next {
-                current_word.push(ch);
-                escape_next = false;
-            } else if ch == '\\' {
-                escape_next = true;
-            } else if ch == '"' {
-                in_quotes = !in_quotes;
-            } else if ch.is_whitespace() && !in_quotes {
-                if !current_word.is_empty() {
-                    words.push(current_word.clone());
-                    current_word.clear();
-                }
-            } else {
-                current_word.push(ch);
-            }
-        }
-
-        if !current_word.is_empty() {
-            words.push(current_word);
-        }
-
-        Ok(words)
-    }
-}
@@ -0,0 +1,67 @@
+//! Run code blocks inside Markdown files and insert their outputs inline.
+//!
+//! Parsing, clearing and rendering are always available. Executing blocks as
+//! local processes needs the default `native` feature; other hosts, such as a
+//! web playground built for wasm32, implement [`executor::Executor`] and call
+//! [`core::process_with`] instead. [`ffi`] exposes the same entry points to C
+//! and, through `bindings/python`, to Python.
+
+#[cfg(feature = "native")]
+pub mod attach;
+pub mod attrs;
+#[cfg(feature = "native")]
+pub mod bench;
+#[cfg(feature = "native")]
+pub mod cache;
+#[cfg(feature = "native")]
+pub mod cast;
+pub mod config;
+#[cfg(feature = "native")]
+pub mod container;
+pub mod core;
+pub mod coverage;
+pub mod deps;
+pub mod detect;
+#[cfg(feature = "native")]
+pub mod daemon;
+pub mod diffstat;
+#[cfg(feature = "native")]
+pub mod disk;
+pub mod embed;
+pub mod executor;
+pub mod ffi;
+pub mod filters;
+pub mod frontmatter;
+pub mod glob;
+pub mod hash;
+pub mod history;
+pub mod ignore;
+pub mod json;
+pub mod languages;
+#[cfg(feature = "native")]
+pub mod limits;
+pub mod manifest;
+#[cfg(feature = "native")]
+pub mod metrics;
+pub mod nesting;
+#[cfg(feature = "native")]
+pub mod progress;
+#[cfg(feature = "native")]
+pub mod pty;
+pub mod report;
+#[cfg(feature = "native")]
+pub mod runner;
+#[cfg(feature = "native")]
+pub mod serve;
+pub mod scaffold;
+pub mod scan;
+pub mod schema;
+pub mod select;
+pub mod summary;
+pub mod timings;
+#[cfg(feature = "native")]
+pub mod toolchain;
+#[cfg(feature = "native")]
+pub mod wizard;
+#[cfg(feature = "native")]
+pub mod workspace;
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::ServiceConfig;
+
+/// Why a request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The client sent more than `rate_limit` requests in the last minute
+    RateLimited,
+    /// `max_concurrent` requests are running and `queue_size` more are waiting
+    QueueFull,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::RateLimited => write!(f, "Rate limit exceeded"),
+            Rejection::QueueFull => write!(f, "Too many requests queued"),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Admission control for service requests: a per-client token bucket in front of a
+/// bounded queue for a fixed number of execution slots.
+pub struct Limiter {
+    slots: Semaphore,
+    /// Requests holding or waiting for a slot
+    admitted: AtomicUsize,
+    capacity: usize,
+    rate_limit: Option<u32>,
+    buckets: Mutex<Buckets>,
+}
+
+/// Time an empty bucket takes to refill completely.
+const REFILL: Duration = Duration::from_secs(60);
+
+struct Buckets {
+    clients: HashMap<String, Bucket>,
+    /// When buckets of clients that went quiet were last dropped
+    swept: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A request's execution slot, released when dropped.
+pub struct Permit<'a> {
+    _slot: SemaphorePermit<'a>,
+    admitted: &'a AtomicUsize,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.admitted.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Limiter {
+    pub fn new(config: &ServiceConfig) -> Self {
+        let slots = config.max_concurrent.max(1);
+        Limiter {
+            slots: Semaphore::new(slots),
+            admitted: AtomicUsize::new(0),
+            capacity: slots + config.queue_size,
+            rate_limit: config.rate_limit,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait for an execution slot on behalf of `client`, or refuse the request
+    /// straight away when the client is over its rate or the queue is full.
+    pub async fn acquire(&self, client: &str) -> Result<Permit<'_>, Rejection> {
+        if !self.take_token(client) {
+            return Err(Rejection::RateLimited);
+        }
+
+        let admitted = self.admitted.fetch_add(1, Ordering::SeqCst);
+        if admitted >= self.capacity {
+            self.admitted.fetch_sub(1, Ordering::SeqCst);
+            return Err(Rejection::QueueFull);
+        }
+
+        let slot = self
+            .slots
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        Ok(Permit {
+            _slot: slot,
+            admitted: &self.admitted,
+        })
+    }
+
+    /// Buckets hold up to a minute's worth of requests and refill continuously.
+    /// A bucket left alone for that long is full, the same as a new one, so
+    /// once a minute those are dropped and the map only holds recent clients.
+    fn take_token(&self, client: &str) -> bool {
+        let Some(rate) = self.rate_limit else {
+            return true;
+        };
+        let rate = f64::from(rate);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.swept) >= REFILL {
+            buckets
+                .clients
+                .retain(|_, bucket| now.duration_since(bucket.updated) < REFILL);
+            buckets.swept = now;
+        }
+        let bucket = buckets.clients.entry(client.to_string()).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate / REFILL.as_secs_f64()).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -1,77 +1,1530 @@
 use anyhow::Result;
 use clap::{Arg, Command};
-use std::path::PathBuf;
+use colored::Colorize;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-mod config;
-mod core;
-mod languages;
-mod runner;
+use runmd::attrs::parse_duration;
+use runmd::bench;
+use runmd::config::{Config, ParallelMode};
+use runmd::diffstat::DiffStat;
+use runmd::embed::{self, Embedded, Format};
+use runmd::frontmatter::document_options;
+use runmd::glob::glob_match;
+use runmd::ignore::IgnoreRules;
+use runmd::history::{self, History, Query};
+use runmd::manifest::{Job, Manifest};
+use runmd::select::Selector;
+use runmd::{coverage, daemon, nesting, runner, scaffold, schema, serve, wizard};
+use runmd::attach::Attached;
+use runmd::core::{
+    clear_outputs, find_orphaned_outputs, runnable_blocks_by_language, process_markdown,
+    process_with, RunContext,
+};
+use runmd::report::{Report, ReportTarget};
 
-use crate::config::Config;
-use crate::core::{clear_outputs, process_markdown};
+fn main() -> Result<()> {
+    // Blocks inherit runmd's environment, so the `serve` token leaves it before
+    // the runtime starts any threads that could be reading the environment
+    let token = std::env::var(serve::TOKEN_VAR)
+        .ok()
+        .filter(|t| !t.is_empty());
+    std::env::remove_var(serve::TOKEN_VAR);
 
-#[tokio::main]
-async fn main() -> Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(async {
+        let mut run = std::pin::pin!(run(token));
+        let code = tokio::select! {
+            result = run.as_mut() => return result,
+            code = runner::interrupted() => code,
+        };
+        // `run` stays alive but unpolled, so no outputs are written for the
+        // interrupted blocks while they are stopped
+        runner::stop_blocks().await;
+        std::process::exit(code)
+    })
+}
+
+async fn run(token: Option<String>) -> Result<()> {
     let matches = Command::new("runmd")
         .version("0.2.0")
         .about("Run code blocks inside Markdown files and insert their outputs inline")
         .arg(
             Arg::new("file")
-                .help("Markdown file to process")
-                .required_unless_present("init-config")
+                .help("Markdown files, directories of Markdown files or patterns such as docs/*.md to process")
+                .required_unless_present_any(["init-config", "manifest", "bench-internal"])
+                .num_args(1..)
                 .index(1),
         )
         .arg(
             Arg::new("clear")
                 .short('c')
                 .long("clear")
-                .help("Clear outputs only")
+                .help("Clear outputs only (same as `runmd clear`)")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("lint")
+                .long("lint")
+                .help("Report problems such as orphaned output blocks without running or changing anything (same as `runmd check --lint-only`)")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("clear"),
+        )
         .arg(
             Arg::new("init-config")
                 .long("init-config")
-                .help("Create ~/.config/runmd/languages.config with sensible defaults")
+                .help("Create ~/.config/runmd/languages.config, asking which languages and settings to use when run in a terminal")
                 .action(clap::ArgAction::SetTrue),
         )
         .arg(
-            Arg::new("parallel")
-                .short('p')
-                .long("parallel")
-                .help("Force parallel execution when more than one runnable code block present")
-                .action(clap::ArgAction::SetTrue),
+            Arg::new("bench-internal")
+                .long("bench-internal")
+                .help("Time parsing, clearing and rendering a synthetic document instead of processing files")
+                .action(clap::ArgAction::SetTrue)
+                .hide(true),
+        )
+        .arg(
+            Arg::new("bench-blocks")
+                .long("bench-blocks")
+                .value_name("N")
+                .help("Blocks in the --bench-internal document")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("10000")
+                .requires("bench-internal")
+                .hide(true),
+        )
+        .arg(
+            Arg::new("bench-baseline")
+                .long("bench-baseline")
+                .value_name("PATH")
+                .help("Fail when a stage is more than 25% slower than the timings saved in PATH")
+                .requires("bench-internal")
+                .hide(true),
+        )
+        .arg(
+            Arg::new("bench-save")
+                .long("bench-save")
+                .value_name("PATH")
+                .help("Save the --bench-internal timings as a baseline")
+                .requires("bench-internal")
+                .hide(true),
+        )
+        .args(document_args())
+        .args(output_args().map(|arg| arg.conflicts_with_all(["clear", "lint"])))
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("run")
+                .about("Run code blocks and write their outputs into the documents (the default)")
+                .arg(file_arg())
+                .args(output_args()),
+        )
+        .subcommand(
+            Command::new("clear")
+                .about("Remove outputs without running anything")
+                .arg(file_arg()),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Run code blocks without writing, failing if a block fails or an output is out of date")
+                .arg(file_arg())
+                .arg(
+                    Arg::new("lint-only")
+                        .long("lint-only")
+                        .help("Only report problems found without running blocks, such as orphaned outputs")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Run code blocks, then run them again whenever a document changes")
+                .arg(file_arg()),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Run code blocks and write the result elsewhere, leaving the document unchanged")
+                .arg(file_arg())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("PATH")
+                        .help("Write the result to PATH instead of stdout"),
+                ),
+        )
+        .subcommand(
+            Command::new("coverage")
+                .about("List code blocks that never execute and why, without running anything")
+                .arg(file_arg())
+                .arg(
+                    Arg::new("fail-under")
+                        .long("fail-under")
+                        .value_name("PERCENT")
+                        .value_parser(clap::value_parser!(f64))
+                        .help("Fail if fewer than PERCENT of the blocks execute"),
+                ),
+        )
+        .subcommand(
+            Command::new("new")
+                .about("Create a Markdown document with front matter, a setup block and example blocks")
+                .arg(
+                    Arg::new("file")
+                        .help("Markdown file to create")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("lang")
+                        .long("lang")
+                        .value_name("LANGS")
+                        .value_delimiter(',')
+                        .help("Languages to add an example block for, e.g. python,bash (default: bash)"),
+                )
+                .arg(
+                    Arg::new("title")
+                        .long("title")
+                        .value_name("TITLE")
+                        .help("Document title (default: from the file name)"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Replace the file if it exists")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the configuration")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("schema")
+                        .about("Print the effective configuration with a comment describing every key"),
+                ),
+        )
+        .subcommand(
+            Command::new("daemon")
+                .about("Serve process, clear and run-block requests on a Unix socket")
+                .arg(
+                    Arg::new("socket")
+                        .long("socket")
+                        .value_name("PATH")
+                        .help("Socket to listen on (default: $XDG_RUNTIME_DIR/runmd.sock)"),
+                )
+                .arg(
+                    Arg::new("metrics")
+                        .long("metrics")
+                        .value_name("ADDR")
+                        .help("Also serve Prometheus metrics over HTTP, e.g. 127.0.0.1:9184"),
+                ),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Process Markdown documents submitted over HTTP")
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("8080")
+                        .value_parser(clap::value_parser!(u16))
+                        .help("Port to listen on"),
+                )
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .value_name("ADDR")
+                        .default_value("127.0.0.1")
+                        .help("Address to listen on"),
+                )
+                .arg(
+                    Arg::new("no-auth")
+                        .long("no-auth")
+                        .help("Accept requests without a token when RUNMD_TOKEN is not set")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dangerously-run-on-host")
+                        .long("dangerously-run-on-host")
+                        .help("Run submitted documents directly on this machine when service.image is not set")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("Show results of earlier runs, oldest first")
+                .arg(
+                    Arg::new("file")
+                        .help("Only runs of this document, or of documents in this directory")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("failed")
+                        .long("failed")
+                        .help("Only show blocks that failed")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("DURATION")
+                        .help("Only show runs within DURATION, e.g. 12h or 7d"),
+                )
+                .arg(
+                    Arg::new("block")
+                        .long("block")
+                        .value_name("NAME|LINE")
+                        .help("Only show runs of one block, following it across edits and moves"),
+                ),
         )
         .get_matches();
 
     if matches.get_flag("init-config") {
         let config_path = Config::default_config_path()?;
+        // Scripts get every default; people get asked what they have and want
+        if std::io::stdin().is_terminal() {
+            return wizard::run(&config_path);
+        }
         Config::write_default_config(&config_path)?;
         println!("Wrote default config to {}", config_path.display());
         return Ok(());
     }
 
-    let file_path = matches
-        .get_one::<String>("file")
-        .map(PathBuf::from)
-        .unwrap();
+    if matches.get_flag("bench-internal") {
+        return bench_internal(&matches);
+    }
+
+    let mut config = Config::load()?;
+    if matches.get_flag("parallel") {
+        config.parallel = ParallelMode::On;
+    }
+    if let Some(patterns) = matches.get_many::<String>("skip-section") {
+        config.skip_sections.extend(patterns.cloned());
+    }
+    if matches.get_flag("ordered") {
+        config.ordered = true;
+    }
+    if matches.get_flag("explicit-run") {
+        config.explicit_run = true;
+    }
+    if matches.get_flag("hermetic") {
+        config.hermetic = true;
+    }
+    if matches.get_flag("keep-temp") {
+        config.keep_temp = true;
+    }
+    if matches.get_flag("cache") {
+        config.cache.enabled = true;
+    }
+    if matches.get_flag("no-cache") {
+        config.cache.enabled = false;
+    }
+    if let Some(selectors) = matches.get_many::<String>("rerun") {
+        config.cache.rerun.extend(selectors.cloned());
+    }
+    if let Some(tags) = matches.get_many::<String>("tags") {
+        config.tags = Some(tags.cloned().collect());
+    }
+    if let Some(tags) = matches.get_many::<String>("exclude-tags") {
+        config.exclude_tags.extend(tags.cloned());
+    }
+    if matches.get_flag("allow-dangerous") {
+        config.scan.allow_dangerous = true;
+    }
+    if let Some(languages) = matches.get_many::<String>("allow-lang") {
+        config.allowed_languages = Some(languages.cloned().collect());
+    }
+
+    if let Some(("config", _)) = matches.subcommand() {
+        print!("{}", schema::describe(&config)?);
+        return Ok(());
+    }
+
+    if let Some(("new", new)) = matches.subcommand() {
+        let path = PathBuf::from(new.get_one::<String>("file").unwrap());
+        if path.exists() && !new.get_flag("force") {
+            anyhow::bail!("{} exists; pass --force to replace it", path.display());
+        }
+        let languages: Vec<String> = match new.get_many::<String>("lang") {
+            Some(languages) => languages.cloned().collect(),
+            None => scaffold::DEFAULT_LANGUAGES
+                .iter()
+                .map(|language| language.to_string())
+                .collect(),
+        };
+        let title = match new.get_one::<String>("title") {
+            Some(title) => title.clone(),
+            None => scaffold::title_from_stem(
+                &path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy())
+                    .unwrap_or_default(),
+            ),
+        };
+        let content = scaffold::template(&title, &languages, &config)?;
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, content)?;
+        println!("Created {}", path.display());
+        return Ok(());
+    }
 
-    let content = std::fs::read_to_string(&file_path)?;
+    if let Some(("daemon", daemon)) = matches.subcommand() {
+        let socket = daemon
+            .get_one::<String>("socket")
+            .map(PathBuf::from)
+            .unwrap_or_else(daemon::default_socket_path);
+        let metrics = daemon.get_one::<String>("metrics").map(String::as_str);
+        return daemon::serve(config, &socket, metrics).await;
+    }
+
+    if let Some(("history", history)) = matches.subcommand() {
+        let query = Query {
+            path: history.get_one::<String>("file").map(PathBuf::from),
+            failed_only: history.get_flag("failed"),
+            since: history
+                .get_one::<String>("since")
+                .map(|since| parse_duration(since).map_err(anyhow::Error::msg))
+                .transpose()?,
+            block: history.get_one::<String>("block").cloned(),
+        };
+        return history::print(&query);
+    }
+
+    if let Some(("coverage", args)) = matches.subcommand() {
+        let paths = document_paths(args)?;
+        let percent = print_coverage(&Documents::find_all(&paths, &matches)?, &config)?;
+        if let Some(&minimum) = args.get_one::<f64>("fail-under") {
+            if percent < minimum {
+                anyhow::bail!(
+                    "Only {:.0}% of blocks execute, less than --fail-under {}",
+                    percent,
+                    minimum
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(("serve", serve)) = matches.subcommand() {
+        if token.is_none() && !serve.get_flag("no-auth") {
+            anyhow::bail!(
+                "Set {} to the token clients must send, or pass --no-auth",
+                serve::TOKEN_VAR
+            );
+        }
+        if config.service.image.is_none() && !serve.get_flag("dangerously-run-on-host") {
+            anyhow::bail!(
+                "Set service.image so submitted documents run in a container, or pass \
+                 --dangerously-run-on-host to run them directly on this machine"
+            );
+        }
+
+        let addr = format!(
+            "{}:{}",
+            serve.get_one::<String>("bind").unwrap(),
+            serve.get_one::<u16>("port").unwrap()
+        );
+        return serve::serve(config, &addr, token).await;
+    }
 
-    let force_parallel = matches.get_flag("parallel");
+    let (mode, args) = match matches.subcommand() {
+        Some(("run", args)) => (run_mode(args), args),
+        Some(("clear", args)) => (Mode::Clear, args),
+        Some(("check", args)) => (
+            Mode::Check {
+                lint_only: args.get_flag("lint-only"),
+            },
+            args,
+        ),
+        Some(("watch", args)) => (Mode::Watch, args),
+        Some(("export", args)) => (
+            Mode::Export {
+                output: args.get_one::<String>("output").map(PathBuf::from),
+            },
+            args,
+        ),
+        // Legacy interface: `runmd file.md`, `runmd -c file.md` and `runmd --lint file.md`
+        _ if matches.get_flag("clear") => (Mode::Clear, &matches),
+        _ if matches.get_flag("lint") => (Mode::Check { lint_only: true }, &matches),
+        _ => (run_mode(&matches), &matches),
+    };
+    if let Some(manifest) = matches.get_one::<String>("manifest") {
+        if !matches!(mode, Mode::Run | Mode::Check { lint_only: false }) {
+            anyhow::bail!("--manifest works with run and check only");
+        }
+        return run_manifest(Path::new(manifest), &mode, &config, &matches).await;
+    }
+    let paths = document_paths(args)?;
+
+    match mode {
+        Mode::Clear => {
+            let documents = Documents::find_all(&paths, &matches)?;
+            for file_path in documents.iter().flat_map(Documents::all) {
+                let document = read_document(file_path)?;
+                let cleared = clear_outputs(document.markdown(), &config)?;
+                std::fs::write(file_path, document.restore(&cleared, &config)?)?;
+                println!("Cleared outputs in {}", file_path.display());
+            }
+            Ok(())
+        }
+        Mode::Check { lint_only: true } => {
+            let problems = lint(&Documents::find_all(&paths, &matches)?, &config)?;
+            if !problems.is_empty() {
+                anyhow::bail!("Found {}", problems.reasons().join("; "));
+            }
+            Ok(())
+        }
+        Mode::Watch => watch(&paths, &config, &matches).await,
+        _ => process_all(&paths, &mode, &config, &matches)
+            .await?
+            .finish(&paths, &matches),
+    }
+}
+
+/// What to do with the documents named on the command line.
+#[derive(Debug, Clone, PartialEq)]
+enum Mode {
+    /// Run blocks and write their outputs into the documents
+    Run,
+    /// Remove outputs without running anything
+    Clear,
+    /// Run blocks without writing, failing if a block fails or an output is out of
+    /// date. With `lint_only`, only look for problems that don't need running blocks
+    Check { lint_only: bool },
+    /// Run, then run again whenever a document changes
+    Watch,
+    /// Run and write the result to `output`, or stdout, leaving the document unchanged
+    Export { output: Option<PathBuf> },
+}
+
+/// Running leaves the document unchanged when its result goes to stdout or
+/// another file, as with export.
+fn run_mode(args: &clap::ArgMatches) -> Mode {
+    match args.get_one::<String>("output") {
+        Some(output) => Mode::Export {
+            output: Some(PathBuf::from(output)),
+        },
+        None if args.get_flag("stdout") => Mode::Export { output: None },
+        None => Mode::Run,
+    }
+}
+
+/// The documents a path on the command line stands for.
+struct Documents {
+    /// Directory blocks run relative to
+    base_dir: PathBuf,
+    /// Document run first when processing a directory
+    setup: Option<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl Documents {
+    /// A directory stands for every Markdown file in it, or with --recursive in
+    /// it and below it, after its setup document. --include and --exclude narrow
+    /// down the files.
+    fn find(path: &Path, matches: &clap::ArgMatches) -> Result<Self> {
+        if path.is_dir() {
+            let no_ignore = matches.get_flag("no-ignore");
+            let ignore = if no_ignore {
+                IgnoreRules::default()
+            } else {
+                IgnoreRules::for_dir(path)
+            };
+            let mut files = if matches.get_flag("recursive") {
+                markdown_tree(path, &ignore, no_ignore)?
+            } else {
+                markdown_files(path, &ignore)?
+            };
+            let patterns = |name: &str| -> Vec<&String> {
+                matches
+                    .get_many::<String>(name)
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            };
+            let (include, exclude) = (patterns("include"), patterns("exclude"));
+            files.retain(|file| {
+                let relative = file.strip_prefix(path).unwrap_or(file);
+                (include.is_empty() || include.iter().any(|p| path_matches(p, relative)))
+                    && !exclude.iter().any(|p| path_matches(p, relative))
+            });
+            return Ok(Documents {
+                base_dir: path.to_path_buf(),
+                setup: Some(path.join(SETUP_FILE)).filter(|p| p.is_file()),
+                files,
+            });
+        }
+
+        let base_dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Ok(Documents {
+            base_dir,
+            setup: None,
+            files: vec![path.to_path_buf()],
+        })
+    }
+
+    /// The documents of each path, in order.
+    fn find_all(paths: &[PathBuf], matches: &clap::ArgMatches) -> Result<Vec<Self>> {
+        paths
+            .iter()
+            .map(|path| Documents::find(path, matches))
+            .collect()
+    }
+
+    fn all(&self) -> impl Iterator<Item = &PathBuf> {
+        self.setup.iter().chain(&self.files)
+    }
+}
+
+/// Problems found in documents without running their blocks.
+#[derive(Debug, Default)]
+struct Lint {
+    orphaned: usize,
+    /// Documents with more runnable blocks than their `max_blocks` budget
+    over_budget: Vec<String>,
+}
+
+impl Lint {
+    fn is_empty(&self) -> bool {
+        self.orphaned == 0 && self.over_budget.is_empty()
+    }
+
+    fn reasons(&self) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if self.orphaned > 0 {
+            reasons.push(format!("{} orphaned output block(s)", self.orphaned));
+        }
+        if !self.over_budget.is_empty() {
+            reasons.push(format!(
+                "more blocks than max_blocks allows in {}",
+                self.over_budget.join(", ")
+            ));
+        }
+        reasons
+    }
+}
+
+/// The `max_blocks` and `max_block_time` budgets of a document: those in its
+/// front matter, or else the config's.
+fn budgets(markdown: &str, config: &Config) -> Result<(Option<usize>, Option<Duration>)> {
+    let options = document_options(markdown)?;
+    let max_block_time = options
+        .max_block_time
+        .or_else(|| config.max_block_time.clone())
+        .map(|time| {
+            parse_duration(&time).map_err(|e| anyhow::anyhow!("Invalid max_block_time: {}", e))
+        })
+        .transpose()?;
+    Ok((options.max_blocks.or(config.max_blocks), max_block_time))
+}
+
+/// Print problems that can be found without running blocks.
+fn lint(documents: &[Documents], config: &Config) -> Result<Lint> {
+    let mut problems = Lint::default();
+    for file_path in documents.iter().flat_map(Documents::all) {
+        let document = read_document(file_path)?;
+        for line in find_orphaned_outputs(document.markdown(), config) {
+            println!(
+                "{}:{}: output not attached to a runnable block",
+                file_path.display(),
+                line
+            );
+            problems.orphaned += 1;
+        }
+
+        if let (Some(max_blocks), _) = budgets(document.markdown(), config)? {
+            let runnable = coverage::blocks(document.markdown(), config)?
+                .iter()
+                .filter(|block| block.not_run.is_none())
+                .count();
+            if runnable > max_blocks {
+                println!(
+                    "{}: {} runnable blocks, over the max_blocks budget of {}",
+                    file_path.display(),
+                    runnable,
+                    max_blocks
+                );
+                problems.over_budget.push(file_path.display().to_string());
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Print the blocks of the documents that would not execute, with the reason, and
+/// how many blocks execute per document and overall. Returns the overall share.
+fn print_coverage(documents: &[Documents], config: &Config) -> Result<f64> {
+    let mut total = 0;
+    let mut executed = 0;
+    let mut reasons: Vec<(String, usize)> = Vec::new();
+    for file_path in documents.iter().flat_map(Documents::all) {
+        let document = read_document(file_path)?;
+        let blocks = coverage::blocks(document.markdown(), config)?;
+        let mut document_executed = 0;
+        for block in &blocks {
+            let Some(not_run) = &block.not_run else {
+                document_executed += 1;
+                continue;
+            };
+            println!(
+                "{}:{}: {} block not executed: {}",
+                file_path.display(),
+                block.line,
+                block.language,
+                not_run
+            );
+            let reason = not_run.to_string();
+            match reasons.iter_mut().find(|(r, _)| *r == reason) {
+                Some((_, count)) => *count += 1,
+                None => reasons.push((reason, 1)),
+            }
+        }
+        println!(
+            "{}: {} of {} blocks executed ({:.0}%)",
+            file_path.display(),
+            document_executed,
+            blocks.len(),
+            coverage::percent(document_executed, blocks.len())
+        );
+        total += blocks.len();
+        executed += document_executed;
+    }
+
+    let percent = coverage::percent(executed, total);
+    println!(
+        "Total: {} of {} blocks executed ({:.0}%)",
+        executed, total, percent
+    );
+    for (reason, count) in reasons {
+        println!("  {} {}", count, reason);
+    }
+    Ok(percent)
+}
+
+/// Reports of the documents a run processed and, when it should fail, why.
+#[derive(Default)]
+struct Outcome {
+    reports: Vec<(String, Report)>,
+    failure: Option<String>,
+}
+
+impl Outcome {
+    fn failed(reports: Vec<(String, Report)>, failure: String) -> Self {
+        Outcome {
+            reports,
+            failure: Some(failure),
+        }
+    }
+
+    /// Write the reports asked for with --report and fail if the run did.
+    fn finish(self, paths: &[PathBuf], matches: &clap::ArgMatches) -> Result<()> {
+        let single = matches!(paths, [path] if !path.is_dir());
+        write_reports(&self.reports, &report_targets(matches)?, single)?;
+        match self.failure {
+            Some(failure) => Err(anyhow::Error::msg(failure)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The reports asked for with --report.
+fn report_targets(matches: &clap::ArgMatches) -> Result<Vec<ReportTarget>> {
+    matches
+        .get_many::<String>("report")
+        .into_iter()
+        .flatten()
+        .map(|value| ReportTarget::parse(value))
+        .collect()
+}
+
+/// Write `reports` to each target. With `single`, the YAML report holds the one
+/// document's report rather than a mapping keyed by file.
+fn write_reports(
+    reports: &[(String, Report)],
+    targets: &[ReportTarget],
+    single: bool,
+) -> Result<()> {
+    for target in targets {
+        match target {
+            ReportTarget::Yaml(path) if single => {
+                if let Some((_, report)) = reports.first() {
+                    report.write(path)?;
+                }
+            }
+            ReportTarget::Yaml(path) => Report::write_all(reports, path)?,
+            ReportTarget::Digest(None) => print!("\n{}", Report::digest(reports)),
+            ReportTarget::Digest(Some(path)) => std::fs::write(path, Report::digest(reports))?,
+        }
+    }
+    Ok(())
+}
 
-    let result = if matches.get_flag("clear") {
-        clear_outputs(&content)?
+/// Process every job of the manifest at `path` as one run with a combined report,
+/// failing at the end if any job failed.
+async fn run_manifest(
+    path: &Path,
+    mode: &Mode,
+    config: &Config,
+    matches: &clap::ArgMatches,
+) -> Result<()> {
+    let manifest = Manifest::load(path)?;
+    let mut reports = Vec::new();
+    let mut failures = Vec::new();
+    for job in &manifest.jobs {
+        let config = manifest.config_for(job, config);
+        let outcome = process(&job.file, mode, &config, matches, Some(job)).await?;
+        reports.extend(outcome.reports);
+        failures.extend(outcome.failure);
+    }
+
+    let mut targets = report_targets(matches)?;
+    if targets.is_empty() {
+        targets.extend(manifest.report.map(ReportTarget::Yaml));
+    }
+    write_reports(&reports, &targets, false)?;
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "{} of {} jobs failed:\n  {}",
+            failures.len(),
+            manifest.jobs.len(),
+            failures.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Process the documents at each of `paths` in turn as one run, failing at the
+/// end if any of them failed or had a failing block.
+async fn process_all(
+    paths: &[PathBuf],
+    mode: &Mode,
+    config: &Config,
+    matches: &clap::ArgMatches,
+) -> Result<Outcome> {
+    if let [path] = paths {
+        return process(path, mode, config, matches, None).await;
+    }
+    if matches!(mode, Mode::Export { .. }) {
+        anyhow::bail!("export, --stdout and --output take a single document, not several");
+    }
+
+    // Each path fails by the same rules as when it is the only one
+    let mut reports = Vec::new();
+    let mut failures = Vec::new();
+    for path in paths {
+        let outcome = process(path, mode, config, matches, None).await?;
+        failures.extend(outcome.failure);
+        reports.extend(outcome.reports);
+    }
+    if failures.is_empty() {
+        return Ok(Outcome {
+            reports,
+            failure: None,
+        });
+    }
+    Ok(Outcome::failed(
+        reports,
+        format!(
+            "{} of {} paths failed:\n  {}",
+            failures.len(),
+            paths.len(),
+            failures.join("\n  ")
+        ),
+    ))
+}
+
+/// Run the blocks of the documents at `path` and, depending on `mode`, write the
+/// results back, compare them with the documents, or export them. A manifest
+/// `job` adds its block selection and environment.
+async fn process(
+    path: &Path,
+    mode: &Mode,
+    config: &Config,
+    matches: &clap::ArgMatches,
+    job: Option<&Job>,
+) -> Result<Outcome> {
+    let documents = Documents::find(path, matches)?;
+    let paths: Vec<PathBuf> = documents.all().cloned().collect();
+    nesting::check(&paths, config.max_depth)?;
+    if matches!(mode, Mode::Export { .. }) && path.is_dir() {
+        anyhow::bail!("export, --stdout and --output take a single document, not a directory");
+    }
+    let problems = match mode {
+        Mode::Check { .. } => lint(std::slice::from_ref(&documents), config)?,
+        _ => Lint::default(),
+    };
+
+    let mut contents = Vec::new();
+    for file_path in documents.all() {
+        let document = read_document(file_path)?;
+        if !confirm_size(file_path, document.markdown(), config, matches)? {
+            anyhow::bail!("Aborted: {} was not run", file_path.display());
+        }
+        contents.push((file_path, document));
+    }
+
+    // With --atomic, results are staged and only written once their blocks succeeded:
+    // all documents together, or each document on its own with --atomic=file
+    let atomic = matches.get_one::<String>("atomic").map(String::as_str);
+    let mut staged = Vec::new();
+    let mut failed = Vec::new();
+    let mut outdated = Vec::new();
+    let mut failed_quiet = Vec::new();
+    let mut failed_expect = Vec::new();
+    let mut slow = Vec::new();
+    let mut changed = Vec::new();
+
+    let mut recorded = if config.history {
+        History::load().unwrap_or_else(|e| {
+            eprintln!("{} {:#}", "warning:".yellow().bold(), e);
+            Vec::new()
+        })
     } else {
-        process_markdown(&content, force_parallel).await?
+        Vec::new()
+    };
+
+    let mut context = RunContext::new(config, &documents.base_dir)?;
+    context.use_expect_files(&documents.base_dir, *mode == Mode::Run);
+    context.mark_nested(&paths);
+    if matches.get_flag("no-cache") {
+        context.disable_cache();
+    }
+    if let Some(job) = job {
+        context.select_blocks(Selector::parse_all(&job.select)?);
+        context.add_env(job.env.clone());
+    }
+    let mut reports = Vec::new();
+    let trace = matches.get_one::<String>("trace").map(PathBuf::from);
+    for (i, (file_path, document)) in contents.iter().enumerate() {
+        let file_path = *file_path;
+        context.trace_into(trace.as_ref().map(|dir| {
+            dir.join(file_path.strip_prefix(&documents.base_dir).unwrap_or(file_path))
+        }));
+        let document_dir = file_path.parent().unwrap_or(Path::new("."));
+        context.record_into(Some(document_dir.join(&config.record_dir)));
+        context.set_document(Some(file_path));
+        let markdown = document.markdown();
+        let (result, mut report) = if i == 0 && documents.setup.is_some() {
+            context.setup(markdown, config).await?
+        } else if matches.get_flag("attach") {
+            let attached = Attached::start(file_path, config).await?;
+            eprintln!(
+                "Running {} in tmux; watch with: tmux attach -t {}",
+                file_path.display(),
+                attached.session()
+            );
+            process_with(markdown, config, &attached).await?
+        } else {
+            process_markdown(markdown, config, &mut context).await?
+        };
+        let changes = DiffStat::outputs(markdown, &result, config);
+        let content = document.host();
+        let result = document.restore(&result, config)?;
+
+        if report.has_failures() {
+            failed.push(file_path.display().to_string());
+        }
+        for line in report.failed_quiet() {
+            failed_quiet.push(format!("{}:{}", file_path.display(), line));
+        }
+        for line in report.failed_expectations() {
+            failed_expect.push(format!("{}:{}", file_path.display(), line));
+        }
+        match mode {
+            Mode::Check { .. } => {
+                if result != content || !report.stale_expect_files().is_empty() {
+                    outdated.push(file_path.display().to_string());
+                }
+                if let (_, Some(max_block_time)) = budgets(markdown, config)? {
+                    for line in report.slow_blocks(max_block_time, &config.exempt) {
+                        println!(
+                            "{}:{}: block ran longer than the max_block_time budget of {:?}",
+                            file_path.display(),
+                            line,
+                            max_block_time
+                        );
+                        slow.push(format!("{}:{}", file_path.display(), line));
+                    }
+                }
+            }
+            Mode::Export { output: None } => print!("{}", result),
+            Mode::Export { output: Some(output) } => std::fs::write(output, &result)?,
+            _ => match atomic {
+                None => {
+                    if !write_unchanged(file_path, content, &result, false)? {
+                        changed.push(file_path.display().to_string());
+                    }
+                }
+                Some("file") if report.has_failures() => {}
+                Some("file") => {
+                    if !write_unchanged(file_path, content, &result, true)? {
+                        changed.push(file_path.display().to_string());
+                    }
+                }
+                Some(_) => staged.push((file_path, content, result)),
+            },
+        }
+
+        if config.history {
+            let entries = History::entries(file_path, &report, &recorded);
+            if let Err(e) = History::append(&entries, &recorded) {
+                eprintln!("{} {:#}", "warning:".yellow().bold(), e);
+            }
+            recorded.extend(entries);
+            history::mark_flaky(&recorded, file_path, &mut report);
+        }
+
+        match mode {
+            // stdout holds the document
+            Mode::Export { output: None } => {}
+            Mode::Check { .. } => {
+                println!("Checked {}: {}", file_path.display(), changes);
+                report.print_summary();
+            }
+            _ => {
+                println!("Processed {}: {}", file_path.display(), changes);
+                report.print_summary();
+            }
+        }
+        reports.push((file_path.display().to_string(), report));
+    }
+
+    if let Mode::Check { .. } = mode {
+        if !problems.is_empty() || !failed.is_empty() || !outdated.is_empty() || !slow.is_empty() {
+            let mut reasons = problems.reasons();
+            if !failed.is_empty() {
+                reasons.push(format!("blocks failed in {}", failed.join(", ")));
+            }
+            if !outdated.is_empty() {
+                reasons.push(format!("outputs out of date in {}", outdated.join(", ")));
+            }
+            if !slow.is_empty() {
+                reasons.push(format!("blocks over max_block_time at {}", slow.join(", ")));
+            }
+            return Ok(Outcome::failed(
+                reports,
+                format!("Check failed: {}", reasons.join("; ")),
+            ));
+        }
+        return Ok(Outcome {
+            reports,
+            failure: None,
+        });
+    }
+    if let Mode::Export { .. } = mode {
+        let failure = (!failed.is_empty())
+            .then(|| format!("Blocks failed in {}", failed.join(", ")));
+        return Ok(Outcome { reports, failure });
+    }
+
+    if atomic.is_some() && !failed.is_empty() {
+        return Ok(Outcome::failed(
+            reports,
+            format!(
+                "Blocks failed in {}; {} left unchanged",
+                failed.join(", "),
+                if atomic == Some("file") {
+                    "those files were"
+                } else {
+                    "no files were"
+                }
+            ),
+        ));
+    }
+    for (file_path, content, result) in staged {
+        if !write_unchanged(file_path, content, &result, true)? {
+            changed.push(file_path.display().to_string());
+        }
+    }
+    if !changed.is_empty() {
+        return Ok(Outcome::failed(
+            reports,
+            format!(
+                "{} changed while blocks ran and {} not overwritten",
+                changed.join(", "),
+                if changed.len() == 1 { "was" } else { "were" }
+            ),
+        ));
+    }
+    if !failed_quiet.is_empty() {
+        return Ok(Outcome::failed(
+            reports,
+            format!("Quiet blocks failed at {}", failed_quiet.join(", ")),
+        ));
+    }
+    if !failed_expect.is_empty() {
+        return Ok(Outcome::failed(
+            reports,
+            format!("Expected output not found at {}", failed_expect.join(", ")),
+        ));
+    }
+    if matches.get_flag("fail-on-error") && !failed.is_empty() {
+        return Ok(Outcome::failed(
+            reports,
+            format!("Blocks failed in {}", failed.join(", ")),
+        ));
+    }
+
+    Ok(Outcome {
+        reports,
+        failure: None,
+    })
+}
+
+/// How often watch mode looks for changed documents.
+const WATCH_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Process the documents at `paths` whenever one of them changes, ignoring the
+/// changes runmd makes itself. Runs until interrupted.
+async fn watch(paths: &[PathBuf], config: &Config, matches: &clap::ArgMatches) -> Result<()> {
+    let mut seen: HashMap<PathBuf, String> = HashMap::new();
+    loop {
+        let documents = Documents::find_all(paths, matches)?;
+        let current: HashMap<PathBuf, String> = documents
+            .iter()
+            .flat_map(Documents::all)
+            .filter_map(|file| Some((file.clone(), std::fs::read_to_string(file).ok()?)))
+            .collect();
+
+        if current != seen {
+            let outcome = process_all(paths, &Mode::Run, config, matches).await;
+            if let Err(e) = outcome.and_then(|outcome| outcome.finish(paths, matches)) {
+                eprintln!("{} {:#}", "error:".red().bold(), e);
+            }
+            seen = documents
+                .iter()
+                .flat_map(Documents::all)
+                .filter_map(|file| Some((file.clone(), std::fs::read_to_string(file).ok()?)))
+                .collect();
+            let watched: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+            println!("Watching {} for changes", watched.join(", "));
+        }
+
+        tokio::time::sleep(WATCH_INTERVAL).await;
+    }
+}
+
+/// The Markdown files or directories positional argument of the document subcommands.
+fn file_arg() -> Arg {
+    Arg::new("file")
+        .help("Markdown files, directories of Markdown files or patterns such as docs/*.md to process")
+        .required_unless_present("manifest")
+        .num_args(1..)
+        .index(1)
+}
+
+/// Options of `run` sending the result somewhere other than the document.
+fn output_args() -> [Arg; 2] {
+    [
+        Arg::new("stdout")
+            .long("stdout")
+            .help("Print the processed document to stdout instead of rewriting it")
+            .conflicts_with("output")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("output")
+            .short('o')
+            .long("output")
+            .value_name("PATH")
+            .help("Write the processed document to PATH instead of rewriting it"),
+    ]
+}
+
+/// Options shared by every way of processing documents, accepted before or
+/// after the subcommand.
+fn document_args() -> Vec<Arg> {
+    [
+        Arg::new("parallel")
+            .short('p')
+            .long("parallel")
+            .help("Force parallel execution when more than one runnable code block present")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("skip-section")
+            .long("skip-section")
+            .value_name("PATTERN")
+            .help("Skip blocks under headings matching PATTERN (supports * and ?)")
+            .action(clap::ArgAction::Append),
+        Arg::new("ordered")
+            .long("ordered")
+            .help("Execute blocks strictly in document order, never in parallel")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("attach")
+            .long("attach")
+            .help("Run blocks one at a time in windows of a tmux session you can attach to and type into")
+            .conflicts_with("parallel")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("explicit-run")
+            .long("explicit-run")
+            .help("Only execute blocks marked `run`, as with explicit_run in the config")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("hermetic")
+            .long("hermetic")
+            .help("Run blocks with LC_ALL=C, COLUMNS=80 and TERM=dumb so listings and sort orders match on every machine")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("report")
+            .long("report")
+            .value_name("PATH")
+            .help("Write a YAML report of the run to PATH, or a Markdown digest of failures with digest=md[:PATH]")
+            .value_parser(|value: &str| {
+                ReportTarget::parse(value)
+                    .map(|_| value.to_string())
+                    .map_err(|e| e.to_string())
+            })
+            .action(clap::ArgAction::Append),
+        Arg::new("keep-temp")
+            .long("keep-temp")
+            .help("Keep temporary source files and build artifacts after each block runs")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("trace")
+            .long("trace")
+            .value_name("DIR")
+            .help("Save each block's source, command, environment and results under DIR"),
+        Arg::new("force")
+            .long("force")
+            .help("Process documents larger than max_file_size after confirming")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("fail-on-error")
+            .long("fail-on-error")
+            .help("Exit with an error when any block fails, after writing every result")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("yes")
+            .short('y')
+            .long("yes")
+            .help("Do not ask for confirmation before running many blocks")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("cache")
+            .long("cache")
+            .help("Reuse cached outputs of unchanged blocks")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("no-cache"),
+        Arg::new("no-cache")
+            .long("no-cache")
+            .help("Run every block even if the cache is enabled in the config")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("rerun")
+            .long("rerun")
+            .value_name("SELECTOR")
+            .help("Bypass the cache for blocks matching name=..., lang=... or line=...")
+            .action(clap::ArgAction::Append),
+        Arg::new("allow-lang")
+            .long("allow-lang")
+            .value_name("LANGS")
+            .value_delimiter(',')
+            .help("Only execute blocks in these comma-separated languages, skipping the rest")
+            .action(clap::ArgAction::Append),
+        Arg::new("tags")
+            .long("tags")
+            .value_name("TAGS")
+            .value_delimiter(',')
+            .help("Only execute blocks with one of these comma-separated tags=, skipping the rest")
+            .action(clap::ArgAction::Append),
+        Arg::new("exclude-tags")
+            .long("exclude-tags")
+            .value_name("TAGS")
+            .value_delimiter(',')
+            .help("Skip blocks with any of these comma-separated tags=")
+            .action(clap::ArgAction::Append),
+        Arg::new("allow-dangerous")
+            .long("allow-dangerous")
+            .help("Run documents even if the scan finds suspicious blocks")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("atomic")
+            .long("atomic")
+            .value_name("SCOPE")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("all")
+            .value_parser(["all", "file"])
+            .help("Only write results if no block failed, across all files or per file"),
+        Arg::new("manifest")
+            .long("manifest")
+            .value_name("PATH")
+            .help("Process the documents listed in a manifest, each with its own profile, selectors and env"),
+        Arg::new("no-ignore")
+            .long("no-ignore")
+            .action(clap::ArgAction::SetTrue)
+            .help("Process files matched by .gitignore or .runmdignore in a directory"),
+        Arg::new("recursive")
+            .short('r')
+            .long("recursive")
+            .action(clap::ArgAction::SetTrue)
+            .help("Process Markdown files in subdirectories of a directory too"),
+        Arg::new("include")
+            .long("include")
+            .value_name("PATTERN")
+            .help("Only process files in a directory whose relative path or name matches PATTERN")
+            .action(clap::ArgAction::Append),
+        Arg::new("exclude")
+            .long("exclude")
+            .value_name("PATTERN")
+            .help("Leave out files in a directory whose relative path or name matches PATTERN")
+            .action(clap::ArgAction::Append),
+    ]
+    .into_iter()
+    .map(|arg| arg.global(true))
+    .collect()
+}
+
+/// Replace `path` with `content` in one step, so readers never see a partial file.
+/// A symlink is followed and its target replaced, and the file keeps its permissions.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    use std::io::Write;
+
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(content.as_bytes())?;
+    if let Ok(metadata) = std::fs::metadata(&path) {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    file.persist(&path)?;
+    Ok(())
+}
 
-    std::fs::write(&file_path, result)?;
+/// Write `result` over `path` unless the file no longer holds `original`, because
+/// it was edited while its blocks ran. The result then goes next to it in
+/// `<path>.new` and `false` is returned.
+fn write_unchanged(path: &Path, original: &str, result: &str, atomic: bool) -> Result<bool> {
+    if std::fs::read_to_string(path).ok().as_deref() != Some(original) {
+        let mut new_path = path.as_os_str().to_owned();
+        new_path.push(".new");
+        let new_path = PathBuf::from(new_path);
+        std::fs::write(&new_path, result)?;
+        eprintln!(
+            "{} {} changed on disk while its blocks ran; wrote the result to {}",
+            "error:".red().bold(),
+            path.display(),
+            new_path.display()
+        );
+        return Ok(false);
+    }
 
-    if matches.get_flag("clear") {
-        println!("Cleared outputs in {}", file_path.display());
+    if atomic {
+        write_atomic(path, result)?;
     } else {
-        println!("Processed {}", file_path.display());
+        std::fs::write(path, result)?;
+    }
+    Ok(true)
+}
+
+/// The Markdown in `path`, which may be embedded in a Rust or MDX file.
+fn read_document(path: &Path) -> Result<Embedded> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(embed::extract(&content, Format::of(path)))
+}
+
+/// The paths named by the file arguments, with patterns the shell left
+/// unexpanded, such as a quoted `"docs/*.md"`, replaced by what they match.
+fn document_paths(args: &clap::ArgMatches) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for arg in args.get_many::<String>("file").into_iter().flatten() {
+        paths.extend(expand_pattern(arg)?);
+    }
+    Ok(paths)
+}
+
+/// The files and directories matching `pattern`, in which `*` and `?` match
+/// within a single path component, in name order. Hidden files only match
+/// components that start with a dot themselves.
+fn expand_pattern(pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = PathBuf::from(pattern);
+    if path.exists() || !pattern.contains(['*', '?']) {
+        return Ok(vec![path]);
+    }
+
+    let mut matched = vec![PathBuf::new()];
+    for component in path.components() {
+        let part = component.as_os_str().to_string_lossy();
+        if !part.contains(['*', '?']) {
+            for path in &mut matched {
+                path.push(component);
+            }
+            continue;
+        }
+        let mut next = Vec::new();
+        for dir in &matched {
+            let listed = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.as_path()
+            };
+            let Ok(entries) = std::fs::read_dir(listed) else {
+                continue;
+            };
+            for entry in entries {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if (part.starts_with('.') || !name.starts_with('.')) && glob_match(&part, &name) {
+                    next.push(dir.join(name));
+                }
+            }
+        }
+        matched = next;
     }
 
+    matched.retain(|path| path.exists());
+    matched.sort();
+    if matched.is_empty() {
+        anyhow::bail!("No files match '{}'", pattern);
+    }
+    Ok(matched)
+}
+
+/// Documents named this are run first, and only once, when processing a directory.
+const SETUP_FILE: &str = "_runmd_setup.md";
+
+/// Markdown and MDX files directly inside `dir` in name order, excluding the setup
+/// document and files matched by `ignore`.
+fn markdown_files(dir: &Path, ignore: &IgnoreRules) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_markdown = path
+            .extension()
+            .is_some_and(|ext| ext == "md" || ext == "mdx");
+        let is_setup = path.file_name().is_some_and(|name| name == SETUP_FILE);
+        if is_markdown && !is_setup && path.is_file() && !ignore.is_ignored(&path, false) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Markdown and MDX files in `dir` and every directory below it, each directory's
+/// files before those of its subdirectories. Hidden directories, symbolic links to
+/// directories and directories matched by `ignore` are skipped; unless
+/// `no_ignore`, the ignore files of each directory apply below it.
+fn markdown_tree(dir: &Path, ignore: &IgnoreRules, no_ignore: bool) -> Result<Vec<PathBuf>> {
+    let mut files = markdown_files(dir, ignore)?;
+    let mut subdirs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if entry.file_type()?.is_dir() && !hidden && !ignore.is_ignored(&path, true) {
+            subdirs.push(path);
+        }
+    }
+    subdirs.sort();
+    for subdir in subdirs {
+        let mut rules = ignore.clone();
+        if !no_ignore {
+            rules.add_dir(&subdir);
+        }
+        files.extend(markdown_tree(&subdir, &rules, no_ignore)?);
+    }
+    Ok(files)
+}
+
+/// Whether `pattern` matches the `relative` path of a file found in a directory:
+/// the whole path, with `*` also matching `/`, when the pattern contains a `/`,
+/// or else the file name.
+fn path_matches(pattern: &str, relative: &Path) -> bool {
+    let relative = relative.to_string_lossy();
+    if pattern.contains('/') {
+        glob_match(pattern.trim_start_matches("./"), &relative)
+    } else {
+        relative
+            .rsplit('/')
+            .next()
+            .is_some_and(|name| glob_match(pattern, name))
+    }
+}
+
+/// Refuse documents over `max_file_size` unless forced, and ask before running
+/// oversized documents or ones with more than `confirm_blocks` blocks.
+fn confirm_size(
+    file_path: &Path,
+    content: &str,
+    config: &Config,
+    matches: &clap::ArgMatches,
+) -> Result<bool> {
+    let size = content.len() as u64;
+    let oversized = size > config.max_file_size;
+    if oversized && !matches.get_flag("force") {
+        anyhow::bail!(
+            "{} is {} bytes, larger than max_file_size ({} bytes); rerun with --force to process it anyway",
+            file_path.display(),
+            size,
+            config.max_file_size
+        );
+    }
+
+    let blocks = runnable_blocks_by_language(content, config)?;
+    let total: usize = blocks.values().sum();
+    if !oversized && total <= config.confirm_blocks {
+        return Ok(true);
+    }
+
+    if oversized {
+        println!(
+            "{} is {} bytes and contains {} runnable blocks:",
+            file_path.display(),
+            size,
+            total
+        );
+    } else {
+        println!("{} contains {} runnable blocks:", file_path.display(), total);
+    }
+    for (language, count) in &blocks {
+        println!("  {}: {}", language, count);
+    }
+
+    Ok(matches.get_flag("yes") || confirm("Continue?")?)
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no; end of input
+/// counts as no.
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::Write;
+
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// `--bench-internal`: time the core stages on a synthetic document, optionally
+/// saving the timings or checking them against a saved baseline.
+fn bench_internal(matches: &clap::ArgMatches) -> Result<()> {
+    let blocks = *matches.get_one::<usize>("bench-blocks").unwrap_or(&10_000);
+    let timings = bench::run(blocks, 5)?;
+    println!("{} blocks, {} KiB", timings.blocks, timings.bytes / 1024);
+    for (stage, ms) in &timings.stages_ms {
+        println!("  {:<8} {:>10.2}ms", stage, ms);
+    }
+
+    if let Some(path) = matches.get_one::<String>("bench-save") {
+        timings.save(Path::new(path))?;
+        println!("Saved timings to {}", path);
+    }
+    if let Some(path) = matches.get_one::<String>("bench-baseline") {
+        let baseline = bench::Timings::load(Path::new(path))?;
+        if baseline.blocks != timings.blocks {
+            anyhow::bail!(
+                "Baseline {} was measured with {} blocks, not {}",
+                path,
+                baseline.blocks,
+                timings.blocks
+            );
+        }
+        let regressions = timings.regressions(&baseline);
+        if !regressions.is_empty() {
+            anyhow::bail!("Slower than baseline {}:\n  {}", path, regressions.join("\n  "));
+        }
+        println!("Within {:.0}% of baseline {}", bench::TOLERANCE * 100.0, path);
+    }
     Ok(())
 }
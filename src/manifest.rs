@@ -0,0 +1,71 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, ConfigOverrides};
+use crate::select::Selector;
+
+/// A batch of documents processed as one run, each with its own settings, read
+/// from a file such as `runmd.jobs.yml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// Named sets of settings that jobs pick with `profile`
+    pub profiles: BTreeMap<String, ConfigOverrides>,
+    pub jobs: Vec<Job>,
+    /// Where the combined report of all jobs is written
+    pub report: Option<PathBuf>,
+}
+
+/// One document or directory of a manifest.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    pub file: PathBuf,
+    /// Profile whose settings apply on top of the config
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Block selectors such as `lang=bash`; when given, only matching blocks run
+    /// and the others keep the outputs they have
+    #[serde(default)]
+    pub select: Vec<String>,
+    /// Environment variables for the job's blocks
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Read a manifest, resolving its paths against the directory it is in and
+    /// checking profiles and selectors before anything runs.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+        let mut manifest: Manifest = serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid manifest {}", path.display()))?;
+        if manifest.jobs.is_empty() {
+            bail!("Manifest {} lists no jobs", path.display());
+        }
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for job in &mut manifest.jobs {
+            job.file = dir.join(&job.file);
+            if let Some(profile) = &job.profile {
+                if !manifest.profiles.contains_key(profile) {
+                    bail!("Job {} uses unknown profile '{}'", job.file.display(), profile);
+                }
+            }
+            Selector::parse_all(&job.select)?;
+        }
+        manifest.report = manifest.report.map(|report| dir.join(report));
+        Ok(manifest)
+    }
+
+    /// `config` with the settings of `job`'s profile applied.
+    pub fn config_for(&self, job: &Job, config: &Config) -> Config {
+        match job.profile.as_ref().and_then(|name| self.profiles.get(name)) {
+            Some(profile) => profile.apply(config),
+            None => config.clone(),
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::limits::Rejection;
+use crate::report::Report;
+
+/// Content type of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds, in seconds, of the block duration histogram buckets.
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Execution counters for long-running modes, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    documents: u64,
+    /// Blocks per (language, result)
+    blocks: BTreeMap<(String, &'static str), u64>,
+    /// Observations per bucket of `DURATION_BUCKETS`, not cumulative
+    duration_buckets: Vec<u64>,
+    duration_sum: f64,
+    duration_count: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    rejected: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    /// Count a processed document and every block in it that ran. Blocks that ran
+    /// with the cache enabled count as cache misses.
+    pub fn record_document(&self, report: &Report, cache_enabled: bool) {
+        self.inner.lock().unwrap().documents += 1;
+        for block in &report.blocks {
+            if let Some(success) = block.success {
+                self.record_block(
+                    &block.language,
+                    success,
+                    block.cached,
+                    cache_enabled,
+                    Duration::from_millis(block.duration_ms.unwrap_or(0)),
+                );
+            }
+        }
+    }
+
+    pub fn record_block(
+        &self,
+        language: &str,
+        success: bool,
+        cached: bool,
+        cache_enabled: bool,
+        duration: Duration,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let result = match (cached, success) {
+            (true, _) => "cached",
+            (false, true) => "success",
+            (false, false) => "failure",
+        };
+        *inner
+            .blocks
+            .entry((language.to_string(), result))
+            .or_insert(0) += 1;
+
+        if cached {
+            inner.cache_hits += 1;
+            return;
+        }
+        if cache_enabled {
+            inner.cache_misses += 1;
+        }
+
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&le| seconds <= le)
+            .unwrap_or(DURATION_BUCKETS.len());
+        inner.duration_buckets.resize(DURATION_BUCKETS.len() + 1, 0);
+        inner.duration_buckets[bucket] += 1;
+        inner.duration_sum += seconds;
+        inner.duration_count += 1;
+    }
+
+    pub fn record_rejection(&self, rejection: Rejection) {
+        let reason = match rejection {
+            Rejection::RateLimited => "rate_limited",
+            Rejection::QueueFull => "queue_full",
+        };
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .rejected
+            .entry(reason)
+            .or_insert(0) += 1;
+    }
+
+    /// The current values in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "runmd_documents_total",
+            "counter",
+            "Documents processed.",
+        );
+        let _ = writeln!(out, "runmd_documents_total {}", inner.documents);
+
+        header(
+            &mut out,
+            "runmd_blocks_total",
+            "counter",
+            "Blocks run or served from the cache, by language and result.",
+        );
+        for ((language, result), count) in &inner.blocks {
+            let _ = writeln!(
+                out,
+                "runmd_blocks_total{{language=\"{}\",result=\"{}\"}} {}",
+                escape_label(language),
+                result,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "runmd_block_duration_seconds",
+            "histogram",
+            "Time taken by blocks that were executed.",
+        );
+        let mut cumulative = 0;
+        for (i, le) in DURATION_BUCKETS.iter().enumerate() {
+            cumulative += inner.duration_buckets.get(i).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "runmd_block_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "runmd_block_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            inner.duration_count
+        );
+        let _ = writeln!(
+            out,
+            "runmd_block_duration_seconds_sum {}",
+            inner.duration_sum
+        );
+        let _ = writeln!(
+            out,
+            "runmd_block_duration_seconds_count {}",
+            inner.duration_count
+        );
+
+        header(
+            &mut out,
+            "runmd_cache_hits_total",
+            "counter",
+            "Blocks served from the cache.",
+        );
+        let _ = writeln!(out, "runmd_cache_hits_total {}", inner.cache_hits);
+        header(
+            &mut out,
+            "runmd_cache_misses_total",
+            "counter",
+            "Blocks executed while the cache was enabled.",
+        );
+        let _ = writeln!(out, "runmd_cache_misses_total {}", inner.cache_misses);
+
+        header(
+            &mut out,
+            "runmd_requests_rejected_total",
+            "counter",
+            "Requests refused by the service limits, by reason.",
+        );
+        for (reason, count) in &inner.rejected {
+            let _ = writeln!(
+                out,
+                "runmd_requests_rejected_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer `GET /metrics` on `addr` over plain HTTP, for modes that don't already
+/// serve HTTP.
+pub async fn listen(metrics: Rc<Metrics>, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    println!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::task::spawn_local(async move {
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).await.is_err() {
+                return;
+            }
+            // Skip the headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).await.is_ok_and(|n| n > 0) && line.trim() != "" {
+                line.clear();
+            }
+
+            let (status, body) = if request_line.starts_with("GET /metrics ") {
+                ("200 OK", metrics.render())
+            } else {
+                ("404 Not Found", "Not found\n".to_string())
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                CONTENT_TYPE,
+                body.len(),
+                body
+            );
+            let mut stream = reader.into_inner();
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
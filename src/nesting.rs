@@ -0,0 +1,57 @@
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+
+/// How many runmd processes enclose the current one, set for every block.
+pub const DEPTH_VAR: &str = "RUNMD_DEPTH";
+/// Documents being processed by enclosing runmd processes, as a path list.
+pub const DOCUMENTS_VAR: &str = "RUNMD_DOCUMENTS";
+
+/// Nesting depth of this process: 0 unless a block of another runmd started it.
+pub fn depth() -> usize {
+    std::env::var(DEPTH_VAR)
+        .ok()
+        .and_then(|depth| depth.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Refuse to go on when runmd is nested `max_depth` deep, or when a block asks it
+/// to process a document an enclosing runmd is still processing, which would
+/// recurse forever.
+pub fn check(documents: &[PathBuf], max_depth: usize) -> Result<()> {
+    let depth = depth();
+    if depth >= max_depth {
+        bail!(
+            "runmd is nested {} levels deep (max_depth is {}); is a block running its own document?",
+            depth,
+            max_depth
+        );
+    }
+
+    let outer = std::env::var_os(DOCUMENTS_VAR).unwrap_or_default();
+    let outer: Vec<PathBuf> = std::env::split_paths(&outer).collect();
+    for document in documents {
+        if outer.contains(&canonical(document)) {
+            bail!(
+                "{} is already being processed by the runmd running this block",
+                document.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Variables telling blocks that run runmd how deeply they are nested and which
+/// documents are in progress, these `documents` included.
+pub fn child_env(documents: &[PathBuf]) -> Vec<(String, String)> {
+    let outer = std::env::var_os(DOCUMENTS_VAR).unwrap_or_default();
+    let paths = std::env::split_paths(&outer).chain(documents.iter().map(|d| canonical(d)));
+    let mut env = vec![(DEPTH_VAR.to_string(), (depth() + 1).to_string())];
+    if let Ok(paths) = std::env::join_paths(paths) {
+        env.push((DOCUMENTS_VAR.to_string(), paths.to_string_lossy().to_string()));
+    }
+    env
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::io::{IsTerminal, Write};
+use std::pin::pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Live display of the blocks running in parallel, one line per block with its
+/// elapsed time, redrawn in place on stderr so a hanging block stands out.
+/// It is drawn with plain ANSI escapes rather than `indicatif`, which the build
+/// does not have available.
+pub struct Progress {
+    total: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// In-flight blocks by index, with their label and start time
+    running: Vec<(usize, String, Instant)>,
+    finished: usize,
+    /// Lines written by the last redraw
+    drawn: usize,
+}
+
+impl Progress {
+    /// A display for `total` blocks, or `None` when stderr isn't a terminal.
+    pub fn new(total: usize) -> Option<Self> {
+        std::io::stderr().is_terminal().then(|| Progress {
+            total,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    pub fn start(&self, index: usize, label: String) {
+        let mut state = self.state.lock().unwrap();
+        state.running.push((index, label, Instant::now()));
+    }
+
+    pub fn finish(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.running.retain(|(i, _, _)| *i != index);
+        state.finished += 1;
+    }
+
+    /// Drive `work` to completion, redrawing the display ten times a second, and
+    /// erase the display afterwards.
+    pub async fn run_until<T>(&self, work: impl Future<Output = T>) -> T {
+        let mut work = pin!(work);
+        let mut ticker = tokio::time::interval(Duration::from_millis(100));
+        let mut frame = 0;
+        loop {
+            tokio::select! {
+                output = &mut work => {
+                    self.erase();
+                    return output;
+                }
+                _ = ticker.tick() => {
+                    self.draw(frame);
+                    frame += 1;
+                }
+            }
+        }
+    }
+
+    fn draw(&self, frame: usize) {
+        let mut state = self.state.lock().unwrap();
+        let spinner = SPINNER[frame % SPINNER.len()];
+
+        let mut out = String::new();
+        if state.drawn > 0 {
+            // Back to the first line of the previous frame, clearing it and everything below
+            out.push_str(&format!("\x1b[{}F", state.drawn));
+        }
+        out.push_str("\x1b[J");
+        out.push_str(&format!(
+            "Running blocks: {}/{} done\n",
+            state.finished, self.total
+        ));
+        for (_, label, started) in &state.running {
+            out.push_str(&format!(
+                "  {} {}  {:.1}s\n",
+                spinner,
+                label,
+                started.elapsed().as_secs_f64()
+            ));
+        }
+        state.drawn = state.running.len() + 1;
+
+        let _ = std::io::stderr().write_all(out.as_bytes());
+    }
+
+    fn erase(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.drawn > 0 {
+            let _ = write!(std::io::stderr(), "\x1b[{}F\x1b[J", state.drawn);
+            state.drawn = 0;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::AtomicU32;
+use tokio::process::Command;
+
+use crate::cast::Recorder;
+use crate::executor::RunResult;
+
+/// Run `cmd` attached to a new pseudo-terminal and return everything written to
+/// the terminal, so programs that check for a TTY behave as they would for a reader.
+/// `input` is typed into the terminal. The child leads its own session, and its pid
+/// is stored in `pid` once spawned. With `cast`, an asciinema recording of the
+/// terminal is written there.
+#[cfg(unix)]
+pub async fn run(
+    mut cmd: Command,
+    input: Option<String>,
+    pid: &AtomicU32,
+    cast: Option<&Path>,
+) -> io::Result<RunResult> {
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let mut master_fd = -1;
+    let mut slave_fd = -1;
+    let size = libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // SAFETY: the two descriptor pointers are valid for writes, the name pointer is
+    // null so openpty writes no name, and `size` outlives the call
+    let rc = unsafe {
+        libc::openpty(
+            &mut master_fd,
+            &mut slave_fd,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: openpty succeeded, so both descriptors are open, and nothing else
+    // owns them; each is wrapped exactly once and closed when dropped
+    let master = unsafe { OwnedFd::from_raw_fd(master_fd) };
+    let slave = unsafe { OwnedFd::from_raw_fd(slave_fd) };
+
+    cmd.stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave))
+        .kill_on_drop(true);
+
+    // Make the terminal the controlling terminal of the child's new session.
+    // SAFETY: the hook runs in the forked child before exec, where only
+    // async-signal-safe calls are allowed; setsid and ioctl are, and the hook
+    // neither allocates nor touches state shared with the parent. Descriptor 0
+    // is the terminal's slave side by then.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::ioctl(0, libc::TIOCSCTTY as _, 0);
+            Ok(())
+        });
+    }
+
+    let mut child = cmd.spawn()?;
+    pid.store(child.id().unwrap_or(0), std::sync::atomic::Ordering::SeqCst);
+    let _group = crate::runner::Tracked::new(child.id().unwrap_or(0));
+    // Close our copies of the terminal so reads end once the child exits
+    drop(cmd);
+
+    if let Some(input) = input {
+        let mut keyboard = File::from(master.try_clone()?);
+        tokio::task::spawn_blocking(move || keyboard.write_all(input.as_bytes()));
+    }
+
+    let mut recorder = cast.map(|_| Recorder::new());
+    let reader = tokio::task::spawn_blocking(move || {
+        let mut terminal = File::from(master);
+        let mut transcript = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            match terminal.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    transcript.extend_from_slice(&chunk[..n]);
+                    if let Some(recorder) = &mut recorder {
+                        recorder.record(&chunk[..n]);
+                    }
+                }
+                // Linux reports EIO on the master once the last slave is closed
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((transcript, recorder))
+    });
+
+    let status = child.wait().await?;
+    let (transcript, recorder) = reader.await.map_err(io::Error::other)??;
+    if let (Some(recorder), Some(cast)) = (recorder, cast) {
+        recorder
+            .write(cast, size.ws_col, size.ws_row)
+            .map_err(io::Error::other)?;
+    }
+
+    let text = String::from_utf8_lossy(&transcript).replace("\r\n", "\n");
+    Ok(RunResult {
+        output: text.trim().to_string(),
+        exit_code: status.code(),
+        stderr: None,
+    })
+}
+
+#[cfg(not(unix))]
+pub async fn run(
+    _cmd: Command,
+    _input: Option<String>,
+    _pid: &AtomicU32,
+    _cast: Option<&Path>,
+) -> io::Result<RunResult> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pseudo-terminal capture is only supported on Unix",
+    ))
+}
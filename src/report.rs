@@ -0,0 +1,379 @@
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::core::CodeBlock;
+use crate::hash::{block_key, fnv1a, line_sketch};
+
+/// The result of executing a single block.
+#[derive(Debug, Clone)]
+pub struct BlockRun {
+    /// Position of the block in the document
+    pub index: usize,
+    pub output: String,
+    /// Exit status of the process, when it ran to completion
+    pub exit_code: Option<i32>,
+    pub success: bool,
+    /// Served from the output cache instead of executing
+    pub cached: bool,
+    pub duration: Duration,
+    /// Standard error on its own, when it was captured apart from the output
+    pub stderr: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LanguageSummary {
+    pub run: usize,
+    pub failed: usize,
+    pub duration: Duration,
+}
+
+/// Lines of each failed block's output quoted in the failure digest.
+const DIGEST_LINES: usize = 5;
+
+/// Where `--report` sends a report.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReportTarget {
+    /// The YAML report, written to a file
+    Yaml(PathBuf),
+    /// The Markdown failure digest, printed or written to a file
+    Digest(Option<PathBuf>),
+}
+
+impl ReportTarget {
+    /// `digest=md` prints the digest and `digest=md:PATH` writes it to PATH; any
+    /// other value is the path of the YAML report.
+    pub fn parse(value: &str) -> Result<Self> {
+        let Some(format) = value.strip_prefix("digest=") else {
+            return Ok(ReportTarget::Yaml(PathBuf::from(value)));
+        };
+        let (format, path) = match format.split_once(':') {
+            Some((format, path)) => (format, Some(PathBuf::from(path))),
+            None => (format, None),
+        };
+        if format != "md" {
+            anyhow::bail!("Unknown digest format '{}' (expected md)", format);
+        }
+        Ok(ReportTarget::Digest(path))
+    }
+}
+
+/// Machine-readable summary of a processing run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub mode: String,
+    /// Block indices in the order their execution finished
+    pub execution_order: Vec<usize>,
+    pub blocks: Vec<BlockReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockReport {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Anchor of the block's output section, from `id=`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub language: String,
+    /// The fence had no language and `language` was guessed from the code
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub detected: bool,
+    pub line: usize,
+    pub skipped: bool,
+    /// Runs without inserting output, so a failure fails the whole run
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub quiet: bool,
+    /// Expected to exit non-zero; `success` says whether it did
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub xfail: bool,
+    /// Text the output has to contain for the block to pass
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect: Option<String>,
+    /// Golden file holding the block's output instead of the document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_file: Option<String>,
+    /// Whether the output matched `expect_file`, when compared rather than written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_matched: Option<bool>,
+    /// Whether the block exited successfully; absent for blocks that did not run
+    pub success: Option<bool>,
+    pub cached: bool,
+    pub duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attr_errors: Vec<String>,
+    /// Why the block's results look flaky across runs of unchanged code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flaky: Option<String>,
+    /// `hash::block_key` of the code, for the run history
+    #[serde(skip)]
+    pub code_key: String,
+    /// Hash of the output, for blocks that ran
+    #[serde(skip)]
+    pub output_key: Option<String>,
+    /// Standard error, or else the output, of a failed run for the failure digest
+    #[serde(skip)]
+    pub failure_output: Option<String>,
+    /// `hash::line_sketch` of the code, for the run history
+    #[serde(skip)]
+    pub sketch: Vec<u32>,
+}
+
+impl Report {
+    pub fn new(blocks: &[CodeBlock]) -> Self {
+        let blocks = blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| BlockReport {
+                index,
+                name: block.attrs.get("name").map(str::to_string),
+                id: block.attrs.get("id").map(str::to_string),
+                language: block.language.clone(),
+                detected: block.detected,
+                line: block.line,
+                skipped: block.skip,
+                quiet: block.quiet(),
+                xfail: block.attrs.flag("xfail"),
+                expect: block.attrs.get("expect").map(str::to_string),
+                expect_file: block.attrs.get("expect-file").map(str::to_string),
+                expect_matched: None,
+                success: None,
+                cached: false,
+                duration_ms: None,
+                attr_errors: block.attr_errors.clone(),
+                flaky: None,
+                code_key: block_key(&block.language, &block.code),
+                output_key: None,
+                failure_output: None,
+                sketch: line_sketch(&block.code),
+            })
+            .collect();
+
+        Report {
+            mode: "sequential".to_string(),
+            execution_order: Vec::new(),
+            blocks,
+        }
+    }
+
+    pub fn record(&mut self, parallel: bool, runs: &[BlockRun]) {
+        if parallel {
+            self.mode = "parallel".to_string();
+        }
+
+        for run in runs {
+            self.execution_order.push(run.index);
+            if let Some(block) = self.blocks.get_mut(run.index) {
+                block.success = Some(run.success);
+                block.cached = run.cached;
+                block.duration_ms = Some(run.duration.as_millis() as u64);
+                block.output_key = Some(format!("{:016x}", fnv1a(run.output.as_bytes())));
+                block.failure_output = (!run.success).then(|| {
+                    run.stderr
+                        .clone()
+                        .filter(|stderr| !stderr.is_empty())
+                        .unwrap_or_else(|| run.output.clone())
+                });
+            }
+        }
+    }
+
+    /// Whether any block that ran failed.
+    pub fn has_failures(&self) -> bool {
+        self.blocks.iter().any(|block| block.success == Some(false))
+    }
+
+    /// Golden files whose contents differ from the output they were compared with.
+    pub fn stale_expect_files(&self) -> Vec<&str> {
+        self.blocks
+            .iter()
+            .filter(|block| block.expect_matched == Some(false))
+            .filter_map(|block| block.expect_file.as_deref())
+            .collect()
+    }
+
+    /// Lines of blocks that ran for longer than `limit`, except those named in
+    /// `exempt`; cached blocks did not run.
+    pub fn slow_blocks(&self, limit: Duration, exempt: &[String]) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|block| !block.cached)
+            .filter(|block| {
+                block
+                    .name
+                    .as_ref()
+                    .is_none_or(|name| !exempt.contains(name))
+            })
+            .filter(|block| {
+                block
+                    .duration_ms
+                    .is_some_and(|ms| ms > limit.as_millis() as u64)
+            })
+            .map(|block| block.line)
+            .collect()
+    }
+
+    /// Lines of `quiet` blocks that failed.
+    pub fn failed_quiet(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|block| block.quiet && block.success == Some(false))
+            .map(|block| block.line)
+            .collect()
+    }
+
+    /// Lines of blocks with `expect=` that failed, so their assertion fails the run.
+    pub fn failed_expectations(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|block| block.expect.is_some() && block.success == Some(false))
+            .map(|block| block.line)
+            .collect()
+    }
+
+    /// Blocks run, failures and total time per language, for blocks that ran.
+    pub fn by_language(&self) -> BTreeMap<String, LanguageSummary> {
+        let mut summary: BTreeMap<String, LanguageSummary> = BTreeMap::new();
+
+        for block in &self.blocks {
+            let Some(success) = block.success else {
+                continue;
+            };
+            let entry = summary.entry(block.language.clone()).or_default();
+            entry.run += 1;
+            if !success {
+                entry.failed += 1;
+            }
+            entry.duration += Duration::from_millis(block.duration_ms.unwrap_or(0));
+        }
+
+        summary
+    }
+
+    /// Print the per-language breakdown shown at the end of a run.
+    pub fn print_summary(&self) {
+        let summary = self.by_language();
+        if summary.is_empty() {
+            return;
+        }
+
+        let width = summary.keys().map(|l| l.len()).max().unwrap_or(0);
+        println!("Summary by language:");
+        for (language, stats) in summary {
+            let failed = format!("{} failed", stats.failed);
+            println!(
+                "  {:width$}  {} run  {}  {:.2}s",
+                language,
+                stats.run,
+                if stats.failed > 0 {
+                    failed.red().to_string()
+                } else {
+                    failed
+                },
+                stats.duration.as_secs_f64(),
+                width = width
+            );
+        }
+
+        let flaky: Vec<_> = self.blocks.iter().filter(|b| b.flaky.is_some()).collect();
+        if !flaky.is_empty() {
+            println!("Flaky blocks:");
+            for block in flaky {
+                println!(
+                    "  line {}{}: {}",
+                    block.line,
+                    block
+                        .name
+                        .as_ref()
+                        .map(|name| format!(" \"{}\"", name))
+                        .unwrap_or_default(),
+                    block.flaky.as_deref().unwrap_or_default().yellow()
+                );
+            }
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_yaml::to_string(self)?;
+        std::fs::write(path, content)
+            .with_context(|| format!("Failed to write report: {}", path.display()))
+    }
+
+    /// A short Markdown summary of the failed blocks of a run, each with the first
+    /// lines of its output, for chat messages and notifications.
+    pub fn digest(reports: &[(String, Report)]) -> String {
+        let total: usize = reports
+            .iter()
+            .map(|(_, report)| report.blocks.iter().filter(|b| b.success.is_some()).count())
+            .sum();
+        let failed: Vec<(&str, &BlockReport)> = reports
+            .iter()
+            .flat_map(|(file, report)| {
+                report
+                    .blocks
+                    .iter()
+                    .filter(|block| block.success == Some(false))
+                    .map(move |block| (file.as_str(), block))
+            })
+            .collect();
+        let documents = reports
+            .iter()
+            .filter(|(_, report)| report.has_failures())
+            .count();
+
+        if failed.is_empty() {
+            return format!(
+                "**runmd: all {} blocks passed in {} documents**\n",
+                total,
+                reports.len()
+            );
+        }
+        let mut digest = format!(
+            "**runmd: {} of {} blocks failed in {} of {} documents**\n",
+            failed.len(),
+            total,
+            documents,
+            reports.len()
+        );
+        for (file, block) in failed {
+            let name = block
+                .name
+                .as_ref()
+                .map(|name| format!(" \"{}\"", name))
+                .unwrap_or_default();
+            digest.push_str(&format!(
+                "\n`{}` line {} ({}{})\n",
+                file, block.line, block.language, name
+            ));
+            let output = block.failure_output.as_deref().unwrap_or("").trim();
+            if output.is_empty() {
+                continue;
+            }
+            let lines: Vec<&str> = output.lines().collect();
+            digest.push_str("```\n");
+            for line in lines.iter().take(DIGEST_LINES) {
+                digest.push_str(line);
+                digest.push('\n');
+            }
+            if lines.len() > DIGEST_LINES {
+                digest.push_str(&format!("... {} more lines\n", lines.len() - DIGEST_LINES));
+            }
+            digest.push_str("```\n");
+        }
+        digest
+    }
+
+    /// Write the reports of a multi-document run as one mapping keyed by file.
+    pub fn write_all(reports: &[(String, Report)], path: &Path) -> Result<()> {
+        let reports: BTreeMap<&str, &Report> = reports
+            .iter()
+            .map(|(file, report)| (file.as_str(), report))
+            .collect();
+        let content = serde_yaml::to_string(&reports)?;
+        std::fs::write(path, content)
+            .with_context(|| format!("Failed to write report: {}", path.display()))
+    }
+}
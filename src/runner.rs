@@ -1,70 +1,1090 @@
 use anyhow::{Context, Result};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::pin::{pin, Pin};
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 use tempfile::NamedTempFile;
 use tokio::process::Command;
 use tokio::time::timeout;
 
+use crate::config::{InvokeMode, Priority};
+use crate::executor::RunResult;
 use crate::languages::Languages;
+use crate::pty;
+
+/// How a block's stdout and stderr end up in its output section.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// stdout, or stderr when the block failed without printing anything
+    #[default]
+    Auto,
+    /// stdout and stderr interleaved in the order they were written
+    Merged,
+    /// stdout followed by a separate stderr section
+    Split,
+    /// Run under a pseudo-terminal and capture the terminal transcript
+    Pty,
+}
+
+impl CaptureMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "merged" => Some(CaptureMode::Merged),
+            "split" => Some(CaptureMode::Split),
+            "pty" => Some(CaptureMode::Pty),
+            _ => None,
+        }
+    }
+}
+
+/// Per-block execution settings.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub timeout: Duration,
+    /// How long a timed-out block may take to exit after SIGTERM before it is killed
+    pub grace: Duration,
+    pub capture: CaptureMode,
+    /// Working directory, defaulting to the current one
+    pub cwd: Option<PathBuf>,
+    /// Extra environment variables
+    pub env: Vec<(String, String)>,
+    /// Give the child only `env` rather than adding it to the inherited environment
+    pub clear_env: bool,
+    pub priority: Priority,
+    /// CPUs the child may run on, or any when empty
+    pub cpu_affinity: Vec<usize>,
+    /// Leave the block's source file and build artifacts on disk
+    pub keep_temp: bool,
+    /// Command the block's command runs under, such as `docker exec -i <id>`
+    pub prefix: Vec<String>,
+    /// Command run when the block times out, to kill it where signals to the
+    /// local process don't reach, such as inside a container
+    pub stop: Vec<String>,
+    /// Where scratch directories are created instead of the system temp directory
+    pub scratch_dir: Option<PathBuf>,
+    /// Text given to the block on its standard input, or typed into its terminal
+    pub stdin: Option<String>,
+    /// Arguments appended to the language command, from `args=`
+    pub args: Vec<String>,
+    /// Where to write an asciinema recording of the block's terminal, under
+    /// `CaptureMode::Pty`
+    pub cast: Option<PathBuf>,
+    /// Directory to save the source, command, environment and results in
+    pub trace: Option<PathBuf>,
+}
 
 pub async fn run_code(
     language: &str,
     code: &str,
     languages: &Languages,
-    timeout_secs: u64,
-) -> Result<String> {
-    // Get command template for the language
-    let temp_file = create_temp_file(language, code)?;
-    let file_path = temp_file.path().to_string_lossy().to_string();
-
-    let command_parts = match languages.get_command(language, &file_path) {
-        Some(parts) => parts,
-        None => return Ok(format!("[error] Language '{}' not supported.", language)),
+    options: &RunOptions,
+) -> Result<RunResult> {
+    let language = languages.resolve(language);
+    if !languages.mappings.contains_key(language) {
+        return Ok(RunResult::error(format!(
+            "Language '{}' not supported.",
+            language
+        )));
+    }
+
+    // Scratch files for file invocation, removed when dropped
+    let mut temp = None;
+    let mut input = options.stdin.clone();
+    let command_parts = match languages.invoke_mode(language) {
+        InvokeMode::Stdin if options.capture == CaptureMode::Pty => {
+            return Ok(RunResult::error(
+                "capture=pty cannot be combined with invoke: stdin.",
+            ))
+        }
+        InvokeMode::Stdin if options.stdin.is_some() => {
+            return Ok(RunResult::error(
+                "stdin= and stdin-file= cannot be combined with invoke: stdin, which passes the code on stdin.",
+            ))
+        }
+        InvokeMode::Stdin => match languages.get_stdin_command(language) {
+            Some(parts) => {
+                input = Some(code.to_string());
+                parts
+            }
+            None => {
+                return Ok(RunResult::error(format!(
+                    "No way to pass code on stdin for '{}'; give a command without {{file}}.",
+                    language
+                )))
+            }
+        },
+        InvokeMode::Arg => match languages.get_arg_command(language, code) {
+            Some(parts) => parts,
+            None => {
+                return Ok(RunResult::error(format!(
+                    "No way to pass code as an argument for '{}'; add {{code}} to its command.",
+                    language
+                )))
+            }
+        },
+        InvokeMode::File => {
+            // Each block gets its own scratch directory so build artifacts are cleaned up with it
+            let mut builder = tempfile::Builder::new();
+            builder.prefix("runmd-");
+            let temp_dir = match &options.scratch_dir {
+                Some(dir) => builder.tempdir_in(dir),
+                None => builder.tempdir(),
+            }
+            .context("Failed to create temporary directory")?;
+            let temp_file = create_temp_file(language, code, temp_dir.path())?;
+            let file_path = temp_file.path().to_string_lossy().to_string();
+            let out_path = temp_dir.path().join("out").to_string_lossy().to_string();
+
+            // Special handling for Racket - add #lang directive if missing
+            if language.to_lowercase() == "racket" && !code.trim_start().starts_with("#lang") {
+                let modified_code = format!("#lang racket\n{}", code);
+                std::fs::write(&file_path, modified_code)?;
+            }
+
+            let parts = languages
+                .get_command(language, &file_path, &out_path)
+                .unwrap_or_default();
+            temp = Some((temp_dir, temp_file));
+            parts
+        }
     };
 
     if command_parts.is_empty() {
-        return Ok("[error] Invalid command configuration.".to_string());
+        return Ok(RunResult::error("Invalid command configuration."));
     }
 
-    // Check if the required executable exists
-    if !languages.check_dependency_exists(&command_parts) {
-        return Ok(format!(
-            "[error] Required interpreter/compiler for '{}' is not installed.",
+    // Check if the required executable exists; a prefix runs it somewhere else
+    let path = options
+        .env
+        .iter()
+        .rev()
+        .find(|(key, _)| key == "PATH")
+        .map(|(_, value)| value.as_str());
+    if options.prefix.is_empty() && !languages.check_dependency_exists(&command_parts, path) {
+        return Ok(RunResult::error(format!(
+            "Required interpreter/compiler for '{}' is not installed.",
             language
-        ));
-    }
-
-    // Special handling for Racket - add #lang directive if missing
-    if language.to_lowercase() == "racket" && !code.trim_start().starts_with("#lang") {
-        let modified_code = format!("#lang racket\n{}", code);
-        std::fs::write(&file_path, modified_code)?;
+        )));
     }
 
     // Execute the command with timeout
+    let command_parts: Vec<String> = options
+        .prefix
+        .iter()
+        .cloned()
+        .chain(command_parts)
+        .chain(options.args.iter().cloned())
+        .collect();
     let mut cmd = Command::new(&command_parts[0]);
     if command_parts.len() > 1 {
         cmd.args(&command_parts[1..]);
     }
+    if let Some(cwd) = &options.cwd {
+        cmd.current_dir(cwd);
+    }
+    if options.clear_env {
+        cmd.env_clear();
+    }
+    cmd.envs(options.env.iter().map(|(k, v)| (k, v)));
+    set_scheduling(&mut cmd, options);
+    if let Some(dir) = &options.trace {
+        let source = temp.as_ref().map(|(_, file)| file.path());
+        write_trace(dir, code, source, &command_parts, options)
+            .with_context(|| format!("Failed to write trace to {}", dir.display()))?;
+    }
+
+    // Set once the child is spawned, so a timed-out block's process group can be signalled
+    let pid = AtomicU32::new(0);
+    let capture = options.capture;
+    let mut run = pin!(async {
+        match capture {
+            CaptureMode::Auto | CaptureMode::Split => {
+                let output = output_with_input(cmd, input, &pid).await?;
+                if let Some(dir) = &options.trace {
+                    std::fs::write(dir.join("stdout"), &output.stdout)?;
+                    std::fs::write(dir.join("stderr"), &output.stderr)?;
+                }
+                Ok(format_output(&output, capture))
+            }
+            CaptureMode::Merged => run_merged(cmd, input, &pid).await,
+            CaptureMode::Pty => pty::run(cmd, input, &pid, options.cast.as_deref()).await,
+        }
+    });
+
+    // Piped stdin ends once `stdin=` is written, but a terminal never does, so a
+    // block prompting for more input than it was given would sit until the timeout
+    let outcome = tokio::select! {
+        result = run.as_mut() => Outcome::Finished(result),
+        _ = tokio::time::sleep(options.timeout) => Outcome::TimedOut,
+        _ = waiting_for_input(&pid), if capture == CaptureMode::Pty => Outcome::WaitingForInput,
+    };
+    let pid = pid.load(Ordering::SeqCst);
+    if !matches!(outcome, Outcome::Finished(_)) {
+        stop(options).await;
+    }
+    let result = match outcome {
+        Outcome::Finished(result) => result,
+        Outcome::TimedOut => Ok(terminate(run, pid, options.grace, TIMED_OUT).await),
+        Outcome::WaitingForInput => {
+            Ok(terminate(run, pid, options.grace, WAITING_FOR_INPUT).await)
+        }
+    };
+
+    if let Some((temp_dir, temp_file)) = temp.filter(|_| options.keep_temp) {
+        temp_file.into_temp_path().keep()?;
+        let kept = temp_dir.keep();
+        eprintln!("Kept temporary files in {}", kept.display());
+    }
+
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => RunResult::error(e),
+    };
+    if let Some(dir) = &options.trace {
+        let exit_code = match result.exit_code {
+            Some(code) => code.to_string(),
+            None => "none".to_string(),
+        };
+        std::fs::write(dir.join("output"), &result.output)
+            .and_then(|_| std::fs::write(dir.join("exit_code"), exit_code + "\n"))
+            .with_context(|| format!("Failed to write trace to {}", dir.display()))?;
+    }
+    Ok(result)
+}
+
+/// Save what a block is about to run into `dir` for `--trace`: the source file it
+/// runs (its code, when not passed as a file), the command line naming the
+/// original file, the working directory and the environment the process starts with.
+fn write_trace(
+    dir: &Path,
+    code: &str,
+    source: Option<&Path>,
+    command: &[String],
+    options: &RunOptions,
+) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    match source {
+        Some(path) => {
+            let mut name = std::ffi::OsString::from("source");
+            if let Some(extension) = path.extension() {
+                name.push(".");
+                name.push(extension);
+            }
+            std::fs::copy(path, dir.join(name))?;
+        }
+        None => std::fs::write(dir.join("source"), code)?,
+    }
+    std::fs::write(dir.join("command"), shell_words::join(command) + "\n")?;
+
+    let cwd = match &options.cwd {
+        Some(cwd) => cwd.clone(),
+        None => std::env::current_dir()?,
+    };
+    std::fs::write(dir.join("cwd"), format!("{}\n", cwd.display()))?;
+
+    let mut env: std::collections::BTreeMap<String, String> = if options.clear_env {
+        Default::default()
+    } else {
+        std::env::vars().collect()
+    };
+    env.extend(options.env.iter().cloned());
+    let env: String = env
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect();
+    std::fs::write(dir.join("env"), env)?;
+    Ok(())
+}
+
+/// How waiting for a block ended.
+enum Outcome {
+    Finished(std::io::Result<RunResult>),
+    TimedOut,
+    WaitingForInput,
+}
+
+const TIMED_OUT: &str = "execution timed out";
+const WAITING_FOR_INPUT: &str =
+    "block appears to be waiting for input; give it with stdin=\"...\"";
+
+/// Run `options.stop`, if any, for a block that has to end early.
+async fn stop(options: &RunOptions) {
+    if let Some((program, args)) = options.stop.split_first() {
+        let _ = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+    }
+}
+
+/// Stop a block that has to end early: SIGTERM its process group so it can flush
+/// output and clean up, then SIGKILL the group if it is still running after `grace`.
+/// Whatever the block printed before exiting is kept ahead of the `reason` error.
+async fn terminate(
+    mut run: Pin<&mut impl Future<Output = std::io::Result<RunResult>>>,
+    pid: u32,
+    grace: Duration,
+    reason: &str,
+) -> RunResult {
+    signal_group(pid, Signal::Terminate);
+    let partial = match timeout(grace, run.as_mut()).await {
+        Ok(result) => result.ok(),
+        Err(_) => {
+            signal_group(pid, Signal::Kill);
+            // Descendants that left the group may still hold the pipes open
+            timeout(Duration::from_secs(1), run)
+                .await
+                .ok()
+                .and_then(Result::ok)
+        }
+    };
+
+    let error = RunResult::error(reason);
+    match partial.filter(|partial| !partial.output.is_empty()) {
+        Some(partial) => RunResult {
+            output: format!("{}\n{}", partial.output, error.output),
+            exit_code: None,
+            stderr: partial.stderr,
+        },
+        None => error,
+    }
+}
+
+/// Resolve once a process of the group or session led by `pid` has spent a second
+/// blocked reading its standard input. Only Linux exposes this, through /proc.
+#[cfg(target_os = "linux")]
+async fn waiting_for_input(pid: &AtomicU32) {
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    let mut blocked = 0;
+    while blocked < 10 {
+        interval.tick().await;
+        let pid = pid.load(Ordering::SeqCst);
+        blocked = if pid != 0 && reading_stdin(pid) {
+            blocked + 1
+        } else {
+            0
+        };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn waiting_for_input(_pid: &AtomicU32) {
+    std::future::pending().await
+}
+
+/// Whether a process in the group or session led by `leader` is in a `read` of fd 0.
+#[cfg(target_os = "linux")]
+fn reading_stdin(leader: u32) -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let dir = entry.path();
+        let Ok(stat) = std::fs::read_to_string(dir.join("stat")) else {
+            return false;
+        };
+        // Fields after the parenthesised command: state, ppid, pgrp, session, ...
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            return false;
+        };
+        let fields: Vec<&str> = fields.split_whitespace().take(4).collect();
+        let leader = leader.to_string();
+        if fields.len() < 4 || (fields[2] != leader && fields[3] != leader) {
+            return false;
+        }
+
+        // `<syscall number> <first argument> ...` while blocked in a system call
+        let syscall = std::fs::read_to_string(dir.join("syscall")).unwrap_or_default();
+        let mut words = syscall.split_whitespace();
+        words.next() == Some(libc::SYS_read.to_string().as_str()) && words.next() == Some("0x0")
+    })
+}
+
+#[derive(Clone, Copy)]
+enum Signal {
+    Interrupt,
+    Terminate,
+    Kill,
+}
+
+/// Leaders of the process groups of blocks and sessions that are still running.
+/// Each block leads a group of its own, so Ctrl-C in the terminal never reaches
+/// it and runmd has to pass the signal on itself.
+static GROUPS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Keeps a process group in `GROUPS` until the child it was spawned for is done.
+pub(crate) struct Tracked(u32);
+
+impl Tracked {
+    pub(crate) fn new(pid: u32) -> Self {
+        GROUPS.lock().unwrap_or_else(|e| e.into_inner()).push(pid);
+        Tracked(pid)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut groups = GROUPS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = groups.iter().position(|&pid| pid == self.0) {
+            groups.swap_remove(i);
+        }
+    }
+}
+
+fn tracked_groups() -> Vec<u32> {
+    GROUPS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Time blocks get to exit after runmd is interrupted before they are killed.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(1);
+
+/// Resolve with the exit status runmd should end with once it receives Ctrl-C
+/// (130) or SIGTERM (143), after passing the signal on to every running block.
+/// Call [`stop_blocks`] next, without polling the interrupted work again.
+pub async fn interrupted() -> i32 {
+    #[cfg(unix)]
+    let (code, signal) = {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => (130, Signal::Interrupt),
+                _ = terminate.recv() => (143, Signal::Terminate),
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                (130, Signal::Interrupt)
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let (code, signal) = {
+        let _ = tokio::signal::ctrl_c().await;
+        (130, Signal::Interrupt)
+    };
+
+    for pid in tracked_groups() {
+        signal_group(pid, signal);
+    }
+    code
+}
+
+/// Give the blocks signalled by [`interrupted`] up to a second to exit, then kill
+/// whatever is left of their process groups.
+pub async fn stop_blocks() {
+    let deadline = tokio::time::Instant::now() + INTERRUPT_GRACE;
+    while tracked_groups().into_iter().any(group_alive) && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    for pid in tracked_groups() {
+        signal_group(pid, Signal::Kill);
+    }
+}
+
+/// Whether any process of the group led by `pid` is still running. The leader is
+/// reaped first, since a zombie would otherwise keep the group alive; runmd is
+/// exiting, so nothing will wait on it again.
+#[cfg(unix)]
+fn group_alive(pid: u32) -> bool {
+    let Ok(pid) = i32::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: waitpid with WNOHANG and a null status pointer only reaps the child
+    // if it has exited, and kill with signal 0 only checks the group exists
+    unsafe {
+        libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG);
+        libc::kill(-pid, 0) == 0
+    }
+}
+
+#[cfg(not(unix))]
+fn group_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Send `signal` to every process in the group led by `pid`.
+#[cfg(unix)]
+fn signal_group(pid: u32, signal: Signal) {
+    let signal = match signal {
+        Signal::Interrupt => libc::SIGINT,
+        Signal::Terminate => libc::SIGTERM,
+        Signal::Kill => libc::SIGKILL,
+    };
+    // A pid of 0 would signal our own group
+    if let Ok(pid) = i32::try_from(pid) {
+        if pid > 0 {
+            // SAFETY: kill has no memory safety requirements
+            unsafe {
+                libc::kill(-pid, signal);
+            }
+        }
+    }
+}
+
+/// Without process groups the child is killed when its handle is dropped.
+#[cfg(not(unix))]
+fn signal_group(_pid: u32, _signal: Signal) {}
+
+/// Apply the configured priority and CPU affinity to the child.
+#[cfg(unix)]
+fn set_scheduling(cmd: &mut Command, options: &RunOptions) {
+    let low = options.priority == Priority::Low;
+    #[cfg(target_os = "linux")]
+    let cpus = (!options.cpu_affinity.is_empty()).then(|| {
+        // SAFETY: cpu_set_t is plain data, and CPU_SET only gets CPUs within its bounds
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in &options.cpu_affinity {
+            if cpu < libc::CPU_SETSIZE as usize {
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+        }
+        set
+    });
+    #[cfg(not(target_os = "linux"))]
+    let cpus: Option<()> = None;
+    if !low && cpus.is_none() {
+        return;
+    }
+
+    // SAFETY: the hook only makes async-signal-safe system calls
+    unsafe {
+        cmd.pre_exec(move || {
+            if low {
+                libc::setpriority(libc::PRIO_PROCESS, 0, 10);
+                // ioprio_set(IOPRIO_WHO_PROCESS, self, best-effort class at its lowest level)
+                #[cfg(target_os = "linux")]
+                libc::syscall(libc::SYS_ioprio_set, 1, 0, (2 << 13) | 7);
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(set) = &cpus {
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(windows)]
+fn set_scheduling(cmd: &mut Command, options: &RunOptions) {
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    if options.priority == Priority::Low {
+        cmd.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn set_scheduling(_cmd: &mut Command, _options: &RunOptions) {}
+
+/// Put the child in a process group of its own, so everything it starts can be
+/// signalled together on timeout or when runmd is interrupted. Spawned children
+/// are registered with [`Tracked`] for the latter.
+fn own_process_group(cmd: &mut Command) {
+    #[cfg(unix)]
+    cmd.process_group(0);
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+fn format_output(output: &Output, capture: CaptureMode) -> RunResult {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let text = if capture == CaptureMode::Split {
+        let stdout = stdout.trim();
+        let stderr = stderr.trim();
+        match (stdout.is_empty(), stderr.is_empty()) {
+            (_, true) => stdout.to_string(),
+            (true, false) => format!("[stderr]\n{}", stderr),
+            (false, false) => format!("{}\n[stderr]\n{}", stdout, stderr),
+        }
+    } else if output.status.success() || !stdout.is_empty() {
+        stdout.trim().to_string()
+    } else {
+        stderr.trim().to_string()
+    };
+
+    RunResult {
+        output: text,
+        exit_code: output.status.code(),
+        stderr: Some(stderr.trim().to_string()),
+    }
+}
+
+/// Run to completion capturing stdout and stderr, writing `input` to stdin if given.
+async fn output_with_input(
+    mut cmd: Command,
+    input: Option<String>,
+    pid: &AtomicU32,
+) -> std::io::Result<Output> {
+    cmd.stdin(if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true);
+    own_process_group(&mut cmd);
+    let mut child = cmd.spawn()?;
+    pid.store(child.id().unwrap_or(0), Ordering::SeqCst);
+    let _group = Tracked::new(child.id().unwrap_or(0));
+
+    // Feed stdin while output is collected so neither side can fill its pipe and stall
+    let stdin = child.stdin.take();
+    let feed = async {
+        if let Some(input) = input {
+            feed_stdin(stdin, input).await;
+        }
+    };
+    let (_, output) = tokio::join!(feed, child.wait_with_output());
+    output
+}
+
+async fn feed_stdin(stdin: Option<tokio::process::ChildStdin>, input: String) {
+    use tokio::io::AsyncWriteExt;
+
+    if let Some(mut stdin) = stdin {
+        // The program may exit without reading everything
+        let _ = stdin.write_all(input.as_bytes()).await;
+    }
+}
+
+/// Run with stdout and stderr sharing one pipe so their interleaving is preserved.
+async fn run_merged(
+    mut cmd: Command,
+    input: Option<String>,
+    pid: &AtomicU32,
+) -> std::io::Result<RunResult> {
+    let (mut reader, writer) = std::io::pipe()?;
+    cmd.stdin(if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    })
+    .stdout(writer.try_clone()?)
+    .stderr(writer)
+    .kill_on_drop(true);
+    own_process_group(&mut cmd);
+
+    let mut child = cmd.spawn()?;
+    pid.store(child.id().unwrap_or(0), Ordering::SeqCst);
+    let _group = Tracked::new(child.id().unwrap_or(0));
+    // Close our copies of the write end so the reader sees EOF when the child exits
+    drop(cmd);
+    if let Some(input) = input {
+        tokio::spawn(feed_stdin(child.stdin.take(), input));
+    }
+
+    let output = tokio::task::spawn_blocking(move || {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let status = child.wait().await?;
+    let output = output.await.map_err(std::io::Error::other)??;
+
+    Ok(RunResult {
+        output: String::from_utf8_lossy(&output).trim().to_string(),
+        exit_code: status.code(),
+        stderr: None,
+    })
+}
+
+/// Interpreters kept running between blocks that share a `session=`, so state
+/// defined by one block is visible to the next. Keyed by session name and language.
+#[derive(Default)]
+pub struct Sessions {
+    running: tokio::sync::Mutex<HashMap<(String, String), Session>>,
+}
+
+/// How code is handed to an interpreter that stays alive between blocks.
+#[derive(Debug, Clone, Copy)]
+enum Driver {
+    /// A loop reading length-prefixed code from stdin and executing it in one namespace
+    Python,
+    /// The same loop with each block run as a script in the global context
+    Node,
+    /// Each block sourced from a file by a shell reading commands from stdin
+    Shell,
+}
+
+const PYTHON_DRIVER: &str = r#"import sys, traceback
+marker = sys.argv[1]
+source = sys.stdin.buffer
+sys.stdin = open("/dev/null")
+scope = {"__name__": "__main__"}
+while True:
+    header = source.readline()
+    if not header:
+        break
+    code = source.read(int(header)).decode()
+    status = 0
+    try:
+        exec(compile(code, "<block>", "exec"), scope)
+    except SystemExit as e:
+        status = e.code if isinstance(e.code, int) else int(e.code is not None)
+    except BaseException:
+        error = sys.exc_info()
+        traceback.print_exception(error[0], error[1], error[2].tb_next)
+        status = 1
+    sys.stdout.flush()
+    sys.stderr.flush()
+    print("\n%s %d" % (marker, status), flush=True)
+"#;
+
+const NODE_DRIVER: &str = r#"const vm = require("vm");
+const marker = process.argv[1];
+globalThis.require = require;
+let pending = Buffer.alloc(0);
+let queue = Promise.resolve();
+async function run(code) {
+  let status = 0;
+  try {
+    await vm.runInThisContext(code, { filename: "block" });
+  } catch (e) {
+    // Frames below the block belong to this driver
+    const lines = String((e && e.stack) || e).split("\n");
+    const driver = lines.findIndex((line) => line.includes("runInThisContext"));
+    console.log((driver < 0 ? lines : lines.slice(0, driver)).join("\n"));
+    status = 1;
+  }
+  process.stdout.write("\n" + marker + " " + status + "\n");
+}
+process.stdin.on("data", (chunk) => {
+  pending = Buffer.concat([pending, chunk]);
+  for (;;) {
+    const newline = pending.indexOf(10);
+    const length = Number(pending.subarray(0, newline).toString());
+    if (newline < 0 || pending.length < newline + 1 + length) break;
+    const code = pending.subarray(newline + 1, newline + 1 + length).toString();
+    pending = pending.subarray(newline + 1 + length);
+    queue = queue.then(() => run(code));
+  }
+});
+"#;
+
+/// Supported interpreters, for error messages.
+const SESSION_INTERPRETERS: &str = "python, node, bash, sh, zsh";
+
+impl Driver {
+    fn for_interpreter(program: &str) -> Option<Driver> {
+        let name = Path::new(program).file_name()?.to_str()?;
+        match name {
+            "node" => Some(Driver::Node),
+            "bash" | "sh" | "zsh" => Some(Driver::Shell),
+            _ if name.starts_with("python") => Some(Driver::Python),
+            _ => None,
+        }
+    }
+
+    fn args(self, marker: &str) -> Vec<String> {
+        let script = match self {
+            Driver::Python => vec!["-u", "-c", PYTHON_DRIVER],
+            Driver::Node => vec!["-e", NODE_DRIVER],
+            Driver::Shell => return Vec::new(),
+        };
+        script
+            .into_iter()
+            .chain([marker])
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// A running interpreter and the pipe its stdout and stderr both write to.
+struct Session {
+    child: tokio::process::Child,
+    stdin: tokio::process::ChildStdin,
+    output: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Command line the interpreter was started with
+    command: Vec<String>,
+    driver: Driver,
+    /// Scratch directory holding the files shell sessions source
+    dir: tempfile::TempDir,
+    marker: String,
+    _group: Tracked,
+}
 
-    let execution_future = cmd.output();
-    let timeout_duration = Duration::from_secs(timeout_secs);
+/// How a block sent to a session ended.
+enum Exchange {
+    /// The block finished with this status and the interpreter is ready for more
+    Done(i32),
+    /// The interpreter itself exited, with this code if it had one
+    Exited(Option<i32>),
+}
+
+impl Sessions {
+    /// Whether `language` runs under an interpreter sessions can drive.
+    pub fn supports(languages: &Languages, language: &str) -> bool {
+        languages
+            .interpreter(languages.resolve(language))
+            .and_then(|program| Driver::for_interpreter(&program))
+            .is_some()
+    }
+
+    /// Run `code` in the session `name` of `language`, starting its interpreter
+    /// with `options` if it isn't running. A session whose interpreter exits or
+    /// times out is discarded, and the next block starts a fresh one.
+    pub async fn run(
+        &self,
+        name: &str,
+        language: &str,
+        code: &str,
+        languages: &Languages,
+        options: &RunOptions,
+    ) -> Result<RunResult> {
+        let language = languages.resolve(language);
+        let Some((program, driver)) = languages
+            .interpreter(language)
+            .and_then(|program| Driver::for_interpreter(&program).map(|d| (program, d)))
+        else {
+            return Ok(RunResult::error(format!(
+                "session= is not supported for '{}' (supported interpreters: {})",
+                language, SESSION_INTERPRETERS
+            )));
+        };
+
+        let mut running = self.running.lock().await;
+        let key = (name.to_string(), language.to_string());
+        let session = match running.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let marker = session_marker();
+                let command: Vec<String> = options
+                    .prefix
+                    .iter()
+                    .cloned()
+                    .chain([program])
+                    .chain(driver.args(&marker))
+                    .collect();
+                let path = options
+                    .env
+                    .iter()
+                    .rev()
+                    .find(|(key, _)| key == "PATH")
+                    .map(|(_, value)| value.as_str());
+                if options.prefix.is_empty() && !languages.check_dependency_exists(&command, path) {
+                    return Ok(RunResult::error(format!(
+                        "Required interpreter/compiler for '{}' is not installed.",
+                        language
+                    )));
+                }
+                entry.insert(Session::start(command, driver, marker, options)?)
+            }
+        };
+        if let Some(dir) = &options.trace {
+            write_trace(dir, code, None, &session.command, options)
+                .with_context(|| format!("Failed to write trace to {}", dir.display()))?;
+        }
 
-    match timeout(timeout_duration, execution_future).await {
-        Ok(Ok(output)) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut collected = Vec::new();
+        let outcome = timeout(options.timeout, session.exchange(code, &mut collected)).await;
+        let text = String::from_utf8_lossy(&collected).trim().to_string();
+        let result = match outcome {
+            Ok(Ok(Exchange::Done(status))) => RunResult {
+                output: text,
+                exit_code: Some(status),
+                stderr: None,
+            },
+            Ok(Ok(Exchange::Exited(code))) => {
+                running.remove(&key);
+                RunResult {
+                    output: text,
+                    exit_code: code,
+                    stderr: None,
+                }
+            }
+            Ok(Err(e)) => {
+                running.remove(&key);
+                RunResult::error(e)
+            }
+            Err(_) => {
+                stop(options).await;
+                if let Some(mut session) = running.remove(&key) {
+                    session.kill().await;
+                }
+                let error = RunResult::error(TIMED_OUT);
+                RunResult {
+                    output: [text, error.output]
+                        .iter()
+                        .filter(|part| !part.is_empty())
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    ..error
+                }
+            }
+        };
+
+        if let Some(dir) = &options.trace {
+            let exit_code = match result.exit_code {
+                Some(code) => code.to_string(),
+                None => "none".to_string(),
+            };
+            std::fs::write(dir.join("output"), &result.output)
+                .and_then(|_| std::fs::write(dir.join("exit_code"), exit_code + "\n"))
+                .with_context(|| format!("Failed to write trace to {}", dir.display()))?;
+        }
+        Ok(result)
+    }
 
-            if output.status.success() || !stdout.is_empty() {
-                Ok(stdout.trim().to_string())
+    /// Stop every interpreter, e.g. once a document's blocks have run.
+    pub async fn close(&mut self) {
+        for (_, mut session) in self.running.get_mut().drain() {
+            session.kill().await;
+        }
+    }
+}
+
+/// A marker no block is likely to print, ending each block's output.
+fn session_marker() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or_default();
+    format!("__runmd_session_{}_{}__", std::process::id(), nanos)
+}
+
+impl Session {
+    fn start(
+        command: Vec<String>,
+        driver: Driver,
+        marker: String,
+        options: &RunOptions,
+    ) -> Result<Session> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("runmd-session-");
+        let dir = match &options.scratch_dir {
+            Some(dir) => builder.tempdir_in(dir),
+            None => builder.tempdir(),
+        }
+        .context("Failed to create temporary directory")?;
+
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..]);
+        if let Some(cwd) = &options.cwd {
+            cmd.current_dir(cwd);
+        }
+        if options.clear_env {
+            cmd.env_clear();
+        }
+        cmd.envs(options.env.iter().map(|(k, v)| (k, v)));
+        set_scheduling(&mut cmd, options);
+
+        // stdout and stderr share one pipe so their interleaving is preserved
+        let (mut reader, writer) = std::io::pipe()?;
+        cmd.stdin(Stdio::piped())
+            .stdout(writer.try_clone()?)
+            .stderr(writer)
+            .kill_on_drop(true);
+        own_process_group(&mut cmd);
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Failed to start {}", command[0]))?;
+        let group = Tracked::new(child.id().unwrap_or(0));
+        drop(cmd);
+        let stdin = child.stdin.take().context("Session has no stdin")?;
+
+        let (sender, output) = tokio::sync::mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let mut buf = [0; 8192];
+            while let Ok(n @ 1..) = reader.read(&mut buf) {
+                if sender.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Session {
+            child,
+            stdin,
+            output,
+            command,
+            driver,
+            dir,
+            marker,
+            _group: group,
+        })
+    }
+
+    /// Send `code` and collect what it prints into `collected` until the marker.
+    async fn exchange(&mut self, code: &str, collected: &mut Vec<u8>) -> Result<Exchange> {
+        use tokio::io::AsyncWriteExt;
+
+        let request = match self.driver {
+            Driver::Python | Driver::Node => format!("{}\n{}", code.len(), code),
+            Driver::Shell => {
+                let file = tempfile::Builder::new()
+                    .suffix(".sh")
+                    .tempfile_in(self.dir.path())
+                    .context("Failed to create temporary file")?;
+                std::fs::write(file.path(), code)?;
+                let (_, path) = file.keep()?;
+                format!(
+                    ". {} </dev/null\nprintf '\\n%s %d\\n' {} \"$?\"\n",
+                    shell_words::quote(&path.to_string_lossy()),
+                    self.marker,
+                )
+            }
+        };
+        if self.stdin.write_all(request.as_bytes()).await.is_err()
+            || self.stdin.flush().await.is_err()
+        {
+            let status = self.child.wait().await?;
+            return Ok(Exchange::Exited(status.code()));
+        }
+
+        let marker = format!("\n{} ", self.marker);
+        let mut searched = 0;
+        loop {
+            if let Some(start) = find(&collected[searched..], marker.as_bytes()) {
+                let start = searched + start;
+                let rest = &collected[start + marker.len()..];
+                if let Some(end) = rest.iter().position(|&b| b == b'\n') {
+                    let status = String::from_utf8_lossy(&rest[..end]).trim().parse()?;
+                    collected.truncate(start);
+                    return Ok(Exchange::Done(status));
+                }
             } else {
-                Ok(stderr.trim().to_string())
+                searched = collected.len().saturating_sub(marker.len());
+            }
+            match self.output.recv().await {
+                Some(chunk) => collected.extend_from_slice(&chunk),
+                None => {
+                    let status = self.child.wait().await?;
+                    return Ok(Exchange::Exited(status.code()));
+                }
             }
         }
-        Ok(Err(e)) => Ok(format!("[error] {}", e)),
-        Err(_) => Ok("[error] execution timed out".to_string()),
     }
+
+    async fn kill(&mut self) {
+        signal_group(self.child.id().unwrap_or(0), Signal::Kill);
+        let _ = self.child.wait().await;
+    }
+}
+
+/// Position of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
 }
 
-fn create_temp_file(language: &str, code: &str) -> Result<NamedTempFile> {
+pub(crate) fn create_temp_file(language: &str, code: &str, dir: &Path) -> Result<NamedTempFile> {
     let suffix = match language {
         "python" => ".py",
         "javascript" | "js" => ".js",
@@ -73,6 +1093,7 @@ fn create_temp_file(language: &str, code: &str) -> Result<NamedTempFile> {
         "bash" | "sh" => ".sh",
         "java" => ".java",
         "cpp" | "c++" => ".cpp",
+        "objc" => ".m",
         "c" => ".c",
         "go" => ".go",
         "ruby" => ".rb",
@@ -90,7 +1111,7 @@ fn create_temp_file(language: &str, code: &str) -> Result<NamedTempFile> {
     };
 
     let mut temp_file =
-        NamedTempFile::with_suffix(suffix).context("Failed to create temporary file")?;
+        NamedTempFile::with_suffix_in(suffix, dir).context("Failed to create temporary file")?;
 
     use std::io::Write;
     temp_file
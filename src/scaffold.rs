@@ -0,0 +1,91 @@
+use anyhow::Result;
+
+use crate::config::Config;
+
+/// Heading and example code for each language `runmd new` can write a section
+/// for, by fence name.
+const EXAMPLES: &[(&str, &str, &str)] = &[
+    ("bash", "Bash", "echo \"Hello from Bash\""),
+    ("sh", "Shell", "echo \"Hello from sh\""),
+    ("python", "Python", "print(\"Hello from Python\")"),
+    ("py", "Python", "print(\"Hello from Python\")"),
+    ("javascript", "JavaScript", "console.log(\"Hello from JavaScript\");"),
+    ("js", "JavaScript", "console.log(\"Hello from JavaScript\");"),
+    ("typescript", "TypeScript", "const greeting: string = \"Hello from TypeScript\";\nconsole.log(greeting);"),
+    ("ruby", "Ruby", "puts \"Hello from Ruby\""),
+    ("php", "PHP", "<?php\necho \"Hello from PHP\\n\";"),
+    ("julia", "Julia", "println(\"Hello from Julia\")"),
+    ("lua", "Lua", "print(\"Hello from Lua\")"),
+    ("r", "R", "cat(\"Hello from R\\n\")"),
+    ("racket", "Racket", "#lang racket\n(displayln \"Hello from Racket\")"),
+    ("rust", "Rust", "fn main() {\n    println!(\"Hello from Rust\");\n}"),
+    ("go", "Go", "package main\n\nimport \"fmt\"\n\nfunc main() {\n\tfmt.Println(\"Hello from Go\")\n}"),
+    ("java", "Java", "public class Main {\n    public static void main(String[] args) {\n        System.out.println(\"Hello from Java\");\n    }\n}"),
+    ("c", "C", "#include <stdio.h>\n\nint main(void) {\n    printf(\"Hello from C\\n\");\n    return 0;\n}"),
+    ("cpp", "C++", "#include <iostream>\n\nint main() {\n    std::cout << \"Hello from C++\" << std::endl;\n}"),
+];
+
+/// Languages `runmd new` uses when none are chosen.
+pub const DEFAULT_LANGUAGES: &[&str] = &["bash"];
+
+/// A new executable document: front matter with `title` and the `runmd`
+/// settings commented out, a quiet `setup` block, and a section with an example
+/// block for each of `languages`, which must be configured.
+pub fn template(title: &str, languages: &[String], config: &Config) -> Result<String> {
+    let mut sections = Vec::new();
+    for language in languages {
+        let resolved = config.aliases.get(language).unwrap_or(language);
+        if !config.languages.contains_key(language) && !config.languages.contains_key(resolved) {
+            anyhow::bail!("No command configured for language `{}`", language);
+        }
+        let Some(&(_, heading, code)) = EXAMPLES
+            .iter()
+            .find(|(name, _, _)| name == language || name == resolved)
+        else {
+            let known: Vec<&str> = EXAMPLES.iter().map(|(name, _, _)| *name).collect();
+            anyhow::bail!(
+                "No example for language `{}`; choose from {}",
+                language,
+                known.join(", ")
+            );
+        };
+        sections.push(format!(
+            "## {}\n\n```{}\n{}\n```\n",
+            heading, language, code
+        ));
+    }
+
+    let yaml_title = serde_yaml::to_string(title)?;
+    Ok(format!(
+        "---\n\
+         title: {}\n\
+         # runmd:\n\
+         #   image: ghcr.io/org/docs-env:1.2\n\
+         #   nix: [nixpkgs#jq]\n\
+         ---\n\
+         \n\
+         # {title}\n\
+         \n\
+         What this document shows and what readers need before starting.\n\
+         \n\
+         ## Setup\n\
+         \n\
+         ```bash name=setup quiet\n\
+         # Runs first and shows no output: create the files and variables the examples use\n\
+         ```\n\
+         \n\
+         {}",
+        yaml_title.trim_end(),
+        sections.join("\n")
+    ))
+}
+
+/// A title from a file name, e.g. `Getting started` for `getting-started.md`.
+pub fn title_from_stem(stem: &str) -> String {
+    let words = stem.replace(['-', '_'], " ");
+    let mut chars = words.trim().chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => "Untitled".to_string(),
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::config::ScanRule;
+use crate::core::CodeBlock;
+
+/// Patterns checked when `scan.rules` is not configured.
+pub fn default_rules() -> Vec<ScanRule> {
+    [
+        ("recursive delete", r"\brm\s+(-\w+\s+)*-\w*[rRf]"),
+        ("download piped to a shell", r"\b(curl|wget)\b[^\n]*\|\s*(sudo\s+)?\w*sh\b"),
+        ("privilege escalation", r"\bsudo\s"),
+        ("disk overwrite", r"\bmkfs\b|\bdd\b[^\n]*\bof=/dev/"),
+        ("fork bomb", r":\(\)\s*\{\s*:\s*\|\s*:\s*&\s*\}\s*;\s*:"),
+        (
+            "network access",
+            r"\b(curl|wget|nc|ncat|ssh|scp|rsync)\s|requests\.(get|post|put|delete)\(|urllib\.request|socket\.socket\(|\bfetch\(",
+        ),
+    ]
+    .into_iter()
+    .map(|(name, pattern)| ScanRule {
+        name: name.to_string(),
+        pattern: pattern.to_string(),
+    })
+    .collect()
+}
+
+/// Attributes whose values become part of the command a block runs.
+const COMMAND_ATTRIBUTES: [&str; 5] = ["cmd", "with", "args", "env", "matrix"];
+
+/// A line of a runnable block that matched a scan rule.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    /// 1-based line in the document
+    pub line: usize,
+    pub language: String,
+    pub rule: String,
+    pub text: String,
+}
+
+/// Check every line of the blocks that would run against `rules`, along with the
+/// attributes that change their command, which are reported on the fence line.
+pub fn scan(blocks: &[CodeBlock], rules: &[ScanRule]) -> Result<Vec<Finding>> {
+    let rules = rules
+        .iter()
+        .map(|rule| {
+            Regex::new(&rule.pattern)
+                .map(|regex| (rule.name.as_str(), regex))
+                .with_context(|| format!("Invalid scan rule '{}'", rule.name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut findings = Vec::new();
+    for block in blocks.iter().filter(|block| !block.skip) {
+        let attributes = block
+            .attrs
+            .values
+            .iter()
+            .filter(|(key, _)| COMMAND_ATTRIBUTES.contains(&key.as_str()))
+            .map(|(key, value)| (block.line, format!("{}={}", key, value)));
+        let code = block
+            .code
+            .lines()
+            .enumerate()
+            .map(|(offset, text)| (block.line + offset + 1, text.to_string()));
+        for (line, text) in attributes.chain(code) {
+            if let Some((rule, _)) = rules.iter().find(|(_, regex)| regex.is_match(&text)) {
+                findings.push(Finding {
+                    line,
+                    language: block.language.clone(),
+                    rule: rule.to_string(),
+                    text: text.trim().to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(findings)
+}
@@ -0,0 +1,154 @@
+use anyhow::Result;
+use serde_yaml::Value;
+
+use crate::config::Config;
+
+/// What each config key does, by dotted path. Keys of user-defined maps such as
+/// `languages` are not listed.
+const DESCRIPTIONS: &[(&str, &str)] = &[
+    ("languages", "Command running each fence language. {file} is the block's source file, {out} a path for build output and {code} the code itself"),
+    ("aliases", "Alternative fence names, e.g. `c++: cpp`, mapped to a configured language"),
+    ("invoke", "How each language receives its code: file (default), arg ({code}) or stdin"),
+    ("allowed_languages", "Only blocks in these languages (or their aliases) execute; others are skipped. null allows every language"),
+    ("tags", "Only blocks with one of these tags= execute; others are skipped. null runs blocks whatever their tags"),
+    ("exclude_tags", "Blocks with any of these tags= are skipped"),
+    ("detect_languages", "Run fences without a language when a shebang line or keywords identify one"),
+    ("ignore_markers", "Comment lines that switch processing off and back on for a region of the document"),
+    ("ignore_markers.off", "Line starting an ignored region"),
+    ("ignore_markers.on", "Line ending an ignored region"),
+    ("skip_sections", "Heading patterns (`*` and `?` wildcards) whose blocks are never executed"),
+    ("ordered", "Always execute blocks one at a time in document order"),
+    ("explicit_run", "Only execute blocks whose fence is marked run, e.g. ```python run; all others are left alone"),
+    ("parallel", "When blocks of a document run concurrently: off, on, or auto (4 or more runnable blocks)"),
+    ("sessions", "Run each document's blocks of a language in one interpreter, as if they all had session=default (python, node and shells)"),
+    ("fixtures", "Directory (relative to the document) copied into the workspace blocks run in"),
+    ("keep_temp", "Keep each block's temporary source file and build artifacts after it runs"),
+    ("record_all", "Record an asciinema cast of every block run, not only those with the record attribute, and link it below the output"),
+    ("record_dir", "Directory next to each document that recordings of its blocks are written to"),
+    ("max_file_size", "Documents larger than this many bytes are only processed with --force"),
+    ("min_free_space", "Bytes that must be free in the temporary directory and workspace for blocks to run; null for no check"),
+    ("confirm_blocks", "Ask for confirmation before running more than this many blocks"),
+    ("max_blocks", "Budget of runnable blocks per document; lint and check fail for documents with more. null for no budget"),
+    ("max_block_time", "Budget of time per block, e.g. 30s; check fails when a block runs longer. null for no budget"),
+    ("foreign_attributes", "Fence attribute names used by other tools, preserved without warnings"),
+    ("output_header", "Line introducing each output section; any other than **Output** gets a hidden marker so outputs are still found after it changes"),
+    ("output_labels", "Label output headers so readers can refer to them: off, number (**Output 2**) or name (**Output: setup**, numbered when unnamed)"),
+    ("exit_codes", "Append each block's exit status to its output header, e.g. **Output** (exit 1)"),
+    ("dedup_outputs", "Show an output identical to that of an earlier named block as *(same output as block \"NAME\")* instead of repeating it"),
+    ("error_summary", "Put a summary of the first error, with its line in the document, above the output of failed blocks in known languages"),
+    ("output_filters", "Built-in filters removing boilerplate warnings from outputs, per language: python-deprecation, node-experimental, jvm-java-options"),
+    ("max_parallel", "Most blocks running at once in parallel mode; null means the number of CPUs"),
+    ("timeout", "Time limit for blocks without a timeout attribute, e.g. 30s; null gives 5s, or 10s for code over 1000 bytes"),
+    ("timeout_grace", "How long a timed-out block gets to exit after SIGTERM before it is killed"),
+    ("exempt", "Names of blocks without the timeout and max_block_time limits, unless they set a timeout attribute"),
+    ("hermetic", "Run every block with LC_ALL=C, COLUMNS=80 and TERM=dumb, unless it sets stable=false"),
+    ("env_policy", "Which environment variables executed blocks see"),
+    ("env_policy.inherit", "Pass the whole parent environment through, apart from deny"),
+    ("env_policy.allow", "Variables (`*` and `?` wildcards) passed through when inherit is off"),
+    ("env_policy.deny", "Variables never passed through, e.g. AWS_*"),
+    ("env_policy.set", "Variables set for every block"),
+    ("priority", "Scheduling priority of executed blocks: normal or low"),
+    ("cpu_affinity", "CPUs executed blocks are restricted to (Linux only)"),
+    ("nix", "Run language commands inside `nix shell` so toolchains come from Nix"),
+    ("nix.enabled", "Use Nix for languages listed in packages"),
+    ("nix.command", "Command the packages and --command are appended to"),
+    ("nix.packages", "Installable providing each language's interpreter, e.g. `python: nixpkgs#python312`"),
+    ("tool_versions", "Run the interpreter versions pinned by .tool-versions or mise.toml next to the document"),
+    ("container_runtime", "Program used for documents that set a container image in their front matter"),
+    ("history", "Log every block result to the history queried by `runmd history`"),
+    ("max_depth", "How deeply runmd may run inside blocks of another runmd before it refuses"),
+    ("cache", "Reuse of block outputs across runs"),
+    ("cache.enabled", "Serve outputs of unchanged blocks from the cache instead of running them"),
+    ("cache.dir", "Local cache directory; null means ~/.cache/runmd/outputs"),
+    ("cache.remote", "Shared cache read on local misses, e.g. for CI machines"),
+    ("cache.remote.url", "Base HTTP(S) URL; entries live at <url>/<key>.yml"),
+    ("cache.remote.write", "Upload new entries as well as reading existing ones"),
+    ("cache.remote.s3_region", "Sign requests for S3 in this region using the standard AWS credential variables"),
+    ("cache.rerun", "Block selectors (name=..., lang=..., line=...) that always bypass the cache"),
+    ("service", "Limits for `runmd serve` and `runmd daemon`"),
+    ("service.max_concurrent", "Most requests executing blocks at once"),
+    ("service.queue_size", "Requests allowed to wait for a free slot; further requests are refused"),
+    ("service.rate_limit", "Requests per minute accepted from each client; null for no limit"),
+    ("service.max_connections", "Most connections serve keeps open at once; further ones are closed"),
+    ("service.allowed_languages", "Languages documents sent to serve may run; null to use allowed_languages"),
+    ("service.env_policy", "Which environment variables blocks of documents sent to serve see"),
+    ("service.env_policy.inherit", "Pass the whole parent environment through, apart from deny"),
+    ("service.env_policy.allow", "Variables (`*` and `?` wildcards) passed through when inherit is off"),
+    ("service.env_policy.deny", "Variables never passed through"),
+    ("service.env_policy.set", "Variables set for every block"),
+    ("service.image", "Container image documents sent to serve run in; required unless --dangerously-run-on-host"),
+    ("scan", "Check of blocks for suspicious commands before anything executes"),
+    ("scan.enabled", "Scan documents before running them"),
+    ("scan.action", "What happens to a document with suspicious blocks: block (refuse without --allow-dangerous) or warn"),
+    ("scan.rules", "Patterns to look for, each with a name and a regular pattern; null uses the built-in rules"),
+];
+
+fn description(path: &str) -> Option<&'static str> {
+    DESCRIPTIONS
+        .iter()
+        .find(|(key, _)| *key == path)
+        .map(|(_, text)| *text)
+}
+
+/// `config` as YAML, with a comment above every key describing it. The result
+/// is itself a valid config file.
+pub fn describe(config: &Config) -> Result<String> {
+    let mut out = String::new();
+    render_mapping(&serde_yaml::to_value(config)?, "", 0, &mut out)?;
+    Ok(out)
+}
+
+fn render_mapping(value: &Value, path: &str, indent: usize, out: &mut String) -> Result<()> {
+    let Value::Mapping(mapping) = value else {
+        return Ok(());
+    };
+    let pad = " ".repeat(indent);
+    let child_path = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+
+    let mut entries: Vec<(String, &Value)> = mapping
+        .iter()
+        .map(|(key, value)| Ok((scalar(key)?, value)))
+        .collect::<Result<_>>()?;
+    // Struct fields keep their declaration order; user-defined maps are sorted
+    if !entries
+        .iter()
+        .any(|(key, _)| description(&child_path(key)).is_some())
+    {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    for (key, value) in entries {
+        let key_path = child_path(&key);
+        if let Some(text) = description(&key_path) {
+            out.push_str(&format!("{}# {}\n", pad, text));
+        }
+        match value {
+            Value::Mapping(mapping) if !mapping.is_empty() => {
+                out.push_str(&format!("{}{}:\n", pad, key));
+                render_mapping(value, &key_path, indent + 2, out)?;
+            }
+            Value::Sequence(items) if !items.is_empty() => {
+                out.push_str(&format!("{}{}:\n", pad, key));
+                for line in serde_yaml::to_string(value)?.lines() {
+                    out.push_str(&format!("{}  {}\n", pad, line));
+                }
+            }
+            _ => {
+                let text = scalar(value)?.replace('\n', &format!("\n{}", pad));
+                out.push_str(&format!("{}{}: {}\n", pad, key, text));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A scalar, empty map or empty list as inline YAML.
+fn scalar(value: &Value) -> Result<String> {
+    Ok(serde_yaml::to_string(value)?.trim_end().to_string())
+}
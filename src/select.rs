@@ -0,0 +1,49 @@
+use anyhow::{bail, Result};
+
+use crate::core::CodeBlock;
+use crate::glob::glob_match;
+
+/// Picks out blocks by a `key=value` expression such as `name=setup`,
+/// `lang=python` or `line=42`. Names and languages accept `*` and `?` wildcards.
+#[derive(Debug, Clone)]
+pub enum Selector {
+    Name(String),
+    Language(String),
+    Line(usize),
+}
+
+impl Selector {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let Some((key, value)) = expr.split_once('=') else {
+            bail!("Invalid block selector '{}': expected key=value", expr);
+        };
+
+        match key {
+            "name" => Ok(Selector::Name(value.to_string())),
+            "lang" | "language" => Ok(Selector::Language(value.to_string())),
+            "line" => match value.parse() {
+                Ok(line) => Ok(Selector::Line(line)),
+                Err(_) => bail!("Invalid line number in block selector '{}'", expr),
+            },
+            _ => bail!(
+                "Unknown block selector key '{}' (expected name, lang or line)",
+                key
+            ),
+        }
+    }
+
+    pub fn parse_all(exprs: &[String]) -> Result<Vec<Self>> {
+        exprs.iter().map(|expr| Self::parse(expr)).collect()
+    }
+
+    pub fn matches(&self, block: &CodeBlock) -> bool {
+        match self {
+            Selector::Name(pattern) => block
+                .attrs
+                .get("name")
+                .is_some_and(|name| glob_match(pattern, name)),
+            Selector::Language(pattern) => glob_match(pattern, &block.language),
+            Selector::Line(line) => block.line == *line,
+        }
+    }
+}
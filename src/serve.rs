@@ -0,0 +1,370 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+use crate::config::Config;
+use crate::core::{clear_outputs, process_markdown, runnable_blocks_by_language, RunContext};
+use crate::limits::{Limiter, Rejection};
+use crate::metrics::{self, Metrics};
+
+/// Environment variable holding the token clients must send as
+/// `Authorization: Bearer <token>`.
+pub const TOKEN_VAR: &str = "RUNMD_TOKEN";
+
+/// How long a client may take to send its request headers, and again its body.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest request line or header line accepted.
+const MAX_LINE: usize = 8 * 1024;
+
+/// Most bytes accepted for the request line and headers together.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Most bytes of an unread body drained after answering, so the client sees the
+/// response rather than a reset connection.
+const MAX_DRAIN: u64 = 1024 * 1024;
+
+/// An HTTP response: status code, reason and plain-text or Markdown body.
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Self {
+        Response {
+            status: 200,
+            reason: "OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: impl Into<String>) -> Self {
+        Response {
+            status,
+            reason,
+            content_type: "text/plain; charset=utf-8",
+            body: message.into() + "\n",
+        }
+    }
+}
+
+/// A request's line and headers; the body is only read once the request is
+/// authorized and admitted.
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Server {
+    config: Config,
+    token: Option<String>,
+    limiter: Limiter,
+    metrics: Metrics,
+    /// Open connections, up to `service.max_connections`
+    connections: Arc<Semaphore>,
+}
+
+/// Serve `POST /process` and `POST /clear` over HTTP until interrupted. Every
+/// request runs in its own scratch workspace, and documents over `max_file_size`
+/// or with more than `confirm_blocks` runnable blocks are refused, as are requests
+/// beyond the limits in `service`. Blocks run under the `service` environment
+/// policy, language allowlist and image rather than those for local runs, and
+/// documents can't pick another image.
+pub async fn serve(mut config: Config, addr: &str, token: Option<String>) -> Result<()> {
+    config.env_policy = config.service.env_policy.clone();
+    if let Some(allowed) = &config.service.allowed_languages {
+        config.allowed_languages = Some(allowed.clone());
+    }
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    println!("Listening on http://{}", listener.local_addr()?);
+
+    let server = Rc::new(Server {
+        limiter: Limiter::new(&config.service),
+        metrics: Metrics::default(),
+        connections: Arc::new(Semaphore::new(config.service.max_connections.max(1))),
+        config,
+        token,
+    });
+
+    // Processing futures borrow the run context across awaits, so connections
+    // are served on this thread
+    let connections = tokio::task::LocalSet::new();
+    connections
+        .run_until(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (stream, _) = accepted?;
+                        // Connections beyond the limit are closed straight away
+                        let Ok(permit) = server.connections.clone().try_acquire_owned() else {
+                            continue;
+                        };
+                        let server = server.clone();
+                        tokio::task::spawn_local(async move {
+                            let _permit = permit;
+                            if let Err(e) = server.handle_connection(stream).await {
+                                eprintln!("runmd serve: {:#}", e);
+                            }
+                        });
+                    }
+                    _ = tokio::signal::ctrl_c() => return Ok(()),
+                }
+            }
+        })
+        .await
+}
+
+impl Server {
+    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let client = stream.peer_addr()?.ip().to_string();
+        let mut reader = BufReader::new(stream);
+        let request = read_head(&mut reader);
+        let response = match tokio::time::timeout(READ_TIMEOUT, request).await {
+            Ok(Ok(request)) => self.handle(request, &mut reader, &client).await,
+            Ok(Err(response)) => response,
+            Err(_) => timed_out(),
+        };
+
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.reason,
+            response.content_type,
+            response.body.len()
+        );
+        let stream = reader.get_mut();
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(response.body.as_bytes()).await?;
+        stream.shutdown().await?;
+
+        // Closing with an unread body would reset the connection
+        let mut drain = (&mut reader).take(MAX_DRAIN);
+        let _ = tokio::time::timeout(
+            Duration::from_secs(1),
+            tokio::io::copy(&mut drain, &mut tokio::io::sink()),
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn handle(
+        &self,
+        request: Request,
+        reader: &mut BufReader<TcpStream>,
+        client: &str,
+    ) -> Response {
+        if request.path == "/health" {
+            return Response::ok("text/plain; charset=utf-8", "ok\n".to_string());
+        }
+
+        if let Some(token) = &self.token {
+            let expected = format!("Bearer {}", token);
+            let given = request.header("authorization").unwrap_or("");
+            if !constant_time_eq(given.as_bytes(), expected.as_bytes()) {
+                return Response::error(401, "Unauthorized", "Missing or invalid token");
+            }
+        }
+
+        if request.path == "/metrics" {
+            return Response::ok(metrics::CONTENT_TYPE, self.metrics.render());
+        }
+
+        let clear = match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/process") => false,
+            ("POST", "/clear") => true,
+            (_, "/process" | "/clear") => {
+                return Response::error(405, "Method Not Allowed", "Use POST")
+            }
+            _ => return Response::error(404, "Not Found", "Unknown endpoint"),
+        };
+        let length = match content_length(&request, self.config.max_file_size) {
+            Ok(length) => length,
+            Err(response) => return response,
+        };
+
+        let _permit = match self.limiter.acquire(client).await {
+            Ok(permit) => permit,
+            Err(e) => {
+                self.metrics.record_rejection(e);
+                return match e {
+                    Rejection::RateLimited => {
+                        Response::error(429, "Too Many Requests", e.to_string())
+                    }
+                    Rejection::QueueFull => {
+                        Response::error(503, "Service Unavailable", e.to_string())
+                    }
+                };
+            }
+        };
+
+        let body = match tokio::time::timeout(READ_TIMEOUT, read_body(reader, length)).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(response)) => return response,
+            Err(_) => return timed_out(),
+        };
+        let Ok(content) = String::from_utf8(body) else {
+            return Response::error(400, "Bad Request", "Document is not valid UTF-8");
+        };
+
+        let result = if clear {
+            clear_outputs(&content, &self.config)
+        } else {
+            match runnable_blocks_by_language(&content, &self.config) {
+                Ok(blocks) if blocks.values().sum::<usize>() > self.config.confirm_blocks => {
+                    return Response::error(
+                        413,
+                        "Payload Too Large",
+                        format!(
+                            "Document has more than confirm_blocks ({}) runnable blocks",
+                            self.config.confirm_blocks
+                        ),
+                    )
+                }
+                Ok(_) => self.process(&content).await,
+                Err(e) => Err(e),
+            }
+        };
+
+        match result {
+            Ok(result) => Response::ok("text/markdown; charset=utf-8", result),
+            Err(e) => Response::error(500, "Internal Server Error", format!("{:#}", e)),
+        }
+    }
+
+    async fn process(&self, content: &str) -> Result<String> {
+        let mut context = RunContext::new(&self.config, Path::new("."))?;
+        context.use_workspace()?;
+        context.distrust_documents();
+        if let Some(image) = &self.config.service.image {
+            context.use_image(image);
+        }
+        let (result, report) = process_markdown(content, &self.config, &mut context).await?;
+        self.metrics
+            .record_document(&report, self.config.cache.enabled);
+        Ok(result)
+    }
+}
+
+fn timed_out() -> Response {
+    Response::error(408, "Request Timeout", "Request not received in time")
+}
+
+/// Compare secrets in time that depends only on their lengths, so a client can't
+/// find the token byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Read a request's line and headers, refusing lines over `MAX_LINE` bytes and
+/// heads over `MAX_HEAD`.
+async fn read_head(reader: &mut BufReader<TcpStream>) -> Result<Request, Response> {
+    let bad_request = |message: &str| Response::error(400, "Bad Request", message);
+    let mut budget = MAX_HEAD;
+
+    let line = read_line(reader, &mut budget).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(bad_request("Malformed request line"));
+    };
+    let method = method.to_string();
+    let path = path.split('?').next().unwrap_or(path).to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader, &mut budget).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(bad_request("Malformed header"));
+        };
+        headers.push((key.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+    })
+}
+
+/// Read one line of at most `MAX_LINE` bytes, taking its length from `budget`.
+async fn read_line(
+    reader: &mut BufReader<TcpStream>,
+    budget: &mut usize,
+) -> Result<String, Response> {
+    let limit = MAX_LINE.min(*budget);
+    let mut line = String::new();
+    reader
+        .take(limit as u64)
+        .read_line(&mut line)
+        .await
+        .map_err(|_| Response::error(400, "Bad Request", "Malformed request"))?;
+    if line.len() == limit && !line.ends_with('\n') {
+        let reason = "Request Header Fields Too Large";
+        return Err(Response::error(431, reason, "Request head too large"));
+    }
+    if !line.ends_with('\n') {
+        return Err(Response::error(400, "Bad Request", "Incomplete request"));
+    }
+    *budget -= line.len();
+    Ok(line)
+}
+
+/// The body length `request` announces, refusing bodies larger than `max_body`.
+fn content_length(request: &Request, max_body: u64) -> Result<u64, Response> {
+    let length: u64 = request
+        .header("content-length")
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| Response::error(400, "Bad Request", "Invalid Content-Length"))
+        })
+        .transpose()?
+        .unwrap_or(0);
+    if length > max_body {
+        return Err(Response::error(
+            413,
+            "Payload Too Large",
+            format!("Document is larger than max_file_size ({} bytes)", max_body),
+        ));
+    }
+    Ok(length)
+}
+
+/// Read a body of `length` bytes, growing the buffer only as data arrives.
+async fn read_body(reader: &mut BufReader<TcpStream>, length: u64) -> Result<Vec<u8>, Response> {
+    let mut body = Vec::new();
+    reader
+        .take(length)
+        .read_to_end(&mut body)
+        .await
+        .map_err(|_| Response::error(400, "Bad Request", "Incomplete body"))?;
+    if (body.len() as u64) < length {
+        return Err(Response::error(400, "Bad Request", "Incomplete body"));
+    }
+    Ok(body)
+}
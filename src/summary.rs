@@ -0,0 +1,115 @@
+use regex::{Captures, Regex};
+
+/// The temporary file a block's code is written to, e.g. `/tmp/runmd-x/.tmpab12cd.py`.
+const TEMP_FILE: &str = r#"(?:[^\s("']*/)?\.tmp[[:alnum:]]{6}(?:\.\w+)?"#;
+
+/// A file in error output that is the block's source: its temporary file, or
+/// the name interpreters give code passed as an argument or run in a session.
+const SOURCE: &str = r#"(?:TEMP_FILE|<string>|<stdin>|<block>|block|\[eval\])"#;
+
+/// What the block's source is called in error output once its line numbers
+/// are those of the document.
+pub const DOCUMENT: &str = "<document>";
+
+/// `output` with each reference to a line of the block's source, such as
+/// `File "/tmp/runmd-x/.tmpab12cd.py", line 3` or `.tmpab12cd.c:3:5`, pointing
+/// at the same line of the document as `<document>`, and other mentions of its
+/// temporary file replaced by `<document>` too. `first_line` is the
+/// document line of the block's first line of code, and `prelude` the number of
+/// lines runmd put before the code in the file it ran.
+pub fn remap(output: &str, first_line: usize, prelude: usize) -> String {
+    let source = SOURCE.replace("TEMP_FILE", TEMP_FILE);
+    let reference = Regex::new(&format!(r#"(^|[\s("]){}(", line |: line |:)(\d+)"#, source))
+        .expect("valid source pattern");
+    let remapped = reference.replace_all(output, |captures: &Captures| {
+        let line: usize = captures[3].parse().unwrap_or(0);
+        let line = (first_line + line)
+            .saturating_sub(prelude + 1)
+            .max(first_line);
+        format!("{}{}{}{}", &captures[1], DOCUMENT, &captures[2], line)
+    });
+    let temp_file = Regex::new(&format!(r#"(^|[\s("]){}"#, TEMP_FILE)).expect("valid file pattern");
+    temp_file
+        .replace_all(&remapped, format!("${{1}}{}", DOCUMENT))
+        .into_owned()
+}
+
+/// A one-line summary of the first error in `output`, e.g.
+/// `First error at line 12: NameError: name 'x' is not defined`, read from
+/// output [`remap`]ped to document lines. `None` when no known toolchain's
+/// error format is found.
+pub fn summarize(output: &str) -> Option<String> {
+    let (line, message) = python(output)
+        .or_else(|| rustc(output))
+        .or_else(|| node(output))
+        .or_else(|| located(output))?;
+    Some(format!("First error at line {}: {}", line, message))
+}
+
+fn pattern(template: &str) -> Regex {
+    Regex::new(&template.replace("SOURCE", &regex::escape(DOCUMENT)))
+        .expect("valid summary pattern")
+}
+
+/// The innermost frame of a traceback in the block's source, and the exception
+/// ending it.
+fn python(output: &str) -> Option<(usize, String)> {
+    let frame = pattern(r#"^\s*File "SOURCE", line (\d+)"#);
+    let line = output
+        .lines()
+        .rev()
+        .find_map(|line| frame.captures(line))?
+        .get(1)?
+        .as_str()
+        .parse()
+        .ok()?;
+    let message = output.lines().rev().find(|line| !line.trim().is_empty())?;
+    Some((line, message.trim().to_string()))
+}
+
+/// `error[E0425]: ...` and the ` --> file:3:5` line after it.
+fn rustc(output: &str) -> Option<(usize, String)> {
+    let error = pattern(r"^error(\[E\d+\])?: ");
+    let location = pattern(r"^\s*--> SOURCE:(\d+):\d+");
+    let lines: Vec<&str> = output.lines().collect();
+    let start = lines.iter().position(|line| error.is_match(line))?;
+    let line = lines[start + 1..]
+        .iter()
+        .find_map(|line| location.captures(line))?
+        .get(1)?
+        .as_str()
+        .parse()
+        .ok()?;
+    Some((line, lines[start].to_string()))
+}
+
+/// A `file:3` line, the offending code and a caret, then the error thrown.
+fn node(output: &str) -> Option<(usize, String)> {
+    let location = pattern(r"^SOURCE:(\d+)$");
+    let error = pattern(r"^[A-Z]\w*(: .*)?$");
+    let lines: Vec<&str> = output.lines().collect();
+    let (start, line) = lines.iter().enumerate().find_map(|(i, line)| {
+        let captures = location.captures(line)?;
+        Some((i, captures.get(1)?.as_str().parse().ok()?))
+    })?;
+    let message = lines[start + 1..]
+        .iter()
+        .find(|line| error.is_match(line))?;
+    Some((line, message.to_string()))
+}
+
+/// `file: line 3: message` from shells, or `file:3:5: message` from C and C++
+/// compilers, javac, Go and Ruby, among others.
+fn located(output: &str) -> Option<(usize, String)> {
+    let patterns = [
+        pattern(r"^SOURCE: line (\d+): (.+)$"),
+        pattern(r"^SOURCE:(\d+):(?:\d+:)? (.+)$"),
+    ];
+    output.lines().find_map(|line| {
+        patterns.iter().find_map(|pattern| {
+            let captures = pattern.captures(line)?;
+            let number = captures.get(1)?.as_str().parse().ok()?;
+            Some((number, captures.get(2)?.as_str().to_string()))
+        })
+    })
+}
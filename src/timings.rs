@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Entries kept in the timing history; saving beyond this drops the least
+/// recently recorded.
+const MAX_ENTRIES: usize = 10_000;
+
+/// How long each block took the last time it ran, keyed by the canonical path of
+/// its document and `hash::block_identity`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    durations_ms: HashMap<String, u64>,
+    /// When each duration was recorded, in seconds since the Unix epoch
+    #[serde(default)]
+    recorded: HashMap<String, u64>,
+    /// Keys recorded since loading, which saving merges into the file
+    #[serde(skip)]
+    changed: HashSet<String>,
+}
+
+impl Timings {
+    pub fn path() -> Result<PathBuf> {
+        let cache_dir = dirs::cache_dir()
+            .context("Could not determine cache directory")?
+            .join("runmd");
+
+        Ok(cache_dir.join("timings.yml"))
+    }
+
+    /// Load the timing history, starting empty if it is missing or unreadable.
+    pub fn load() -> Self {
+        Self::path()
+            .ok()
+            .map(|path| Self::load_from(&path))
+            .unwrap_or_default()
+    }
+
+    fn load_from(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_yaml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Merge the durations recorded since loading into the file, so nested and
+    /// concurrent runmd processes don't drop each other's, and replace it in one
+    /// rename.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        let parent = path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(parent)?;
+
+        let mut merged = Self::load_from(&path);
+        for key in &self.changed {
+            if let Some(ms) = self.durations_ms.get(key) {
+                merged.durations_ms.insert(key.clone(), *ms);
+                merged
+                    .recorded
+                    .insert(key.clone(), self.recorded.get(key).copied().unwrap_or(0));
+            }
+        }
+        merged.prune();
+
+        let content = serde_yaml::to_string(&merged)?;
+        let staged = path.with_extension(format!("yml.{}", std::process::id()));
+        std::fs::write(&staged, content)
+            .and_then(|_| std::fs::rename(&staged, &path))
+            .with_context(|| format!("Failed to write timings: {}", path.display()))
+    }
+
+    /// Drop the least recently recorded entries beyond `MAX_ENTRIES`, along with
+    /// timestamps left without a duration.
+    fn prune(&mut self) {
+        let durations = &self.durations_ms;
+        self.recorded.retain(|key, _| durations.contains_key(key));
+        if self.durations_ms.len() <= MAX_ENTRIES {
+            return;
+        }
+
+        let mut keys: Vec<(u64, String)> = self
+            .durations_ms
+            .keys()
+            .map(|key| (self.recorded.get(key).copied().unwrap_or(0), key.clone()))
+            .collect();
+        keys.sort();
+        for (_, key) in &keys[..keys.len() - MAX_ENTRIES] {
+            self.durations_ms.remove(key);
+            self.recorded.remove(key);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Duration> {
+        self.durations_ms
+            .get(key)
+            .map(|ms| Duration::from_millis(*ms))
+    }
+
+    pub fn record(&mut self, key: String, duration: Duration) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.durations_ms
+            .insert(key.clone(), duration.as_millis() as u64);
+        self.recorded.insert(key.clone(), now);
+        self.changed.insert(key);
+    }
+}
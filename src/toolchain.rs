@@ -0,0 +1,86 @@
+use colored::Colorize;
+use std::path::{Path, PathBuf};
+
+/// Tool versions pinned by the `.tool-versions`, `mise.toml` or `.mise.toml` file
+/// nearest to `dir`, searching its parents like asdf and mise do.
+pub fn pinned_versions(dir: &Path) -> Vec<(String, String)> {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    for dir in dir.ancestors() {
+        if let Ok(content) = std::fs::read_to_string(dir.join(".tool-versions")) {
+            return parse_tool_versions(&content);
+        }
+        for name in ["mise.toml", ".mise.toml"] {
+            if let Ok(content) = std::fs::read_to_string(dir.join(name)) {
+                return parse_mise_toml(&content);
+            }
+        }
+    }
+    Vec::new()
+}
+
+/// `tool version [fallback...]` lines; only the first version is used.
+fn parse_tool_versions(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            Some((words.next()?.to_string(), words.next()?.to_string()))
+        })
+        .collect()
+}
+
+/// `name = "version"` entries of the `[tools]` table.
+fn parse_mise_toml(content: &str) -> Vec<(String, String)> {
+    let mut versions = Vec::new();
+    let mut in_tools = false;
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.starts_with('[') {
+            in_tools = line == "[tools]";
+        } else if let Some((name, version)) = line.split_once('=').filter(|_| in_tools) {
+            let version = version.trim().trim_matches(|c| c == '"' || c == '\'');
+            if !version.starts_with(['[', '{']) {
+                versions.push((name.trim().trim_matches('"').to_string(), version.to_string()));
+            }
+        }
+    }
+    versions
+}
+
+/// Where asdf and mise keep installed tools.
+fn install_roots() -> Vec<PathBuf> {
+    let mut roots = Vec::new();
+    match std::env::var_os("ASDF_DATA_DIR") {
+        Some(dir) => roots.push(PathBuf::from(dir)),
+        None => roots.extend(dirs::home_dir().map(|home| home.join(".asdf"))),
+    }
+    match std::env::var_os("MISE_DATA_DIR") {
+        Some(dir) => roots.push(PathBuf::from(dir)),
+        None => roots.extend(dirs::data_dir().map(|data| data.join("mise"))),
+    }
+    roots
+}
+
+/// `bin` directories of the installed pinned versions, warning about versions
+/// that aren't installed.
+pub fn bin_dirs(versions: &[(String, String)]) -> Vec<PathBuf> {
+    let roots = install_roots();
+    let mut dirs = Vec::new();
+    for (tool, version) in versions {
+        let found = roots
+            .iter()
+            .map(|root| root.join("installs").join(tool).join(version).join("bin"))
+            .find(|bin| bin.is_dir());
+        match found {
+            Some(bin) => dirs.push(bin),
+            None => eprintln!(
+                "{} {} {} is pinned but not installed by asdf or mise; using PATH",
+                "warning:".yellow().bold(),
+                tool,
+                version
+            ),
+        }
+    }
+    dirs
+}
@@ -0,0 +1,111 @@
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+
+use crate::attrs::parse_duration;
+use crate::config::{Config, ParallelMode};
+use crate::languages::Languages;
+use crate::schema;
+
+/// Build a config by asking on the terminal, offering only the default languages
+/// whose tools are installed, and write it to `path` with every key described.
+pub fn run(path: &Path) -> Result<()> {
+    if path.exists() && !ask_yes_no(&format!("{} exists. Replace it?", path.display()), false)? {
+        println!("Left {} unchanged", path.display());
+        return Ok(());
+    }
+
+    let mut config = Config::default();
+    let languages = Languages::new(Default::default(), Default::default(), Default::default());
+
+    println!("Looking for interpreters and compilers:");
+    let mut names: Vec<String> = config.languages.keys().cloned().collect();
+    names.sort();
+    let mut missing = Vec::new();
+    for name in names {
+        let command = &config.languages[&name];
+        let tool = required_tool(command);
+        if languages.check_dependency_exists(std::slice::from_ref(&tool), None) {
+            println!("  found    {:<12} {}", name, command);
+        } else {
+            println!("  missing  {:<12} ({} not found)", name, tool);
+            missing.push(name);
+        }
+    }
+    if !missing.is_empty()
+        && !ask_yes_no("Also include the languages whose tools are missing?", false)?
+    {
+        config.languages.retain(|name, _| !missing.contains(name));
+        let languages = &config.languages;
+        config
+            .aliases
+            .retain(|_, language| languages.contains_key(language));
+    }
+
+    config.timeout = ask(
+        "Time limit for each block, e.g. 30s (empty for 5s, or 10s for long blocks)",
+        |answer| {
+            if answer.is_empty() {
+                return Ok(None);
+            }
+            parse_duration(answer).map(|_| Some(answer.to_string()))
+        },
+    )?;
+    config.parallel = ask(
+        "Run a document's blocks in parallel: off, on, or auto (4 or more blocks)? [off]",
+        |answer| match answer {
+            "" | "off" => Ok(ParallelMode::Off),
+            "on" => Ok(ParallelMode::On),
+            "auto" => Ok(ParallelMode::Auto),
+            _ => Err("expected off, on or auto".to_string()),
+        },
+    )?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, schema::describe(&config)?)?;
+    println!("Wrote config to {}", path.display());
+    Ok(())
+}
+
+/// The program a language command needs, looking inside `sh -c '...'` wrappers.
+fn required_tool(command: &str) -> String {
+    let words = shell_words::split(command).unwrap_or_default();
+    match words.as_slice() {
+        [shell, flag, script, ..] if (shell == "sh" || shell == "bash") && flag == "-c" => {
+            script.split_whitespace().next().unwrap_or(shell).to_string()
+        }
+        [program, ..] => program.clone(),
+        [] => String::new(),
+    }
+}
+
+/// Ask until `parse` accepts the trimmed answer.
+fn ask<T>(prompt: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<T> {
+    loop {
+        print!("{} ", prompt);
+        std::io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            anyhow::bail!("No answer given");
+        }
+        match parse(answer.trim()) {
+            Ok(value) => return Ok(value),
+            Err(e) => println!("  {}", e),
+        }
+    }
+}
+
+fn ask_yes_no(prompt: &str, default: bool) -> Result<bool> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    ask(&format!("{} {}", prompt, hint), |answer| {
+        match answer.to_lowercase().as_str() {
+            "" => Ok(default),
+            "y" | "yes" => Ok(true),
+            "n" | "no" => Ok(false),
+            _ => Err("expected y or n".to_string()),
+        }
+    })
+}
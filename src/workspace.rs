@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// A scratch directory blocks run in, removed when the run ends.
+pub struct Workspace {
+    dir: TempDir,
+}
+
+impl Workspace {
+    pub fn new() -> Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("runmd-workspace-")
+            .tempdir()
+            .context("Failed to create workspace directory")?;
+
+        Ok(Workspace { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Copy the contents of `fixtures` into the workspace.
+    pub fn copy_fixtures(&self, fixtures: &Path) -> Result<()> {
+        copy_dir(fixtures, self.path())
+            .with_context(|| format!("Failed to copy fixtures from {}", fixtures.display()))
+    }
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+
+    Ok(())
+}
@@ -3,6 +3,22 @@ use predicates::prelude::*;
 use std::fs;
 use tempfile::TempDir;
 
+/// `runmd` logging its history under `temp_dir` rather than the user's data directory.
+fn runmd_command(temp_dir: &TempDir) -> Command {
+    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    cmd.env("XDG_DATA_HOME", temp_dir.path().join("data"));
+    cmd
+}
+
+/// Write `config` as the runmd config under `temp_dir`, returning the directory
+/// to pass as `XDG_CONFIG_HOME`.
+fn write_config(temp_dir: &TempDir, config: impl AsRef<[u8]>) -> std::path::PathBuf {
+    let config_dir = temp_dir.path().join("config");
+    fs::create_dir_all(config_dir.join("runmd")).unwrap();
+    fs::write(config_dir.join("runmd/languages.config"), config).unwrap();
+    config_dir
+}
+
 #[test]
 fn test_cli_help() {
     let mut cmd = Command::cargo_bin("runmd").unwrap();
@@ -17,7 +33,7 @@ fn test_init_config() {
     let temp_dir = TempDir::new().unwrap();
     std::env::set_var("HOME", temp_dir.path());
 
-    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
     cmd.arg("--init-config");
     cmd.assert()
         .success()
@@ -38,7 +54,7 @@ echo "hello"
 
     fs::write(&test_file, content).unwrap();
 
-    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
     cmd.arg(test_file.to_str().unwrap());
     cmd.timeout(std::time::Duration::from_secs(10)); // Short timeout
 
@@ -72,7 +88,7 @@ print("hello world")
 
     fs::write(&test_file, content).unwrap();
 
-    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
     cmd.arg(test_file.to_str().unwrap());
     cmd.timeout(std::time::Duration::from_secs(30)); // 30 second timeout
     cmd.assert().success();
@@ -99,7 +115,7 @@ hello
 
     fs::write(&test_file, content).unwrap();
 
-    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
     cmd.arg("-c").arg(test_file.to_str().unwrap());
     cmd.timeout(std::time::Duration::from_secs(5)); // Should be fast
     cmd.assert().success();
@@ -109,6 +125,129 @@ hello
     assert!(!result.contains("hello\n```"));
 }
 
+#[test]
+fn test_crlf_multibyte_document() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    // Found by the parse fuzz target: CRLF lines before a block shifted its span
+    // into the middle of the 'é'
+    fs::write(&test_file, "a\r\nb\r\né\r\n```sh\r\necho x\r\n```\r\nafter\r\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    // The block and its output keep the document's line breaks
+    assert_eq!(
+        fs::read_to_string(&test_file).unwrap(),
+        "a\r\nb\r\né\r\n```sh\r\necho x\r\n```\r\n**Output**\r\n```\r\nx\r\n```\r\nafter\r\n"
+    );
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("-c").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert_eq!(
+        fs::read_to_string(&test_file).unwrap(),
+        "a\r\nb\r\né\r\n```sh\r\necho x\r\n```\r\nafter\r\n"
+    );
+}
+
+#[test]
+fn test_multibyte_text_around_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    // Multi-byte text right up against every fence, in LF and CRLF documents,
+    // and a final block without a line break
+    let base = "# 日本語 🦀\n\
+                説明🦀\n```bash name=\"éà\"\necho 'ünïcödé 👋'\n```\n🎉 после\n\n\
+                <!-- runmd:off -->\n```bash\necho 無視\n```\n<!-- runmd:on -->\n\
+                ```bash hide-code\nprintf '界\\n'\n```\n€\n```bash\necho 末尾\n```";
+    for newline in ["\n", "\r\n"] {
+        let content = base.replace('\n', newline);
+        fs::write(&test_file, &content).unwrap();
+
+        runmd(&[]).success();
+        let processed = fs::read_to_string(&test_file).unwrap();
+        let expected = "# 日本語 🦀\n\
+                        説明🦀\n```bash name=\"éà\"\necho 'ünïcödé 👋'\n```\n**Output**\n```\nünïcödé 👋\n```\n🎉 после\n\n\
+                        <!-- runmd:off -->\n```bash\necho 無視\n```\n<!-- runmd:on -->\n\
+                        <!-- runmd:hide-code\n```bash hide-code\nprintf '界\\n'\n```\n-->\n**Output**\n```\n界\n```\n€\n\
+                        ```bash\necho 末尾\n```\n**Output**\n```\n末尾\n```";
+        assert_eq!(processed, expected.replace('\n', newline));
+
+        // Running again changes nothing, and clearing restores the text around the blocks
+        runmd(&["check"]).success();
+        runmd(&["clear"]).success();
+        let hidden = "```bash hide-code\nprintf '界\\n'\n```\n";
+        let cleared = base.replace(hidden, &format!("<!-- runmd:hide-code\n{}-->\n", hidden));
+        assert_eq!(fs::read_to_string(&test_file).unwrap(), cleared.replace('\n', newline));
+    }
+}
+
+#[test]
+fn test_bench_internal() {
+    let temp_dir = TempDir::new().unwrap();
+    let baseline = temp_dir.path().join("baseline.yml");
+    let bench = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(["--bench-internal", "--bench-blocks", "50"]).args(args);
+        cmd.timeout(std::time::Duration::from_secs(30));
+        cmd.assert()
+    };
+
+    bench(&["--bench-save", baseline.to_str().unwrap()])
+        .success()
+        .stdout(predicate::str::contains("parse"))
+        .stdout(predicate::str::contains("clear"))
+        .stdout(predicate::str::contains("render"));
+    assert!(fs::read_to_string(&baseline).unwrap().contains("blocks: 50"));
+
+    // An impossibly fast baseline is a regression
+    fs::write(
+        &baseline,
+        "blocks: 50\nbytes: 1\nstages_ms:\n  parse: 0.000001\n  clear: 0.000001\n",
+    )
+    .unwrap();
+    bench(&["--bench-baseline", baseline.to_str().unwrap()])
+        .failure()
+        .stderr(predicate::str::contains("Slower than baseline"))
+        .stderr(predicate::str::contains("parse:"));
+}
+
+#[test]
+fn test_clear_outputs_pathological() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    // Many outputs, then headers whose fences never close
+    let block = "```sh\necho hi\n```\n";
+    let mut content = String::new();
+    for _ in 0..20_000 {
+        content.push_str(block);
+        content.push_str("**Output**\n```\nhi\n```\n\n");
+    }
+    let unclosed = "**Output**\n```text\n".repeat(20_000);
+    content.push_str(&unclosed);
+    fs::write(&test_file, &content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("-c").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(5));
+    cmd.assert().success();
+
+    let expected = format!("{}{}", format!("{}\n", block).repeat(20_000), unclosed);
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), expected);
+}
+
 #[test]
 fn test_error_handling() {
     let temp_dir = TempDir::new().unwrap();
@@ -123,7 +262,7 @@ raise Exception("error")
 
     fs::write(&test_file, content).unwrap();
 
-    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
     cmd.arg(test_file.to_str().unwrap());
     cmd.timeout(std::time::Duration::from_secs(30)); // 30 second timeout
     cmd.assert().success();
@@ -154,7 +293,7 @@ print("block 3")
 
     fs::write(&test_file, content).unwrap();
 
-    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
     cmd.arg(test_file.to_str().unwrap());
     cmd.timeout(std::time::Duration::from_secs(45)); // Longer timeout for multiple blocks
     cmd.assert().success();
@@ -165,3 +304,4042 @@ print("block 3")
     assert!(result.contains("block 2"));
     assert!(result.contains("block 3"));
 }
+
+#[test]
+fn test_ignore_markers() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = r#"# Test
+
+```bash
+echo "runs"
+```
+
+<!-- runmd:off -->
+```bash
+echo "ignored"
+```
+**Output**
+```
+stale
+```
+<!-- runmd:on -->
+"#;
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert_eq!(result.matches("**Output**").count(), 2);
+    assert!(result.contains("stale"));
+    assert!(!result.contains("ignored\n```\n**Output**\n```\nignored"));
+}
+
+#[test]
+fn test_skip_section() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = r#"# Guide
+
+```bash
+echo "main"
+```
+
+## Appendix A
+
+```bash
+echo "appendix"
+```
+
+# Next
+
+```bash
+echo "after"
+```"#;
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--skip-section")
+        .arg("Appendix*")
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert_eq!(result.matches("**Output**").count(), 2);
+    assert!(!result.contains("```\nappendix\n```"));
+    assert!(result.contains("```\nafter\n```"));
+}
+
+#[test]
+fn test_ordered_report() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let report_file = temp_dir.path().join("report.yml");
+
+    let content = r#"# Test
+
+```bash
+echo "one"
+```
+
+```bash
+echo "two"
+```
+
+```bash
+echo "three"
+```
+
+```bash
+echo "four"
+```"#;
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--ordered")
+        .arg("--report")
+        .arg(report_file.to_str().unwrap())
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let report = fs::read_to_string(&report_file).unwrap();
+    assert!(report.contains("mode: sequential"));
+    assert!(report.contains("execution_order:\n- 0\n- 1\n- 2\n- 3\n"));
+}
+
+#[test]
+fn test_parallel_opt_in() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let report_file = temp_dir.path().join("report.yml");
+
+    let content = "```bash\necho 1\n```\n\n```bash\necho 2\n```\n\n```bash\necho 3\n```\n\n```bash\necho 4\n```\n";
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--report")
+        .arg(report_file.to_str().unwrap())
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let report = fs::read_to_string(&report_file).unwrap();
+    assert!(report.contains("mode: sequential"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("-p")
+        .arg("--report")
+        .arg(report_file.to_str().unwrap())
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let report = fs::read_to_string(&report_file).unwrap();
+    assert!(report.contains("mode: parallel"));
+}
+
+#[test]
+fn test_fence_attributes() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = r#"# Test
+
+```bash timeout=1s name="slow block"
+sleep 3
+```
+
+```bash colour=blue
+echo "still runs"
+```
+
+```bash timeout=999999999999999999h
+echo "no overflow"
+```"#;
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    let too_long = "attribute 'timeout': duration '999999999999999999h' is too long";
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("unknown attribute 'colour'"))
+        .stderr(predicate::str::contains(too_long));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("execution timed out"));
+    assert!(result.contains("still runs"));
+}
+
+#[test]
+fn test_malformed_attribute_keeps_no_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash -nr name=\ntouch ran.txt\n```\n\n\
+                   ```bash name=\"unterminated -nr\ntouch ran-too.txt\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.current_dir(temp_dir.path())
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    let empty = predicate::str::contains("attribute 'name' has an empty value");
+    let unterminated = predicate::str::contains("unterminated quote in attribute 'name'");
+    cmd.assert().success().stderr(empty).stderr(unterminated);
+
+    assert!(!temp_dir.path().join("ran.txt").exists());
+    assert!(!temp_dir.path().join("ran-too.txt").exists());
+}
+
+#[test]
+fn test_capture_modes() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = r#"# Test
+
+```bash capture=merged
+echo "first"; echo "second" >&2; echo "third"
+```
+
+```bash capture=pty
+[ -t 1 ] && echo "terminal" || echo "pipe"
+```"#;
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("first\nsecond\nthird"));
+    assert!(result.contains("```\nterminal\n```"));
+}
+
+#[test]
+fn test_fixtures_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\nfixtures: fixtures\n",
+    );
+
+    fs::create_dir_all(temp_dir.path().join("fixtures")).unwrap();
+    fs::write(temp_dir.path().join("fixtures/data.txt"), "fixture data\n").unwrap();
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\ncat data.txt\nls \"$RUNMD_FIXTURES\"\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("fixture data\ndata.txt"));
+}
+
+#[test]
+fn test_temp_files_cleaned_up() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"$0\"\n```\n";
+
+    for keep in [false, true] {
+        fs::write(&test_file, content).unwrap();
+
+        let mut cmd = runmd_command(&temp_dir);
+        if keep {
+            cmd.arg("--keep-temp");
+        }
+        cmd.arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+
+        let result = fs::read_to_string(&test_file).unwrap();
+        let source = result
+            .lines()
+            .find(|line| line.ends_with(".sh"))
+            .expect("block should print its source path");
+        let source_dir = std::path::Path::new(source).parent().unwrap();
+        assert_eq!(source_dir.exists(), keep);
+
+        if keep {
+            fs::remove_dir_all(source_dir).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_max_file_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\nmax_file_size: 10\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"big\"\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("larger than max_file_size"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg("--force")
+        .arg(test_file.to_str().unwrap())
+        .write_stdin("n\n");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("contains 1 runnable blocks"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg("--force")
+        .arg(test_file.to_str().unwrap())
+        .write_stdin("y\n");
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert!(fs::read_to_string(&test_file).unwrap().contains("**Output**"));
+}
+
+#[test]
+fn test_confirm_many_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\nconfirm_blocks: 1\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho 1\n```\n\n```bash\necho 2\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap())
+        .write_stdin("n\n");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("bash: 2"))
+        .stderr(predicate::str::contains("Aborted"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    // Without an answer the prompt is declined too
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap())
+        .write_stdin("");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Aborted"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg("--yes")
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert_eq!(
+        fs::read_to_string(&test_file)
+            .unwrap()
+            .matches("**Output**")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn test_foreign_fence_attributes_preserved() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = r#"# Test
+
+```bash{highlight=1}
+echo "braces"
+```
+
+```bash {hl=1-2} title="demo.sh" timeout=5s
+echo "titled"
+```"#;
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("warning").not());
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```bash{highlight=1}\n"));
+    assert!(result.contains("```bash {hl=1-2} title=\"demo.sh\" timeout=5s\n"));
+    assert!(result.contains("```\nbraces\n```"));
+    assert!(result.contains("```\ntitled\n```"));
+}
+
+#[test]
+fn test_language_aliases() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\naliases:\n  bash5.2: bash\n  shell+: bash\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash5.2\necho \"dotted\"\n```\n\n```shell+\necho \"plus\"\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\ndotted\n```"));
+    assert!(result.contains("```\nplus\n```"));
+}
+
+#[test]
+fn test_versioned_fences() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "languages:\n  tool: tool {file}\n");
+
+    // Only the versioned interpreter is installed
+    let bin = temp_dir.path().join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    let tool = bin.join("tool9.1");
+    fs::write(&tool, "#!/bin/sh\necho \"tool 9.1 ran $(cat \"$1\")\"\n").unwrap();
+    fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(&test_file, "```tool9.1\nthe code\n```\n").unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .env("PATH", path)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("```\ntool 9.1 ran the code\n```"),
+        "{}",
+        result
+    );
+}
+
+#[test]
+fn test_commonmark_closing_fences() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = "# Test\n\n````bash\ncat <<'EOF'\n```\nEOF\n````\n\n```bash\necho \"trailing\"\n```   \n\n```bash\necho \"longer\"\n`````\n";
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert_eq!(result.matches("**Output**").count(), 3);
+    assert!(result.contains("````bash\ncat <<'EOF'\n```\nEOF\n````\n**Output**"));
+    assert!(result.contains("```\ntrailing\n```"));
+    assert!(result.contains("```\nlonger\n```"));
+}
+
+#[test]
+fn test_indented_fences() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    // Fences in list items keep their indentation; four spaces make indented code
+    let content = "- step\n\n  ```python\n  if True:\n      print(\"listed\")\n  ```\n\nIndented code:\n\n    ```bash\n    echo \"indented\"\n    ```\n";
+
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("  ```python\n  if True:\n      print(\"listed\")\n  ```\n  **Output**\n  ```\n  listed\n  ```\n"),
+        "{}",
+        result
+    );
+    assert!(!result.contains("\nindented\n"), "{}", result);
+    assert_eq!(result.matches("**Output**").count(), 1);
+
+    // Rerunning reads the indented output back and changes nothing
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("check").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), result);
+
+    // An ordered item's text starts four columns in, and so do its fences
+    let content = "1.  Install\n\n    ```bash\n    echo \"first step\"\n    ```\n\n2.  Check\n\n    - nested\n\n      ```bash -nr\n      echo \"skipped\"\n      ```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    let expected = "    ```\n    **Output**\n    ```\n    first step\n    ```\n\n2.  Check";
+    assert!(result.contains(expected), "{}", result);
+    assert!(!result.contains("\nskipped"), "{}", result);
+
+    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    cmd.arg("check").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), result);
+
+    let mut cmd = Command::cargo_bin("runmd").unwrap();
+    cmd.arg("clear").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+}
+
+#[test]
+fn test_summary_by_language() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = "```bash\necho ok\n```\n\n```bash\nexit 3\n```\n\n```python\nprint('fine')\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(30));
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Summary by language:"));
+    let bash = stdout.lines().find(|l| l.trim_start().starts_with("bash")).unwrap();
+    assert!(bash.contains("2 run") && bash.contains("1 failed"));
+    let python = stdout.lines().find(|l| l.trim_start().starts_with("python")).unwrap();
+    assert!(python.contains("1 run") && python.contains("0 failed"));
+}
+
+#[test]
+fn test_parallel_schedules_slowest_first() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\nmax_parallel: 1\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let report_file = temp_dir.path().join("report.yml");
+    let content = "```bash\necho fast\n```\n\n```bash\nsleep 0.5; echo slow\n```\n";
+
+    // The same blocks in another document have no timings of their own yet
+    let other_file = temp_dir.path().join("other.md");
+    let mut orders = Vec::new();
+    for file in [&test_file, &test_file, &other_file] {
+        fs::write(file, content).unwrap();
+
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .env("XDG_CACHE_HOME", temp_dir.path().join("cache"))
+            .arg("-p")
+            .arg("--report")
+            .arg(report_file.to_str().unwrap())
+            .arg(file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+
+        orders.push(fs::read_to_string(&report_file).unwrap());
+    }
+
+    assert!(orders[0].contains("execution_order:\n- 0\n- 1\n"));
+    assert!(orders[1].contains("execution_order:\n- 1\n- 0\n"));
+    assert!(orders[2].contains("execution_order:\n- 0\n- 1\n"));
+}
+
+#[test]
+fn test_timings_keep_other_entries_and_stay_capped() {
+    let temp_dir = TempDir::new().unwrap();
+    let cache_dir = temp_dir.path().join("cache");
+    fs::create_dir_all(cache_dir.join("runmd")).unwrap();
+
+    // A full history left by other documents, the first entry the oldest
+    let mut durations = String::from("durations_ms:\n");
+    let mut recorded = String::from("recorded:\n");
+    for i in 0..10_000 {
+        durations.push_str(&format!("  other{}: 5\n", i));
+        recorded.push_str(&format!("  other{}: {}\n", i, 1_000 + i));
+    }
+    let timings_file = cache_dir.join("runmd/timings.yml");
+    fs::write(&timings_file, durations + &recorded).unwrap();
+
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(&test_file, "```bash\necho hi\n```\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CACHE_HOME", &cache_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let timings = fs::read_to_string(&timings_file).unwrap();
+    let durations = timings.split("recorded:").next().unwrap();
+    assert_eq!(durations.lines().count(), 10_001);
+    assert!(!durations.contains("other0:"));
+    assert!(durations.contains("other9999:"));
+    assert!(durations.contains("test.md#"));
+}
+
+#[test]
+fn test_remote_cache_shared_between_machines() {
+    let temp_dir = TempDir::new().unwrap();
+    let remote_dir = temp_dir.path().join("remote");
+    fs::create_dir_all(&remote_dir).unwrap();
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"$RANDOM$RANDOM$RANDOM\"\n```\n";
+
+    // Two "machines" with separate local caches sharing one remote
+    let mut outputs = Vec::new();
+    for machine in ["a", "b"] {
+        let machine_dir = TempDir::new().unwrap();
+        let config_dir = write_config(
+            &machine_dir,
+            format!(
+                "languages:\n  bash: bash {{file}}\ncache:\n  enabled: true\n  dir: {}\n  remote:\n    url: file://{}\n    write: true\n",
+                temp_dir.path().join(format!("local-{}", machine)).display(),
+                remote_dir.display()
+            ),
+        );
+
+        fs::write(&test_file, content).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+
+        outputs.push(fs::read_to_string(&test_file).unwrap());
+    }
+
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(fs::read_dir(&remote_dir).unwrap().count(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_remote_cache_credentials_stay_off_the_command_line() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("curl.log");
+
+    // Stands in for curl: logs its arguments and the config file it was given
+    let bin = temp_dir.path().join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(
+        bin.join("curl"),
+        format!(
+            r#"#!/bin/sh
+echo "args: $*" >> {log}
+while [ $# -gt 0 ]; do
+  if [ "$1" = --config ]; then
+    ls -l "$2" | cut -c1-10 >> {log}
+    cat "$2" >> {log}
+  fi
+  shift
+done
+exit 22
+"#,
+            log = log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("curl"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_dir = write_config(
+        &temp_dir,
+        format!(
+            "cache:\n  enabled: true\n  dir: {}\n  remote:\n    url: https://example.org/cache\n    s3_region: eu-west-1\n",
+            temp_dir.path().join("local").display()
+        ),
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(&test_file, "```bash\necho hi\n```\n").unwrap();
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap());
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .env("PATH", path)
+        .env("AWS_ACCESS_KEY_ID", "AKID")
+        .env("AWS_SECRET_ACCESS_KEY", "top\"secret")
+        .env("AWS_SESSION_TOKEN", "session-token")
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let log = fs::read_to_string(&log).unwrap();
+    let args: Vec<&str> = log.lines().filter(|l| l.starts_with("args:")).collect();
+    assert!(!args.is_empty(), "{}", log);
+    for line in args {
+        assert!(line.contains("--aws-sigv4 aws:amz:eu-west-1:s3"), "{}", log);
+        assert!(!line.contains("secret"), "{}", log);
+        assert!(!line.contains("session-token"), "{}", log);
+    }
+    assert!(log.contains("\n-rw-------\n"), "{}", log);
+    assert!(log.contains("user = \"AKID:top\\\"secret\""), "{}", log);
+    let header = "header = \"x-amz-security-token: session-token\"";
+    assert!(log.contains(header), "{}", log);
+}
+
+#[test]
+fn test_cache_invalidated_by_interpreter_version() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let version_file = temp_dir.path().join("version");
+    let interpreter = temp_dir.path().join("fake-bash");
+    fs::write(
+        &interpreter,
+        format!(
+            "#!/bin/sh\nif [ \"$1\" = --version ]; then cat {}; exit 0; fi\nexec bash \"$1\"\n",
+            version_file.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&interpreter, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_dir = write_config(
+        &temp_dir,
+        format!(
+            "languages:\n  fake: {} {{file}}\ncache:\n  enabled: true\n  dir: {}\n",
+            interpreter.display(),
+            temp_dir.path().join("cache").display()
+        ),
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```fake\necho \"$RANDOM$RANDOM$RANDOM\"\n```\n";
+    let run = |version: &str| {
+        fs::write(&version_file, version).unwrap();
+        fs::write(&test_file, content).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        fs::read_to_string(&test_file).unwrap()
+    };
+
+    let first = run("fake 1.0");
+    assert_eq!(run("fake 1.0"), first);
+    assert_ne!(run("fake 2.0"), first);
+}
+
+#[test]
+fn test_rerun_bypasses_cache() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash name=kept\necho \"$RANDOM$RANDOM$RANDOM\"\n```\n\n```bash name=fresh\necho \"$RANDOM$RANDOM$RANDOM\"\n```\n";
+
+    let run = |rerun: Option<&str>| {
+        fs::write(&test_file, content).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CACHE_HOME", temp_dir.path().join("cache"))
+            .arg("--cache");
+        if let Some(selector) = rerun {
+            cmd.arg("--rerun").arg(selector);
+        }
+        cmd.arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+
+        let result = fs::read_to_string(&test_file).unwrap();
+        result
+            .split("**Output**\n```\n")
+            .skip(1)
+            .map(|s| s.lines().next().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let first = run(None);
+    let second = run(Some("name=fresh"));
+    assert_eq!(first[0], second[0]);
+    assert_ne!(first[1], second[1]);
+}
+
+#[test]
+fn test_directory_setup_shared() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+
+    fs::write(
+        docs.join("_runmd_setup.md"),
+        "```bash\necho prepared > state.txt\necho \"GREETING=hello\" >> \"$RUNMD_ENV\"\n```\n",
+    )
+    .unwrap();
+    let doc = "```bash\ncat state.txt\necho \"$GREETING\"\n```\n";
+    fs::write(docs.join("a.md"), doc).unwrap();
+    fs::write(docs.join("b.md"), doc).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(docs.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    for name in ["a.md", "b.md"] {
+        let result = fs::read_to_string(docs.join(name)).unwrap();
+        assert!(result.contains("prepared\nhello"), "{}", result);
+    }
+    assert!(!docs.join("state.txt").exists());
+    assert!(fs::read_to_string(docs.join("_runmd_setup.md"))
+        .unwrap()
+        .contains("**Output**"));
+}
+
+#[test]
+fn test_directory_respects_ignore_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir_all(temp_dir.path().join(".git/info")).unwrap();
+    fs::create_dir(&docs).unwrap();
+
+    fs::write(temp_dir.path().join(".gitignore"), "generated-*.md\n").unwrap();
+    fs::write(temp_dir.path().join(".git/info/exclude"), "scratch.md\n").unwrap();
+    fs::write(docs.join(".runmdignore"), "vendored.md\n!generated-keep.md\n").unwrap();
+    let doc = "```bash\necho ran\n```\n";
+    for name in ["a.md", "generated-api.md", "generated-keep.md", "vendored.md", "scratch.md"] {
+        fs::write(docs.join(name), doc).unwrap();
+    }
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(docs.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let ran = |name: &str| fs::read_to_string(docs.join(name)).unwrap() != doc;
+    assert!(ran("a.md"));
+    assert!(ran("generated-keep.md"));
+    assert!(!ran("generated-api.md"));
+    assert!(!ran("vendored.md"));
+    assert!(!ran("scratch.md"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--no-ignore").arg(docs.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert!(ran("generated-api.md"));
+    assert!(ran("vendored.md"));
+}
+
+#[cfg(feature = "native")]
+struct EchoExecutor;
+
+#[cfg(feature = "native")]
+impl runmd::executor::Executor for EchoExecutor {
+    async fn execute(
+        &self,
+        block: &runmd::core::CodeBlock,
+    ) -> anyhow::Result<runmd::executor::RunResult> {
+        Ok(runmd::executor::RunResult {
+            output: format!("{} ran {}", block.language, block.code.trim()),
+            exit_code: Some(0),
+            stderr: None,
+        })
+    }
+}
+
+#[cfg(feature = "native")]
+#[tokio::test]
+async fn test_custom_executor() {
+    let config = runmd::config::Config::default();
+    let content = "```python\n1 + 1\n```\n\n```bash -nr\nls\n```\n\n```js\n2\n```\n**Output**\n```\nstale\n```\n";
+
+    let (result, report) = runmd::core::process_with(content, &config, &EchoExecutor)
+        .await
+        .unwrap();
+
+    assert!(result.contains("**Output**\n```\npython ran 1 + 1\n```"));
+    assert!(result.contains("**Output**\n```\njs ran 2\n```"));
+    assert!(!result.contains("stale"));
+    assert!(!result.contains("bash ran"));
+    assert_eq!(report.execution_order, vec![0, 2]);
+}
+
+extern "C" fn shout(
+    _user_data: *mut std::ffi::c_void,
+    _language: *const std::ffi::c_char,
+    code: *const std::ffi::c_char,
+    result: *mut runmd::ffi::RunmdResult,
+) -> std::ffi::c_int {
+    let code = unsafe { std::ffi::CStr::from_ptr(code) }.to_str().unwrap();
+    let output = std::ffi::CString::new(code.to_uppercase()).unwrap();
+    unsafe { runmd::ffi::runmd_result_set_output(result, output.as_ptr()) };
+    0
+}
+
+#[test]
+fn test_ffi_process_and_clear() {
+    use std::ffi::{CStr, CString};
+
+    let content = CString::new("```bash\necho hi\n```\n").unwrap();
+    let config = CString::new("").unwrap();
+
+    unsafe {
+        let processed = runmd::ffi::runmd_process(
+            content.as_ptr(),
+            config.as_ptr(),
+            shout,
+            std::ptr::null_mut(),
+        );
+        assert!(!processed.is_null());
+        let text = CStr::from_ptr(processed).to_str().unwrap().to_string();
+        assert!(text.contains("**Output**\n```\nECHO HI\n```"));
+
+        let cleared = runmd::ffi::runmd_clear_outputs(processed, config.as_ptr());
+        assert_eq!(CStr::from_ptr(cleared).to_str().unwrap().trim_end(), "```bash\necho hi\n```");
+        runmd::ffi::runmd_string_free(processed);
+        runmd::ffi::runmd_string_free(cleared);
+
+        let bad_config = CString::new("languages: [").unwrap();
+        let failed = runmd::ffi::runmd_clear_outputs(content.as_ptr(), bad_config.as_ptr());
+        assert!(failed.is_null());
+        let error = runmd::ffi::runmd_last_error();
+        assert!(CStr::from_ptr(error)
+            .to_str()
+            .unwrap()
+            .contains("Failed to parse config file"));
+        runmd::ffi::runmd_string_free(error);
+    }
+}
+
+#[test]
+fn test_python_bindings() {
+    // The shared library is built next to the binary
+    let bin = assert_cmd::cargo::cargo_bin("runmd");
+    let library = bin.with_file_name(if cfg!(target_os = "macos") {
+        "librunmd.dylib"
+    } else if cfg!(windows) {
+        "runmd.dll"
+    } else {
+        "librunmd.so"
+    });
+    let bindings = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("bindings/python");
+    let Ok(output) = std::process::Command::new("python3")
+        .args(["-B", "-m", "unittest", "-v", "test_runmd_ffi"])
+        .current_dir(&bindings)
+        .env("RUNMD_LIB", &library)
+        .output()
+    else {
+        return;
+    };
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_socket() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("runmd.sock");
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(&test_file, "```bash\necho from-file\n```\n").unwrap();
+
+    let mut daemon = std::process::Command::new(assert_cmd::cargo::cargo_bin("runmd"))
+        .arg("daemon")
+        .arg("--socket")
+        .arg(&socket)
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(s) = UnixStream::connect(&socket) {
+            stream = Some(s);
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    let mut stream = stream.expect("daemon did not start");
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = |line: String| {
+        writeln!(stream, "{}", line).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        response
+    };
+
+    let response = request(format!(
+        r#"{{"method": "process", "path": "{}"}}"#,
+        test_file.display()
+    ));
+    assert!(response.starts_with(r#"{"ok":true"#), "{}", response);
+    assert!(fs::read_to_string(&test_file)
+        .unwrap()
+        .contains("**Output**\n```\nfrom-file\n```"));
+
+    let response = request(r#"{"method": "run-block", "info": "bash", "code": "echo \"a\tb\""}"#.to_string());
+    assert_eq!(response, "{\"ok\":true,\"output\":\"a\\tb\",\"success\":true}\n");
+
+    let response = request(r#"{"method": "clear", "content": "```sh\nx\n```\n**Output**\n```\n1\n```\n"}"#.to_string());
+    assert!(response.contains(r#""content":"```sh\nx\n```"#), "{}", response);
+
+    // `render` works on the content alone, with per-request settings
+    let response = request(
+        r#"{"method": "render", "content": "```bash\necho one\n```\n# Slow\n```bash\necho two\n```\n", "options": {"skip_sections": ["Slow"]}}"#.to_string(),
+    );
+    assert!(response.contains(r#""content":"```bash\necho one\n```\n**Output**\n```\none\n```\n# Slow"#), "{}", response);
+    assert!(response.contains(r#""outputs":["one",null]"#), "{}", response);
+
+    let response = request(r#"[{"method": "run-block", "info": "bash", "code": "echo 1"}, {"method": "run-block", "info": "bash", "code": "echo 2"}]"#.to_string());
+    assert_eq!(
+        response,
+        "[{\"ok\":true,\"output\":\"1\",\"success\":true},{\"ok\":true,\"output\":\"2\",\"success\":true}]\n"
+    );
+
+    // Relative paths and blocks are resolved against the client's directory
+    let cwd = temp_dir.path().canonicalize().unwrap();
+    fs::write(&test_file, "```bash\npwd\n```\n").unwrap();
+    let response = request(format!(
+        r#"{{"method": "process", "path": "test.md", "cwd": "{}"}}"#,
+        cwd.display()
+    ));
+    assert!(response.starts_with(r#"{"ok":true"#), "{}", response);
+    let printed = format!("```\n{}\n```", cwd.display());
+    assert!(fs::read_to_string(&test_file).unwrap().contains(&printed));
+    let response = request(format!(
+        r#"{{"method": "run-block", "info": "bash", "code": "pwd", "cwd": "{}"}}"#,
+        cwd.display()
+    ));
+    let printed = format!(r#""output":"{}""#, cwd.display());
+    assert!(response.contains(&printed), "{}", response);
+    let response = request(r#"{"method": "process", "path": "test.md"}"#.to_string());
+    assert!(response.contains("needs the client's 'cwd'"), "{}", response);
+    let relative = r#"{"method": "run-block", "info": "sh", "code": "pwd", "cwd": "rel"}"#;
+    let response = request(relative.to_string());
+    assert!(response.contains("must be an absolute path"), "{}", response);
+
+    let response = request(r#"{"method": "explode"}"#.to_string());
+    assert!(response.starts_with(r#"{"ok":false,"error":"Invalid request"#), "{}", response);
+
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+}
+
+/// Start `runmd serve` on a free port, returning the process and its address.
+fn start_server(config_dir: Option<&std::path::Path>) -> (std::process::Child, String) {
+    use std::io::{BufRead, BufReader};
+
+    let mut cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin("runmd"));
+    cmd.args(["serve", "--port", "0", "--dangerously-run-on-host"])
+        .env("RUNMD_TOKEN", "secret")
+        .env("SERVER_SECRET", "leaked")
+        .stdout(std::process::Stdio::piped());
+    if let Some(config_dir) = config_dir {
+        cmd.env("XDG_CONFIG_HOME", config_dir);
+    }
+    let mut server = cmd.spawn().unwrap();
+
+    let mut line = String::new();
+    BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr = line.trim().trim_start_matches("Listening on http://").to_string();
+    (server, addr)
+}
+
+fn http_post(addr: &str, path: &str, token: Option<&str>, body: &str) -> String {
+    use std::io::{Read, Write};
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    let auth = token
+        .map(|t| format!("Authorization: Bearer {}\r\n", t))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+        path,
+        auth,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_serve_http() {
+    let (mut server, addr) = start_server(None);
+
+    let post = |path: &str, token: Option<&str>, body: &str| http_post(&addr, path, token, body);
+
+    let doc = "```bash\necho \"${RUNMD_TOKEN:-no token}\"\nbasename \"$PWD\"\n```\n";
+    assert!(post("/process", None, doc).starts_with("HTTP/1.1 401"));
+    assert!(post("/process", Some("wrong"), doc).starts_with("HTTP/1.1 401"));
+
+    let response = post("/process", Some("secret"), doc);
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("**Output**\n```\nno token\nrunmd-workspace-"), "{}", response);
+
+    let response = post("/clear", Some("secret"), "```sh\nx\n```\n**Output**\n```\n1\n```\n");
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(!response.contains("**Output**"));
+
+    assert!(post("/nope", Some("secret"), "").starts_with("HTTP/1.1 404"));
+
+    let metrics = post("/metrics", Some("secret"), "");
+    assert!(metrics.contains("runmd_documents_total 1\n"), "{}", metrics);
+    assert!(metrics.contains("runmd_blocks_total{language=\"bash\",result=\"success\"} 1\n"));
+    assert!(metrics.contains("runmd_block_duration_seconds_count 1\n"));
+
+    // Served blocks only see an allowlisted environment, in allowlisted languages
+    let doc = "```bash\necho \"[${SERVER_SECRET:-}]\"\n```\n\n```perl\nprint 1\n```\n";
+    let response = post("/process", Some("secret"), doc);
+    let output = "**Output**\n```\n[]\n```";
+    assert!(response.contains(output), "{}", response);
+    assert_eq!(response.matches("**Output**").count(), 1, "{}", response);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn test_serve_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "service:\n  max_concurrent: 1\n  queue_size: 0\n  rate_limit: 3\n",
+    );
+
+    let (mut server, addr) = start_server(Some(&config_dir));
+
+    let slow = {
+        let addr = addr.clone();
+        std::thread::spawn(move || {
+            http_post(&addr, "/process", Some("secret"), "```bash\nsleep 1\n```\n")
+        })
+    };
+    std::thread::sleep(std::time::Duration::from_millis(300));
+
+    let doc = "```sh\nx\n```\n";
+    assert!(http_post(&addr, "/clear", Some("secret"), doc).starts_with("HTTP/1.1 503"));
+    assert!(slow.join().unwrap().starts_with("HTTP/1.1 200"));
+
+    // The third request within the minute is the last one allowed
+    assert!(http_post(&addr, "/clear", Some("secret"), doc).starts_with("HTTP/1.1 200"));
+    assert!(http_post(&addr, "/clear", Some("secret"), doc).starts_with("HTTP/1.1 429"));
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_serve_runs_documents_in_the_service_image() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = temp_dir.path().join("config");
+
+    // Without an image, documents would run on the host
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .env("RUNMD_TOKEN", "secret")
+        .args(["serve", "--port", "0"]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--dangerously-run-on-host"));
+
+    // Stands in for docker, running `exec` commands locally
+    let log = temp_dir.path().join("runtime.log");
+    let runtime = temp_dir.path().join("fake-docker");
+    fs::write(
+        &runtime,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\ncase \"$1\" in\n  run) echo fake-id ;;\n  \
+             exec) while [ \"$1\" != fake-id ]; do shift; done; shift; exec \"$@\" ;;\nesac\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755)).unwrap();
+    write_config(
+        &temp_dir,
+        format!(
+            "container_runtime: {}\nservice:\n  image: docs/sandbox:1\n",
+            runtime.display()
+        ),
+    );
+
+    // The document's own image is ignored
+    let (mut server, addr) = start_server(Some(&config_dir));
+    let doc = "---\nrunmd:\n  image: attacker/escape:latest\n---\n\n```bash\necho contained\n```\n";
+    let response = http_post(&addr, "/process", Some("secret"), doc);
+    assert!(response.contains("```\ncontained\n```"), "{}", response);
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("docs/sandbox:1"), "{}", log);
+    assert!(!log.contains("attacker"), "{}", log);
+
+    // Nor can it read files of the host
+    let doc = "```bash stdin-file=/etc/hostname\ncat\n```\n";
+    let response = http_post(&addr, "/process", Some("secret"), doc);
+    let refused = "stdin-file= can't be used in documents sent to the server";
+    assert!(response.contains(refused), "{}", response);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn test_serve_bounds_unauthenticated_requests() {
+    use std::io::{Read, Write};
+
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "service:\n  max_connections: 2\n");
+    let (mut server, addr) = start_server(Some(&config_dir));
+
+    let send = |request: &[u8]| {
+        let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    };
+
+    // A large announced body isn't waited for without the token
+    let response = send(b"POST /process HTTP/1.1\r\nContent-Length: 9000000\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+
+    // Nor are endless header lines
+    let mut long = b"POST /process HTTP/1.1\r\nX-Filler: ".to_vec();
+    long.extend([b'a'; 100_000]);
+    let response = send(&long);
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    // Connections beyond max_connections are closed without an answer
+    let idle: Vec<_> = (0..2)
+        .map(|_| std::net::TcpStream::connect(&addr).unwrap())
+        .collect();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(send(b"GET /health HTTP/1.1\r\n\r\n"), "");
+    drop(idle);
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let response = send(b"GET /health HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn test_atomic_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    let good = "```bash\necho ok\n```\n";
+    let bad = "```bash\nexit 1\n```\n";
+
+    let run = |atomic: &str| {
+        fs::write(docs.join("a.md"), good).unwrap();
+        fs::write(docs.join("b.md"), bad).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg(atomic).arg(docs.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Blocks failed in"));
+    };
+
+    run("--atomic");
+    assert_eq!(fs::read_to_string(docs.join("a.md")).unwrap(), good);
+    assert_eq!(fs::read_to_string(docs.join("b.md")).unwrap(), bad);
+
+    run("--atomic=file");
+    assert!(fs::read_to_string(docs.join("a.md"))
+        .unwrap()
+        .contains("**Output**\n```\nok\n```"));
+    assert_eq!(fs::read_to_string(docs.join("b.md")).unwrap(), bad);
+
+    // Documents keep their permissions, and a symlink keeps pointing at the document
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let target = temp_dir.path().join("target.md");
+        let link = temp_dir.path().join("link.md");
+        fs::write(&target, good).unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg("--atomic").arg(link.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        let link_type = fs::symlink_metadata(&link).unwrap().file_type();
+        assert!(link_type.is_symlink());
+        assert!(fs::read_to_string(&target).unwrap().contains("**Output**"));
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+}
+
+#[test]
+fn test_invoke_arg_mode() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "languages:\n  bash: bash {file}\n  perl: perl -e {code}\ninvoke:\n  bash: arg\n  perl: arg\n");
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"source=[${BASH_SOURCE[0]}] 'quoted'\"\n```\n\n```perl\nprint \"perl \" . (1 + 1);\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\nsource=[] 'quoted'\n```"), "{}", result);
+    assert!(result.contains("```\nperl 2\n```"), "{}", result);
+}
+
+#[test]
+fn test_invoke_stdin_mode() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\ninvoke:\n  bash: stdin\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"source=[${BASH_SOURCE[0]}]\"\n```\n\n```bash capture=merged\necho out\necho err >&2\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\nsource=[]\n```"), "{}", result);
+    assert!(result.contains("```\nout\nerr\n```"), "{}", result);
+}
+
+#[test]
+fn test_timeout_grace_period() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\ntimeout_grace: 500ms\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash timeout=1s\ntrap 'echo cleaning up; exit 1' TERM\necho started\nsleep 30\n```\n\n```bash timeout=1s\ntrap '' TERM\necho stubborn\nsleep 30\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let started = std::time::Instant::now();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert!(started.elapsed() < std::time::Duration::from_secs(8));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("started\ncleaning up\n[error] execution timed out"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("stubborn\n[error] execution timed out"),
+        "{}",
+        result
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupt_stops_running_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "languages:\n  bash: bash {file}\n");
+
+    let pid_file = temp_dir.path().join("sleep.pid");
+    let test_file = temp_dir.path().join("test.md");
+    let content = format!(
+        "```bash\nsleep 30 &\necho $! > {}\nwait\n```\n",
+        pid_file.display()
+    );
+    fs::write(&test_file, &content).unwrap();
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("runmd"))
+        .arg(&test_file)
+        .env("XDG_DATA_HOME", temp_dir.path().join("data"))
+        .env("XDG_CONFIG_HOME", &config_dir)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    let sleep_pid = loop {
+        let pid = fs::read_to_string(&pid_file).unwrap_or_default();
+        if pid.ends_with('\n') {
+            break pid.trim().to_string();
+        }
+        assert!(std::time::Instant::now() < deadline);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+
+    // What a terminal sends to runmd, but not to the block's own process group
+    let interrupted = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(std::time::Instant::now() < deadline, "runmd kept running");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+    assert_eq!(status.code(), Some(130));
+
+    let sleep_running = || {
+        std::process::Command::new("kill")
+            .args(["-0", &sleep_pid])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .unwrap()
+            .success()
+    };
+    while sleep_running() {
+        assert!(std::time::Instant::now() < deadline, "sleep survived");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+}
+
+#[test]
+fn test_env_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "languages:\n  bash: bash {file}\nenv_policy:\n  inherit: false\n  allow: [PATH, RUNMD_TEST_*]\n  deny: [RUNMD_TEST_SECRET]\n  set:\n    CI: \"1\"\n");
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"public=${RUNMD_TEST_PUBLIC-unset} secret=${RUNMD_TEST_SECRET-unset} other=${OTHER_VAR-unset} ci=$CI\"\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .env("RUNMD_TEST_PUBLIC", "yes")
+        .env("RUNMD_TEST_SECRET", "hunter2")
+        .env("OTHER_VAR", "leak")
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("public=yes secret=unset other=unset ci=1"),
+        "{}",
+        result
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_low_priority_and_affinity() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\npriority: low\ncpu_affinity: [0]\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"nice=$(nice)\"\ngrep Cpus_allowed_list /proc/self/status\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("nice=10\n"), "{}", result);
+    assert!(result.contains("Cpus_allowed_list:\t0\n"), "{}", result);
+
+    // CPUs beyond what an affinity mask holds are a config error
+    write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\ncpu_affinity: [0, 5000]\n",
+    );
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("cpu_affinity lists CPU 5000"));
+}
+
+#[test]
+fn test_history() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let other_file = temp_dir.path().join("other.md");
+
+    fs::write(&test_file, "```bash name=\"greet\"\necho hi\n```\n").unwrap();
+    fs::write(&other_file, "```bash\necho other\n```\n").unwrap();
+    for file in [&test_file, &other_file] {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg(file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+    }
+
+    fs::write(&test_file, "```bash name=\"greet\"\nexit 3\n```\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("history")
+        .arg(test_file.to_str().unwrap())
+        .arg("--since")
+        .arg("7d");
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.lines().count(), 2, "{}", output);
+    assert!(output.contains("test.md:1  bash  ok  \"greet\""), "{}", output);
+    assert!(!output.contains("other.md"), "{}", output);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("history")
+        .arg("--failed");
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.lines().count(), 1, "{}", output);
+    assert!(output.contains("test.md:1  bash  FAILED"), "{}", output);
+
+    // The log keeps the newest 2,000 entries of each document, so a busy one
+    // doesn't crowd out the others
+    let log = temp_dir.path().join("data/runmd/history.jsonl");
+    let content = fs::read_to_string(&log).unwrap();
+    let old = content.lines().next().unwrap().to_string();
+    let other = content.lines().find(|line| line.contains("other.md")).unwrap();
+    fs::write(&log, format!("{}\n{}\n", other, format!("{}\n", old).repeat(2_600))).unwrap();
+    let run = |file: &std::path::Path| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg(file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+    };
+    let count = |name: &str| {
+        let kept = fs::read_to_string(&log).unwrap();
+        kept.lines().filter(|line| line.contains(name)).count()
+    };
+    run(&other_file);
+    assert_eq!(count("test.md"), 2_600);
+    run(&test_file);
+    assert_eq!(count("test.md"), 2_000);
+    assert_eq!(count("other.md"), 2);
+
+    // Concurrent runs each get their entries in
+    let children: Vec<_> = (0..8)
+        .map(|i| {
+            let file = temp_dir.path().join(format!("concurrent-{}.md", i));
+            fs::write(&file, "```bash\necho concurrent\n```\n").unwrap();
+            std::process::Command::new(assert_cmd::cargo::cargo_bin("runmd"))
+                .arg(&file)
+                .env("XDG_DATA_HOME", temp_dir.path().join("data"))
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    assert_eq!(count("concurrent-"), 8);
+}
+
+#[test]
+fn test_flaky_detection() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let report_file = temp_dir.path().join("report.yml");
+    let counter = temp_dir.path().join("counter");
+
+    // Fails on every other run, and prints a different number each time
+    let content = format!(
+        "```bash name=\"coin\"\nn=$(cat {0} 2>/dev/null || echo 0)\necho $((n + 1)) > {0}\necho $n\n[ $((n % 2)) -eq 0 ]\n```\n\n```bash\necho steady\n```\n",
+        counter.display()
+    );
+
+    let mut stdout = String::new();
+    for _ in 0..3 {
+        fs::write(&test_file, &content).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg("--report")
+            .arg(report_file.to_str().unwrap())
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        let output = cmd.assert().success().get_output().stdout.clone();
+        stdout = String::from_utf8(output).unwrap();
+    }
+
+    assert!(stdout.contains("Flaky blocks:"), "{}", stdout);
+    assert!(
+        stdout.contains("line 1 \"coin\": passed 2 of the last 3 runs"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("line 9"), "{}", stdout);
+    let report = fs::read_to_string(&report_file).unwrap();
+    assert!(report.contains("flaky: passed 2 of the last 3 runs"), "{}", report);
+}
+
+#[test]
+fn test_orphaned_outputs() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = "# Test\n\n```bash\necho kept\n```\n**Output**\n```\nkept\n```\n\nA deleted block's output:\n\n**Output**\n```\nstale\n```\n\n```bash -nr\necho skipped\n```\n**Output**\n```\nskipped\n```\n\n````markdown\n**Output**\n```\nexample\n```\n````\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--lint").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("test.md:13: output not attached"))
+        .stdout(predicate::str::contains("test.md:21: output not attached"))
+        .stdout(predicate::str::contains(":6:").not())
+        .stdout(predicate::str::contains(":27:").not());
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    let content = content.split("````markdown").next().unwrap();
+    fs::write(&test_file, content).unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("line 13: removed output"));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(!result.contains("stale"), "{}", result);
+    assert!(result.contains("kept\n```"), "{}", result);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--lint").arg(test_file.to_str().unwrap());
+    cmd.assert().success();
+}
+
+#[test]
+fn test_history_follows_blocks_across_edits() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let run = |content: &str| {
+        fs::write(&test_file, content).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+    };
+    let history = |block: &str| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg("history")
+            .arg(test_file.to_str().unwrap())
+            .arg("--block")
+            .arg(block);
+        let output = cmd.assert().success().get_output().stdout.clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    run("```bash\necho one\necho two\necho three\n```\n\n```bash name=\"named\"\necho a\n```\n");
+    // A new paragraph moves both blocks; one line of the first and all of the named block change
+    run("Intro.\n\n```bash\necho one\necho two\necho four\n```\n\n```bash name=\"named\"\necho b\n```\n\n```bash\necho unrelated\n```\n");
+
+    let output = history("3");
+    assert_eq!(output.lines().count(), 2, "{}", output);
+    assert!(output.contains("test.md:1  bash"), "{}", output);
+    assert!(output.contains("test.md:3  bash"), "{}", output);
+
+    let output = history("named");
+    assert_eq!(output.lines().count(), 2, "{}", output);
+
+    let output = history("13");
+    assert_eq!(output.lines().count(), 1, "{}", output);
+}
+
+#[test]
+fn test_allowed_languages() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = "```bash\necho allowed\n```\n\n```shell\necho alias\n```\n\n```python\nprint('blocked')\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--allow-lang")
+        .arg("bash,ruby")
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success().stderr(predicate::str::contains(
+        "line 9: skipped python block, language not in allowed_languages",
+    ));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("**Output**\n```\nallowed\n```"), "{}", result);
+    assert!(result.contains("**Output**\n```\nalias\n```"), "{}", result);
+    assert!(!result.contains("**Output**\n```\nblocked"), "{}", result);
+}
+
+#[test]
+fn test_allowed_languages_with_command_override() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```python cmd=\"bash {file}\"\necho PWNED from cmd\n```\n\n\
+                   ```python with=bash\necho PWNED from with\n```\n\n\
+                   ```python matrix=bash\necho PWNED from matrix\n```\n\n\
+                   ```python\nprint('allowed')\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["--allow-lang", "python", test_file.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success().stderr(predicate::str::contains(
+        "line 1: skipped python block, cmd=, with= or matrix= while allowed_languages is set",
+    ));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(!result.contains("\nPWNED"), "{}", result);
+    assert!(
+        result.contains("**Output**\n```\nallowed\n```"),
+        "{}",
+        result
+    );
+}
+
+#[test]
+fn test_dangerous_pattern_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  bash: bash {file}\nscan:\n  enabled: true\n",
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho safe\n```\n\n```bash\necho cleanup # rm -rf ./build\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "line 6: suspicious bash code (recursive delete)",
+        ))
+        .stderr(predicate::str::contains("--allow-dangerous"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg("--allow-dangerous")
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("**Output**\n```\ncleanup\n```"), "{}", result);
+
+    // Commands given through attributes are checked like the code
+    let victim = temp_dir.path().join("victim");
+    fs::create_dir(&victim).unwrap();
+    let content = format!(
+        "```bash\necho safe\n```\n\n```text cmd=\"rm -rf {}\"\nnothing\n```\n",
+        victim.display()
+    );
+    fs::write(&test_file, &content).unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "line 5: suspicious text code (recursive delete): cmd=rm -rf",
+    ));
+    assert!(victim.exists());
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_front_matter_container_image() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("runtime.log");
+
+    // Stands in for docker: `exec` runs the command locally in the requested directory
+    let runtime = temp_dir.path().join("fake-docker");
+    fs::write(
+        &runtime,
+        format!(
+            r#"#!/bin/sh
+echo "$@" >> {}
+case "$1" in
+  run) echo fake-id ;;
+  exec)
+    shift
+    while [ "$1" != fake-id ]; do
+      case "$1" in
+        -w) cd "$2"; shift 2 ;;
+        -e) export "$2"; shift 2 ;;
+        *) shift ;;
+      esac
+    done
+    shift
+    exec "$@" ;;
+esac
+"#,
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_dir = write_config(
+        &temp_dir,
+        format!(
+            "languages:\n  bash: bash {{file}}\ncontainer_runtime: {}\n",
+            runtime.display()
+        ),
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "---\ntitle: Tutorial\nrunmd:\n  image: example.org/docs-env:1.2\n---\n\n```bash\necho shared > state.txt\n```\n\n```bash\ncat state.txt\n```\n\n\
+                   ```bash stable=true env=TOKEN=s3cret\necho \"$TOKEN $LC_ALL\"\n```\n\n\
+                   ```bash timeout=1s\nsleep 30\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.starts_with("---\ntitle: Tutorial\n"), "{}", result);
+    assert!(result.contains("**Output**\n```\nshared\n```"), "{}", result);
+    assert!(result.contains("```\ns3cret C\n```"), "{}", result);
+    assert!(result.contains("execution timed out"), "{}", result);
+
+    // Variables reach the container by name only, and a timed-out block is
+    // killed inside it
+    let log = fs::read_to_string(&log).unwrap();
+    let commands: Vec<&str> = log.lines().map(|l| l.split(' ').next().unwrap()).collect();
+    assert_eq!(
+        commands,
+        ["run", "exec", "exec", "exec", "exec", "exec", "rm"],
+        "{}",
+        log
+    );
+    assert!(log.contains("-- example.org/docs-env:1.2 "), "{}", log);
+    assert!(log.contains("-e TOKEN fake-id"), "{}", log);
+    assert!(!log.contains("s3cret"), "{}", log);
+    assert!(log.contains("exec fake-id sh -c kill"), "{}", log);
+    assert!(log.contains("rm -f fake-id"), "{}", log);
+
+    // An image that would be read as one of `run`'s options never reaches the runtime
+    fs::remove_file(temp_dir.path().join("runtime.log")).unwrap();
+    for image in ["--privileged", "-v/:/host", "alpine --privileged"] {
+        let content = format!("---\nrunmd:\n  image: \"{image}\"\n---\n\n```bash\nid\n```\n");
+        fs::write(&test_file, &content).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        let refused = predicate::str::contains("is not a container image reference");
+        cmd.assert().failure().stderr(refused);
+        assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+    }
+    assert!(!temp_dir.path().join("runtime.log").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_nix_backend() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("nix.log");
+
+    // Stands in for `nix shell`: records the packages and runs what follows --command
+    let nix = temp_dir.path().join("fake-nix");
+    fs::write(
+        &nix,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\nwhile [ \"$1\" != --command ]; do shift; done\nshift\nexec \"$@\"\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&nix, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_dir = write_config(
+        &temp_dir,
+        format!(
+            "languages:\n  bash: bash {{file}}\n  sh: sh {{file}}\nnix:\n  enabled: true\n  command: {} shell\n  packages:\n    bash: nixpkgs#bash_5\n",
+            nix.display()
+        ),
+    );
+
+    let test_file = temp_dir.path().join("test.md");
+    let content = "---\nrunmd:\n  nix: [nixpkgs#jq]\n---\n\n```bash\necho from bash\n```\n\n```sh\necho from sh\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("**Output**\n```\nfrom bash\n```"), "{}", result);
+    assert!(result.contains("**Output**\n```\nfrom sh\n```"), "{}", result);
+
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(
+        log.contains("shell nixpkgs#jq nixpkgs#bash_5 --command bash "),
+        "{}",
+        log
+    );
+    assert!(log.contains("shell nixpkgs#jq --command sh "), "{}", log);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tool_versions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let asdf_dir = temp_dir.path().join("asdf");
+    let bin = asdf_dir.join("installs/fakelang/1.2.3/bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(bin.join("fakelang"), "#!/bin/sh\necho \"fakelang 1.2.3 ran $(basename $1)\"\n").unwrap();
+    fs::set_permissions(bin.join("fakelang"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  fakelang: fakelang {file}\ntool_versions: true\n",
+    );
+
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir_all(&docs).unwrap();
+    fs::write(
+        temp_dir.path().join(".tool-versions"),
+        "fakelang 1.2.3\nnodejs 99.0.0 # not installed\n",
+    )
+    .unwrap();
+    let test_file = docs.join("test.md");
+    fs::write(&test_file, "```fakelang\nanything\n```\n").unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .env("ASDF_DATA_DIR", &asdf_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("nodejs 99.0.0 is pinned but not installed"));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("**Output**\n```\nfakelang 1.2.3 ran "), "{}", result);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_parallel_progress_display() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash name=\"slow one\"\nsleep 0.5\necho a\n```\n\n```bash\necho b\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    // Piped stderr gets no live display
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("-p").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("Running blocks").not());
+
+    // On a terminal (provided by util-linux `script`) in-flight blocks are listed
+    let runmd = assert_cmd::cargo::cargo_bin("runmd");
+    let Ok(output) = std::process::Command::new("script")
+        .arg("-qec")
+        .arg(format!("{} -p {}", runmd.display(), test_file.display()))
+        .arg("/dev/null")
+        .env("XDG_DATA_HOME", temp_dir.path().join("data"))
+        .output()
+    else {
+        return;
+    };
+    let transcript = String::from_utf8_lossy(&output.stdout);
+    assert!(transcript.contains("Running blocks: 0/2 done"), "{}", transcript);
+    assert!(transcript.contains("line 1 bash \"slow one\""), "{}", transcript);
+}
+
+#[test]
+fn test_output_diffstat() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    let content = "```bash\nprintf 'a\\nb\\nc\\n'\n```\n**Output**\n```\na\nold\n```\n\n```bash\necho new\n```\n\n```bash\necho same\n```\n**Output**\n```\nsame\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("+3 −1 lines across 2 output blocks"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("no output changes"));
+}
+
+#[test]
+fn test_subcommands() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let file = test_file.to_str().unwrap();
+    let content = "```bash\necho hello\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args);
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    runmd(&["check", file])
+        .failure()
+        .stderr(predicate::str::contains("outputs out of date"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    runmd(&["export", file])
+        .success()
+        .stdout(predicate::str::starts_with("```bash\necho hello\n```\n**Output**\n```\nhello\n```"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    runmd(&["run", "--ordered", file]).success();
+    assert!(fs::read_to_string(&test_file).unwrap().contains("hello\n```"));
+    runmd(&["--ordered", "check", file]).success();
+
+    runmd(&["clear", file]).success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap().trim_end(), content.trim_end());
+
+    // The flag-only interface still works
+    runmd(&[file]).success();
+    assert!(fs::read_to_string(&test_file).unwrap().contains("hello\n```"));
+    runmd(&["-c", file]).success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap().trim_end(), content.trim_end());
+}
+
+#[test]
+fn test_watch_mode() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(&test_file, "```bash\necho first\n```\n").unwrap();
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("runmd"))
+        .arg("watch")
+        .arg(&test_file)
+        .env("XDG_DATA_HOME", temp_dir.path().join("data"))
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let wait_for = |text: &str| {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        loop {
+            let content = fs::read_to_string(&test_file).unwrap();
+            if content.contains(text) {
+                return content;
+            }
+            assert!(std::time::Instant::now() < deadline, "{}", content);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    };
+
+    let content = wait_for("first\n```");
+    fs::write(&test_file, content.replace("echo first", "echo second")).unwrap();
+    let content = wait_for("second\n```");
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(!content.contains("first"), "{}", content);
+}
+
+#[test]
+fn test_config_schema() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "languages:\n  zsh: zsh {file}\nenv_policy:\n  set:\n    GREETING: \"multi\\nline\"\n",
+    );
+
+    let schema = || {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .arg("config")
+            .arg("schema");
+        let output = cmd.assert().success().get_output().stdout.clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    let first = schema();
+    assert!(first.contains("  zsh: zsh {file}\n"), "{}", first);
+    assert!(first.contains("# Most blocks running at once in parallel mode"), "{}", first);
+    assert!(first.contains("  # Serve outputs of unchanged blocks from the cache"), "{}", first);
+    let top_level: Vec<&str> = first.lines().filter(|l| !l.starts_with([' ', '-'])).collect();
+    for pair in top_level.windows(2).filter(|pair| !pair[1].starts_with('#')) {
+        assert!(pair[0].starts_with("# "), "{} has no description", pair[1]);
+    }
+
+    // The output is a config file describing the same configuration
+    write_config(&temp_dir, &first);
+    assert_eq!(schema(), first);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_init_config_wizard() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let temp_dir = TempDir::new().unwrap();
+    let bin = temp_dir.path().join("bin");
+    fs::create_dir(&bin).unwrap();
+    let Some(which) = ["/usr/bin/which", "/bin/which"]
+        .into_iter()
+        .find(|path| std::path::Path::new(path).exists())
+    else {
+        return;
+    };
+    symlink(which, bin.join("which")).unwrap();
+    fs::write(bin.join("python3"), "#!/bin/sh\n").unwrap();
+    fs::set_permissions(bin.join("python3"), fs::Permissions::from_mode(0o755)).unwrap();
+
+    // The wizard only asks questions on a terminal, provided by util-linux `script`
+    let runmd = assert_cmd::cargo::cargo_bin("runmd");
+    let mut child = match std::process::Command::new("script")
+        .arg("-qec")
+        .arg(format!("{} --init-config", runmd.display()))
+        .arg("/dev/null")
+        .env("PATH", &bin)
+        .env("SHELL", "/bin/sh")
+        .env("XDG_CONFIG_HOME", temp_dir.path())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(_) => return,
+    };
+    use std::io::Write;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"n\n45s\nsometimes\nauto\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let transcript = String::from_utf8_lossy(&output.stdout);
+    assert!(transcript.contains("missing  ruby"), "{}", transcript);
+    assert!(transcript.contains("expected off, on or auto"), "{}", transcript);
+
+    let config = fs::read_to_string(temp_dir.path().join("runmd/languages.config")).unwrap();
+    assert!(config.contains("# Time limit for blocks"), "{}", config);
+    assert!(config.contains("\ntimeout: 45s\n"), "{}", config);
+    assert!(config.contains("\nparallel: auto\n"), "{}", config);
+    assert!(config.contains("  python: python3 {file}\n"), "{}", config);
+    assert!(config.contains("  bash: bash {file}\n"), "{}", config);
+    assert!(!config.contains("ruby"), "{}", config);
+    assert!(!config.contains("nodejs"), "{}", config);
+}
+
+#[test]
+fn test_quiet_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash quiet\necho checked\n```\n**Output**\n```\nstale\n```\n\n```bash\necho shown\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(!result.contains("```\nchecked"), "{}", result);
+    assert!(!result.contains("stale"), "{}", result);
+    assert!(result.contains("shown\n```"), "{}", result);
+    assert_eq!(result.matches("**Output**").count(), 1, "{}", result);
+
+    fs::write(&test_file, "```bash quiet\necho 'assertion failed'\nexit 1\n```\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("line 1 bash: quiet block failed"))
+        .stderr(predicate::str::contains("assertion failed"))
+        .stderr(predicate::str::contains("Quiet blocks failed at"));
+    assert!(!fs::read_to_string(&test_file).unwrap().contains("**Output**"));
+}
+
+#[test]
+fn test_silent_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+
+    // Run for its side effect alone, under either name
+    let content = "```bash silent\necho data > made.txt\n```\n\n\
+                   ```bash hide-output\necho more >> made.txt\n```\n\n```bash\ncat made.txt\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.current_dir(temp_dir.path())
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert_eq!(result.matches("**Output**").count(), 1, "{}", result);
+    assert!(
+        result.ends_with("```bash\ncat made.txt\n```\n**Output**\n```\ndata\nmore\n```\n"),
+        "{}",
+        result
+    );
+}
+
+#[test]
+fn test_xfail_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let report_file = temp_dir.path().join("report.yml");
+    fs::write(
+        &test_file,
+        "```bash xfail\necho 'error: no such file' >&2\nexit 2\n```\n",
+    )
+    .unwrap();
+
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    runmd(&["--report", report_file.to_str().unwrap()]).success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("error: no such file"), "{}", result);
+    let report = fs::read_to_string(&report_file).unwrap();
+    assert!(report.contains("xfail: true"), "{}", report);
+    assert!(report.contains("success: true"), "{}", report);
+    runmd(&["check"]).success();
+
+    // A block expected to fail that succeeds, or times out, is a failure
+    fs::write(
+        &test_file,
+        "```bash xfail\necho fine\n```\n\n```bash xfail timeout=100ms\nsleep 5\n```\n",
+    )
+    .unwrap();
+    runmd(&["check"])
+        .failure()
+        .stderr(predicate::str::contains("line 1 bash: expected to fail but succeeded"))
+        .stdout(predicate::str::contains("bash  2 run  2 failed"));
+}
+
+#[test]
+fn test_expect_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    // An exact match or a substring of the output passes
+    fs::write(
+        &test_file,
+        "```bash expect=\"hello world\"\necho hello world\n```\n\n\
+         ```bash expect=\"two\\nthree\"\nseq 1 3 | sed 's/2/two/;s/3/three/'\n```\n",
+    )
+    .unwrap();
+    runmd(&[]).success();
+    runmd(&["check"]).success();
+
+    fs::write(&test_file, "# Doc\n\n```bash expect=goodbye\necho hello\n```\n").unwrap();
+    runmd(&[])
+        .failure()
+        .stderr(predicate::str::contains("line 3 bash: output does not contain \"goodbye\""))
+        .stderr(predicate::str::contains("Expected output not found at"));
+    // The output is still inserted, showing what the block printed instead
+    assert!(fs::read_to_string(&test_file).unwrap().contains("**Output**\n```\nhello\n```"));
+    runmd(&["check"]).failure();
+}
+
+#[test]
+fn test_hide_code() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+    fs::write(&test_file, "# Doc\n\n```bash hide-code\necho setup done\n```\n\nText\n").unwrap();
+
+    // The code goes into an HTML comment, the output stays visible
+    runmd(&[]).success();
+    let hidden = "# Doc\n\n<!-- runmd:hide-code\n```bash hide-code\necho setup done\n```\n-->\n\
+                  **Output**\n```\nsetup done\n```\n\nText\n";
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), hidden);
+
+    // The hidden block still runs and keeps its place on later runs
+    runmd(&["check"]).success();
+    runmd(&[]).success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), hidden);
+
+    // Without the attribute the comment goes away again
+    fs::write(&test_file, hidden.replace(" hide-code\n", "\n")).unwrap();
+    runmd(&[]).success();
+    assert_eq!(
+        fs::read_to_string(&test_file).unwrap(),
+        "# Doc\n\n```bash\necho setup done\n```\n**Output**\n```\nsetup done\n```\n\nText\n"
+    );
+}
+
+#[test]
+fn test_args_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "```bash args=\"--verbose 'my file.csv'\"\nprintf '[%s]\\n' \"$@\"\n```\n\n\
+         ```python args=\"a 'b\"\nprint('no args')\n```\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("attribute 'args': unbalanced quotes"));
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\n[--verbose]\n[my file.csv]\n```"), "{}", result);
+    assert!(result.contains("```\nno args\n```"), "{}", result);
+}
+
+#[test]
+fn test_session_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "```python session=calc\nx = 41\n```\n\n\
+         ```python session=calc\nprint(x + 1)\n```\n\n\
+         ```python session=other\nprint(x)\n```\n\n\
+         ```bash session=calc\nGREETING=hello\n```\n\n\
+         ```bash session=calc\necho \"$GREETING world\"\n```\n\n\
+         ```bash session=calc\nexit 3\n```\n\n\
+         ```bash session=calc\necho \"[$GREETING]\"\n```\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\n42\n```"), "{}", result);
+    assert!(result.contains("NameError: name 'x' is not defined"), "{}", result);
+    assert!(result.contains("```\nhello world\n```"), "{}", result);
+    // Exiting the shell ends the session, and the next block starts afresh
+    assert!(result.contains("```\n[]\n```"), "{}", result);
+
+    // The config toggle shares one interpreter between all blocks of a language
+    let config_dir = write_config(&temp_dir, "sessions: true\n");
+    fs::write(
+        &test_file,
+        "```python\nnames = ['a', 'b']\n```\n\n```python\nprint(len(names))\n```\n",
+    )
+    .unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\n2\n```"), "{}", result);
+}
+
+#[test]
+fn test_exit_code_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash exit-code\necho partial; exit 3\n```\n\n```bash\necho plain\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("**Output** (exit 3)\n```\npartial\n```"), "{}", result);
+    assert!(result.contains("```\n**Output**\n```\nplain\n```"), "{}", result);
+
+    // The config option labels every block, alongside a custom header and labels
+    let config_dir = write_config(
+        &temp_dir,
+        "exit_codes: true\noutput_labels: number\noutput_header: \"**Result**\"\n",
+    );
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        fs::read_to_string(&test_file).unwrap()
+    };
+    let result = runmd(&[]);
+    assert!(
+        result.contains("**Result 1** (exit 3) <!-- runmd:output -->\n```\npartial\n```"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("**Result 2** (exit 0) <!-- runmd:output -->\n```\nplain\n```"),
+        "{}",
+        result
+    );
+    assert_eq!(runmd(&["clear"]), content);
+}
+
+#[test]
+fn test_embedded_markdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let runmd = |args: &[&str], path: &std::path::Path| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(path.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        fs::read_to_string(path).unwrap()
+    };
+
+    let source = temp_dir.path().join("lib.rs");
+    let content = "//! Crate docs\n//!\n//! ```bash\n//! echo crate\n//! ```\n\n\
+                   struct S;\n\n\
+                   impl S {\n    /// Method\n    ///```bash\n    ///echo method\n    ///```\n    fn m(&self) {}\n}\n";
+    fs::write(&source, content).unwrap();
+    let result = runmd(&[], &source);
+    assert!(
+        result.contains("//! ```\n//! **Output**\n//! ```text\n//! crate\n//! ```\n\nstruct S;"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("    ///```\n    /// **Output**\n    /// ```text\n    /// method\n    /// ```\n    fn m"),
+        "{}",
+        result
+    );
+    assert_eq!(runmd(&[], &source), result);
+    assert_eq!(runmd(&["clear"], &source), content);
+
+    // MDX has no HTML comments, so markers are written as MDX comments
+    let page = temp_dir.path().join("page.mdx");
+    let content = "import Chart from './Chart'\n\n{/* runmd:off */}\n```bash\necho skipped\n```\n\
+                   {/* runmd:on */}\n\n```bash hide-code\necho hidden\n```\n\nArrows --> stay\n";
+    fs::write(&page, content).unwrap();
+    let result = runmd(&[], &page);
+    assert!(!result.contains("<!--"), "{}", result);
+    assert!(!result.contains("skipped\n```\n**Output**"), "{}", result);
+    assert!(
+        result.contains("{/* runmd:hide-code\n```bash hide-code\necho hidden\n```\n*/}\n**Output**\n```\nhidden\n```"),
+        "{}",
+        result
+    );
+    assert!(result.contains("Arrows --> stay"), "{}", result);
+}
+
+#[test]
+fn test_raw_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    // The generated Markdown holds a fence and something like an output section
+    let content = "```bash output=raw\nprintf '| a | b |\\n|---|---|\\n'\n\
+                   printf '```bash\\necho inner\\n```\\n**Output**\\n```\\nfake\\n```\\n'\n```\n\n\
+                   ```bash\necho next\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("not attached").not());
+        fs::read_to_string(&test_file).unwrap()
+    };
+
+    let result = runmd(&[]);
+    assert!(
+        result.contains(
+            "```\n**Output**\n<!-- runmd:raw -->\n| a | b |\n|---|---|\n```bash\necho inner\n```\n\
+             **Output**\n```\nfake\n```\n<!-- /runmd:raw -->\n\n```bash\necho next\n```\n**Output**\n```\nnext\n```"
+        ),
+        "{}",
+        result
+    );
+    assert_eq!(runmd(&[]), result);
+    assert_eq!(runmd(&["clear"]), content);
+}
+
+#[test]
+fn test_new_document() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("docs").join("getting-started.md");
+    let new = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg("new").arg(test_file.to_str().unwrap()).args(args);
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    new(&["--lang", "python,bash"]).success();
+    let content = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        content.starts_with("---\ntitle: Getting started\n"),
+        "{}",
+        content
+    );
+    assert!(
+        content.contains("```bash name=setup quiet\n"),
+        "{}",
+        content
+    );
+    assert!(content.contains("## Python\n\n```python\n"), "{}", content);
+    assert!(content.contains("## Bash\n\n```bash\n"), "{}", content);
+
+    // The document runs as created
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("**Output**\n```\nHello from Python\n```"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("**Output**\n```\nHello from Bash\n```"),
+        "{}",
+        result
+    );
+
+    new(&[])
+        .failure()
+        .stderr(predicate::str::contains("--force"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), result);
+    new(&["--lang", "cobol", "--force"])
+        .failure()
+        .stderr(predicate::str::contains("cobol"));
+    new(&["--title", "Guide", "--force"]).success();
+    let content = fs::read_to_string(&test_file).unwrap();
+    assert!(content.contains("# Guide\n"), "{}", content);
+    assert!(!content.contains("python"), "{}", content);
+}
+
+#[test]
+fn test_explicit_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "explicit_run: true\n");
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash run\necho ran\n```\n\n```bash\necho illustrative\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert_eq!(
+        fs::read_to_string(&test_file).unwrap(),
+        "```bash run\necho ran\n```\n**Output**\n```\nran\n```\n\n```bash\necho illustrative\n```\n"
+    );
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg("coverage")
+        .arg(test_file.to_str().unwrap());
+    cmd.assert().success().stdout(predicate::str::contains(
+        "test.md:5: bash block not executed: not marked run",
+    ));
+
+    // The command-line flag turns the mode on without a config
+    fs::write(&test_file, content).unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", temp_dir.path().join("none"))
+        .arg("--explicit-run")
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert!(!fs::read_to_string(&test_file)
+        .unwrap()
+        .contains("illustrative\n```\n**Output**"));
+}
+
+#[test]
+fn test_cache_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "```bash cache=true\ndate +%s%N\n```\n\n```bash\ndate +%s%N\n```\n",
+    )
+    .unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CACHE_HOME", temp_dir.path().join("cache"))
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        let content = fs::read_to_string(&test_file).unwrap();
+        let outputs: Vec<String> = content
+            .split("**Output**\n```\n")
+            .skip(1)
+            .map(|rest| rest.lines().next().unwrap().to_string())
+            .collect();
+        outputs
+    };
+
+    let first = runmd(&[]);
+    let second = runmd(&[]);
+    assert_eq!(second[0], first[0], "the cache=true block ran again");
+    assert_ne!(second[1], first[1], "the other block was cached");
+
+    let third = runmd(&["--no-cache"]);
+    assert_ne!(third[0], first[0], "--no-cache reused the output");
+}
+
+#[test]
+fn test_check_budgets() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "max_blocks: 1\nmax_block_time: 300ms\n");
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho a\n```\n\n```bash\nsleep 0.5; echo b\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    // Budgets only apply when checking
+    runmd(&[]).success();
+    runmd(&["check", "--lint-only"])
+        .failure()
+        .stdout(predicate::str::contains(
+            "test.md: 2 runnable blocks, over the max_blocks budget of 1",
+        ));
+    runmd(&["check"])
+        .failure()
+        .stdout(predicate::str::contains(
+            "test.md:5: block ran longer than the max_block_time budget of 300ms",
+        ))
+        .stderr(predicate::str::contains("blocks over max_block_time at"));
+
+    // The document's front matter replaces the config's budgets
+    let result = fs::read_to_string(&test_file).unwrap();
+    fs::write(
+        &test_file,
+        format!(
+            "---\nrunmd:\n  max_blocks: 2\n  max_block_time: 5s\n---\n{}",
+            result
+        ),
+    )
+    .unwrap();
+    runmd(&["check"]).success();
+}
+
+#[test]
+fn test_command_override_attributes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    // An interpreter announcing itself before running the block with bash
+    let wrapper = temp_dir.path().join("wrapper");
+    fs::write(&wrapper, "#!/bin/sh\necho wrapped\nexec bash \"$@\"\n").unwrap();
+    fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(
+        &test_file,
+        format!(
+            "```bash with={}\necho bash\n```\n\n\
+         ```python cmd=\"sh -c 'echo overridden; python3 {{file}}'\"\nprint('python')\n```\n\n\
+         ```awk cmd=\"awk -f {{file}}\"\nBEGIN {{ print \"awk ran\" }}\n```\n\n\
+         ```bash cmd=x with=y\necho never\n```\n",
+            wrapper.display()
+        ),
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    let outputs: Vec<&str> = result
+        .split("**Output**\n```\n")
+        .skip(1)
+        .map(|rest| rest.split("```").next().unwrap())
+        .collect();
+    assert_eq!(outputs[0], "wrapped\nbash\n");
+    assert_eq!(outputs[1], "overridden\npython\n");
+    assert_eq!(outputs[2], "awk ran\n");
+    assert!(
+        outputs[3].contains("give either cmd= or with=, not both"),
+        "{}",
+        result
+    );
+}
+
+#[test]
+fn test_dedup_outputs() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "dedup_outputs: true\n");
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash name=setup\nseq 3\n```\n\n```bash\necho other\n```\n\n\
+                   ```python\nfor i in range(1, 4):\n    print(i)\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("not attached").not());
+        fs::read_to_string(&test_file).unwrap()
+    };
+
+    let result = runmd(&[]);
+    assert!(
+        result.contains("**Output**\n```\n1\n2\n3\n```"),
+        "{}",
+        result
+    );
+    assert!(
+        result.ends_with("    print(i)\n```\n**Output**\n*(same output as block \"setup\")*\n"),
+        "{}",
+        result
+    );
+    assert!(result.contains("**Output**\n```\nother\n```"), "{}", result);
+
+    assert_eq!(runmd(&["check"]), result);
+    assert_eq!(runmd(&["clear"]), content);
+}
+
+#[test]
+fn test_tags_filter() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho plain\n```\n\n```bash tags=slow\necho slow\n```\n\n\
+                   ```bash tags=slow,network\necho network\n```\n";
+    let run = |args: &[&str]| {
+        fs::write(&test_file, content).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        let result = fs::read_to_string(&test_file).unwrap();
+        ["plain", "slow", "network"]
+            .into_iter()
+            .filter(|name| result.contains(&format!("**Output**\n```\n{}\n```", name)))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(run(&[]), ["plain", "slow", "network"]);
+    assert_eq!(run(&["--exclude-tags", "slow"]), ["plain"]);
+    assert_eq!(run(&["--tags", "network"]), ["network"]);
+    assert_eq!(
+        run(&["--tags", "slow", "--exclude-tags", "network"]),
+        ["slow"]
+    );
+}
+
+#[test]
+fn test_error_line_numbers() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "# Errors\n\nSome text.\n\n```python\ndef f():\n    return y\n\nf()\n```\n\n\
+         ```python quiet\nraise ValueError(\"boom\")\n```\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("File \"<document>\", line 13"));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("File \"<document>\", line 9, in <module>"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("File \"<document>\", line 7, in f"),
+        "{}",
+        result
+    );
+    assert!(!result.contains(".tmp"), "{}", result);
+}
+
+#[test]
+fn test_error_summary() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "error_summary: true\n");
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "# Errors\n\n```python\nx = 1\nprint(y)\n```\n\n\
+         ```bash\necho before\nnot_a_command_xyz\n```\n\n```bash\nexit 3\n```\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("```\nFirst error at line 5: NameError: name 'y' is not defined\n\n"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains(
+            "```\nFirst error at line 10: not_a_command_xyz: command not found\n\nbefore\n```"
+        ),
+        "{}",
+        result
+    );
+    assert_eq!(result.matches("First error").count(), 2, "{}", result);
+}
+
+#[test]
+fn test_interactive_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "```bash interactive\n[ -t 0 ] && [ -t 1 ] && echo terminal || echo pipe\n```\n\n\
+         ```bash interactive capture=split\necho split\n```\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("**Output**\n```\nterminal\n```"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("[error] interactive cannot be combined with capture="),
+        "{}",
+        result
+    );
+}
+
+#[test]
+fn test_attach_runs_blocks_in_tmux() {
+    if std::process::Command::new("tmux")
+        .arg("-V")
+        .output()
+        .is_err()
+    {
+        return;
+    }
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "```bash\necho hello\n[ -t 1 ] && echo terminal\n```\n\n```bash\necho failing\nexit 3\n```\n\n\
+         ```bash env=GREETING=hi args=\"one two\" stdin=typed timeout=5s\n\
+         echo \"$GREETING $2 $(cat) $(basename \"$PWD\")\"\n```\n\n\
+         ```text cmd=\"cat {file}\"\nas is\n```\n",
+    )
+    .unwrap();
+    let cwd = temp_dir.path().join("cwd");
+    fs::create_dir(&cwd).unwrap();
+
+    // A tmux server of its own, whether or not the tests run inside tmux
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env_remove("TMUX")
+        .env("TMUX_TMPDIR", temp_dir.path())
+        .current_dir(&cwd)
+        .args(["--attach", test_file.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(20));
+    let watch = predicate::str::contains("watch with: tmux attach -t runmd-test-");
+    cmd.assert()
+        .success()
+        .stderr(watch)
+        .stderr(predicate::str::contains("timeout is ignored with --attach"));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("**Output**\n```\nhello\nterminal\n```"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("**Output**\n```\nfailing\n```"),
+        "{}",
+        result
+    );
+    // Attributes shape the command as they do without --attach
+    assert!(
+        result.contains("**Output**\n```\nhi two typed cwd\n```"),
+        "{}",
+        result
+    );
+    assert!(result.contains("**Output**\n```\nas is\n```"), "{}", result);
+
+    // The session is gone, and the server with it
+    let sessions = std::process::Command::new("tmux")
+        .env_remove("TMUX")
+        .env("TMUX_TMPDIR", temp_dir.path())
+        .arg("ls")
+        .output()
+        .unwrap();
+    assert!(!String::from_utf8_lossy(&sessions.stdout).contains("runmd-test-"));
+}
+
+#[test]
+fn test_output_lang_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash output-lang=json\necho '{\"ok\": true}'\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        fs::read_to_string(&test_file).unwrap()
+    };
+
+    let result = runmd(&[]);
+    assert!(
+        result.ends_with("```\n**Output**\n```json\n{\"ok\": true}\n```\n"),
+        "{}",
+        result
+    );
+    assert_eq!(runmd(&[]), result);
+    assert_eq!(runmd(&["clear"]), content);
+}
+
+#[test]
+fn test_record_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash record name=demo\necho one; sleep 0.1; echo two\n```\n\n\
+                   ```bash\necho plain\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        fs::read_to_string(&test_file).unwrap()
+    };
+
+    let result = runmd(&[]);
+    assert!(
+        result.contains("**Output**\n```\none\ntwo\n```\n[▶ Recording](casts/demo.cast)\n"),
+        "{}",
+        result
+    );
+    assert_eq!(result.matches("Recording").count(), 1, "{}", result);
+
+    let cast = fs::read_to_string(temp_dir.path().join("casts/demo.cast")).unwrap();
+    let lines: Vec<&str> = cast.lines().collect();
+    assert_eq!(lines[0], r#"{"version":2,"width":80,"height":24}"#);
+    assert!(lines[1].ends_with(r#","o","one\r\n"]"#), "{}", cast);
+    assert!(
+        lines.last().unwrap().ends_with(r#","o","two\r\n"]"#),
+        "{}",
+        cast
+    );
+
+    assert_eq!(runmd(&[]), result);
+    assert_eq!(runmd(&["clear"]), content);
+}
+
+#[test]
+fn test_id_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let report_file = temp_dir.path().join("report.yml");
+    let content = "```bash id=install-step\necho installed\n```\n\n\
+                   ```bash id=install-step\necho again\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["--report", report_file.to_str().unwrap()])
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success().stderr(predicate::str::contains(
+        "line 5: id 'install-step' is already used by the block at line 1",
+    ));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("```\n<a id=\"install-step\"></a>**Output**\n```\ninstalled\n```"),
+        "{}",
+        result
+    );
+    let report = fs::read_to_string(&report_file).unwrap();
+    assert!(report.contains("id: install-step"), "{}", report);
+
+    // The anchored header is still recognised as an output section
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["check", test_file.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["clear", test_file.to_str().unwrap()]);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+}
+
+#[test]
+fn test_multiple_files_and_patterns() {
+    let temp_dir = TempDir::new().unwrap();
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    fs::write(docs.join("a.md"), "```bash\necho alpha\n```\n").unwrap();
+    fs::write(docs.join("b.md"), "```bash\necho beta\n```\n").unwrap();
+    let readme = temp_dir.path().join("README.md");
+    fs::write(&readme, "```bash\nexit 3\n```\n").unwrap();
+
+    // The quoted pattern reaches runmd unexpanded
+    let pattern = docs.join("*.md");
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args([pattern.to_str().unwrap(), readme.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("a.md"))
+        .stdout(predicate::str::contains("b.md"))
+        .stdout(predicate::str::contains("README.md"));
+    assert!(fs::read_to_string(docs.join("a.md"))
+        .unwrap()
+        .contains("alpha"));
+    assert!(fs::read_to_string(docs.join("b.md"))
+        .unwrap()
+        .contains("beta"));
+
+    // A failing block fails the run with --fail-on-error, whether one path or
+    // several are given, and only then
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["run", "--fail-on-error", pattern.to_str().unwrap()])
+        .arg(readme.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("1 of 3 paths failed"))
+        .stderr(predicate::str::contains("Blocks failed in"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["run", "--fail-on-error", readme.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Blocks failed in"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["run", readme.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    fs::write(&readme, "```bash\necho fixed\n```\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    let first = docs.join("a.md");
+    cmd.args(["run", "--fail-on-error", first.to_str().unwrap()])
+        .arg(readme.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    fs::write(&readme, "```bash\nexit 3\n```\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["check", pattern.to_str().unwrap(), readme.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("1 of 3 paths failed"))
+        .stderr(predicate::str::contains("README.md"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(docs.join("*.txt").to_str().unwrap());
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("No files match"));
+}
+
+#[test]
+fn test_exempt_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "timeout: 300ms\nmax_block_time: 300ms\nexempt: [slow]\n",
+    );
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash name=slow\nsleep 0.8; echo done\n```\n\n\
+                   ```bash name=other\nsleep 0.8; echo late\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    runmd(&[]).success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\ndone\n```"), "{}", result);
+    assert!(result.contains("execution timed out"), "{}", result);
+    assert!(!result.contains("\nlate\n"), "{}", result);
+
+    // Only the block that isn't exempt is over the budget
+    runmd(&["check"])
+        .failure()
+        .stdout(predicate::str::contains("test.md:1: block ran longer").not())
+        .stderr(predicate::str::contains("blocks failed in"));
+}
+
+#[test]
+fn test_min_free_space() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho hello\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |config: &str| {
+        let config_dir = write_config(&temp_dir, config);
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    // No disk has this much free, so the run stops before the block
+    runmd("min_free_space: 18446744073709551615\n")
+        .failure()
+        .stderr(predicate::str::contains("less than min_free_space"));
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    runmd("min_free_space: 1024\n").success();
+    assert!(fs::read_to_string(&test_file)
+        .unwrap()
+        .contains("hello\n```"));
+}
+
+#[test]
+fn test_recursive_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let site = temp_dir.path().join("site");
+    for dir in ["guides/deep", "guides/vendor", ".hidden"] {
+        fs::create_dir_all(site.join(dir)).unwrap();
+    }
+    let pages = [
+        "index.md",
+        "guides/intro.md",
+        "guides/intro-draft.md",
+        "guides/deep/nested.md",
+        "guides/vendor/lib.md",
+        ".hidden/secret.md",
+    ];
+    for page in pages {
+        fs::write(site.join(page), format!("```bash\necho {}\n```\n", page)).unwrap();
+    }
+    fs::write(site.join("guides/.runmdignore"), "vendor/\n").unwrap();
+    let ran = |page: &str| {
+        fs::read_to_string(site.join(page))
+            .unwrap()
+            .contains("**Output**")
+    };
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["run", "--recursive", "--exclude", "*-draft.md"])
+        .arg(site.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert!(ran("index.md"));
+    assert!(ran("guides/intro.md"));
+    assert!(ran("guides/deep/nested.md"));
+    assert!(!ran("guides/intro-draft.md"));
+    assert!(!ran("guides/vendor/lib.md"));
+    assert!(!ran(".hidden/secret.md"));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["coverage", "-r", "--include", "guides/*"])
+        .arg(site.to_str().unwrap());
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Total: 3 of 3 blocks executed"));
+}
+
+#[test]
+fn test_run_to_stdout_or_output_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let out_file = temp_dir.path().join("out.md");
+    let content = "```bash\necho hello\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let expected = "```bash\necho hello\n```\n**Output**\n```\nhello\n```\n";
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args([test_file.to_str().unwrap(), "--stdout"]);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success().stdout(expected);
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["run", "-o", out_file.to_str().unwrap()])
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&out_file).unwrap(), expected);
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), content);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["run", "--stdout", temp_dir.path().to_str().unwrap()]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("take a single document"));
+}
+
+#[test]
+fn test_stable_environment() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash\necho \"[$LC_ALL $COLUMNS $TERM]\"\n```\n\n\
+                   ```bash stable=true env=COLUMNS=120\necho \"<$LC_ALL $COLUMNS $TERM>\"\n```\n\n\
+                   ```bash stable=false\necho \"{$LC_ALL}\"\n```\n";
+    fs::write(&test_file, content).unwrap();
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("LC_ALL", "en_US.UTF-8")
+            .env("COLUMNS", "200")
+            .env("TERM", "xterm-256color")
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success()
+    };
+
+    runmd(&["--stdout"])
+        .stdout(predicate::str::contains("[en_US.UTF-8 200 xterm-256color]"))
+        .stdout(predicate::str::contains("<C 120 dumb>"));
+
+    runmd(&["--hermetic", "--stdout"])
+        .stdout(predicate::str::contains("[C 80 dumb]"))
+        .stdout(predicate::str::contains("{en_US.UTF-8}"));
+
+    // Output cached without --hermetic isn't reused with it
+    fs::write(&test_file, "```bash cache=true\necho \"[$LC_ALL]\"\n```\n").unwrap();
+    let cache = temp_dir.path().join("cache");
+    let cached = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("LC_ALL", "en_US.UTF-8")
+            .env("XDG_CACHE_HOME", &cache)
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success()
+    };
+    cached(&["--stdout"]).stdout(predicate::str::contains("[en_US.UTF-8]"));
+    cached(&["--hermetic", "--stdout"]).stdout(predicate::str::contains("[C]"));
+}
+
+#[test]
+fn test_stdin_attribute() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "```bash stdin=\"Ada\\nLovelace\\n\"\nread first\nread last\necho \"Hello, $first $last\"\n```\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("Hello, Ada Lovelace"), "{}", result);
+
+    // Longer input can come from a file next to the document
+    fs::write(temp_dir.path().join("answers.txt"), "Grace\nHopper\n").unwrap();
+    fs::write(
+        &test_file,
+        "```bash stdin-file=answers.txt\nread first\nread last\necho \"Hello, $first $last\"\n```\n\n\
+         ```bash stdin-file=missing.txt\ncat\n```\n",
+    )
+    .unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("Hello, Grace Hopper"), "{}", result);
+    assert!(result.contains("[error] Failed to read stdin-file"), "{}", result);
+
+    // Files outside the document's directory can't be read
+    let docs = temp_dir.path().join("docs");
+    fs::create_dir(&docs).unwrap();
+    let secret = temp_dir.path().join("secret.txt");
+    fs::write(&secret, "hunter2\n").unwrap();
+    let outside = docs.join("outside.md");
+    fs::write(
+        &outside,
+        format!(
+            "```bash stdin-file=../secret.txt\ncat\n```\n\n```bash stdin-file={}\ncat\n```\n",
+            secret.display()
+        ),
+    )
+    .unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(outside.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&outside).unwrap();
+    assert!(!result.contains("hunter2"), "{}", result);
+    let refused = result.matches("is outside the document directory");
+    assert_eq!(refused.count(), 2, "{}", result);
+
+    // Cached output is only reused for the same input
+    fs::write(
+        &test_file,
+        "```bash stdin-file=answers.txt cache=true\ncat\n```\n",
+    )
+    .unwrap();
+    for answers in ["Grace\n", "Ada\n"] {
+        fs::write(temp_dir.path().join("answers.txt"), answers).unwrap();
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CACHE_HOME", temp_dir.path().join("cache"))
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        let result = fs::read_to_string(&test_file).unwrap();
+        assert!(result.contains(&format!("```\n{}```", answers)), "{}", result);
+    }
+
+    // A terminal never reaches end of input, so a prompt without an answer is
+    // reported long before the block's time limit
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    fs::write(
+        &test_file,
+        "```bash capture=pty timeout=30s\nread -p 'Name? ' name\necho \"Hi $name\"\n```\n",
+    )
+    .unwrap();
+    let started = std::time::Instant::now();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.args(["check", test_file.to_str().unwrap()]);
+    cmd.timeout(std::time::Duration::from_secs(20));
+    cmd.assert().failure();
+    assert!(started.elapsed() < std::time::Duration::from_secs(15));
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(20));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("block appears to be waiting for input"), "{}", result);
+}
+
+#[test]
+fn test_matrix_blocks() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let code = "if [ -n \"$BASH_VERSION\" ]; then echo bash; else echo posix; fi\n";
+    fs::write(&test_file, format!("```bash matrix=bash,dash\n{}```\n", code)).unwrap();
+
+    let runmd = || {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    // `bash` is a configured language; `dash` replaces the bash interpreter
+    runmd().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("```\n[bash]\nbash\n[dash]\nposix\n```"),
+        "{}",
+        result
+    );
+
+    // One failing variant fails the block
+    fs::write(
+        &test_file,
+        format!("```bash matrix=bash,no-such-shell\n{}```\n", code),
+    )
+    .unwrap();
+    runmd()
+        .success()
+        .stdout(predicate::str::contains("bash  1 run  1 failed"));
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("[no-such-shell]\n[error]"), "{}", result);
+}
+
+#[test]
+fn test_per_os_outputs() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    // The output recorded on another platform sorts after this one's
+    fs::write(
+        &test_file,
+        "```bash per-os\necho here\n```\n**Output (~other)**\n```\nthere\n```\n\nAfter.\n",
+    )
+    .unwrap();
+
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    runmd(&[]).success();
+    let expected = format!(
+        "```bash per-os\necho here\n```\n**Output ({})**\n```\nhere\n```\n\
+         **Output (~other)**\n```\nthere\n```\n\nAfter.\n",
+        std::env::consts::OS
+    );
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), expected);
+
+    // Running again replaces only this platform's output
+    runmd(&[]).success();
+    assert_eq!(fs::read_to_string(&test_file).unwrap(), expected);
+    runmd(&["check"]).success();
+
+    runmd(&["clear"]).success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(!result.contains("\nhere\n"), "{}", result);
+    assert!(result.contains("**Output (~other)**\n```\nthere\n```"), "{}", result);
+}
+
+#[test]
+fn test_output_header() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = temp_dir.path().join("config");
+    let set_header = |header: &str| {
+        write_config(&temp_dir, format!("output_header: \"{}\"\n", header));
+    };
+    let test_file = temp_dir.path().join("test.md");
+    fs::write(
+        &test_file,
+        "```bash\necho hallo\n```\n**Output**\n```\nhello\n```\n\nText.\n",
+    )
+    .unwrap();
+
+    let runmd = || {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        fs::read_to_string(&test_file).unwrap()
+    };
+
+    set_header("**Ausgabe**");
+    assert_eq!(
+        runmd(),
+        "```bash\necho hallo\n```\n**Ausgabe** <!-- runmd:output -->\n```\nhallo\n```\n\nText.\n"
+    );
+
+    // The marker identifies the section after the header changes again
+    set_header("**結果**");
+    assert_eq!(
+        runmd(),
+        "```bash\necho hallo\n```\n**結果** <!-- runmd:output -->\n```\nhallo\n```\n\nText.\n"
+    );
+}
+
+#[test]
+fn test_file_changed_during_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    // The block stands in for an editor saving the document mid-run
+    fs::write(
+        &test_file,
+        format!(
+            "```bash\necho 'New notes.' >> '{}'\necho done\n```\n",
+            test_file.display()
+        ),
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("changed on disk while its blocks ran"));
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.ends_with("```\nNew notes.\n"), "{}", result);
+    let new = fs::read_to_string(temp_dir.path().join("test.md.new")).unwrap();
+    assert!(new.contains("**Output**\n```\ndone\n```"), "{}", new);
+}
+
+#[test]
+fn test_produces_consumes_ordering() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "max_parallel: 4\n");
+    let test_file = temp_dir.path().join("test.md");
+
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .current_dir(temp_dir.path())
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    // The consumer comes first in the document but runs after its producer
+    fs::write(
+        &test_file,
+        "```bash consumes=data.csv\nwc -l < data.csv\n```\n\n\
+         ```bash produces=data.csv\nsleep 0.3\nseq 1 5 > data.csv\n```\n",
+    )
+    .unwrap();
+    for args in [&[][..], &["--parallel"][..]] {
+        let _ = fs::remove_file(temp_dir.path().join("data.csv"));
+        runmd(args).success();
+        let result = fs::read_to_string(&test_file).unwrap();
+        assert!(result.contains("**Output**\n```\n5\n```"), "{}", result);
+    }
+
+    fs::write(
+        &test_file,
+        "```bash consumes=a produces=b\ntrue\n```\n\n```bash consumes=b produces=a\ntrue\n```\n",
+    )
+    .unwrap();
+    runmd(&[])
+        .failure()
+        .stderr(predicate::str::contains("Blocks at lines 1, 5 can't be ordered"));
+}
+
+#[test]
+fn test_trace_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let trace = temp_dir.path().join("trace");
+    fs::write(
+        &test_file,
+        "```bash\necho \"out $TRACE_ME\"\necho err >&2\nexit 3\n```\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("TRACE_ME", "yes")
+        .args(["--trace", trace.to_str().unwrap()])
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let block = trace.join("test.md/line-1-bash");
+    let read = |name: &str| fs::read_to_string(block.join(name)).unwrap();
+    assert!(read("source.sh").contains("echo err >&2"));
+    assert!(read("command").starts_with("bash "), "{}", read("command"));
+    assert!(read("env").contains("TRACE_ME=yes\n"));
+    assert_eq!(read("stdout"), "out yes\n");
+    assert_eq!(read("stderr"), "err\n");
+    assert_eq!(read("output"), "out yes");
+    assert_eq!(read("exit_code"), "3\n");
+}
+
+#[test]
+fn test_nested_runmd() {
+    let temp_dir = TempDir::new().unwrap();
+    let runmd_bin = assert_cmd::cargo::cargo_bin("runmd");
+    let outer = temp_dir.path().join("outer.md");
+    let inner = temp_dir.path().join("inner.md");
+    fs::write(&inner, "```bash\necho \"inner at depth $RUNMD_DEPTH\"\n```\n").unwrap();
+    // One block runs another document, the other the document containing it
+    fs::write(
+        &outer,
+        format!(
+            "```bash\n'{bin}' '{inner}' > /dev/null && grep '^inner' '{inner}'\n```\n\n\
+             ```bash\n'{bin}' '{outer}' 2>&1 | grep -o 'already being processed.*'\n```\n",
+            bin = runmd_bin.display(),
+            inner = inner.display(),
+            outer = outer.display()
+        ),
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(outer.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(20));
+    cmd.assert().success();
+    let result = fs::read_to_string(&outer).unwrap();
+    // Blocks of the inner document are enclosed by two runmd processes
+    assert!(result.contains("```\ninner at depth 2\n```"), "{}", result);
+    assert!(
+        result.contains("```\nalready being processed by the runmd running this block\n```"),
+        "{}",
+        result
+    );
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("RUNMD_DEPTH", "3").arg(inner.to_str().unwrap());
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("runmd is nested 3 levels deep"));
+}
+
+#[test]
+fn test_output_labels() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let runmd = |labels: &str, args: &[&str]| {
+        let config_dir = write_config(&temp_dir, format!("output_labels: {}\n", labels));
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.env("XDG_CONFIG_HOME", &config_dir)
+            .args(args)
+            .arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert().success();
+        fs::read_to_string(&test_file).unwrap()
+    };
+    let content = "```bash name=setup\necho a\n```\n\n```bash\necho b\n```\n\n\
+                   ```bash name=\"demo (linux)\"\necho c\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let result = runmd("number", &[]);
+    assert!(result.contains("**Output 1**\n```\na\n```"), "{}", result);
+    assert!(result.contains("**Output 2**\n```\nb\n```"), "{}", result);
+    assert!(result.contains("**Output 3**\n```\nc\n```"), "{}", result);
+
+    // A name in parentheses is a label, not a platform
+    let result = runmd("name", &[]);
+    assert!(result.contains("**Output: setup**\n```\na\n```"), "{}", result);
+    assert!(result.contains("**Output 2**\n```\nb\n```"), "{}", result);
+    assert!(result.contains("**Output: demo (linux)**\n```\nc\n```"), "{}", result);
+    runmd("name", &["check"]);
+
+    // Labelled outputs are replaced or cleared after the setting is turned off
+    let result = runmd("off", &[]);
+    assert_eq!(result.matches("**Output**").count(), 3, "{}", result);
+    runmd("name", &[]);
+    assert_eq!(runmd("off", &["clear"]), content);
+}
+
+#[test]
+fn test_output_filters() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(
+        &temp_dir,
+        "output_filters:\n  bash: [python-deprecation, jvm-java-options]\n",
+    );
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```shell\n\
+                   echo '/usr/lib/app.py:3: DeprecationWarning: old API'\n\
+                   echo '  import imp'\n\
+                   echo 'Picked up _JAVA_OPTIONS: -Xmx1g'\n\
+                   echo result\n\
+                   echo '  indented result'\n\
+                   ```\n";
+    fs::write(&test_file, content).unwrap();
+
+    // Filters configured for a language apply to its aliases
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("**Output**\n```\nresult\n  indented result\n```"),
+        "{}",
+        result
+    );
+
+    write_config(&temp_dir, "output_filters:\n  bash: [no-such-filter]\n");
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Unknown output filter 'no-such-filter'"));
+}
+
+#[test]
+fn test_progress_output() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let code = "printf 'start\\n'\nfor i in 10 50 100; do printf '\\rDownloading %3s%%' $i; done\n\
+                printf '\\n'\nprintf 'working...\\r\\033[Kdone\\n'\n";
+    fs::write(
+        &test_file,
+        format!("```bash\n{code}```\n\n```bash progress=frames\n{code}```\n"),
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(
+        result.contains("**Output**\n```\nstart\nDownloading 100%\ndone\n```"),
+        "{}",
+        result
+    );
+    assert!(
+        result.contains("start\n\nDownloading  10%\nDownloading  50%\nDownloading 100%\n"),
+        "{}",
+        result
+    );
+}
+
+#[test]
+fn test_failure_digest() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let digest = temp_dir.path().join("digest.md");
+    let content = "```bash name=broken\necho progress\nfor i in 1 2 3 4 5 6 7; do echo \"problem $i\" >&2; done\nexit 1\n```\n\n\
+                   ```bash\necho fine\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--report")
+        .arg(format!("digest=md:{}", digest.display()))
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    // Failures are summarized with the first lines of their stderr
+    let digest = fs::read_to_string(&digest).unwrap();
+    assert!(
+        digest.starts_with("**runmd: 1 of 2 blocks failed in 1 of 1 documents**\n"),
+        "{}",
+        digest
+    );
+    assert!(digest.contains("test.md` line 1 (bash \"broken\")\n```\nproblem 1\n"), "{}", digest);
+    assert!(digest.contains("problem 5\n... 2 more lines\n```"), "{}", digest);
+    assert!(!digest.contains("progress"), "{}", digest);
+
+    // Printed after the run when no path is given
+    fs::write(&test_file, "```bash\necho fine\n```\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--report").arg("digest=md").arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("**runmd: all 1 blocks passed in 1 documents**"));
+}
+
+#[test]
+fn test_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::create_dir_all(temp_dir.path().join("docs")).unwrap();
+    let first = temp_dir.path().join("docs/first.md");
+    let second = temp_dir.path().join("docs/second.md");
+    fs::write(
+        &first,
+        "```bash name=greet\necho \"greeting: $GREETING\"\n```\n\n\
+         ```bash name=other\necho fresh\n```\n**Output**\n```\nkept\n```\n",
+    )
+    .unwrap();
+    fs::write(&second, "```bash\necho one\n```\n\n```python\nprint('two')\n```\n").unwrap();
+    let manifest = temp_dir.path().join("runmd.jobs.yml");
+    fs::write(
+        &manifest,
+        "profiles:\n  shell:\n    allowed_languages: [bash]\n\
+         jobs:\n\
+         \x20 - file: docs/first.md\n    select: [name=greet]\n    env:\n      GREETING: hello\n\
+         \x20 - file: docs/second.md\n    profile: shell\n\
+         report: report.yml\n",
+    )
+    .unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("--manifest").arg(manifest.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    // Only the selected block ran; the other kept its output
+    let result = fs::read_to_string(&first).unwrap();
+    assert!(result.contains("```\ngreeting: hello\n```"), "{}", result);
+    assert!(result.contains("```\nkept\n```"), "{}", result);
+    assert!(!result.contains("```\nfresh\n```"), "{}", result);
+    // The profile left python out
+    let result = fs::read_to_string(&second).unwrap();
+    assert!(result.contains("```\none\n```"), "{}", result);
+    assert!(!result.contains("```\ntwo\n```"), "{}", result);
+
+    let report = fs::read_to_string(temp_dir.path().join("report.yml")).unwrap();
+    assert!(report.contains("first.md:"), "{}", report);
+    assert!(report.contains("second.md:"), "{}", report);
+
+    // A failing job doesn't stop the others, but fails the run
+    fs::write(&first, "```bash name=greet\nexit 1\n```\n").unwrap();
+    fs::write(&second, "```bash\necho again\n```\n").unwrap();
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("check").arg("--manifest").arg(manifest.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("2 of 2 jobs failed"));
+}
+
+#[test]
+fn test_coverage() {
+    let temp_dir = TempDir::new().unwrap();
+    let content = "# Guide\n```bash\necho run\n```\n\n```bash -nr\necho skipped\n```\n\n\
+                   ```cobol\nDISPLAY 'HI'.\n```\n\n```\nplain text\n```\n\n\
+                   ## Appendix\n```python\nprint(1)\n```\n";
+    fs::write(temp_dir.path().join("guide.md"), content).unwrap();
+    fs::write(temp_dir.path().join("other.md"), "```bash\necho ok\n```\n").unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("coverage")
+        .arg("--skip-section")
+        .arg("Appendix")
+        .arg(temp_dir.path().to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("guide.md:6: bash block not executed: marked -nr"))
+        .stdout(predicate::str::contains(
+            "guide.md:10: cobol block not executed: no command configured",
+        ))
+        .stdout(predicate::str::contains(
+            "guide.md:19: python block not executed: under a skipped section",
+        ))
+        .stdout(predicate::str::contains("guide.md: 1 of 4 blocks executed (25%)"))
+        .stdout(predicate::str::contains("Total: 2 of 5 blocks executed (40%)"));
+    // Nothing ran
+    assert_eq!(fs::read_to_string(temp_dir.path().join("guide.md")).unwrap(), content);
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg("coverage")
+        .arg("--fail-under")
+        .arg("70")
+        .arg(temp_dir.path().to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Only 60% of blocks execute"));
+}
+
+#[test]
+fn test_custom_attributes() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash x-region=eu-west x-max-retries=\"3 times\"\n\
+                   echo \"$RUNMD_ATTR_REGION/$RUNMD_ATTR_MAX_RETRIES\"\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("unknown attribute").not());
+
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.starts_with("```bash x-region=eu-west x-max-retries=\"3 times\"\n"));
+    assert!(result.contains("```\neu-west/3 times\n```"), "{}", result);
+}
+
+#[test]
+fn test_block_env() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let content = "```bash env=GREETING=hi,TARGET=a=b\necho \"$GREETING $TARGET\"\n```\n\n\
+                   ```bash\necho \"[${GREETING:-unset}]\"\n```\n\n\
+                   ```bash env=BROKEN\necho ran\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success().stderr(predicate::str::contains(
+        "attribute 'env': expected NAME=value, got 'BROKEN'",
+    ));
+
+    // Variables apply to their own block only
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("```\nhi a=b\n```"), "{}", result);
+    assert!(result.contains("```\n[unset]\n```"), "{}", result);
+}
+
+#[test]
+fn test_detect_languages() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_dir = write_config(&temp_dir, "detect_languages: true\n");
+    let test_file = temp_dir.path().join("test.md");
+    let report_file = temp_dir.path().join("report.yml");
+    let content = "```\n#!/usr/bin/env bash\necho \"from $0\" | grep -o from\n```\n\n\
+                   ```\nif [ -d / ]; then\n  echo root exists\nfi\n```\n\n\
+                   ```\nplain text stays put\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let mut cmd = runmd_command(&temp_dir);
+    cmd.env("XDG_CONFIG_HOME", &config_dir)
+        .arg("--report")
+        .arg(report_file.to_str().unwrap())
+        .arg(test_file.to_str().unwrap());
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    // Detected blocks run but their fences stay unlabeled
+    let result = fs::read_to_string(&test_file).unwrap();
+    assert!(result.contains("**Output**\n```\nfrom\n```"), "{}", result);
+    assert!(result.contains("**Output**\n```\nroot exists\n```"), "{}", result);
+    assert!(!result.contains("```bash"), "{}", result);
+    assert_eq!(result.matches("**Output**").count(), 2, "{}", result);
+    let report = fs::read_to_string(&report_file).unwrap();
+    assert_eq!(report.matches("detected: true").count(), 2, "{}", report);
+}
+
+#[test]
+fn test_expect_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let test_file = temp_dir.path().join("test.md");
+    let golden = temp_dir.path().join("expected/listing.txt");
+    let content = "```bash expect-file=expected/listing.txt\nseq 1 3\n```\n";
+    fs::write(&test_file, content).unwrap();
+
+    let runmd = |args: &[&str]| {
+        let mut cmd = runmd_command(&temp_dir);
+        cmd.args(args).arg(test_file.to_str().unwrap());
+        cmd.timeout(std::time::Duration::from_secs(10));
+        cmd.assert()
+    };
+
+    // Running writes the golden file and leaves the document without an output
+    runmd(&[]).success();
+    assert_eq!(fs::read_to_string(&golden).unwrap(), "1\n2\n3\n");
+    assert!(!fs::read_to_string(&test_file).unwrap().contains("**Output**"));
+    runmd(&["check"]).success();
+
+    // Checking compares without writing
+    fs::write(&golden, "1\n2\n").unwrap();
+    runmd(&["check"])
+        .failure()
+        .stderr(predicate::str::contains("line 1 bash: output differs from"))
+        .stderr(predicate::str::contains("outputs out of date"));
+    assert_eq!(fs::read_to_string(&golden).unwrap(), "1\n2\n");
+}